@@ -0,0 +1,52 @@
+//! Benchmarks for guest/host ID translation.
+//!
+//! This does not use the unstable `test` crate, so it runs on stable Rust:
+//!
+//! ```text
+//! cargo bench --features bench --bench maps
+//! ```
+
+use notification_emitter::maps::{GuestId, HostId, Maps};
+use std::hint::black_box;
+use std::time::Instant;
+
+fn host(id: u32) -> HostId {
+    HostId::new_less_safe(id).expect("IDs start at 1")
+}
+
+fn bench(name: &str, iterations: u32, f: impl FnOnce()) {
+    let start = Instant::now();
+    f();
+    let elapsed = start.elapsed();
+    println!(
+        "{:<40} {:>10.1} ns/iter",
+        name,
+        elapsed.as_nanos() as f64 / f64::from(iterations)
+    );
+}
+
+fn main() {
+    for live in [100, 10_000, 1_000_000] {
+        let mut maps = Maps::default();
+        bench(&format!("allocate, {} live", live), live, || {
+            for id in 1..=live {
+                black_box(maps.next_id(host(id), None).unwrap());
+            }
+        });
+        bench(&format!("lookup host ID, {} live", live), live, || {
+            for id in 1..=live {
+                black_box(maps.lookup_host_id(host(id)));
+            }
+        });
+        bench(&format!("lookup guest ID, {} live", live), live, || {
+            for id in 1..=live {
+                black_box(maps.lookup_guest_id(GuestId::new_less_safe(id).expect("nonzero")));
+            }
+        });
+        bench(&format!("remove, {} live", live), live, || {
+            for id in 1..=live {
+                black_box(maps.remove_host_id(host(id)));
+            }
+        });
+    }
+}
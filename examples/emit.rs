@@ -0,0 +1,34 @@
+//! Embedding the emitter: show a notification on the local notification
+//! daemon, exactly as the dom0 side of the proxy would show one from a
+//! qube, and wait for it to be closed.
+//!
+//! Run with `cargo run --example emit`.
+use futures_util::StreamExt as _;
+use notification_emitter::{NotificationBuilder, NotificationEmitter, Urgency};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> zbus::Result<()> {
+    let (emitter, _name_owner_changed) =
+        NotificationEmitter::new("example: ".to_owned(), "Example".to_owned()).await?;
+    eprintln!("Server capabilities: {}", emitter.capabilities());
+    let mut closed = emitter.closed().await?;
+    let id = emitter
+        .send_notification(
+            NotificationBuilder::new("Hello")
+                .body("This text has been sanitized.")
+                .urgency(Urgency::Normal)
+                .build()
+                .expect("notification is valid"),
+            std::time::SystemTime::now(),
+        )
+        .await?;
+    eprintln!("Sent notification {}", u32::from(id));
+    while let Some(signal) = closed.next().await {
+        let args = signal.args()?;
+        if emitter.remove_host_id(args.id) == Some(id.into()) {
+            eprintln!("Notification closed, reason {}", args.reason);
+            break;
+        }
+    }
+    Ok(())
+}
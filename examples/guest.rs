@@ -0,0 +1,74 @@
+//! Speaking the proxy protocol: send one notification to dom0 and print
+//! the replies.  This is what the guest side of the proxy does for every
+//! `Notify` call it receives.
+//!
+//! Run in a qube with
+//! `qrexec-client-vm '' qubes.Notifications target/debug/examples/guest`.
+use notification_emitter::{
+    codec::{self, WireCodec as _, WireU32},
+    merge_versions, split_version, Features, Message, MessageWriter, NegotiatedFeatures,
+    NotificationBuilder, ReplyMessage, Request, CLOSE_VERSION, MAJOR_VERSION, MINOR_VERSION,
+};
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> std::io::Result<()> {
+    let mut stdin = tokio::io::stdin();
+
+    // dom0 sends its version first.  Reply with the highest version both
+    // sides support.
+    let (major, minor) = split_version(WireU32::read(&mut stdin).await?.get());
+    if major != MAJOR_VERSION {
+        panic!("dom0 speaks protocol version {major}, not {MAJOR_VERSION}");
+    }
+    let minor = minor.min(MINOR_VERSION);
+    WireU32::new(merge_versions(MAJOR_VERSION, minor))
+        .write(&mut tokio::io::stdout())
+        .await?;
+    // Since version 13, both sides then say which optional features they
+    // want.  Only those both want are used, including the encoding.
+    let features = if NegotiatedFeatures::exchanges_masks(minor) {
+        WireU32::new(Features::all().bits())
+            .write(&mut tokio::io::stdout())
+            .await?;
+        let mask = WireU32::read(&mut stdin).await?.get();
+        NegotiatedFeatures::negotiate(minor, Features::all(), mask)
+    } else {
+        NegotiatedFeatures::from_version(minor)
+    };
+    let encoding = features.encoding();
+
+    let message = Message {
+        id: 0,
+        notification: NotificationBuilder::new("Hello from a qube")
+            .body("See https://www.qubes-os.org")
+            .action("default", "Open")
+            .transient(true)
+            .build()
+            .expect("notification is valid"),
+    };
+    // Since version 7, messages are wrapped in a request.
+    let data = if minor >= CLOSE_VERSION {
+        encoding.encode(&Request::Notify(message))
+    } else {
+        encoding.encode(&message)
+    };
+    MessageWriter::with_framing(features.framing())
+        .transmit(&data.expect("serialization cannot fail"))
+        .await;
+
+    loop {
+        let bytes = match codec::read_frame(&mut stdin, features.framing()).await {
+            Ok(Some(bytes)) => bytes,
+            Ok(None) => return Ok(()),
+            Err(e) => panic!("Malformed frame from dom0: {}", e),
+        };
+        match encoding.decode(&bytes) {
+            Ok(ReplyMessage::Dismissed { id, reason }) => {
+                println!("Notification {} dismissed, reason {}", id, reason);
+                return Ok(());
+            }
+            Ok(reply) => println!("{:?}", reply),
+            Err(e) => panic!("Malformed reply from dom0: {}", e),
+        }
+    }
+}
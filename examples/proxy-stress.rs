@@ -0,0 +1,378 @@
+//! Stress test for the dom0 side of the proxy.  Starts the server with its
+//! standard input and output connected to pipes, plays the guest side of
+//! the protocol, sends a mix of notifications as fast as the server accepts
+//! them, and checks the replies:
+//!
+//! - every call gets exactly one reply, and no reply is for an unknown call;
+//! - an `ExpireTimeout` comes right before the `Id` it belongs to;
+//! - a successful replace returns the ID it replaced;
+//! - no ID is handed out twice while it is live.
+//!
+//! The server talks to whatever notification daemon owns
+//! `org.freedesktop.Notifications` on the session bus, so run this in a
+//! throwaway session:
+//!
+//! ```text
+//! cargo build && dbus-run-session -- sh -c 'dunst & cargo run --example proxy-stress -- --count 5000'
+//! ```
+//!
+//! Options: `--server PATH` (default `target/debug/notification-proxy-server`),
+//! `--qube NAME`, `--count N`, `--seed N`, and `--mix KINDS`, a
+//! comma-separated list of `plain`, `image`, `actions`, `replace`, and
+//! `text`.  The tool exits with status 1 if any check fails.
+use bincode::Options as _;
+use notification_emitter::codec::WireU32;
+use notification_emitter::{
+    merge_versions, split_version, ExpireTimeout, Features, ImageParameters, Message,
+    NegotiatedFeatures, Notification, ReplyMessage, Request, CLOSE_VERSION, MAJOR_VERSION,
+    MAX_MESSAGE_SIZE, MINOR_VERSION,
+};
+use std::collections::{BTreeSet, HashMap};
+use std::io::{Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Plain,
+    Image,
+    Actions,
+    Replace,
+    Text,
+}
+
+impl Kind {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "plain" => Kind::Plain,
+            "image" => Kind::Image,
+            "actions" => Kind::Actions,
+            "replace" => Kind::Replace,
+            "text" => Kind::Text,
+            _ => return None,
+        })
+    }
+}
+
+/// xorshift64, so that runs can be repeated without extra dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Text that exercises the sanitizer: markup, bidi controls, long and many
+/// lines, and characters outside the BMP.
+fn pathological_text(rng: &mut Rng) -> String {
+    const PIECES: &[&str] = &[
+        "<b>bold</b>",
+        "<a href=\"https://example.com\">link</a>",
+        "&amp;&lt;&#x41;",
+        "\u{202E}reversed\u{202C}",
+        "\u{2067}isolate",
+        "\n",
+        "\t",
+        "\u{1F600}",
+        "e\u{301}",
+        "\u{0}",
+        "https://www.qubes-os.org/",
+    ];
+    let mut text = String::new();
+    for _ in 0..rng.below(200) {
+        text.push_str(PIECES[rng.below(PIECES.len())]);
+    }
+    if rng.below(4) == 0 {
+        text.push_str(&"x".repeat(20_000));
+    }
+    text
+}
+
+fn notification(kind: Kind, rng: &mut Rng, replaces_id: u32) -> Notification {
+    let (mut summary, mut body) = (format!("{:?}", kind), "stress test".to_owned());
+    let mut actions = vec![];
+    let mut image = None;
+    match kind {
+        Kind::Plain | Kind::Replace => {}
+        Kind::Image => {
+            let (width, height) = (1 + rng.below(256) as i32, 1 + rng.below(256) as i32);
+            image = Some(ImageParameters {
+                untrusted_width: width,
+                untrusted_height: height,
+                untrusted_rowstride: width * 4,
+                untrusted_has_alpha: true,
+                untrusted_bits_per_sample: 8,
+                untrusted_channels: 4,
+                untrusted_data: vec![0x80; (width * height * 4) as usize],
+            })
+        }
+        Kind::Actions => {
+            for i in 0..rng.below(64) {
+                actions.push(format!("action{}", i));
+                actions.push(format!("Action {}", i));
+            }
+        }
+        Kind::Text => {
+            summary = pathological_text(rng);
+            body = pathological_text(rng);
+        }
+    }
+    Notification::V1 {
+        suppress_sound: false,
+        transient: true,
+        resident: false,
+        urgency: None,
+        replaces_id,
+        summary,
+        body,
+        actions,
+        category: None,
+        expire_timeout: [
+            ExpireTimeout::Default,
+            ExpireTimeout::Never,
+            ExpireTimeout::millis(1),
+            ExpireTimeout::millis(1000),
+        ][rng.below(4)],
+        image,
+    }
+}
+
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let size = WireU32::from_bytes(header).get();
+    assert!(size <= MAX_MESSAGE_SIZE, "frame too large ({} bytes)", size);
+    let mut bytes = vec![0; size as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+/// What the reader has seen so far.
+#[derive(Default)]
+struct State {
+    /// Calls without a reply, with the ID they replace.
+    pending: HashMap<u64, u32>,
+    /// Live IDs.  Ordered, so that runs with the same seed replace the
+    /// same notifications as far as timing allows.
+    live: BTreeSet<u32>,
+    /// ID of the last `ExpireTimeout`, which must be followed by its `Id`.
+    expire_timeout_for: Option<u32>,
+    replies: u64,
+    errors: u64,
+    failures: Vec<String>,
+}
+
+fn main() {
+    let mut server = "target/debug/notification-proxy-server".to_owned();
+    let mut qube = "stress".to_owned();
+    let mut count = 1000u64;
+    let mut seed = 0x9E37_79B9_7F4A_7C15u64;
+    let mut mix = vec![
+        Kind::Plain,
+        Kind::Image,
+        Kind::Actions,
+        Kind::Replace,
+        Kind::Text,
+    ];
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .unwrap_or_else(|| panic!("{} requires an argument", arg))
+        };
+        match &*arg {
+            "--server" => server = value(),
+            "--qube" => qube = value(),
+            "--count" => count = value().parse().expect("invalid count"),
+            "--seed" => seed = value().parse().expect("invalid seed"),
+            "--mix" => {
+                mix = value()
+                    .split(',')
+                    .map(|name| {
+                        Kind::from_name(name).unwrap_or_else(|| panic!("Unknown kind {:?}", name))
+                    })
+                    .collect()
+            }
+            _ => panic!("Unknown argument {:?}", arg),
+        }
+    }
+
+    let mut child = Command::new(&server)
+        .env("QREXEC_REMOTE_DOMAIN", &qube)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Cannot start {}: {}", server, e));
+    let mut to_server = child.stdin.take().expect("stdin is piped");
+    let mut from_server = child.stdout.take().expect("stdout is piped");
+
+    let mut version = [0; 4];
+    from_server
+        .read_exact(&mut version)
+        .expect("Cannot read version");
+    let (major, minor) = split_version(WireU32::from_bytes(version).get());
+    assert_eq!(major, MAJOR_VERSION, "server speaks another major version");
+    let minor = minor.min(MINOR_VERSION);
+    let version = WireU32::new(merge_versions(MAJOR_VERSION, minor));
+    to_server
+        .write_all(&version.to_bytes())
+        .expect("Cannot write version");
+    if NegotiatedFeatures::exchanges_masks(minor) {
+        to_server
+            .write_all(&WireU32::new(Features::IMAGES.bits()).to_bytes())
+            .expect("Cannot write feature mask");
+        let mut mask = [0; 4];
+        from_server
+            .read_exact(&mut mask)
+            .expect("Cannot read feature mask");
+        let mask = WireU32::from_bytes(mask).get();
+        let features = NegotiatedFeatures::negotiate(minor, Features::IMAGES, mask);
+        assert!(
+            features.has(Features::IMAGES) || !mix.contains(&Kind::Image),
+            "server does not accept images"
+        );
+    }
+
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_native_endian()
+        .reject_trailing_bytes();
+    let state = Arc::new(Mutex::new(State::default()));
+    let reader_state = state.clone();
+    let reader = std::thread::spawn(move || {
+        while let Some(bytes) = read_frame(&mut from_server).expect("Cannot read from server") {
+            let reply: ReplyMessage = options.deserialize(&bytes).expect("malformed reply");
+            let mut state = reader_state.lock().unwrap();
+            if let Some(id) = state.expire_timeout_for.take() {
+                if !matches!(reply, ReplyMessage::Id { id: i, .. } if i == id) {
+                    state
+                        .failures
+                        .push(format!("ExpireTimeout for {} not followed by its Id", id));
+                }
+            }
+            match reply {
+                ReplyMessage::ExpireTimeout { id, .. } => state.expire_timeout_for = Some(id),
+                ReplyMessage::Id { id, sequence } => match state.pending.remove(&sequence) {
+                    None => state
+                        .failures
+                        .push(format!("Id for unknown call {}", sequence)),
+                    Some(replaced) => {
+                        state.replies += 1;
+                        if replaced != 0 && replaced != id && state.live.contains(&replaced) {
+                            state
+                                .failures
+                                .push(format!("replacing {} returned {}", replaced, id));
+                        }
+                        if replaced != id && !state.live.insert(id) {
+                            state
+                                .failures
+                                .push(format!("live ID {} handed out again", id));
+                        }
+                    }
+                },
+                ReplyMessage::DBusError { sequence, .. }
+                | ReplyMessage::UnknownError { sequence } => {
+                    if state.pending.remove(&sequence).is_none() {
+                        state
+                            .failures
+                            .push(format!("error for unknown call {}", sequence));
+                    }
+                    state.replies += 1;
+                    state.errors += 1;
+                }
+                ReplyMessage::Dismissed { id, .. } => {
+                    state.live.remove(&id);
+                }
+                ReplyMessage::ActionInvoked { .. }
+                | ReplyMessage::OpenUrl { .. }
+                | ReplyMessage::Sanitized { .. }
+                | ReplyMessage::Suppressed { .. }
+                | ReplyMessage::Capabilities { .. }
+                | ReplyMessage::Replied { .. }
+                | ReplyMessage::DaemonCapabilities { .. }
+                | ReplyMessage::Pong { .. }
+                | ReplyMessage::Batch(_)
+                | ReplyMessage::History { .. }
+                | ReplyMessage::Draining
+                | ReplyMessage::Drained { .. }
+                | ReplyMessage::Reshown { .. }
+                | ReplyMessage::ServerRestart => {}
+            }
+        }
+    });
+
+    let mut rng = Rng(seed.max(1));
+    let start = Instant::now();
+    for sequence in 0..count {
+        let kind = mix[rng.below(mix.len())];
+        let replaces_id = match kind {
+            Kind::Replace => {
+                let state = state.lock().unwrap();
+                state.live.iter().next_back().copied().unwrap_or(0)
+            }
+            _ => 0,
+        };
+        let message = Message {
+            id: sequence,
+            notification: notification(kind, &mut rng, replaces_id),
+        };
+        let data = if minor >= CLOSE_VERSION {
+            options.serialize(&Request::Notify(message))
+        } else {
+            options.serialize(&message)
+        }
+        .expect("serialization cannot fail");
+        if data.len() > MAX_MESSAGE_SIZE as usize {
+            continue;
+        }
+        state.lock().unwrap().pending.insert(sequence, replaces_id);
+        to_server
+            .write_all(&WireU32::new(data.len() as u32).to_bytes())
+            .and_then(|()| to_server.write_all(&data))
+            .expect("Cannot write to server");
+    }
+    eprintln!("Sent {} calls in {:?}", count, start.elapsed());
+    // The server exits as soon as its input is closed, so wait for the
+    // replies first.
+    let deadline = Instant::now() + Duration::from_secs(60);
+    while !state.lock().unwrap().pending.is_empty() && Instant::now() < deadline {
+        std::thread::sleep(Duration::from_millis(10))
+    }
+    drop(to_server);
+    let status = child.wait().expect("Cannot wait for server");
+    reader.join().expect("reader panicked");
+    let elapsed = start.elapsed();
+
+    let state = state.lock().unwrap();
+    println!(
+        "{} replies ({} errors) in {:?}, {:.0} calls/s, server exited with {}",
+        state.replies,
+        state.errors,
+        elapsed,
+        state.replies as f64 / elapsed.as_secs_f64(),
+        status
+    );
+    let mut failed = !state.failures.is_empty();
+    for failure in &state.failures {
+        println!("FAIL: {}", failure)
+    }
+    if !state.pending.is_empty() {
+        println!("FAIL: {} calls got no reply", state.pending.len());
+        failed = true
+    }
+    if failed {
+        std::process::exit(1)
+    }
+}
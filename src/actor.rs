@@ -0,0 +1,1153 @@
+//! The event loop of the dom0 side of the proxy.
+//!
+//! Everything that happens to a connection is an [`Event`]: a frame from
+//! the qube, or a signal from a notification daemon.  A single [`Actor`]
+//! owns all state of the connection and handles events one at a time, so
+//! nothing is shared between tasks.  Calls to the daemon take a while, and
+//! several may be in flight at once, so they run as futures owned by the
+//! actor, and it handles their results like events.  Timers are futures of
+//! the same kind.  When the connection ends, dropping the actor drops all
+//! of them, and no task is left to abort.
+//!
+//! The actor only talks to the daemon through [`Daemon`], so that it can be
+//! driven without a bus.
+
+use crate::codec::{self, FrameError, Framing, OutboundQueue, WireCodec as _};
+use crate::config::Config;
+use crate::control;
+use crate::hooks::Hooks;
+use crate::resume::{self, ClockWatch};
+use crate::signals::SignalLimiter;
+use crate::{crash, Capabilities, ExpireTimeout, Notification, NotificationEmitter, Sent};
+use crate::{Features, NegotiatedFeatures};
+use crate::{HistoryEntry, Message, Outcome, ReplyMessage, Request, Urgency};
+use crate::{BATCH_VERSION, PING_VERSION, SANITIZED_VERSION, SUPPRESSED_VERSION};
+use crate::{CLOSE_VERSION, DAEMON_CAPABILITIES_VERSION, EXPIRE_TIMEOUT_VERSION, PROBE_VERSION};
+use crate::{DRAIN_VERSION, HISTORY_VERSION, MAX_HISTORY, MAX_REPLY_SIZE};
+use futures_util::stream::{FuturesUnordered, LocalBoxStream};
+use futures_util::StreamExt;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::io::AsyncRead;
+use tokio::sync::{OnceCell, OwnedSemaphorePermit, Semaphore};
+use zbus::fdo::NameOwnerChangedStream;
+
+/// Something that happened to the connection.
+#[derive(Debug)]
+pub enum Event {
+    /// The qube sent a frame.
+    Frame(Vec<u8>),
+    /// The qube closed the connection.
+    Eof,
+    /// A frame from the qube could not be read.
+    BadFrame(FrameError),
+    /// The main daemon closed the notification with this host ID.
+    Closed { host_id: u32, reason: u32 },
+    /// The daemon of mirror `bus`, numbered as in [`Event::OwnerChanged`],
+    /// closed the notification with this host ID.
+    MirrorClosed { bus: usize, host_id: u32 },
+    /// The user invoked an action on the notification with this host ID.
+    Invoked { host_id: u32, action: String },
+    /// The user replied to the notification with this host ID.
+    Replied { host_id: u32, text: String },
+    /// `org.freedesktop.Notifications` changed owners on bus `bus`: 0 for
+    /// the main daemon, and 1 and up for the mirrors.
+    OwnerChanged { bus: usize, new_owner: bool },
+    /// dom0 is shutting down.
+    Shutdown,
+    /// dom0 resumed after being suspended for this long.
+    Resumed(Duration),
+}
+
+/// How long a qube has to answer [`ReplyMessage::Draining`] before the
+/// connection is drained without it.
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the qube can have dom0 ask the daemons for their capabilities.
+/// Probes sooner than this after the last one are answered with the
+/// capabilities already known.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The notification daemon, as far as the actor is concerned.  See the
+/// methods of [`NotificationEmitter`] with the same names.
+///
+/// Everything runs on one thread, so the futures need not be `Send`.
+#[allow(async_fn_in_trait)]
+pub trait Daemon {
+    async fn send_call(
+        &self,
+        notification: Notification,
+        received: SystemTime,
+        call: Option<String>,
+    ) -> zbus::Result<Sent>;
+    async fn close_notification(&self, untrusted_id: u32) -> zbus::Result<Option<(u32, bool)>>;
+    async fn refresh_capabilities(&self) -> zbus::Result<()>;
+    async fn resend_upgradable(&self) -> Vec<(u32, u32)>;
+    async fn expire_overdue(&self) -> Vec<u32>;
+    async fn ask_approval(&self, qube_name: &str);
+    fn capabilities(&self) -> Capabilities;
+    fn next_deadline(&self) -> Option<tokio::time::Instant>;
+    fn notification_closed(&self, id: u32, reason: u32) -> Option<u32>;
+    fn remove_host_id(&self, id: u32) -> Option<u32>;
+    fn translate_host_id(&self, id: u32) -> Option<u32>;
+    fn url_for_action(&self, id: u32, action: &str) -> Option<String>;
+    fn effective_expire_timeout(&self, requested: ExpireTimeout) -> ExpireTimeout;
+    fn control_state(&self) -> control::SharedState;
+    fn clear(&self);
+}
+
+impl Daemon for NotificationEmitter {
+    async fn send_call(
+        &self,
+        notification: Notification,
+        received: SystemTime,
+        call: Option<String>,
+    ) -> zbus::Result<Sent> {
+        NotificationEmitter::send_call(self, notification, received, call).await
+    }
+    async fn close_notification(&self, untrusted_id: u32) -> zbus::Result<Option<(u32, bool)>> {
+        NotificationEmitter::close_notification(self, untrusted_id).await
+    }
+    async fn refresh_capabilities(&self) -> zbus::Result<()> {
+        NotificationEmitter::refresh_capabilities(self).await
+    }
+    async fn resend_upgradable(&self) -> Vec<(u32, u32)> {
+        NotificationEmitter::resend_upgradable(self).await
+    }
+    async fn expire_overdue(&self) -> Vec<u32> {
+        NotificationEmitter::expire_overdue(self).await
+    }
+    async fn ask_approval(&self, qube_name: &str) {
+        NotificationEmitter::ask_approval(self, qube_name).await
+    }
+    fn capabilities(&self) -> Capabilities {
+        NotificationEmitter::capabilities(self)
+    }
+    fn next_deadline(&self) -> Option<tokio::time::Instant> {
+        NotificationEmitter::next_deadline(self)
+    }
+    fn notification_closed(&self, id: u32, reason: u32) -> Option<u32> {
+        NotificationEmitter::notification_closed(self, id, reason)
+    }
+    fn remove_host_id(&self, id: u32) -> Option<u32> {
+        NotificationEmitter::remove_host_id(self, id)
+    }
+    fn translate_host_id(&self, id: u32) -> Option<u32> {
+        NotificationEmitter::translate_host_id(self, id)
+    }
+    fn url_for_action(&self, id: u32, action: &str) -> Option<String> {
+        NotificationEmitter::url_for_action(self, id, action)
+    }
+    fn effective_expire_timeout(&self, requested: ExpireTimeout) -> ExpireTimeout {
+        NotificationEmitter::effective_expire_timeout(self, requested)
+    }
+    fn control_state(&self) -> control::SharedState {
+        NotificationEmitter::control_state(self)
+    }
+    fn clear(&self) {
+        NotificationEmitter::clear(self)
+    }
+}
+
+/// The frames read from `reader` with `framing`, ending with [`Event::Eof`]
+/// or [`Event::BadFrame`].
+pub fn frames<R: AsyncRead + Unpin + 'static>(
+    reader: R,
+    framing: Framing,
+) -> LocalBoxStream<'static, Event> {
+    futures_util::stream::unfold(Some(reader), move |reader| async move {
+        let mut reader = reader?;
+        Some(match codec::read_frame(&mut reader, framing).await {
+            Ok(Some(bytes)) => (Event::Frame(bytes), Some(reader)),
+            Ok(None) => (Event::Eof, None),
+            Err(e) => (Event::BadFrame(e), None),
+        })
+    })
+    .boxed_local()
+}
+
+/// An [`Event::Shutdown`] each time the process is asked to terminate.
+pub fn termination() -> std::io::Result<LocalBoxStream<'static, Event>> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let signal = signal(SignalKind::terminate())?;
+    Ok(
+        futures_util::stream::unfold(signal, |mut signal| async move {
+            signal.recv().await?;
+            Some((Event::Shutdown, signal))
+        })
+        .boxed_local(),
+    )
+}
+
+/// An [`Event::Resumed`] each time the system resumes from a suspend.
+pub fn resumes() -> LocalBoxStream<'static, Event> {
+    futures_util::stream::unfold(ClockWatch::new(), |mut watch| async move {
+        let suspended = resume::next_resume(&mut watch).await;
+        Some((Event::Resumed(suspended), watch))
+    })
+    .boxed_local()
+}
+
+/// Changes of the owner of `org.freedesktop.Notifications` on bus `bus`,
+/// as reported by `name_owner_changed`.
+pub fn owner_changes(
+    bus: usize,
+    name_owner_changed: NameOwnerChangedStream<'static>,
+) -> LocalBoxStream<'static, Event> {
+    name_owner_changed
+        .map(move |item| {
+            let item = item
+                .args()
+                .expect("Got invalid NameOwnerChanged message from bus daemon");
+            assert_eq!(
+                item.name, "org.freedesktop.Notifications",
+                "Bus daemon sent message for name we didn't register for"
+            );
+            Event::OwnerChanged {
+                bus,
+                new_owner: item.new_owner.is_some(),
+            }
+        })
+        .boxed_local()
+}
+
+/// The signals of the daemon behind `emitter` that are passed on to the
+/// qube.  `NotificationReplied` is only included if `replies` is set.
+pub async fn daemon_signals(
+    emitter: &NotificationEmitter,
+    replies: bool,
+) -> zbus::Result<LocalBoxStream<'static, Event>> {
+    fn invalid(e: zbus::Error) -> Option<Event> {
+        eprintln!("Got invalid message from notification daemon: {}", e);
+        None
+    }
+    let closed = emitter.closed().await?.filter_map(|item| async move {
+        match item.args() {
+            Ok(item) => Some(Event::Closed {
+                host_id: item.id,
+                reason: item.reason,
+            }),
+            Err(e) => invalid(e),
+        }
+    });
+    let invoked = emitter.invocations().await?.filter_map(|item| async move {
+        match item.args() {
+            Ok(item) => Some(Event::Invoked {
+                host_id: item.id,
+                action: item.action_key,
+            }),
+            Err(e) => invalid(e),
+        }
+    });
+    let mut streams = vec![closed.boxed_local(), invoked.boxed_local()];
+    if replies {
+        let replied = emitter.replies().await?.filter_map(|item| async move {
+            match item.args() {
+                Ok(item) => Some(Event::Replied {
+                    host_id: item.id,
+                    text: item.text,
+                }),
+                Err(e) => invalid(e),
+            }
+        });
+        streams.push(replied.boxed_local())
+    }
+    Ok(futures_util::stream::select_all(streams).boxed_local())
+}
+
+/// The notifications the daemon of mirror `bus` closed.  Nothing is
+/// reported to the qube, but the mirror must forget them.
+pub async fn mirror_signals(
+    bus: usize,
+    mirror: &NotificationEmitter,
+) -> zbus::Result<LocalBoxStream<'static, Event>> {
+    let closed = mirror.closed().await?.filter_map(move |item| async move {
+        match item.args() {
+            Ok(item) => Some(Event::MirrorClosed {
+                bus,
+                host_id: item.id,
+            }),
+            Err(e) => {
+                eprintln!("Got invalid message from mirrored daemon: {}", e);
+                None
+            }
+        }
+    });
+    Ok(closed.boxed_local())
+}
+
+/// The reply to call `sequence`, which returned `out`.
+pub fn reply(out: zbus::Result<u32>, sequence: u64) -> ReplyMessage {
+    match out {
+        Ok(id) => ReplyMessage::Id { id, sequence },
+        Err(zbus::Error::MethodError(name, message, _)) => ReplyMessage::DBusError {
+            name: name.to_string(),
+            message,
+            sequence,
+        },
+        Err(e) => {
+            eprintln!("Serialization failed for {:?}", e);
+            ReplyMessage::UnknownError { sequence }
+        }
+    }
+}
+
+/// Shows the notifications of the main daemon on another session bus.
+/// Notifications are replaced and closed along with those of the main
+/// daemon, but dismissals and actions are not reported to the qube.
+struct Mirror<D> {
+    daemon: Rc<D>,
+    /// IDs from the mirror, by the ID from the main daemon.
+    ids: HashMap<u32, u32>,
+}
+
+/// A call to a daemon that completed, or a timer that fired.
+enum Done {
+    /// `Notify` for call `sequence` returned `out`.
+    Notified {
+        sequence: u64,
+        call: String,
+        received: SystemTime,
+        critical: bool,
+        /// The notification, if it is to be mirrored.
+        copy: Option<Box<Notification>>,
+        expire_timeout: ExpireTimeout,
+        out: zbus::Result<Sent>,
+        permit: OwnedSemaphorePermit,
+        /// The calls left in the batch this call belongs to, if any.
+        batch: Option<VecDeque<Message>>,
+    },
+    /// `CloseNotification` for call `sequence` returned `out`.
+    Closed {
+        sequence: u64,
+        untrusted_id: u32,
+        out: zbus::Result<Option<(u32, bool)>>,
+        permit: OwnedSemaphorePermit,
+    },
+    /// Probing capabilities for call `sequence` returned `out`.  The main
+    /// daemon had the capabilities `before`, or was not asked if `None`.
+    Probed {
+        sequence: u64,
+        call: String,
+        before: Option<Capabilities>,
+        out: zbus::Result<Capabilities>,
+        permit: OwnedSemaphorePermit,
+    },
+    /// Mirror `mirror` showed the notification with ID `id` as `mirror_id`.
+    Mirrored {
+        mirror: usize,
+        id: u32,
+        mirror_id: u32,
+    },
+    /// The earliest deadline passed, and these notifications were forgotten
+    /// as expired.
+    Expired(Vec<u32>),
+    /// The main daemon was replaced, and its capabilities are known.
+    Refreshed,
+    /// The daemon on bus `bus`, numbered as in [`Event::OwnerChanged`],
+    /// showed the notifications in `reshown` again, as pairs of old and new
+    /// IDs, after taking over from one that went away.  For a mirror,
+    /// `copies` are the IDs its copies had then.  The capabilities of the
+    /// main daemon are known if `refreshed`.
+    Reshown {
+        bus: usize,
+        reshown: Vec<(u32, u32)>,
+        copies: HashMap<u32, u32>,
+        refreshed: bool,
+    },
+    /// The main daemon cannot be reached anymore.
+    Lost,
+    /// Nothing left to do.
+    Nothing,
+}
+
+type Pending = Pin<Box<dyn Future<Output = Done>>>;
+
+/// Handles all events of one connection.  See the module documentation.
+pub struct Actor<D> {
+    qube_name: String,
+    /// Negotiated protocol version and features.
+    features: NegotiatedFeatures,
+    report_suppressed: bool,
+    /// How long the qube may stay silent, if it pings.
+    keepalive_timeout: Option<Duration>,
+    daemon: Rc<D>,
+    mirrors: Vec<Mirror<D>>,
+    hooks: Hooks,
+    limiter: SignalLimiter,
+    /// Permits for calls in flight.  Frames from the qube are only read
+    /// while one is free, or one ahead, so that pings are answered.
+    calls: Arc<Semaphore>,
+    /// A request read while no permit was free, with when it was received.
+    /// No more frames are read until it is made.
+    stalled: Option<(Request, SystemTime)>,
+    /// How many permits `calls` has.
+    max_calls: usize,
+    /// Set once the connection is being drained: no frames are read, and
+    /// the connection ends when no calls are left.  Holds the sequence
+    /// number of the [`Request::Drain`], if the qube sent one.
+    drain: Option<Option<u64>>,
+    /// When the qube must have answered [`ReplyMessage::Draining`].
+    drain_deadline: Option<tokio::time::Instant>,
+    /// When the daemons were last probed for the qube.
+    last_probe: Option<tokio::time::Instant>,
+    /// Set while overdue notifications are being expired, so that the
+    /// deadline timer does not fire again for them.
+    expiring: bool,
+    /// Everything sent to the qube goes through this queue, so the order on
+    /// the wire is the order in which messages are queued.  In particular,
+    /// the reply to a call is queued as soon as the notification is mapped,
+    /// so a Dismissed or ActionInvoked for it can only come afterwards.
+    out: OutboundQueue,
+    pending: FuturesUnordered<Pending>,
+    /// Number of batch requests in progress.  While there is one, messages
+    /// for the qube are held back in `held`.
+    batches: usize,
+    held: Vec<ReplyMessage>,
+    /// The last [`MAX_HISTORY`] outcomes reported to the qube, for
+    /// [`Request::History`].
+    history: VecDeque<HistoryEntry>,
+    /// Set if the user must approve the qube before its notifications are
+    /// shown.  The first notification asks, and the others wait for the
+    /// answer.
+    approval: Option<Rc<OnceCell<()>>>,
+}
+
+impl<D: Daemon + 'static> Actor<D> {
+    /// An actor for the connection from `qube_name`, which negotiated
+    /// `features`.  Notifications are shown by `daemon`, and copied to
+    /// `mirrors`.
+    pub fn new(
+        qube_name: String,
+        features: NegotiatedFeatures,
+        config: &Config,
+        daemon: Rc<D>,
+        mirrors: Vec<Rc<D>>,
+        calls: Arc<Semaphore>,
+        out: OutboundQueue,
+    ) -> Self {
+        Self {
+            hooks: Hooks::new(qube_name.clone(), config),
+            limiter: SignalLimiter::new(config),
+            report_suppressed: config.report_suppressed,
+            keepalive_timeout: config
+                .keepalive_timeout
+                .filter(|_| features.minor() >= PING_VERSION),
+            qube_name,
+            features,
+            daemon,
+            mirrors: mirrors
+                .into_iter()
+                .map(|daemon| Mirror {
+                    daemon,
+                    ids: HashMap::new(),
+                })
+                .collect(),
+            calls,
+            stalled: None,
+            max_calls: config.max_concurrent_calls,
+            drain: None,
+            drain_deadline: None,
+            last_probe: None,
+            expiring: false,
+            out,
+            pending: FuturesUnordered::new(),
+            batches: 0,
+            held: vec![],
+            history: VecDeque::new(),
+            approval: None,
+        }
+    }
+    /// Have the user approve the qube before any of its notifications is
+    /// shown, if `required`.
+    pub fn with_approval(mut self, required: bool) -> Self {
+        self.approval = required.then(Default::default);
+        self
+    }
+    /// Handle events until the qube closes the connection, which returns
+    /// `Ok`, or breaks the protocol or goes silent, which returns why.
+    /// Frames come from `requests`, and signals from `signals`.  Completed
+    /// calls are handled first, then signals, then frames, so that the
+    /// order is the same every time.
+    pub async fn run(
+        mut self,
+        mut requests: LocalBoxStream<'static, Event>,
+        mut signals: LocalBoxStream<'static, Event>,
+    ) -> Result<(), String> {
+        self.report_capabilities();
+        let mut signals_done = false;
+        let mut waited = false;
+        let timeout = self.keepalive_timeout;
+        let idle = tokio::time::sleep(timeout.unwrap_or_default());
+        tokio::pin!(idle);
+        loop {
+            if self.calls.available_permits() > 0 {
+                if let Some((request, received)) = self.stalled.take() {
+                    self.request(request, received)
+                }
+            }
+            let idle_calls = self.calls.available_permits() >= self.max_calls;
+            if let (Some(drain), true) = (self.drain, idle_calls) {
+                self.send_held();
+                if let Some(sequence) = drain {
+                    self.send(&ReplyMessage::Drained { sequence })
+                }
+                return Ok(());
+            }
+            let reading = self.stalled.is_none() && self.drain.is_none();
+            let drain_deadline = self.drain_deadline.filter(|_| self.drain.is_none());
+            let deadline = self.daemon.next_deadline().filter(|_| !self.expiring);
+            // The qube is not heard while no frames are read, and need not
+            // send anything while every call is in flight, so that time
+            // does not count, up to when it ends.
+            let waiting = !reading || self.calls.available_permits() == 0;
+            if let Some(timeout) = timeout.filter(|_| waiting || waited) {
+                idle.as_mut().reset(tokio::time::Instant::now() + timeout)
+            }
+            waited = waiting;
+            tokio::select! {
+                biased;
+                Some(done) = self.pending.next(), if !self.pending.is_empty() => {
+                    self.complete(done)
+                }
+                event = signals.next(), if !signals_done => match event {
+                    Some(event) => self.handle(event)?,
+                    None => signals_done = true,
+                },
+                event = requests.next(), if reading => {
+                    if let Some(timeout) = timeout {
+                        idle.as_mut().reset(tokio::time::Instant::now() + timeout)
+                    }
+                    match event {
+                        Some(Event::Eof) | None => {
+                            self.send_held();
+                            return Ok(())
+                        }
+                        Some(event) => self.handle(event)?,
+                    }
+                }
+                () = &mut idle, if !waiting && timeout.is_some() => {
+                    return Err(format!("nothing received for {:?}", idle.deadline().elapsed()))
+                }
+                () = tokio::time::sleep_until(drain_deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if drain_deadline.is_some() => {
+                    eprintln!("{} did not drain in time", self.qube_name);
+                    self.drain = Some(None)
+                }
+                () = tokio::time::sleep_until(deadline.unwrap_or_else(tokio::time::Instant::now)),
+                    if deadline.is_some() => {
+                    self.expiring = true;
+                    let daemon = self.daemon.clone();
+                    self.spawn(async move { Done::Expired(daemon.expire_overdue().await) })
+                }
+            }
+        }
+    }
+    /// Queue `message` for the qube, or hold it back until the batches in
+    /// progress complete, so that it cannot overtake their replies.
+    fn push(&mut self, message: &ReplyMessage) {
+        match self.batches {
+            0 => self.send(message),
+            _ => self.held.push(message.clone()),
+        }
+    }
+    /// Queue `message` for the qube right away.
+    fn send(&self, message: &ReplyMessage) {
+        let data = self
+            .features
+            .encoding()
+            .encode(message)
+            .expect("Serialization failed?");
+        self.out.push(data)
+    }
+    /// Remember that `outcome` happened to notification `id`, forgetting
+    /// the oldest outcome if there are too many.
+    fn remember(&mut self, id: u32, outcome: Outcome) {
+        if self.history.len() == MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(HistoryEntry { id, outcome })
+    }
+    /// Tell the qube which capabilities the main daemon has, if it
+    /// understands [`ReplyMessage::DaemonCapabilities`].
+    fn report_capabilities(&mut self) {
+        if self.features.minor() >= DAEMON_CAPABILITIES_VERSION {
+            self.push(&ReplyMessage::DaemonCapabilities {
+                capabilities: self.daemon.capabilities().bits().into(),
+            })
+        }
+    }
+    fn spawn(&self, future: impl Future<Output = Done> + 'static) {
+        self.pending.push(Box::pin(future))
+    }
+    /// Handle `event`, returning why the connection must end if it must.
+    pub fn handle(&mut self, event: Event) -> Result<(), String> {
+        match event {
+            Event::Frame(bytes) => return self.frame(&bytes),
+            Event::Eof => {}
+            Event::BadFrame(e) => return Err(e.to_string()),
+            Event::Closed { host_id, reason } => {
+                let Some(id) = self.daemon.notification_closed(host_id, reason) else {
+                    return Ok(());
+                };
+                self.limiter.dismissed(id);
+                self.close_mirrored(id);
+                self.remember(id, Outcome::Dismissed { reason });
+                self.push(&ReplyMessage::Dismissed { id, reason })
+            }
+            Event::MirrorClosed { bus, host_id } => {
+                let Some(mirror) = bus.checked_sub(1).and_then(|i| self.mirrors.get_mut(i)) else {
+                    return Ok(());
+                };
+                if let Some(mirror_id) = mirror.daemon.remove_host_id(host_id) {
+                    mirror.ids.retain(|_, &mut id| id != mirror_id)
+                }
+            }
+            Event::Invoked { host_id, action } => {
+                let url = self.daemon.url_for_action(host_id, &action);
+                let Some(id) = self.daemon.translate_host_id(host_id) else {
+                    return Ok(());
+                };
+                if !self.limiter.action(id, &action, std::time::Instant::now()) {
+                    return Ok(());
+                }
+                let outcome = Outcome::ActionInvoked {
+                    action: action.clone(),
+                };
+                self.remember(id, outcome);
+                self.push(&match url {
+                    Some(url) => ReplyMessage::OpenUrl { id, url },
+                    None => ReplyMessage::ActionInvoked { id, action },
+                })
+            }
+            Event::Replied { host_id, text } => {
+                let Some(id) = self.daemon.translate_host_id(host_id) else {
+                    return Ok(());
+                };
+                if text.len() > MAX_REPLY_SIZE {
+                    eprintln!("Dropping reply of {} bytes to {}", text.len(), id);
+                    return Ok(());
+                }
+                // Counted as an action, so that repeated replies are
+                // coalesced and limited along with actions.
+                if !self.limiter.action(id, &text, std::time::Instant::now()) {
+                    return Ok(());
+                }
+                self.remember(id, Outcome::Replied);
+                self.push(&ReplyMessage::Replied { id, text })
+            }
+            // A qube that knows how is asked to stop sending first.
+            Event::Shutdown if self.features.minor() >= DRAIN_VERSION => {
+                if self.drain_deadline.is_none() {
+                    self.drain_deadline = Some(tokio::time::Instant::now() + DRAIN_TIMEOUT);
+                    self.push(&ReplyMessage::Draining)
+                }
+            }
+            Event::Shutdown => {
+                self.drain.get_or_insert(None);
+            }
+            // Signals may have been lost, and the daemon may have changed,
+            // while suspended.  Calling it shows whether it is still there.
+            Event::Resumed(suspended) => {
+                eprintln!("Resumed after {:?}, checking the daemon", suspended);
+                let daemon = self.daemon.clone();
+                self.spawn(async move {
+                    let before = daemon.capabilities();
+                    match daemon.refresh_capabilities().await {
+                        Ok(()) if daemon.capabilities() == before => Done::Nothing,
+                        Ok(()) => Done::Refreshed,
+                        Err(e) => {
+                            eprintln!("Cannot reach daemon after resume: {}", e);
+                            Done::Lost
+                        }
+                    }
+                })
+            }
+            // Forget the notifications of a daemon that went away, and
+            // show the ones that benefit from the capabilities of the
+            // daemon that replaces it.
+            Event::OwnerChanged { bus, new_owner } => {
+                let (daemon, copies) = match bus {
+                    0 => (self.daemon.clone(), HashMap::new()),
+                    bus => match self.mirrors.get_mut(bus - 1) {
+                        Some(mirror) => (mirror.daemon.clone(), std::mem::take(&mut mirror.ids)),
+                        None => return Ok(()),
+                    },
+                };
+                daemon.clear();
+                if new_owner {
+                    self.spawn(async move {
+                        let refreshed = match daemon.refresh_capabilities().await {
+                            Ok(()) => bus == 0,
+                            Err(e) => {
+                                eprintln!("Cannot get capabilities of new daemon: {}", e);
+                                false
+                            }
+                        };
+                        Done::Reshown {
+                            bus,
+                            reshown: daemon.resend_upgradable().await,
+                            copies,
+                            refreshed,
+                        }
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Handle a frame from the qube.
+    fn frame(&mut self, bytes: &[u8]) -> Result<(), String> {
+        // The guest is not trusted, so a bad frame ends the connection
+        // instead of crashing with a backtrace.  There is no way to
+        // resynchronize after a bad length, and a guest sending garbage is
+        // broken or malicious anyway.
+        let encoding = self.features.encoding();
+        let minor = self.features.minor();
+        let request = if minor >= CLOSE_VERSION {
+            encoding.decode(bytes)
+        } else {
+            encoding.decode::<Message>(bytes).map(Request::Notify)
+        };
+        let request = match request {
+            Ok(Request::Probe { .. }) if minor < PROBE_VERSION => {
+                return Err(format!("probe not supported by version {}", minor))
+            }
+            Ok(Request::Ping { .. }) if minor < PING_VERSION => {
+                return Err(format!("ping not supported by version {}", minor))
+            }
+            Ok(Request::Batch(_)) if minor < BATCH_VERSION => {
+                return Err(format!("batch not supported by version {}", minor))
+            }
+            Ok(Request::History { .. }) if minor < HISTORY_VERSION => {
+                return Err(format!("history not supported by version {}", minor))
+            }
+            Ok(Request::Drain { .. }) if minor < DRAIN_VERSION => {
+                return Err(format!("drain not supported by version {}", minor))
+            }
+            Ok(Request::Close { .. }) if !self.features.has(Features::CLOSE) => {
+                return Err("close not negotiated".to_owned())
+            }
+            Ok(request) => request,
+            Err(e) => return Err(format!("malformed message: {}", e)),
+        };
+        crash::set_sequence(request.sequence());
+        // Taken before the call starts, so that it is the time the qube
+        // sent the notification, not the time it was shown.
+        let received = SystemTime::now();
+        match request {
+            // Answered right away, even while calls wait for approval, so
+            // that the qube does not take the connection to be dead.
+            Request::Ping { sequence } => self.push(&ReplyMessage::Pong { sequence }),
+            request if self.calls.available_permits() == 0 => {
+                self.stalled = Some((request, received))
+            }
+            request => self.request(request, received),
+        }
+        Ok(())
+    }
+    /// Make `request`, which the qube sent at `received`, with a free
+    /// permit.  Requests are made in the order the qube sent them.
+    fn request(&mut self, request: Request, received: SystemTime) {
+        let permit = self
+            .calls
+            .clone()
+            .try_acquire_owned()
+            .expect("requests are only made with a permit free");
+        let daemon = self.daemon.clone();
+        match request {
+            Request::Notify(message) => self.notify(message, received, permit, None),
+            // The calls are made one at a time, under the permit of the
+            // batch.  An empty batch has nothing to answer.
+            Request::Batch(messages) => {
+                let mut messages = VecDeque::from(messages);
+                if let Some(message) = messages.pop_front() {
+                    self.batches += 1;
+                    self.notify(message, received, permit, Some(messages))
+                }
+            }
+            Request::Close {
+                sequence,
+                untrusted_id,
+            } => self.spawn(async move {
+                let out = daemon.close_notification(untrusted_id).await;
+                Done::Closed {
+                    sequence,
+                    untrusted_id,
+                    out,
+                    permit,
+                }
+            }),
+            Request::Probe { sequence } => {
+                let call = control::correlation_id(&self.qube_name, sequence);
+                let now = tokio::time::Instant::now();
+                if let Some(last_probe) = self.last_probe.filter(|&t| now < t + PROBE_INTERVAL) {
+                    eprintln!(
+                        "[{}] Probed {:?} ago, not asking again",
+                        call,
+                        now - last_probe
+                    );
+                    return self.complete(Done::Probed {
+                        sequence,
+                        call,
+                        before: None,
+                        out: Ok(daemon.capabilities()),
+                        permit,
+                    });
+                }
+                self.last_probe = Some(now);
+                self.spawn(async move {
+                    let before = Some(daemon.capabilities());
+                    let out = match daemon.refresh_capabilities().await {
+                        Ok(()) => Ok(daemon.capabilities()),
+                        Err(e) => Err(e),
+                    };
+                    Done::Probed {
+                        sequence,
+                        call,
+                        before,
+                        out,
+                        permit,
+                    }
+                })
+            }
+            Request::Ping { sequence } => self.push(&ReplyMessage::Pong { sequence }),
+            // Also answered right away.  Only outcomes already reported to
+            // the qube are remembered, so nothing can be told about the
+            // notifications of other qubes.
+            Request::History {
+                sequence,
+                untrusted_ids,
+            } => {
+                let ids: HashSet<u32> = untrusted_ids.into_iter().collect();
+                let entries = self
+                    .history
+                    .iter()
+                    .filter(|entry| ids.is_empty() || ids.contains(&entry.id))
+                    .cloned()
+                    .collect();
+                self.push(&ReplyMessage::History { entries, sequence })
+            }
+            // Answered once the calls in flight are done.
+            Request::Drain { sequence } => self.drain = Some(Some(sequence)),
+        }
+    }
+    /// Show the notification in `message`, which the qube sent at
+    /// `received`.  `batch` holds the calls that follow it in its batch, if
+    /// it is part of one.
+    fn notify(
+        &self,
+        message: Message,
+        received: SystemTime,
+        permit: OwnedSemaphorePermit,
+        batch: Option<VecDeque<Message>>,
+    ) {
+        let daemon = self.daemon.clone();
+        let sequence = message.id;
+        let call = control::correlation_id(&self.qube_name, sequence);
+        let critical = message.notification.urgency() == Some(Urgency::Critical);
+        let copy = (!self.mirrors.is_empty()).then(|| Box::new(message.notification.clone()));
+        let expire_timeout = daemon.effective_expire_timeout(message.notification.expire_timeout());
+        let approval = self.approval.clone();
+        let qube_name = self.qube_name.clone();
+        self.spawn(async move {
+            if let Some(approval) = approval {
+                approval
+                    .get_or_init(|| daemon.ask_approval(&qube_name))
+                    .await;
+            }
+            let out = daemon
+                .send_call(message.notification, received, Some(call.clone()))
+                .await;
+            Done::Notified {
+                sequence,
+                call,
+                received,
+                critical,
+                copy,
+                expire_timeout,
+                out,
+                permit,
+                batch,
+            }
+        })
+    }
+    /// Send everything held back as one batch.
+    fn send_held(&mut self) {
+        let held = std::mem::take(&mut self.held);
+        if !held.is_empty() {
+            self.send(&ReplyMessage::Batch(held))
+        }
+    }
+    /// Handle a completed call or timer.
+    fn complete(&mut self, done: Done) {
+        match done {
+            Done::Notified {
+                sequence,
+                call,
+                received,
+                critical,
+                copy,
+                expire_timeout,
+                out,
+                permit,
+                batch,
+            } => {
+                if let Err(ref e) = out {
+                    eprintln!("[{}] Notification rejected: {}", call, e);
+                    self.daemon
+                        .control_state()
+                        .lock()
+                        .expect("poisoned")
+                        .rejected(&call, &e.to_string())
+                }
+                let event = match out {
+                    Ok(_) if critical => self.hooks.critical(),
+                    Ok(_) => None,
+                    Err(_) => self.hooks.rejected(std::time::Instant::now()),
+                };
+                if let Some(event) = event {
+                    self.hooks.fire(event)
+                }
+                let minor = self.features.minor();
+                if let (Ok(sent), true) = (&out, minor >= SANITIZED_VERSION) {
+                    self.push(&ReplyMessage::Sanitized {
+                        id: sent.id.into(),
+                        truncated: sent.truncated,
+                    })
+                }
+                if let (Ok(sent), true) =
+                    (&out, self.report_suppressed && minor >= SUPPRESSED_VERSION)
+                {
+                    if let Some(reason) = sent.suppressed {
+                        self.push(&ReplyMessage::Suppressed {
+                            id: sent.id.into(),
+                            reason,
+                        })
+                    }
+                }
+                if let (Ok(sent), true) = (&out, minor >= EXPIRE_TIMEOUT_VERSION) {
+                    self.push(&ReplyMessage::ExpireTimeout {
+                        id: sent.id.into(),
+                        expire_timeout,
+                    })
+                }
+                let shown = out.as_ref().ok().map(|sent| u32::from(sent.id));
+                self.push(&reply(out.map(|sent| sent.id.into()), sequence));
+                match batch.map(|mut rest| (rest.pop_front(), rest)) {
+                    Some((Some(next), rest)) => self.notify(next, received, permit, Some(rest)),
+                    Some((None, _)) => {
+                        self.batches -= 1;
+                        self.send_held();
+                        drop(permit)
+                    }
+                    None => drop(permit),
+                }
+                if let (Some(id), Some(copy)) = (shown, copy) {
+                    self.mirror(id, call, *copy, received)
+                }
+            }
+            Done::Closed {
+                sequence,
+                untrusted_id,
+                out,
+                permit,
+            } => {
+                let closed = match out {
+                    Ok(Some((id, true))) => Some(id),
+                    _ => None,
+                };
+                let reply = match out {
+                    Ok(None) => ReplyMessage::invalid_id(untrusted_id, sequence),
+                    out => reply(
+                        out.map(|id| id.map_or(untrusted_id, |(id, _)| id)),
+                        sequence,
+                    ),
+                };
+                self.push(&reply);
+                if let Some(id) = closed {
+                    let reason = control::CLOSED_BY_CALL;
+                    self.remember(id, Outcome::Dismissed { reason });
+                    self.push(&ReplyMessage::Dismissed { id, reason })
+                }
+                drop(permit);
+                self.close_mirrored(untrusted_id)
+            }
+            // Notifications are only shown again if a daemon gained
+            // capabilities.
+            Done::Probed {
+                sequence,
+                call,
+                before,
+                out,
+                permit,
+            } => {
+                let changed =
+                    matches!((before, &out), (Some(before), Ok(after)) if before != *after);
+                let reply = match out {
+                    Ok(capabilities) => ReplyMessage::Capabilities {
+                        capabilities: capabilities
+                            .names()
+                            .into_iter()
+                            .map(str::to_owned)
+                            .collect(),
+                        sequence,
+                    },
+                    Err(e) => {
+                        eprintln!("[{}] Cannot probe capabilities: {}", call, e);
+                        reply(Err(e), sequence)
+                    }
+                };
+                self.push(&reply);
+                drop(permit);
+                let daemon = self.daemon.clone();
+                if changed {
+                    self.spawn(async move {
+                        Done::Reshown {
+                            bus: 0,
+                            reshown: daemon.resend_upgradable().await,
+                            copies: HashMap::new(),
+                            refreshed: false,
+                        }
+                    })
+                }
+                if before.is_none() {
+                    return;
+                }
+                for (bus, mirror) in (1..).zip(&self.mirrors) {
+                    let mirror = mirror.daemon.clone();
+                    let call = call.clone();
+                    self.spawn(async move {
+                        let before = mirror.capabilities();
+                        if let Err(e) = mirror.refresh_capabilities().await {
+                            eprintln!("[{}] Cannot probe mirror: {}", call, e);
+                            return Done::Nothing;
+                        }
+                        if mirror.capabilities() == before {
+                            return Done::Nothing;
+                        }
+                        Done::Reshown {
+                            bus,
+                            reshown: mirror.resend_upgradable().await,
+                            copies: HashMap::new(),
+                            refreshed: false,
+                        }
+                    })
+                }
+            }
+            Done::Mirrored {
+                mirror,
+                id,
+                mirror_id,
+            } => {
+                if let Some(mirror) = self.mirrors.get_mut(mirror) {
+                    mirror.ids.insert(id, mirror_id);
+                }
+            }
+            // The copies expire along with them.
+            Done::Expired(ids) => {
+                self.expiring = false;
+                let reason = control::CLOSED_EXPIRED;
+                for id in ids {
+                    self.remember(id, Outcome::Dismissed { reason });
+                    self.push(&ReplyMessage::Dismissed { id, reason });
+                    self.close_mirrored(id)
+                }
+            }
+            Done::Refreshed => self.report_capabilities(),
+            // The copies follow the notifications to their new IDs.
+            Done::Reshown {
+                bus: 0,
+                reshown,
+                refreshed,
+                ..
+            } => {
+                for (id, new_id) in reshown {
+                    for mirror in &mut self.mirrors {
+                        if let Some(copy) = mirror.ids.remove(&id) {
+                            mirror.ids.insert(new_id, copy);
+                        }
+                    }
+                    self.push(&ReplyMessage::Reshown { id, new_id })
+                }
+                if refreshed {
+                    self.report_capabilities()
+                }
+            }
+            // Copies that were shown again are still copies.
+            Done::Reshown {
+                bus,
+                reshown,
+                copies,
+                ..
+            } => {
+                let Some(mirror) = self.mirrors.get_mut(bus - 1) else {
+                    return;
+                };
+                let reshown: HashMap<_, _> = reshown.into_iter().collect();
+                for (id, copy) in copies {
+                    if let Some(&copy) = reshown.get(&copy) {
+                        mirror.ids.insert(id, copy);
+                    }
+                }
+            }
+            // The qube starts over, and the connection ends once the calls
+            // in flight are done.
+            Done::Lost => {
+                self.push(&ReplyMessage::ServerRestart);
+                self.drain.get_or_insert(None);
+            }
+            Done::Nothing => {}
+        }
+    }
+    /// Close the copies of the notification the main daemon showed as `id`.
+    /// The mirrors forget them when their daemons report them closed.
+    fn close_mirrored(&mut self, id: u32) {
+        for mirror in &mut self.mirrors {
+            let Some(mirror_id) = mirror.ids.remove(&id) else {
+                continue;
+            };
+            let daemon = mirror.daemon.clone();
+            self.pending.push(Box::pin(async move {
+                if let Err(e) = daemon.close_notification(mirror_id).await {
+                    eprintln!("Cannot close mirrored notification {}: {}", id, e)
+                }
+                Done::Nothing
+            }))
+        }
+    }
+    /// Show `notification`, which the main daemon showed as `id` for
+    /// `call`, on every mirror.
+    fn mirror(&self, id: u32, call: String, notification: Notification, received: SystemTime) {
+        let muted = self.daemon.control_state().lock().expect("poisoned").muted;
+        for (index, mirror) in self.mirrors.iter().enumerate() {
+            let daemon = mirror.daemon.clone();
+            daemon.control_state().lock().expect("poisoned").muted = muted;
+            let mut notification = notification.clone();
+            let replaces_id = notification.replaces_id();
+            let replaces_id = mirror.ids.get(&replaces_id).copied().unwrap_or(0);
+            notification.set_replaces_id(replaces_id);
+            let call = call.clone();
+            self.spawn(async move {
+                match daemon
+                    .send_call(notification, received, Some(call.clone()))
+                    .await
+                {
+                    Ok(sent) => Done::Mirrored {
+                        mirror: index,
+                        id,
+                        mirror_id: sent.id.into(),
+                    },
+                    Err(e) => {
+                        eprintln!("[{}] Cannot mirror notification {}: {}", call, id, e);
+                        Done::Nothing
+                    }
+                }
+            })
+        }
+    }
+}
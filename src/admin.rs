@@ -0,0 +1,153 @@
+//! Qube metadata from the Qubes Admin API.
+//!
+//! In dom0, the Admin API is served by qubesd on [`QUBESD_SOCKET`].  When
+//! the server runs in a GUI domain such as sys-gui, there is no qubesd, and
+//! calls go to dom0 over qrexec instead, which the qrexec policy must allow
+//! for the GUI domain.  Either way a reply is a status byte and a NUL,
+//! followed by the result or by the type of the exception and a message.
+//!
+//! Lookups that fail are not fatal: the server logs them and does without
+//! the metadata.
+
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Socket on which qubesd serves the Admin API in dom0.
+pub const QUBESD_SOCKET: &str = "/var/run/qubesd.sock";
+
+/// Command that makes a qrexec call, from a domain other than dom0.
+const QREXEC_CLIENT: &str = "/usr/lib/qubes/qrexec-client-vm";
+
+/// How long a call may take.
+const CALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest reply read.  Replies to the calls made here are a few bytes.
+const MAX_REPLY: u64 = 4096;
+
+/// How Admin API calls are made.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    /// Through qubesd if its socket exists, and over qrexec otherwise.
+    Auto,
+    /// Through qubesd, in dom0.
+    Qubesd,
+    /// Over qrexec, from a GUI domain.
+    Qrexec,
+}
+
+impl Transport {
+    /// The transport [`Transport::Auto`] stands for on this system.
+    fn resolve(self) -> Self {
+        match self {
+            Self::Auto if Path::new(QUBESD_SOCKET).exists() => Self::Qubesd,
+            Self::Auto => Self::Qrexec,
+            transport => transport,
+        }
+    }
+}
+
+/// Whether `arg` can be passed as the argument of a call.  qrexec only
+/// allows some characters in it, and qube and label names are made of
+/// these.
+fn is_valid_arg(arg: &str) -> bool {
+    !arg.is_empty()
+        && arg
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'))
+}
+
+/// Call `method` on `dest` with `arg`, returning the result.
+async fn call(
+    transport: Transport,
+    method: &str,
+    dest: &str,
+    arg: &str,
+) -> Result<Vec<u8>, String> {
+    if !is_valid_arg(dest) || !is_valid_arg(arg) {
+        return Err(format!("invalid call {} {} {}", method, dest, arg));
+    }
+    let mut reply = vec![];
+    let read = async {
+        match transport.resolve() {
+            Transport::Qubesd => {
+                let mut stream = tokio::net::UnixStream::connect(QUBESD_SOCKET).await?;
+                let header = format!("dom0\0{}\0{}\0{}\0", method, dest, arg);
+                stream.write_all(header.as_bytes()).await?;
+                stream.shutdown().await?;
+                stream.take(MAX_REPLY).read_to_end(&mut reply).await
+            }
+            _ => {
+                let mut child = tokio::process::Command::new(QREXEC_CLIENT)
+                    .arg(dest)
+                    .arg(format!("{}+{}", method, arg))
+                    .stdin(std::process::Stdio::null())
+                    .stdout(std::process::Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()?;
+                let stdout = child.stdout.take().expect("stdout is piped");
+                stdout.take(MAX_REPLY).read_to_end(&mut reply).await
+            }
+        }
+    };
+    match tokio::time::timeout(CALL_TIMEOUT, read).await {
+        Ok(Ok(_)) => parse_reply(&reply).map(<[u8]>::to_vec),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_owned()),
+    }
+}
+
+/// The result in `reply`, or the exception it reports.
+pub fn parse_reply(reply: &[u8]) -> Result<&[u8], String> {
+    match reply {
+        [b'0', 0, result @ ..] => Ok(result),
+        [b'2', 0, exception @ ..] => {
+            let mut fields = exception.split(|&c| c == 0);
+            let kind = fields.next().unwrap_or_default();
+            let message = fields.nth(1).unwrap_or_default();
+            Err(format!(
+                "{}: {}",
+                String::from_utf8_lossy(kind),
+                String::from_utf8_lossy(message)
+            ))
+        }
+        // qrexec-client-vm prints nothing if the policy denied the call.
+        [] => Err("no reply, the call may have been denied".to_owned()),
+        _ => Err("malformed reply".to_owned()),
+    }
+}
+
+/// The value in the result of `admin.vm.property.Get`, which looks like
+/// `default=False type=label red`.
+pub fn parse_property(result: &[u8]) -> Result<String, String> {
+    let result = std::str::from_utf8(result).map_err(|_| "property is not UTF-8")?;
+    let mut fields = result.splitn(3, ' ');
+    match (fields.next(), fields.next(), fields.next()) {
+        (Some(default), Some(kind), Some(value))
+            if default.starts_with("default=") && kind.starts_with("type=") =>
+        {
+            Ok(value.to_owned())
+        }
+        _ => Err(format!("malformed property {:?}", result)),
+    }
+}
+
+/// The color in the result of `admin.label.Get`, which looks like
+/// `0xcc0000`.
+pub fn parse_color(result: &[u8]) -> Result<[u8; 3], String> {
+    let invalid = || format!("invalid color {:?}", String::from_utf8_lossy(result));
+    let hex = result.strip_prefix(b"0x").ok_or_else(invalid)?;
+    if hex.len() != 6 || !hex.iter().all(u8::is_ascii_hexdigit) {
+        return Err(invalid());
+    }
+    let hex = std::str::from_utf8(hex).map_err(|_| invalid())?;
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok([component(0)?, component(2)?, component(4)?])
+}
+
+/// The color of the label of `qube_name`.
+pub async fn label_color(transport: Transport, qube_name: &str) -> Result<[u8; 3], String> {
+    let property = call(transport, "admin.vm.property.Get", qube_name, "label").await?;
+    let label = parse_property(&property)?;
+    parse_color(&call(transport, "admin.label.Get", "dom0", &label).await?)
+}
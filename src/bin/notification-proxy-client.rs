@@ -1,20 +1,643 @@
-use bincode::Options;
 use futures_channel::oneshot::Sender;
-use notification_emitter::{ImageParameters, ReplyMessage, MAX_MESSAGE_SIZE};
-use notification_emitter::{Message, Notification, Urgency, MAJOR_VERSION, MINOR_VERSION};
-use std::collections::HashMap;
+use futures_util::StreamExt;
+use notification_emitter::codec::{self, WireCodec as _, WireU32};
+use notification_emitter::crash;
+use notification_emitter::l10n::Catalog;
+use notification_emitter::resume::{self, ClockWatch};
+use notification_emitter::{forwarded_capabilities, guest_capabilities, Capabilities};
+use notification_emitter::{is_valid_language_tag, language_tag_from_locale, LOCALE_VERSION};
+use notification_emitter::{
+    is_valid_tag, Suppression, INVALID_ID_ERROR, PING_VERSION, TAG_VERSION,
+};
+use notification_emitter::{ExpireTimeout, ImageParameters, ReplyMessage};
+use notification_emitter::{Extension, FIRST_PROVISIONAL_ID, PROVISIONAL_IDS_VERSION};
+use notification_emitter::{Features, NegotiatedFeatures, PROGRESS_VERSION};
+use notification_emitter::{HistoryEntry, Outcome, BATCH_VERSION, HISTORY_VERSION};
+use notification_emitter::{Message, NotificationBuilder, Urgency, MAJOR_VERSION, MINOR_VERSION};
+use notification_emitter::{Request, CLOSE_VERSION, POSITION_VERSION, PROBE_VERSION};
+use notification_emitter::{DRAIN_VERSION, MAX_MESSAGE_SIZE};
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::sync::Mutex;
 use zbus::zvariant::{DeserializeDict, SerializeDict, Type, Value};
 
-#[derive(Debug)]
+/// How long a `Notify` call waits for dom0 before returning a provisional
+/// ID, so that a lost reply never hangs the calling application.
+const NOTIFY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(3);
+
+/// How long a `Notify` call waits for a dom0 too old for provisional IDs
+/// before failing.  Its reply may have been in a malformed frame, which is
+/// dropped.
+const REPLY_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+/// Default for [`ServerInner::max_in_flight`].
+const DEFAULT_MAX_IN_FLIGHT: usize = 64;
+
+/// D-Bus error for a `Notify` call made while too many calls are waiting for
+/// dom0.  The caller can try again later.
+const BUSY_ERROR: &str = "org.freedesktop.Notifications.Error.Busy";
+
+/// Default for [`ServerInner::batch_window`].
+const DEFAULT_BATCH_WINDOW: std::time::Duration = std::time::Duration::from_millis(10);
+
+/// Room left in a frame for the encoding of a [`Request::Batch`] around the
+/// notifications in it.
+const BATCH_OVERHEAD: usize = 64;
+
+/// How long dom0 has to answer a [`Request::Drain`] sent on termination.
+const DRAIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Default interval between pings to dom0.
+const DEFAULT_PING_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Default time dom0 has to answer a ping.  Generous, because dom0 does not
+/// read anything while calls to a slow daemon take up all its slots.
+const DEFAULT_PING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+
+/// Command that connects to dom0 again after it restarted.  Its standard
+/// input and output are the new connection.
+const RECONNECT_COMMAND: [&str; 3] = [
+    "/usr/lib/qubes/qrexec-client-vm",
+    "dom0",
+    "qubes.Notifications",
+];
+
+/// What a call to dom0 returned: a value, or a D-Bus error name and
+/// message.
+type CallResult<T> = Result<T, (String, Option<String>)>;
+
+/// Where requests to dom0 are written: standard output at first, and the
+/// connection made by [`RECONNECT_COMMAND`] after dom0 restarted.
+type Output = Box<dyn tokio::io::AsyncWrite + Unpin + Send>;
+
 struct ServerInner {
-    out: tokio::io::Stdout,
+    out: Output,
     map: HashMap<u64, Sender<Result<u32, (String, Option<String>)>>>,
+    /// How many calls may wait in `map` before `Notify` fails with
+    /// [`BUSY_ERROR`], so that an application cannot use up memory and
+    /// flood dom0.
+    max_in_flight: usize,
+    /// How long a notification waits for others to send along with it in
+    /// one [`Request::Batch`], or `None` to send each right away.
+    batch_window: Option<std::time::Duration>,
+    /// Notifications waiting to be sent in a batch, with the size of each
+    /// once encoded.
+    queued: Vec<(Message, usize)>,
+    /// Whether dom0 keeps its IDs below [`FIRST_PROVISIONAL_ID`], so that
+    /// provisional IDs can be handed out.
+    provisional_ids: bool,
+    /// Negotiated protocol version and features.
+    features: NegotiatedFeatures,
+    /// Calls whose reply came too late, keyed by sequence number.  The
+    /// value is the ID the application was given instead.
+    late: HashMap<u64, u32>,
+    /// Provisional IDs whose real ID is known, keyed by the real ID.
+    aliases: HashMap<u32, u32>,
+    /// The reverse of `aliases`: real IDs, keyed by the provisional ID.
+    dom0_ids: HashMap<u32, u32>,
+    next_provisional: u32,
+    /// Application IDs with a call in flight that replaces them, or that
+    /// are provisional and whose real ID is not known yet.  Later calls
+    /// replacing the same ID queue up here, so that updates are applied
+    /// in order and only once the ID can be translated.
+    busy: HashMap<u32, VecDeque<Sender<()>>>,
+    /// The application ID each call in flight keeps busy, keyed by
+    /// sequence number.
+    holds: HashMap<u64, u32>,
+    /// Effective expire timeouts reported by dom0, keyed by dom0 ID.
+    expire_timeouts: HashMap<u32, ExpireTimeout>,
+    /// Whether dom0 truncated the text, keyed by dom0 ID.
+    truncated: HashMap<u32, bool>,
+    /// Why dom0 did not show notifications, keyed by dom0 ID.
+    suppressed: HashMap<u32, Suppression>,
+    /// Callers of `ProbeCapabilities`, keyed by sequence number.
+    probes: HashMap<u64, Sender<CallResult<Vec<String>>>>,
+    /// Callers of `GetHistory`, keyed by sequence number.
+    histories: HashMap<u64, Sender<CallResult<Vec<HistoryEntry>>>>,
+    /// Pings waiting for their pong, keyed by sequence number.
+    pings: HashMap<u64, Sender<()>>,
+    /// Capabilities of the daemon in dom0, once dom0 reported them.
+    daemon_capabilities: Option<Capabilities>,
+    /// Whether a [`Request::Drain`] was sent, after which no notifications
+    /// are accepted.
+    draining: bool,
+    /// Notifications dom0 did not answer yet, keyed by sequence number, so
+    /// that they can be sent again if dom0 restarts.
+    pending: HashMap<u64, Message>,
+    /// Replacements sent again as new notifications after dom0 restarted,
+    /// keyed by sequence number.  The value is the application ID they
+    /// replace, which the new notification takes over.
+    replayed: HashMap<u64, u32>,
+    /// Whether dom0 announced a restart.  Notifications are then held until
+    /// the connection to the new instance is made.
+    restarting: bool,
+}
+
+impl ServerInner {
+    fn new(
+        out: impl tokio::io::AsyncWrite + Unpin + Send + 'static,
+        features: NegotiatedFeatures,
+    ) -> Self {
+        Self {
+            out: Box::new(out),
+            map: HashMap::new(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            batch_window: Some(DEFAULT_BATCH_WINDOW).filter(|_| features.minor() >= BATCH_VERSION),
+            queued: vec![],
+            provisional_ids: features.minor() >= PROVISIONAL_IDS_VERSION,
+            features,
+            late: HashMap::new(),
+            aliases: HashMap::new(),
+            dom0_ids: HashMap::new(),
+            next_provisional: FIRST_PROVISIONAL_ID,
+            busy: HashMap::new(),
+            holds: HashMap::new(),
+            expire_timeouts: HashMap::new(),
+            truncated: HashMap::new(),
+            suppressed: HashMap::new(),
+            probes: HashMap::new(),
+            histories: HashMap::new(),
+            pings: HashMap::new(),
+            daemon_capabilities: None,
+            draining: false,
+            pending: HashMap::new(),
+            replayed: HashMap::new(),
+            restarting: false,
+        }
+    }
+
+    /// Hand out a provisional ID for the call with the given sequence
+    /// number.  The ID stays busy until the real ID is known.
+    fn provisional_id(&mut self, sequence: u64) -> u32 {
+        let id = self.next_provisional;
+        self.next_provisional = id.checked_add(1).unwrap_or(FIRST_PROVISIONAL_ID);
+        self.late.insert(sequence, id);
+        self.busy.insert(id, VecDeque::new());
+        self.holds.insert(sequence, id);
+        id
+    }
+
+    /// Write one frame to dom0, after the notifications waiting to be
+    /// batched, so that requests reach dom0 in the order they were made.
+    async fn transmit(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.flush().await?;
+        codec::write_frame(&mut self.out, self.features.framing(), data).await
+    }
+
+    /// Send the notifications waiting to be batched, in as few frames as
+    /// fit them.  A frame with a single notification is a plain
+    /// [`Request::Notify`].
+    async fn flush(&mut self) -> std::io::Result<()> {
+        let limit = MAX_MESSAGE_SIZE as usize - BATCH_OVERHEAD;
+        let mut queued = std::mem::take(&mut self.queued).into_iter().peekable();
+        while let Some((message, mut size)) = queued.next() {
+            let mut batch = vec![message];
+            while let Some((message, next)) = queued.next_if(|&(_, next)| size + next <= limit) {
+                batch.push(message);
+                size += next;
+            }
+            let request = match batch.len() {
+                1 => Request::Notify(batch.remove(0)),
+                _ => Request::Batch(batch),
+            };
+            let data = self
+                .features
+                .encoding()
+                .encode(&request)
+                .expect("Cannot serialize object?");
+            codec::write_frame(&mut self.out, self.features.framing(), &data).await?
+        }
+        Ok(())
+    }
+
+    /// Capabilities to advertise to applications: those of the daemon in
+    /// dom0 that are passed on, or a fixed set if dom0 does not report
+    /// them.  Inline replies are left out if dom0 turned them off.
+    fn capabilities(&self) -> Capabilities {
+        let minor = self.features.minor();
+        let mut capabilities = match self.daemon_capabilities {
+            Some(daemon) => forwarded_capabilities(daemon, minor),
+            None => guest_capabilities(minor),
+        };
+        if !self.features.has(Features::INLINE_REPLY) {
+            capabilities -= Capabilities::INLINE_REPLY
+        }
+        capabilities
+    }
+
+    /// Translate an ID from dom0 to the ID the application knows.
+    fn application_id(&self, id: u32) -> u32 {
+        *self.aliases.get(&id).unwrap_or(&id)
+    }
+
+    /// Translate an ID from the application to the ID dom0 knows.
+    fn dom0_id(&self, id: u32) -> u32 {
+        *self.dom0_ids.get(&id).unwrap_or(&id)
+    }
+
+    /// Remember that dom0 knows the notification the application knows as
+    /// `application_id` as `id`.
+    fn alias(&mut self, id: u32, application_id: u32) {
+        self.unalias(id);
+        if application_id != id {
+            self.aliases.insert(id, application_id);
+            self.dom0_ids.insert(application_id, id);
+        }
+    }
+
+    /// Forget the alias of `id`, returning the ID the application knows.
+    fn unalias(&mut self, id: u32) -> u32 {
+        let Some(application_id) = self.aliases.remove(&id) else {
+            return id;
+        };
+        if self.dom0_ids.get(&application_id) == Some(&id) {
+            self.dom0_ids.remove(&application_id);
+        }
+        application_id
+    }
+
+    /// Forget what dom0 reported about its notifications, once they are
+    /// gone.
+    fn forget_notifications(&mut self) {
+        self.aliases.clear();
+        self.dom0_ids.clear();
+        self.expire_timeouts.clear();
+        self.truncated.clear();
+        self.suppressed.clear();
+    }
+
+    /// Hand `id` to the next call waiting for it, or mark it as no longer
+    /// busy if there is none.
+    fn release(&mut self, id: u32) {
+        if let Some(waiters) = self.busy.get_mut(&id) {
+            while let Some(waiter) = waiters.pop_front() {
+                if waiter.send(()).is_ok() {
+                    return;
+                }
+            }
+            self.busy.remove(&id);
+        }
+    }
+
+    /// Carry what is known about the notification dom0 knew as `id` over to
+    /// `new_id`, under which dom0 showed it again.  The application keeps
+    /// the ID it has.
+    fn reshown(&mut self, id: u32, new_id: u32) {
+        let application_id = self.unalias(id);
+        self.alias(new_id, application_id);
+        if let Some(expire_timeout) = self.expire_timeouts.remove(&id) {
+            self.expire_timeouts.insert(new_id, expire_timeout);
+        }
+        if let Some(truncated) = self.truncated.remove(&id) {
+            self.truncated.insert(new_id, truncated);
+        }
+        if let Some(suppression) = self.suppressed.remove(&id) {
+            self.suppressed.insert(new_id, suppression);
+        }
+    }
+
+    /// Fail every call waiting for dom0 with `reason`, since nothing will
+    /// answer them anymore.
+    fn abandon(&mut self, reason: &str) {
+        // Dropping the waiters wakes them up.
+        self.busy.clear();
+        self.holds.clear();
+        for (_key, value) in self.map.drain() {
+            // The caller may have gone away already.
+            let _ = value.send(Err((reason.to_owned(), None)));
+        }
+        for (_key, value) in self.probes.drain() {
+            let _ = value.send(Err((reason.to_owned(), None)));
+        }
+        for (_key, value) in self.histories.drain() {
+            let _ = value.send(Err((reason.to_owned(), None)));
+        }
+        self.pending.clear();
+        self.replayed.clear();
+    }
+
+    /// The call with the given sequence number has completed.
+    fn completed(&mut self, sequence: u64) {
+        self.pending.remove(&sequence);
+        self.replayed.remove(&sequence);
+        if let Some(id) = self.holds.remove(&sequence) {
+            self.release(id)
+        }
+    }
+
+    /// Switch to `out`, the connection to the new instance of dom0, and send
+    /// it the notifications the old one did not answer, in the order they
+    /// were made.  What the old instance reported about its notifications
+    /// is forgotten, since they are gone.  Replacements become new
+    /// notifications, which take over the ID of those they replace.
+    async fn replay(&mut self, out: Output) -> std::io::Result<()> {
+        self.out = out;
+        self.restarting = false;
+        // Also in `pending`.
+        self.queued.clear();
+        // The old instance answered what it could with its IDs, but the
+        // new one knows none of them.
+        self.forget_notifications();
+        self.daemon_capabilities = None;
+        // Dropping the senders tells the pinger to carry on.
+        self.pings.clear();
+        for (_key, value) in self.probes.drain() {
+            let _ = value.send(Err(("Server restarted".to_owned(), None)));
+        }
+        for (_key, value) in self.histories.drain() {
+            let _ = value.send(Err(("Server restarted".to_owned(), None)));
+        }
+        let mut pending: Vec<_> = self.pending.values().cloned().collect();
+        pending.sort_by_key(|message| message.id);
+        eprintln!("Sending {} calls to dom0 again", pending.len());
+        for mut message in pending {
+            let sequence = message.id;
+            if message.notification.replaces_id() != 0 {
+                message.notification.set_replaces_id(0);
+                if let Some(&application_id) = self.holds.get(&sequence) {
+                    self.replayed.insert(sequence, application_id);
+                }
+            }
+            let encoding = self.features.encoding();
+            let data = if self.features.minor() >= CLOSE_VERSION {
+                encoding.encode(&Request::Notify(message))
+            } else {
+                encoding.encode(&message)
+            }
+            .expect("Cannot serialize object?");
+            match self.transmit(&data).await {
+                Ok(()) => {}
+                // Only possible for a notification held while restarting,
+                // which was never checked against the batch limit.
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                    self.completed(sequence);
+                    if let Some(sender) = self.map.remove(&sequence) {
+                        let message = format!("Notification too large ({} bytes)", data.len());
+                        let _ = sender.send(Err(("Failed".to_owned(), Some(message))));
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Wait until no other call is replacing `id`, then mark it busy.
+async fn acquire(server: &Mutex<ServerInner>, id: u32) -> tokio::sync::MutexGuard<'_, ServerInner> {
+    let mut guard = server.lock().await;
+    if let Some(waiters) = guard.busy.get_mut(&id) {
+        let (sender, receiver) = futures_channel::oneshot::channel();
+        waiters.push_back(sender);
+        drop(guard);
+        // The sender is only dropped without sending if the queue is
+        // cleared, in which case there is nothing left to wait for.
+        let _ = receiver.await;
+        guard = server.lock().await;
+    }
+    guard.busy.entry(id).or_default();
+    guard
 }
 
-struct Server(Arc<Mutex<ServerInner>>, core::sync::atomic::AtomicU64);
+macro_rules! log_return {
+    ($($arg:tt),*$(,)?) => {{
+        eprintln!($($arg),*);
+        return Err(zbus::fdo::Error::InvalidArgs(format!($($arg),*)).into())
+    }};
+}
+
+/// Errors returned by `org.freedesktop.Notifications` methods.  This is not
+/// derived, since a derived error would report the standard errors as
+/// `org.freedesktop.zbus.Error` instead of under their own names.
+#[derive(Debug)]
+enum NotificationsError {
+    Fdo(zbus::fdo::Error),
+    /// `CloseNotification` was called with an ID that is not in use.
+    InvalidId(String),
+    /// `Notify` was called while too many calls were in flight.
+    Busy(String),
+}
+
+impl From<zbus::fdo::Error> for NotificationsError {
+    fn from(e: zbus::fdo::Error) -> Self {
+        NotificationsError::Fdo(e)
+    }
+}
+
+impl zbus::DBusError for NotificationsError {
+    fn create_reply(&self, call: &zbus::MessageHeader<'_>) -> zbus::Result<zbus::Message> {
+        match self {
+            NotificationsError::Fdo(e) => e.create_reply(call),
+            NotificationsError::InvalidId(message) | NotificationsError::Busy(message) => {
+                zbus::MessageBuilder::error(call, self.name())?.build(&(message,))
+            }
+        }
+    }
+    fn name(&self) -> zbus::names::ErrorName<'_> {
+        match self {
+            NotificationsError::Fdo(e) => e.name(),
+            NotificationsError::InvalidId(_) => {
+                zbus::names::ErrorName::from_static_str_unchecked(INVALID_ID_ERROR)
+            }
+            NotificationsError::Busy(_) => {
+                zbus::names::ErrorName::from_static_str_unchecked(BUSY_ERROR)
+            }
+        }
+    }
+    fn description(&self) -> Option<&str> {
+        match self {
+            NotificationsError::Fdo(e) => e.description(),
+            NotificationsError::InvalidId(message) | NotificationsError::Busy(message) => {
+                Some(message)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Server(Arc<Mutex<ServerInner>>, Arc<core::sync::atomic::AtomicU64>);
+
+/// Qubes-specific extensions, served next to the standard interface.
+struct ClientInterface {
+    server: Server,
+    /// Notification showing each operation reported with
+    /// `ReportProgress`, keyed by operation name.
+    progress: Mutex<HashMap<String, u32>>,
+}
+
+impl ClientInterface {
+    fn new(server: Server) -> Self {
+        Self {
+            server,
+            progress: Default::default(),
+        }
+    }
+}
+
+#[zbus::dbus_interface(name = "org.qubes.NotificationProxy1.Client")]
+impl ClientInterface {
+    /// Protocol version negotiated with dom0, as (major, minor).
+    #[dbus_interface(property)]
+    async fn protocol_version(&self) -> (u16, u16) {
+        (MAJOR_VERSION, self.server.0.lock().await.features.minor())
+    }
+    /// Protocol features negotiated with dom0.
+    #[dbus_interface(property)]
+    async fn features(&self) -> Vec<String> {
+        let features = self.server.0.lock().await.features.names().into_iter();
+        features.map(str::to_owned).collect()
+    }
+    /// The expire timeout dom0 actually used for notification `id`, in
+    /// milliseconds.  -1 means the daemon's default and 0 means never.
+    async fn get_expire_timeout(&self, id: u32) -> zbus::fdo::Result<i32> {
+        let guard = self.server.0.lock().await;
+        match guard.expire_timeouts.get(&guard.dom0_id(id)) {
+            Some(&expire_timeout) => Ok(expire_timeout.into()),
+            None => Err(zbus::fdo::Error::InvalidArgs(format!(
+                "No expire timeout known for notification {}",
+                id
+            ))),
+        }
+    }
+    /// Whether dom0 cut the summary or body of notification `id` short
+    /// because it had too many lines.
+    async fn was_truncated(&self, id: u32) -> zbus::fdo::Result<bool> {
+        let guard = self.server.0.lock().await;
+        match guard.truncated.get(&guard.dom0_id(id)) {
+            Some(&truncated) => Ok(truncated),
+            None => Err(zbus::fdo::Error::InvalidArgs(format!(
+                "Not known whether notification {} was truncated",
+                id
+            ))),
+        }
+    }
+    /// Why dom0 did not show notification `id`: `muted`, `quiet-hours`, or
+    /// `log-only`.  An empty string means that it was shown, or that dom0
+    /// does not report this, which can be told from `Features`.  Tools
+    /// can use this to try again later.
+    async fn get_suppression(&self, id: u32) -> String {
+        let guard = self.server.0.lock().await;
+        match guard.suppressed.get(&guard.dom0_id(id)) {
+            Some(reason) => reason.name().to_owned(),
+            None => String::new(),
+        }
+    }
+    /// Make dom0 ask its notification daemon for its capabilities again,
+    /// and return them.  Useful after switching daemons in dom0, if dom0
+    /// did not notice.  Notifications that lost hints because the old
+    /// daemon lacked them are shown again if the new one has them.
+    async fn probe_capabilities(&self) -> zbus::fdo::Result<Vec<String>> {
+        if self.server.0.lock().await.features.minor() < PROBE_VERSION {
+            log_return!("dom0 cannot probe capabilities");
+        }
+        probe(&self.server)
+            .await
+            .await
+            .expect("sender crashed")
+            .map_err(|(_name, message)| {
+                zbus::fdo::Error::Failed(message.unwrap_or("failed".to_owned()))
+            })
+    }
+    /// What dom0 remembers happening to notifications `ids`, or to all
+    /// notifications if `ids` is empty, oldest first.  Each entry is the
+    /// ID, `dismissed`, `action-invoked` or `replied`, the reason for a
+    /// dismissal, and the key of an invoked action.  Applications can use
+    /// this to catch up on signals they missed.
+    async fn get_history(
+        &self,
+        ids: Vec<u32>,
+    ) -> zbus::fdo::Result<Vec<(u32, String, u32, String)>> {
+        let mut guard = self.server.0.lock().await;
+        if guard.features.minor() < HISTORY_VERSION {
+            log_return!("dom0 does not keep a history");
+        }
+        let sequence = self
+            .server
+            .1
+            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let untrusted_ids = ids.into_iter().map(|id| guard.dom0_id(id)).collect();
+        let data = guard
+            .features
+            .encoding()
+            .encode(&Request::History {
+                sequence,
+                untrusted_ids,
+            })
+            .expect("Cannot serialize object?");
+        if let Err(e) = guard.transmit(&data).await {
+            panic!("Error writing to stdout: {}", e)
+        }
+        let (sender, receiver) = futures_channel::oneshot::channel();
+        guard.histories.insert(sequence, sender);
+        drop(guard);
+        let entries = receiver
+            .await
+            .expect("sender crashed")
+            .map_err(|(_name, message)| {
+                zbus::fdo::Error::Failed(message.unwrap_or("failed".to_owned()))
+            })?;
+        let guard = self.server.0.lock().await;
+        Ok(entries
+            .into_iter()
+            .map(|entry| {
+                let id = guard.application_id(entry.id);
+                let name = entry.outcome.name().to_owned();
+                match entry.outcome {
+                    Outcome::Dismissed { reason } => (id, name, reason, String::new()),
+                    Outcome::ActionInvoked { action } => (id, name, 0, action),
+                    Outcome::Replied => (id, name, 0, String::new()),
+                }
+            })
+            .collect())
+    }
+    /// Report the progress of the long-running operation `operation`,
+    /// as a percentage.  All reports for the same operation update a
+    /// single notification, whose ID is returned.  Names are chosen by the
+    /// caller and only need to be unique within this qube.
+    async fn report_progress(
+        &self,
+        operation: String,
+        summary: String,
+        body: String,
+        value: i32,
+    ) -> Result<u32, NotificationsError> {
+        if !(0..=100).contains(&value) {
+            log_return!("Progress {} is not a percentage", value);
+        }
+        // Held across the call, so that reports for one operation are
+        // applied in order.
+        let mut progress = self.progress.lock().await;
+        let replaces_id = progress.get(&operation).copied().unwrap_or(0);
+        let hints = HashMap::from([
+            ("value".to_owned(), Value::I32(value)),
+            ("category".to_owned(), Value::from("transfer")),
+            ("transient".to_owned(), Value::Bool(true)),
+        ]);
+        let id = self
+            .server
+            .notify(
+                "",
+                replaces_id,
+                String::new(),
+                summary,
+                body,
+                vec![],
+                hints,
+                -1,
+            )
+            .await?;
+        progress.insert(operation, id);
+        Ok(id)
+    }
+    /// Stop tracking `operation`.  The notification stays as it was last
+    /// reported; a later report with the same name starts a new one.
+    async fn end_progress(&self, operation: String) -> zbus::fdo::Result<()> {
+        match self.progress.lock().await.remove(&operation) {
+            Some(_) => Ok(()),
+            None => log_return!("Unknown operation {:?}", operation),
+        }
+    }
+}
 
 #[derive(SerializeDict, DeserializeDict, Type)]
 #[zvariant(signature = "a{sv}")]
@@ -30,13 +653,6 @@ struct Hints {
     image_data_deprecated1: Option<ImageParameters>,
 }
 
-macro_rules! log_return {
-    ($($arg:tt),*$(,)?) => {{
-        eprintln!($($arg),*);
-        return Err(zbus::fdo::Error::InvalidArgs(format!($($arg),*)))
-    }};
-}
-
 fn is_valid_action_name(action: &[u8]) -> zbus::fdo::Result<()> {
     // 255 is arbitrary but should be more than enough
     if action.is_empty() {
@@ -72,7 +688,8 @@ fn is_valid_action_name(action: &[u8]) -> zbus::fdo::Result<()> {
 #[zbus::dbus_interface(name = "org.freedesktop.Notifications")]
 impl Server {
     async fn get_capabilities(&self) -> zbus::fdo::Result<(Vec<String>,)> {
-        Ok((vec!["persistence".to_owned(), "actions".to_owned()],))
+        let capabilities = self.0.lock().await.capabilities().names().into_iter();
+        Ok((capabilities.map(str::to_owned).collect(),))
     }
     #[dbus_interface(signal)]
     async fn notification_closed(
@@ -88,6 +705,50 @@ impl Server {
         id: u32,
         action_key: String,
     ) -> zbus::Result<()>;
+    /// The user replied to notification `id`, which had an `inline-reply`
+    /// action.  A KDE extension.
+    #[dbus_interface(signal)]
+    async fn notification_replied(
+        &self,
+        signal_context: &zbus::SignalContext<'_>,
+        id: u32,
+        text: String,
+    ) -> zbus::Result<()>;
+    async fn close_notification(&self, id: u32) -> Result<(), NotificationsError> {
+        let sequence = self.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        // Wait for calls replacing the notification, so that a provisional
+        // ID is translated and an update is not overtaken.
+        let mut guard = acquire(&self.0, id).await;
+        guard.holds.insert(sequence, id);
+        if !guard.features.has(Features::CLOSE) {
+            guard.completed(sequence);
+            log_return!("dom0 cannot close notifications");
+        }
+        let request = Request::Close {
+            sequence,
+            untrusted_id: guard.dom0_id(id),
+        };
+        let data = guard
+            .features
+            .encoding()
+            .encode(&request)
+            .expect("Cannot serialize object?");
+        if let Err(e) = guard.transmit(&data).await {
+            panic!("Error writing to stdout: {}", e)
+        }
+        let (sender, receiver) = futures_channel::oneshot::channel();
+        guard.map.insert(sequence, sender);
+        drop(guard);
+        match receiver.await.expect("sender crashed") {
+            Ok(_) => Ok(()),
+            Err((name, message)) if name == INVALID_ID_ERROR => Err(NotificationsError::InvalidId(
+                message.unwrap_or_else(|| format!("No notification with ID {}", id)),
+            )),
+            Err((_name, message)) => {
+                Err(zbus::fdo::Error::Failed(message.unwrap_or("failed".to_owned())).into())
+            }
+        }
+    }
     async fn get_server_information(&self) -> zbus::fdo::Result<(String, String, String, String)> {
         Ok((
             "Qubes OS Notification Proxy".to_owned(),
@@ -107,19 +768,20 @@ impl Server {
         actions: Vec<String>,
         hints: HashMap<String, zbus::zvariant::Value<'_>>,
         expire_timeout: i32,
-    ) -> zbus::fdo::Result<u32> {
-        let options = bincode::DefaultOptions::new()
-            .with_fixint_encoding()
-            .with_native_endian()
-            .reject_trailing_bytes();
+    ) -> Result<u32, NotificationsError> {
         let mut image: Option<ImageParameters> = None;
         let mut suppress_sound = false;
         let mut transient = false;
         let mut urgency = None;
         let mut resident = false;
-        let mut category = None;
+        let mut category: Option<String> = None;
+        let (mut x, mut y, mut value) = (None, None, None);
+        let mut tag: Option<String> = None;
+        let mut locale: Option<String> = None;
         for (i, j) in hints.into_iter() {
             match &*i {
+                // Not advertised, so labels are never icon names, not even
+                // those of the reserved actions.
                 "action-icons" => {}
                 "category" => {
                     category = Some(
@@ -169,12 +831,41 @@ impl Server {
                 "suppress-sound" => suppress_sound = true,
                 "transient" => transient = true,
                 "resident" => resident = true,
-                "x" | "y" => eprintln!("Ignoring coordinate hint {} {:?}", i, j),
-                "urgency" => match j {
-                    Value::U8(0) => urgency = Some(Urgency::Low),
-                    Value::U8(1) => urgency = Some(Urgency::Normal),
-                    Value::U8(2) => urgency = Some(Urgency::Critical),
-                    _ => eprintln!("Ignoring unknown urgency value {:?}", j),
+                "x" => {
+                    x = Some(
+                        j.try_into()
+                            .map_err(|f: zbus::zvariant::Error| zbus::fdo::Error::ZBus(f.into()))?,
+                    )
+                }
+                "y" => {
+                    y = Some(
+                        j.try_into()
+                            .map_err(|f: zbus::zvariant::Error| zbus::fdo::Error::ZBus(f.into()))?,
+                    )
+                }
+                "value" => {
+                    value = Some(
+                        j.try_into()
+                            .map_err(|f: zbus::zvariant::Error| zbus::fdo::Error::ZBus(f.into()))?,
+                    )
+                }
+                // The Qubes name takes precedence over the dunst one.
+                "x-qubes-tag" | "x-dunst-stack-tag" if tag.is_none() || i == "x-qubes-tag" => {
+                    match String::try_from(j) {
+                        Ok(untrusted_tag) => tag = Some(untrusted_tag),
+                        Err(e) => eprintln!("Ignoring tag hint {:?}: {}", &*i, e),
+                    }
+                }
+                "x-dunst-stack-tag" => {}
+                "x-qubes-locale" => {
+                    locale = Some(
+                        j.try_into()
+                            .map_err(|f: zbus::zvariant::Error| zbus::fdo::Error::ZBus(f.into()))?,
+                    )
+                }
+                "urgency" => match Urgency::try_from(j) {
+                    Ok(u) => urgency = Some(u),
+                    Err(e) => eprintln!("Ignoring urgency hint: {}", e),
                 },
                 _ => {
                     eprintln!("Unknown hint {:?}, ignoring", &*i);
@@ -185,170 +876,814 @@ impl Server {
         if actions.len() & 1 != 0 {
             log_return!("Actions array has odd length");
         }
+        let Some(expire_timeout) = ExpireTimeout::from_wire(expire_timeout) else {
+            log_return!("Expire timeout must be at least -1, got {}", expire_timeout);
+        };
 
         for i in 0..actions.len() / 2 {
             is_valid_action_name(actions[i * 2].as_bytes())?
         }
 
-        let notification = Message {
-            id,
-            notification: Notification::V1 {
-                suppress_sound,
-                transient,
-                resident,
-                urgency,
-                replaces_id,
-                summary,
-                body,
-                actions,
-                category,
-                expire_timeout,
-                image,
-            },
+        let application_replaces_id = replaces_id;
+        let mut guard = match replaces_id {
+            0 => self.0.lock().await,
+            _ => acquire(&self.0, replaces_id).await,
+        };
+        if replaces_id != 0 {
+            guard.holds.insert(id, replaces_id);
+        }
+        if guard.draining {
+            guard.completed(id);
+            eprintln!("Refusing call {}: shutting down", id);
+            return Err(zbus::fdo::Error::Failed("Shutting down".to_owned()).into());
+        }
+        if guard.map.len() >= guard.max_in_flight {
+            guard.completed(id);
+            eprintln!("Refusing call {}: {} calls in flight", id, guard.map.len());
+            return Err(NotificationsError::Busy(format!(
+                "Too many notifications waiting for dom0 ({})",
+                guard.max_in_flight
+            )));
+        }
+        let replaces_id = guard.dom0_id(replaces_id);
+        let mut builder = NotificationBuilder::new(summary)
+            .body(body)
+            .actions(actions)
+            .replaces_id(replaces_id)
+            .expire_timeout(expire_timeout)
+            .suppress_sound(suppress_sound)
+            .transient(transient)
+            .resident(resident);
+        if let Some(urgency) = urgency {
+            builder = builder.urgency(urgency)
+        }
+        if let Some(category) = category {
+            builder = builder.category(category)
+        }
+        match image {
+            Some(image) if guard.features.has(Features::IMAGES) => builder = builder.image(image),
+            None => {}
+            Some(_) => eprintln!("Ignoring image, which dom0 does not accept"),
+        }
+        match (x, y) {
+            (Some(untrusted_x), Some(untrusted_y))
+                if guard.features.minor() >= POSITION_VERSION =>
+            {
+                builder = builder.extension(Extension::Position {
+                    untrusted_x,
+                    untrusted_y,
+                })
+            }
+            (None, None) => {}
+            _ => eprintln!("Ignoring coordinate hints {:?} {:?}", x, y),
+        }
+        match value {
+            Some(untrusted_value) if guard.features.minor() >= PROGRESS_VERSION => {
+                builder = builder.extension(Extension::Progress { untrusted_value })
+            }
+            None => {}
+            _ => eprintln!("Ignoring value hint {:?}", value),
+        }
+        match tag {
+            Some(untrusted_tag) if !is_valid_tag(&untrusted_tag) => {
+                eprintln!("Ignoring invalid tag {:?}", untrusted_tag)
+            }
+            Some(untrusted_tag) if guard.features.minor() >= TAG_VERSION => {
+                builder = builder.extension(Extension::Tag { untrusted_tag })
+            }
+            None => {}
+            Some(tag) => eprintln!("Ignoring tag hint {:?}", tag),
+        }
+        // Without a hint, the text is most likely in the language of the
+        // session.
+        let locale = locale.or_else(|| {
+            Catalog::environment_locale().and_then(|locale| language_tag_from_locale(&locale))
+        });
+        match locale {
+            _ if guard.features.minor() < LOCALE_VERSION => {}
+            Some(untrusted_language) if is_valid_language_tag(&untrusted_language) => {
+                builder = builder.extension(Extension::Locale { untrusted_language })
+            }
+            None => {}
+            Some(locale) => eprintln!("Ignoring invalid locale hint {:?}", locale),
+        }
+        let notification = match builder.build() {
+            Ok(notification) => notification,
+            Err(e) => {
+                guard.completed(id);
+                log_return!("{}", e)
+            }
         };
+        let notification = Message { id, notification };
+        guard.pending.insert(id, notification.clone());
 
-        let data = options
-            .serialize(&notification)
+        let encoding = guard.features.encoding();
+        let batch_window = guard.batch_window;
+        // The first notification queued for a batch sends it.
+        let mut sends_batch = false;
+        if guard.restarting {
+            // Sent by ServerInner::replay() once dom0 is back.
+            eprintln!("Holding call {} until dom0 restarted", id);
+        } else if batch_window.is_some() {
+            let size = encoding
+                .encode(&notification)
+                .expect("Cannot serialize object?")
+                .len();
+            if size > MAX_MESSAGE_SIZE as usize - BATCH_OVERHEAD {
+                guard.completed(id);
+                log_return!("Notification too large ({} bytes)", size);
+            }
+            sends_batch = guard.queued.is_empty();
+            guard.queued.push((notification, size));
+        } else {
+            let data = if guard.features.minor() >= CLOSE_VERSION {
+                encoding.encode(&Request::Notify(notification))
+            } else {
+                encoding.encode(&notification)
+            }
             .expect("Cannot serialize object?");
 
-        let len: u32 = data.len().try_into().unwrap();
-        let mut guard = self.0.lock().await;
-        guard
-            .out
-            .write_u32_le(len.to_le())
-            .await
-            .expect("error writing to stdout");
-        guard
-            .out
-            .write_all(&*data)
-            .await
-            .expect("error writing to stdout");
-        guard.out.flush().await.expect("Error writing to stdout");
+            match guard.transmit(&data).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {
+                    guard.completed(id);
+                    log_return!("Notification too large ({} bytes)", (data.len()));
+                }
+                Err(e) => panic!("Error writing to stdout: {}", e),
+            }
+        }
         let (sender, receiver) = futures_channel::oneshot::channel();
         guard.map.insert(id, sender);
+        let provisional_ids = guard.provisional_ids;
         drop(guard);
-        eprintln!("Message sent to server");
+        if let (Some(window), true) = (batch_window, sends_batch) {
+            tokio::time::sleep(window).await;
+            if let Err(e) = self.0.lock().await.flush().await {
+                panic!("Error writing to stdout: {}", e)
+            }
+        }
+        // dom0 logs it as <qube>:<call>.
+        eprintln!("Call {} sent to dom0", id);
 
+        let mut receiver = receiver;
+        let timeout = match provisional_ids {
+            true => NOTIFY_TIMEOUT,
+            false => REPLY_TIMEOUT,
+        };
+        if tokio::time::timeout(timeout, &mut receiver).await.is_err() {
+            let mut guard = self.0.lock().await;
+            // If the sender is gone, the reply arrived just now.
+            if guard.map.remove(&id).is_some() {
+                if !provisional_ids {
+                    guard.completed(id);
+                    eprintln!("No reply from dom0 for call {}", id);
+                    return Err(zbus::fdo::Error::Failed(format!(
+                        "No reply from dom0 for call {}",
+                        id
+                    ))
+                    .into());
+                }
+                if application_replaces_id != 0 {
+                    // A successful call returns the ID it replaced.
+                    guard.late.insert(id, application_replaces_id);
+                    eprintln!("No reply from dom0 for call {}", id);
+                    return Ok(application_replaces_id);
+                }
+                let provisional = guard.provisional_id(id);
+                eprintln!(
+                    "No reply from dom0 for call {}, returning provisional ID {}",
+                    id, provisional
+                );
+                return Ok(provisional);
+            }
+        }
         receiver
             .await
             .expect("sender crashed")
-            .map_err(|(_a, b)| zbus::fdo::Error::Failed(b.unwrap_or("failed".to_owned())))
+            .map_err(|(_a, b)| zbus::fdo::Error::Failed(b.unwrap_or("failed".to_owned())).into())
     }
 }
 
-async fn client_server() {
-    let mut stdin = tokio::io::stdin();
-    let mut out = tokio::io::stdout();
-    let version = stdin
-        .read_u32_le()
+/// What to do if another notification daemon already owns
+/// `org.freedesktop.Notifications` in this qube.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NameMode {
+    /// Exit with an error.
+    Fail,
+    /// Take the name over, which only works if the other daemon allows it.
+    Replace,
+    /// Wait until the other daemon releases the name.
+    Queue,
+}
+
+/// Acquire `org.freedesktop.Notifications` on `connection`, as `mode` says.
+async fn acquire_name(connection: &zbus::Connection, mode: NameMode) {
+    use zbus::fdo::{RequestNameFlags, RequestNameReply};
+    const NAME: &str = "org.freedesktop.Notifications";
+    let dbus = zbus::fdo::DBusProxy::new(connection)
+        .await
+        .expect("cannot talk to bus daemon");
+    let mut acquired = dbus
+        .receive_name_acquired()
+        .await
+        .expect("cannot watch for name");
+    let flags = match mode {
+        NameMode::Fail => RequestNameFlags::DoNotQueue.into(),
+        NameMode::Replace => RequestNameFlags::ReplaceExisting | RequestNameFlags::DoNotQueue,
+        NameMode::Queue => Default::default(),
+    };
+    // Only the daemon being replaced needs to be known.
+    let previous_owner = match mode {
+        NameMode::Replace => dbus
+            .get_name_owner(NAME.try_into().expect("valid name"))
+            .await
+            .ok(),
+        NameMode::Fail | NameMode::Queue => None,
+    };
+    let reply = connection
+        .request_name_with_flags(NAME, flags)
         .await
-        .expect("Error reading from stdin")
-        .to_le();
+        .expect("cannot request name");
+    if matches!(
+        reply,
+        RequestNameReply::PrimaryOwner | RequestNameReply::AlreadyOwner
+    ) {
+        if let Some(previous_owner) = previous_owner {
+            eprintln!("Replaced {} as owner of {}", previous_owner, NAME);
+            if let Err(e) = explain_takeover(connection, previous_owner).await {
+                eprintln!("Cannot tell the user about the replacement: {}", e)
+            }
+        }
+        return;
+    }
+    let owner = match dbus
+        .get_name_owner(NAME.try_into().expect("valid name"))
+        .await
+    {
+        Ok(owner) => match dbus
+            .get_connection_unix_process_id(owner.clone().into())
+            .await
+        {
+            Ok(pid) => format!("{} (process {})", owner, pid),
+            Err(_) => owner.to_string(),
+        },
+        Err(_) => "another client".to_owned(),
+    };
+    match (reply, mode) {
+        // The owner did not allow replacement when it requested the name.
+        (RequestNameReply::Exists, NameMode::Replace) => {
+            eprintln!(
+                "{} is owned by {}, which refused to be replaced.  \
+                Stop it, or pass --queue.",
+                NAME, owner
+            );
+            std::process::exit(1)
+        }
+        (RequestNameReply::Exists, _) => {
+            eprintln!(
+                "{} is owned by {}, so another notification daemon runs in this qube.  \
+                Stop it, or pass --replace or --queue.",
+                NAME, owner
+            );
+            std::process::exit(1)
+        }
+        _ => {}
+    }
+    eprintln!("Waiting for {} to release {}", owner, NAME);
+    while let Some(signal) = acquired.next().await {
+        if signal.args().is_ok_and(|args| args.name == NAME) {
+            eprintln!("Acquired {}", NAME);
+            return;
+        }
+    }
+    panic!("bus daemon went away")
+}
+
+/// Show a notification through `daemon`, which just lost
+/// `org.freedesktop.Notifications` to this client, telling the user that
+/// notifications are now shown by dom0.  The daemon still runs and can be
+/// reached by its unique name.  The notifications it shows stay with it:
+/// there is no way to enumerate them, so their IDs cannot be taken over.
+async fn explain_takeover(
+    connection: &zbus::Connection,
+    daemon: zbus::names::OwnedUniqueName,
+) -> zbus::Result<()> {
+    let proxy = zbus::Proxy::new(
+        connection,
+        daemon,
+        "/org/freedesktop/Notifications",
+        "org.freedesktop.Notifications",
+    )
+    .await?;
+    let actions: &[&str] = &[];
+    let hints: HashMap<&str, Value<'_>> = HashMap::new();
+    let _id: u32 = proxy
+        .call(
+            "Notify",
+            &(
+                "Qubes OS",
+                0u32,
+                "",
+                "Notifications are now shown by dom0",
+                "The Qubes notification proxy replaced the notification \
+                daemon of this qube.  New notifications are shown by dom0, \
+                with the name of this qube.",
+                actions,
+                hints,
+                -1i32,
+            ),
+        )
+        .await?;
+    Ok(())
+}
+
+/// Open a link the user clicked in dom0.  By default this goes through
+/// `qvm-open-in-dvm`, so qrexec policy for `qubes.OpenURL` decides where the
+/// link is opened.  `url` always starts with `http://` or `https://`, so it
+/// cannot be mistaken for an option.
+fn open_url(url: String, locally: bool) {
+    let mut command = if locally {
+        std::process::Command::new("xdg-open")
+    } else {
+        std::process::Command::new("qvm-open-in-dvm")
+    };
+    match command.arg(&url).spawn() {
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => eprintln!("Cannot open {:?}: {}", url, e),
+    }
+}
+
+/// Ping dom0 every `interval`, and give up if a ping is not answered
+/// within `timeout`.  Otherwise, a connection that died silently is only
+/// noticed when the next notification is sent, which may be much later.
+async fn keepalive(server: Server, interval: std::time::Duration, timeout: std::time::Duration) {
+    if server.0.lock().await.features.minor() < PING_VERSION {
+        return;
+    }
+    loop {
+        tokio::time::sleep(interval).await;
+        let ping = async {
+            let mut guard = server.0.lock().await;
+            let sequence = server.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let data = guard
+                .features
+                .encoding()
+                .encode(&Request::Ping { sequence })
+                .expect("Cannot serialize object?");
+            if let Err(e) = guard.transmit(&data).await {
+                panic!("Error writing to stdout: {}", e)
+            }
+            let (sender, receiver) = futures_channel::oneshot::channel();
+            guard.pings.insert(sequence, sender);
+            drop(guard);
+            receiver.await
+        };
+        match tokio::time::timeout(timeout, ping).await {
+            Ok(Ok(())) => {}
+            // dom0 restarted before answering.
+            Ok(Err(_)) => {}
+            Err(_) => panic!("dom0 did not answer a ping within {:?}", timeout),
+        }
+    }
+}
+
+/// Ask dom0 to probe the capabilities of its daemon.  The reply also
+/// updates [`ServerInner::daemon_capabilities`].
+async fn probe(server: &Server) -> futures_channel::oneshot::Receiver<CallResult<Vec<String>>> {
+    let mut guard = server.0.lock().await;
+    let sequence = server.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let data = guard
+        .features
+        .encoding()
+        .encode(&Request::Probe { sequence })
+        .expect("Cannot serialize object?");
+    if let Err(e) = guard.transmit(&data).await {
+        panic!("Error writing to stdout: {}", e)
+    }
+    let (sender, receiver) = futures_channel::oneshot::channel();
+    guard.probes.insert(sequence, sender);
+    receiver
+}
+
+/// After the qube was suspended, check that dom0 still answers within
+/// `timeout`, and learn the capabilities of its daemon again, since they
+/// may have changed meanwhile.
+async fn resync_on_resume(server: Server, timeout: std::time::Duration) {
+    if server.0.lock().await.features.minor() < PROBE_VERSION {
+        return;
+    }
+    let mut watch = ClockWatch::new();
+    loop {
+        let suspended = resume::next_resume(&mut watch).await;
+        eprintln!("Resumed after {:?}, probing dom0", suspended);
+        match tokio::time::timeout(timeout, probe(&server).await).await {
+            Ok(Ok(Ok(_))) => {}
+            Ok(Ok(Err((name, _)))) => eprintln!("Cannot probe capabilities: {}", name),
+            Ok(Err(_)) => return,
+            Err(_) => panic!("dom0 did not answer within {:?} after resume", timeout),
+        }
+    }
+}
+
+/// Tell dom0 that the connection is ending, unless that was done already.
+/// dom0 answers with [`ReplyMessage::Drained`] once every call made before
+/// is answered.
+async fn drain(server: &Server) {
+    let mut guard = server.0.lock().await;
+    if guard.draining {
+        return;
+    }
+    guard.draining = true;
+    let sequence = server.1.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let data = guard
+        .features
+        .encoding()
+        .encode(&Request::Drain { sequence })
+        .expect("Cannot serialize object?");
+    if let Err(e) = guard.transmit(&data).await {
+        panic!("Error writing to stdout: {}", e)
+    }
+}
+
+/// Drain the connection when asked to terminate, and give up if dom0 does
+/// not answer in time.
+async fn drain_on_termination(server: Server) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate()).expect("Cannot handle SIGTERM");
+    terminate.recv().await;
+    eprintln!("Terminating, draining connection to dom0");
+    drain(&server).await;
+    tokio::time::sleep(DRAIN_TIMEOUT).await;
+    eprintln!("dom0 did not drain the connection in time");
+    std::process::exit(1)
+}
+
+/// Agree with dom0 on a protocol version and features, reading from
+/// `input` and writing to `out`.
+async fn handshake(
+    input: &mut (impl tokio::io::AsyncRead + Unpin),
+    out: &mut (impl tokio::io::AsyncWrite + Unpin),
+) -> std::io::Result<NegotiatedFeatures> {
+    let version = WireU32::read(input).await?.get();
     let (daemon_major_version, daemon_minor_version) = notification_emitter::split_version(version);
-    let minor_version = (daemon_minor_version as u16).min(MINOR_VERSION);
-    out.write_u32_le(notification_emitter::merge_versions(
+    let minor_version = daemon_minor_version.min(MINOR_VERSION);
+    WireU32::new(notification_emitter::merge_versions(
         MAJOR_VERSION,
         minor_version,
     ))
-    .await
-    .expect("error writing to daemon");
-    out.flush().await.expect("flush failed");
+    .write(out)
+    .await?;
     if daemon_major_version != MAJOR_VERSION {
         panic!(
             "Major version mismatch: Daemon supports {} but this client supports {}",
             daemon_major_version, MAJOR_VERSION
         );
     }
-    'outer: loop {
-        let server = Arc::new(Mutex::new(ServerInner {
-            out,
-            map: HashMap::new(),
-        }));
-
-        let connection = zbus::ConnectionBuilder::session()
-            .expect("cannot create session bus")
-            .name("org.freedesktop.Notifications")
-            .expect("cannot acquire name")
-            .serve_at(
-                "/org/freedesktop/Notifications",
-                Server(server.clone(), 0u64.into()),
-            )
-            .expect("cannot serve")
-            .build()
-            .await
-            .expect("error");
-        let interface_ref = connection
-            .object_server()
-            .interface::<_, Server>("/org/freedesktop/Notifications")
-            .await
-            .expect("something went wrong");
-        loop {
-            let size = stdin
-                .read_u32_le()
-                .await
-                .expect("Error reading from stdin")
-                .to_le();
-            if size > MAX_MESSAGE_SIZE {
-                panic!("Message too large ({} bytes)", size)
+    let features = if NegotiatedFeatures::exchanges_masks(minor_version) {
+        WireU32::new(Features::all().bits()).write(out).await?;
+        let untrusted_mask = WireU32::read(input).await?.get();
+        NegotiatedFeatures::negotiate(minor_version, Features::all(), untrusted_mask)
+    } else {
+        NegotiatedFeatures::from_version(minor_version)
+    };
+    crash::set_version(MAJOR_VERSION, minor_version);
+    Ok(features)
+}
+
+/// Connect to the new instance of dom0 after a restart, and send it the
+/// notifications the old one did not answer.  Returns the process making
+/// the connection, which must be kept, and what dom0 sends.  The new
+/// instance must agree to the same `features`, since the notifications
+/// were built for them.
+async fn reconnect(
+    server: &Server,
+    features: NegotiatedFeatures,
+) -> Result<(tokio::process::Child, tokio::process::ChildStdout), String> {
+    let [program, args @ ..] = RECONNECT_COMMAND;
+    let mut child = tokio::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|e| format!("cannot run {}: {}", program, e))?;
+    let mut out = child.stdin.take().expect("stdin is piped");
+    let mut input = child.stdout.take().expect("stdout is piped");
+    let negotiated = handshake(&mut input, &mut out)
+        .await
+        .map_err(|e| format!("cannot negotiate: {}", e))?;
+    if negotiated != features {
+        return Err(format!(
+            "features changed to {}",
+            negotiated.names().join(", ")
+        ));
+    }
+    server
+        .0
+        .lock()
+        .await
+        .replay(Box::new(out))
+        .await
+        .map_err(|e| format!("cannot write: {}", e))?;
+    Ok((child, input))
+}
+
+async fn client_server(
+    open_urls_locally: bool,
+    name_mode: NameMode,
+    max_in_flight: usize,
+    batch_window: Option<std::time::Duration>,
+    ping_interval: Option<std::time::Duration>,
+    ping_timeout: std::time::Duration,
+) {
+    let mut stdin: Box<dyn tokio::io::AsyncRead + Unpin> = Box::new(tokio::io::stdin());
+    let mut out = tokio::io::stdout();
+    let features = handshake(&mut stdin, &mut out)
+        .await
+        .expect("Cannot negotiate with dom0");
+    let minor_version = features.minor();
+    let mut inner = ServerInner::new(out, features);
+    inner.max_in_flight = max_in_flight;
+    inner.batch_window = batch_window.filter(|_| features.minor() >= BATCH_VERSION);
+    let server = Arc::new(Mutex::new(inner));
+
+    let notification_server = Server(server.clone(), Default::default());
+    let pinger = notification_server.clone();
+    let resyncer = notification_server.clone();
+    let drainer = notification_server.clone();
+    let restarter = notification_server.clone();
+    let connection = zbus::ConnectionBuilder::session()
+        .expect("cannot create session bus")
+        .serve_at(
+            "/org/freedesktop/Notifications",
+            notification_server.clone(),
+        )
+        .expect("cannot serve")
+        .serve_at(
+            "/org/freedesktop/Notifications",
+            ClientInterface::new(notification_server),
+        )
+        .expect("cannot serve")
+        .build()
+        .await
+        .expect("error");
+    acquire_name(&connection, name_mode).await;
+    // Background tasks, aborted once nothing would answer them anymore.
+    let mut tasks = vec![tokio::task::spawn_local(resync_on_resume(
+        resyncer,
+        ping_timeout,
+    ))];
+    if let Some(interval) = ping_interval {
+        tasks.push(tokio::task::spawn_local(keepalive(
+            pinger,
+            interval,
+            ping_timeout,
+        )))
+    }
+    // Without a way to drain, the default action of the signal is as good
+    // as anything.
+    if minor_version >= DRAIN_VERSION {
+        tasks.push(tokio::task::spawn_local(drain_on_termination(
+            drainer.clone(),
+        )))
+    }
+    let interface_ref = connection
+        .object_server()
+        .interface::<_, Server>("/org/freedesktop/Notifications")
+        .await
+        .expect("something went wrong");
+    // The process connecting to dom0 after a restart.
+    let mut _connection = None;
+    loop {
+        let bytes = match codec::read_frame(&mut stdin, features.framing()).await {
+            Ok(Some(bytes)) => bytes,
+            // The old instance answered what it could before going away.
+            Ok(None) if server.lock().await.restarting => {
+                match reconnect(&restarter, features).await {
+                    Ok((child, input)) => {
+                        eprintln!("Reconnected to dom0");
+                        _connection = Some(child);
+                        stdin = Box::new(input);
+                        continue;
+                    }
+                    Err(e) => {
+                        eprintln!("Cannot reconnect to dom0: {}", e);
+                        server.lock().await.abandon("Server died");
+                        for task in tasks {
+                            task.abort()
+                        }
+                        return;
+                    }
+                }
             }
+            Ok(None) => panic!("dom0 closed the connection"),
+            Err(e) => panic!("Error reading from dom0: {}", e),
+        };
+        eprintln!("{} bytes read!", bytes.len());
 
-            let mut bytes = vec![0; size as _];
-            let bytes_read = stdin
-                .read_exact(&mut bytes[..])
-                .await
-                .expect("error reading from stdin");
-            assert_eq!(bytes_read, size as _);
-            eprintln!("{} bytes read!", bytes_read);
-
-            let options = bincode::DefaultOptions::new()
-                .with_fixint_encoding()
-                .with_native_endian()
-                .reject_trailing_bytes();
-            match options
-                .deserialize(&bytes)
-                .expect("malformed input from client")
-            {
-                ReplyMessage::Id { id, sequence } => server
-                    .lock()
-                    .await
-                    .map
-                    .remove(&sequence)
-                    .expect("server violated the protocol")
-                    .send(Ok(id))
-                    .expect("task died"),
+        let replies = match features.encoding().decode(&bytes) {
+            Ok(ReplyMessage::Batch(replies)) => replies,
+            Ok(message) => vec![message],
+            Err(e) => {
+                // Frames are length-prefixed, so the next one can still
+                // be read.  A Notify call whose reply was dropped times
+                // out, whatever the protocol version.
+                eprintln!("Dropping malformed frame from dom0: {}", e);
+                continue;
+            }
+        };
+        for message in replies {
+            let message = match message {
+                ReplyMessage::UnknownError { sequence } => ReplyMessage::DBusError {
+                    name: "org.freedesktop.DBus.Error.Failed".to_owned(),
+                    message: None,
+                    sequence,
+                },
+                ReplyMessage::Batch(_) => {
+                    eprintln!("Dropping nested batch from dom0");
+                    continue;
+                }
+                message => message,
+            };
+            match message {
+                ReplyMessage::Id { id, sequence } => {
+                    crash::set_sequence(sequence);
+                    let mut guard = server.lock().await;
+                    if let Some(application_id) = guard.replayed.remove(&sequence) {
+                        guard.alias(id, application_id)
+                    }
+                    if let Some(sender) = guard.map.remove(&sequence) {
+                        if sender.send(Ok(guard.application_id(id))).is_err() {
+                            eprintln!("Caller of call {} went away", sequence)
+                        }
+                    } else if let Some(application_id) = guard.late.remove(&sequence) {
+                        eprintln!("Late reply: ID {} is notification {}", application_id, id);
+                        guard.alias(id, application_id)
+                    } else {
+                        eprintln!("Ignoring reply to unknown call {}", sequence)
+                    }
+                    guard.completed(sequence)
+                }
                 ReplyMessage::DBusError {
                     name,
                     message,
                     sequence,
-                } => server
-                    .lock()
-                    .await
-                    .map
-                    .remove(&sequence)
-                    .expect("server violated the protocol")
-                    .send(Err((name, message)))
-                    .expect("task died"),
+                } => {
+                    crash::set_sequence(sequence);
+                    let mut guard = server.lock().await;
+                    guard.completed(sequence);
+                    if let Some(sender) = guard.probes.remove(&sequence) {
+                        if sender.send(Err((name, message))).is_err() {
+                            eprintln!("Caller of call {} went away", sequence)
+                        }
+                    } else if let Some(sender) = guard.histories.remove(&sequence) {
+                        if sender.send(Err((name, message))).is_err() {
+                            eprintln!("Caller of call {} went away", sequence)
+                        }
+                    } else if let Some(sender) = guard.map.remove(&sequence) {
+                        if sender.send(Err((name, message))).is_err() {
+                            eprintln!("Caller of call {} went away", sequence)
+                        }
+                    } else if let Some(application_id) = guard.late.remove(&sequence) {
+                        // The application already got an ID.  Behave as if
+                        // the notification had expired immediately.
+                        drop(guard);
+                        eprintln!("Late call for ID {} failed: {}", application_id, name);
+                        let x = interface_ref.get().await;
+                        x.notification_closed(interface_ref.signal_context(), application_id, 1)
+                            .await
+                            .expect("cannot emit signal");
+                    } else {
+                        eprintln!("Ignoring reply to unknown call {}", sequence)
+                    }
+                }
                 ReplyMessage::Dismissed { id, reason } => {
+                    let id = {
+                        let mut guard = server.lock().await;
+                        guard.expire_timeouts.remove(&id);
+                        guard.truncated.remove(&id);
+                        guard.suppressed.remove(&id);
+                        guard.unalias(id)
+                    };
                     let x = interface_ref.get().await;
                     x.notification_closed(interface_ref.signal_context(), id, reason)
                         .await
                         .expect("cannot emit signal");
                 }
                 ReplyMessage::ActionInvoked { id, action } => {
+                    let id = server.lock().await.application_id(id);
                     let x = interface_ref.get().await;
                     x.action_invoked(interface_ref.signal_context(), id, action)
                         .await
                         .expect("cannot emit signal");
                 }
+                ReplyMessage::Replied { id, text } => {
+                    let id = server.lock().await.application_id(id);
+                    let x = interface_ref.get().await;
+                    x.notification_replied(interface_ref.signal_context(), id, text)
+                        .await
+                        .expect("cannot emit signal");
+                }
+                ReplyMessage::ExpireTimeout { id, expire_timeout } => {
+                    server
+                        .lock()
+                        .await
+                        .expire_timeouts
+                        .insert(id, expire_timeout);
+                }
+                ReplyMessage::Sanitized { id, truncated } => {
+                    let mut guard = server.lock().await;
+                    guard.truncated.insert(id, truncated);
+                    // Sent for every notification shown, before Suppressed.
+                    guard.suppressed.remove(&id);
+                }
+                ReplyMessage::Suppressed { id, reason } => {
+                    server.lock().await.suppressed.insert(id, reason);
+                }
+                ReplyMessage::Capabilities {
+                    capabilities,
+                    sequence,
+                } => {
+                    crash::set_sequence(sequence);
+                    let mut guard = server.lock().await;
+                    guard.daemon_capabilities = Some(Capabilities::from(&capabilities[..]));
+                    match guard.probes.remove(&sequence) {
+                        Some(sender) => {
+                            if sender.send(Ok(capabilities)).is_err() {
+                                eprintln!("Caller of call {} went away", sequence)
+                            }
+                        }
+                        None => eprintln!("Ignoring reply to unknown call {}", sequence),
+                    }
+                }
+                ReplyMessage::History { entries, sequence } => {
+                    crash::set_sequence(sequence);
+                    match server.lock().await.histories.remove(&sequence) {
+                        Some(sender) => {
+                            if sender.send(Ok(entries)).is_err() {
+                                eprintln!("Caller of call {} went away", sequence)
+                            }
+                        }
+                        None => eprintln!("Ignoring reply to unknown call {}", sequence),
+                    }
+                }
+                ReplyMessage::DaemonCapabilities { capabilities } => {
+                    let capabilities = Capabilities::from_bits_truncate(capabilities as u16);
+                    eprintln!("Daemon in dom0 has capabilities {:?}", capabilities);
+                    server.lock().await.daemon_capabilities = Some(capabilities);
+                }
+                ReplyMessage::OpenUrl { id, url } => {
+                    eprintln!("Opening link from notification {}", id);
+                    open_url(url, open_urls_locally)
+                }
                 ReplyMessage::ServerRestart => {
-                    for (_key, value) in server.lock().await.map.drain() {
-                        value
-                            .send(Err(("Server died".to_string(), None)))
-                            .expect("task died");
+                    let mut guard = server.lock().await;
+                    if guard.draining {
+                        guard.abandon("Server died");
+                        for task in tasks {
+                            task.abort()
+                        }
+                        return;
+                    }
+                    // Calls made from now on wait for the new instance,
+                    // and so do those the old one does not answer.  What
+                    // the old one reported about its notifications is
+                    // forgotten by ServerInner::replay(), since its last
+                    // replies and signals still need translating.
+                    eprintln!("dom0 is restarting");
+                    guard.restarting = true
+                }
+                ReplyMessage::Draining => {
+                    eprintln!("dom0 is shutting down");
+                    drain(&drainer).await
+                }
+                ReplyMessage::Reshown { id, new_id } => {
+                    eprintln!("dom0 showed notification {} again as {}", id, new_id);
+                    server.lock().await.reshown(id, new_id)
+                }
+                ReplyMessage::Drained { sequence } => {
+                    crash::set_sequence(sequence);
+                    eprintln!("Connection to dom0 drained");
+                    // Every call made before the drain was answered.
+                    server.lock().await.abandon("Connection drained");
+                    for task in tasks {
+                        task.abort()
+                    }
+                    return;
+                }
+                ReplyMessage::Pong { sequence } => {
+                    match server.lock().await.pings.remove(&sequence) {
+                        Some(sender) => {
+                            let _ = sender.send(());
+                        }
+                        None => eprintln!("Ignoring pong for unknown ping {}", sequence),
                     }
-                    break 'outer;
                 }
-                ReplyMessage::UnknownError { sequence: _ } => todo!(),
+                ReplyMessage::UnknownError { .. } | ReplyMessage::Batch(_) => {
+                    unreachable!("converted above")
+                }
             }
         }
     }
@@ -358,6 +1693,462 @@ async fn client_server() {
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let local_set = tokio::task::LocalSet::new();
 
-    local_set.spawn_local(client_server());
+    let mut dump_introspection = false;
+    let mut open_urls_locally = false;
+    let mut name_mode = NameMode::Fail;
+    let mut max_in_flight = DEFAULT_MAX_IN_FLIGHT;
+    let mut batch_window = Some(DEFAULT_BATCH_WINDOW);
+    let mut ping_interval = Some(DEFAULT_PING_INTERVAL);
+    let mut ping_timeout = DEFAULT_PING_TIMEOUT;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--dump-introspection" => dump_introspection = true,
+            "--open-urls-locally" => open_urls_locally = true,
+            "--replace" => name_mode = NameMode::Replace,
+            "--queue" => name_mode = NameMode::Queue,
+            "--max-in-flight" => {
+                let value = args.next().expect("--max-in-flight requires an argument");
+                max_in_flight = match value.parse() {
+                    Ok(count @ 1..) => count,
+                    _ => panic!("Invalid number of calls {:?}", value),
+                }
+            }
+            // In milliseconds.  A window of 0 turns batching off.
+            "--batch-window" => {
+                let value = args.next().expect("--batch-window requires an argument");
+                let millis: u64 = value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid number of milliseconds {:?}", value));
+                batch_window =
+                    Some(std::time::Duration::from_millis(millis)).filter(|d| !d.is_zero())
+            }
+            // In seconds.  An interval of 0 turns pings off.
+            "--ping-interval" | "--ping-timeout" => {
+                let value = args
+                    .next()
+                    .unwrap_or_else(|| panic!("{} requires an argument", arg));
+                let seconds: u64 = value
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Invalid number of seconds {:?}", value));
+                let duration = std::time::Duration::from_secs(seconds);
+                match &*arg {
+                    "--ping-interval" => ping_interval = Some(duration).filter(|d| !d.is_zero()),
+                    _ if seconds == 0 => panic!("--ping-timeout must be positive"),
+                    _ => ping_timeout = duration,
+                }
+            }
+            _ => panic!("Unknown argument {:?}", arg),
+        }
+    }
+    if dump_introspection {
+        let features = NegotiatedFeatures::from_version(0);
+        let inner = Arc::new(Mutex::new(ServerInner::new(tokio::io::stdout(), features)));
+        let server = Server(inner, Default::default());
+        let client_interface = ClientInterface::new(server.clone());
+        print!(
+            "{}",
+            notification_emitter::introspection_xml(&[&server, &client_interface])
+        );
+        return Ok(());
+    }
+    crash::install("notification-proxy-client");
+    local_set.spawn_local(client_server(
+        open_urls_locally,
+        name_mode,
+        max_in_flight,
+        batch_window,
+        ping_interval,
+        ping_timeout,
+    ));
     Ok(local_set.await)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notification_emitter::{GUEST_CAPABILITIES, REPLY_VERSION};
+    use std::collections::BTreeMap;
+
+    fn server(minor_version: u16) -> Server {
+        Server(
+            Arc::new(Mutex::new(ServerInner::new(
+                tokio::io::stdout(),
+                NegotiatedFeatures::from_version(minor_version),
+            ))),
+            Default::default(),
+        )
+    }
+
+    /// The members of the first interface in `xml`, as produced by
+    /// introspection, with the input and output signatures of each.
+    fn members(xml: &str) -> BTreeMap<String, (String, String)> {
+        let attribute = |line: &str, name: &str| {
+            let start = line.find(&format!(" {}=\"", name))? + name.len() + 3;
+            let len = line[start..].find('"').expect("unterminated attribute");
+            Some(line[start..start + len].to_owned())
+        };
+        let mut members: BTreeMap<String, (String, String)> = BTreeMap::new();
+        let mut current = None;
+        for line in xml.lines().map(str::trim) {
+            if line.starts_with("<method ") || line.starts_with("<signal ") {
+                let kind = &line[1..7];
+                let name = attribute(line, "name").expect("member without name");
+                current = Some(format!("{} {}", kind, name));
+            } else if line.starts_with("<arg ") {
+                let member = current.as_ref().expect("argument outside member");
+                let (input, output) = members.entry(member.clone()).or_default();
+                let signature = attribute(line, "type").expect("argument without type");
+                match attribute(line, "direction").as_deref() {
+                    Some("out") => output.push_str(&signature),
+                    Some("in") | None => input.push_str(&signature),
+                    Some(other) => panic!("bad direction {}", other),
+                }
+            } else if line.starts_with("</method>") || line.starts_with("</signal>") {
+                members.entry(current.take().unwrap()).or_default();
+            } else if line.starts_with("</interface>") {
+                break;
+            }
+        }
+        members
+    }
+
+    #[test]
+    fn test_spec_interface() {
+        let mut xml = String::new();
+        zbus::Interface::introspect_to_writer(&server(MINOR_VERSION), &mut xml, 0);
+        assert!(xml.starts_with("<interface name=\"org.freedesktop.Notifications\">"));
+        // Desktop Notifications Specification 1.2, "D-BUS Protocol".
+        let expected = [
+            ("method GetCapabilities", "", "as"),
+            ("method Notify", "susssasa{sv}i", "u"),
+            ("method CloseNotification", "u", ""),
+            ("method GetServerInformation", "", "ssss"),
+            ("signal NotificationClosed", "uu", ""),
+            ("signal ActionInvoked", "us", ""),
+            // The KDE extension for inline replies.
+            ("signal NotificationReplied", "us", ""),
+        ];
+        let expected: BTreeMap<_, _> = expected
+            .iter()
+            .map(|&(member, input, output)| {
+                (member.to_owned(), (input.to_owned(), output.to_owned()))
+            })
+            .collect();
+        assert_eq!(members(&xml), expected);
+    }
+
+    #[test]
+    fn test_extension_interface() {
+        let mut xml = String::new();
+        let interface = ClientInterface::new(server(MINOR_VERSION));
+        zbus::Interface::introspect_to_writer(&interface, &mut xml, 0);
+        assert!(xml.starts_with("<interface name=\"org.qubes.NotificationProxy1.Client\">"));
+        let members = members(&xml);
+        // Nothing that belongs in the standard interface.
+        assert!(members.keys().all(|member| !matches!(
+            member.split_once(' ').unwrap().1,
+            "GetCapabilities" | "Notify" | "CloseNotification" | "GetServerInformation"
+        )));
+        assert_eq!(
+            members["method GetExpireTimeout"],
+            ("u".to_owned(), "i".to_owned())
+        );
+        assert!(xml.contains(r#"<property name="ProtocolVersion" type="(qq)" access="read"/>"#));
+        assert!(xml.contains(r#"<property name="Features" type="as" access="read"/>"#));
+        assert_eq!(
+            members["method WasTruncated"],
+            ("u".to_owned(), "b".to_owned())
+        );
+        assert_eq!(
+            members["method GetSuppression"],
+            ("u".to_owned(), "s".to_owned())
+        );
+        assert_eq!(
+            members["method ProbeCapabilities"],
+            ("".to_owned(), "as".to_owned())
+        );
+        assert_eq!(
+            members["method GetHistory"],
+            ("au".to_owned(), "a(usus)".to_owned())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_spec_behavior() {
+        let notifications = server(MINOR_VERSION);
+        let (capabilities,) = notifications.get_capabilities().await.unwrap();
+        const SPEC_CAPABILITIES: &[&str] = &[
+            "action-icons",
+            "actions",
+            "body",
+            "body-hyperlinks",
+            "body-images",
+            "body-markup",
+            "icon-multi",
+            "icon-static",
+            "persistence",
+            "sound",
+        ];
+        for capability in capabilities {
+            assert!(
+                SPEC_CAPABILITIES.contains(&&*capability)
+                    || capability.starts_with("x-")
+                    || capability == "inline-reply",
+                "{}",
+                capability
+            )
+        }
+        // The list comes from the library, where it is checked against
+        // the escaping done in dom0.
+        assert_eq!(
+            notifications.get_capabilities().await.unwrap().0,
+            guest_capabilities(MINOR_VERSION).names()
+        );
+        // Only offered if dom0 passes replies on.
+        let old = server(REPLY_VERSION - 1);
+        assert!(!old
+            .get_capabilities()
+            .await
+            .unwrap()
+            .0
+            .contains(&"inline-reply".to_owned()));
+        assert!(notifications
+            .get_capabilities()
+            .await
+            .unwrap()
+            .0
+            .contains(&"inline-reply".to_owned()));
+        // Once dom0 reports its daemon, only what it supports is offered.
+        notifications.0.lock().await.daemon_capabilities =
+            Some(Capabilities::BODY_MARKUP | Capabilities::ACTIONS);
+        assert_eq!(
+            notifications.get_capabilities().await.unwrap().0,
+            ["actions"]
+        );
+        let (_, _, _, spec_version) = notifications.get_server_information().await.unwrap();
+        assert!(["1.2", "1.3"].contains(&&*spec_version), "{}", spec_version);
+        // Rejected before anything is sent to dom0.
+        let actions = vec!["default".to_owned()];
+        let out = notifications
+            .notify(
+                "",
+                0,
+                String::new(),
+                String::new(),
+                String::new(),
+                actions,
+                HashMap::new(),
+                -1,
+            )
+            .await;
+        assert!(matches!(
+            out,
+            Err(NotificationsError::Fdo(zbus::fdo::Error::InvalidArgs(_)))
+        ));
+        let out = server(CLOSE_VERSION - 1).close_notification(1).await;
+        assert!(matches!(
+            out,
+            Err(NotificationsError::Fdo(zbus::fdo::Error::InvalidArgs(_)))
+        ));
+        // Nor if dom0 turned closing off, which also hides inline replies.
+        let features = NegotiatedFeatures::negotiate(MINOR_VERSION, Features::all(), 0);
+        let limited = Server(
+            Arc::new(Mutex::new(ServerInner::new(tokio::io::stdout(), features))),
+            Default::default(),
+        );
+        let out = limited.close_notification(1).await;
+        assert!(matches!(
+            out,
+            Err(NotificationsError::Fdo(zbus::fdo::Error::InvalidArgs(_)))
+        ));
+        assert_eq!(
+            limited.get_capabilities().await.unwrap().0,
+            GUEST_CAPABILITIES.names()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_window() {
+        let server = server(MINOR_VERSION);
+        {
+            let mut guard = server.0.lock().await;
+            guard.max_in_flight = 2;
+            for sequence in 0..2 {
+                let (sender, _) = futures_channel::oneshot::channel();
+                guard.map.insert(sequence, sender);
+            }
+        }
+        let notify = || {
+            server.notify(
+                "",
+                0,
+                String::new(),
+                "summary".to_owned(),
+                String::new(),
+                vec![],
+                HashMap::new(),
+                -1,
+            )
+        };
+        assert!(matches!(notify().await, Err(NotificationsError::Busy(_))));
+        // Nothing is left behind by the refused call.
+        let mut guard = server.0.lock().await;
+        assert_eq!(guard.map.len(), 2);
+        assert!(guard.holds.is_empty() && guard.busy.is_empty());
+        // Once draining, calls are refused however few are in flight.
+        guard.map.clear();
+        guard.draining = true;
+        drop(guard);
+        assert!(matches!(
+            notify().await,
+            Err(NotificationsError::Fdo(zbus::fdo::Error::Failed(_)))
+        ));
+        assert!(server.0.lock().await.map.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lost_reply() {
+        // dom0 is too old for provisional IDs, and its reply never comes.
+        let server = Server(
+            Arc::new(Mutex::new(ServerInner::new(
+                tokio::io::sink(),
+                NegotiatedFeatures::from_version(1),
+            ))),
+            Default::default(),
+        );
+        let reply = server
+            .notify(
+                "",
+                0,
+                String::new(),
+                "summary".to_owned(),
+                String::new(),
+                vec![],
+                HashMap::new(),
+                -1,
+            )
+            .await;
+        assert!(matches!(
+            reply,
+            Err(NotificationsError::Fdo(zbus::fdo::Error::Failed(_)))
+        ));
+        let guard = server.0.lock().await;
+        assert!(guard.map.is_empty() && guard.pending.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reshown() {
+        let server = server(MINOR_VERSION);
+        let mut guard = server.0.lock().await;
+        // ID 5 was replayed as 7 after dom0 restarted, and 8 was not.
+        guard.alias(7, 5);
+        guard.truncated.insert(7, true);
+        guard.suppressed.insert(8, Suppression::Muted);
+        guard.reshown(7, 9);
+        guard.reshown(8, 10);
+        assert_eq!(guard.aliases, HashMap::from([(9, 5), (10, 8)]));
+        assert_eq!(guard.dom0_ids, HashMap::from([(5, 9), (8, 10)]));
+        assert_eq!(guard.dom0_id(5), 9);
+        assert_eq!(guard.application_id(10), 8);
+        assert_eq!(guard.truncated, HashMap::from([(9, true)]));
+        assert_eq!(guard.suppressed, HashMap::from([(10, Suppression::Muted)]));
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        // dom0 cannot frame images, so it does not offer them.
+        let mut dom0 = vec![];
+        let version = notification_emitter::merge_versions(MAJOR_VERSION, MINOR_VERSION);
+        WireU32::new(version).write(&mut dom0).await.unwrap();
+        let offered = Features::all() - Features::IMAGES;
+        WireU32::new(offered.bits()).write(&mut dom0).await.unwrap();
+        let mut out = vec![];
+        let features = handshake(&mut &dom0[..], &mut out).await.unwrap();
+        assert_eq!(features.minor(), MINOR_VERSION);
+        assert!(!features.has(Features::IMAGES) && features.has(Features::CLOSE));
+        assert!(!features.names().contains(&"images"));
+        let mut written = &out[..];
+        assert_eq!(WireU32::read(&mut written).await.unwrap().get(), version);
+        let mask = WireU32::read(&mut written).await.unwrap().get();
+        assert_eq!(mask, Features::all().bits());
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        use futures_util::FutureExt;
+        let server = server(MINOR_VERSION);
+        let features = server.0.lock().await.features;
+        {
+            let mut guard = server.0.lock().await;
+            guard.restarting = true;
+            // A replacement of ID 5, which dom0 knew as 7, sent before the
+            // restart and never answered.
+            let notification = NotificationBuilder::new("old")
+                .replaces_id(7)
+                .build()
+                .unwrap();
+            let message = Message {
+                id: 100,
+                notification,
+            };
+            guard.pending.insert(100, message);
+            guard.holds.insert(100, 5);
+            guard.alias(7, 5);
+            guard.expire_timeouts.insert(7, ExpireTimeout::Never);
+            guard.truncated.insert(7, false);
+        }
+        let call = server.notify(
+            "",
+            0,
+            String::new(),
+            "new".to_owned(),
+            String::new(),
+            vec![],
+            HashMap::new(),
+            -1,
+        );
+        tokio::pin!(call);
+        assert!(call.as_mut().now_or_never().is_none());
+        let (out, mut input) = tokio::io::duplex(1 << 16);
+        let mut guard = server.0.lock().await;
+        assert!(guard.queued.is_empty());
+        guard.replay(Box::new(out)).await.unwrap();
+        assert!(!guard.restarting && guard.aliases.is_empty() && guard.dom0_ids.is_empty());
+        assert!(guard.expire_timeouts.is_empty() && guard.truncated.is_empty());
+        assert_eq!(guard.replayed, HashMap::from([(100, 5)]));
+        // In the order the calls were made, the replacement as a new
+        // notification.
+        for (sequence, replaces_id) in [(0, 0), (100, 0)] {
+            let frame = codec::read_frame(&mut input, features.framing())
+                .await
+                .unwrap()
+                .unwrap();
+            match features.encoding().decode(&frame).unwrap() {
+                Request::Notify(message) => {
+                    assert_eq!(message.id, sequence);
+                    assert_eq!(message.notification.replaces_id(), replaces_id);
+                }
+                request => panic!("unexpected {:?}", request),
+            }
+        }
+        // Answered calls are not sent again.
+        guard.completed(0);
+        guard.completed(100);
+        assert!(guard.pending.is_empty() && guard.replayed.is_empty());
+    }
+
+    #[test]
+    fn test_error_names() {
+        use zbus::DBusError;
+        let error = NotificationsError::InvalidId(String::new());
+        assert_eq!(error.name().as_str(), INVALID_ID_ERROR);
+        let error = NotificationsError::Busy(String::new());
+        assert_eq!(error.name().as_str(), BUSY_ERROR);
+        let error: NotificationsError = zbus::fdo::Error::InvalidArgs(String::new()).into();
+        assert_eq!(
+            error.name().as_str(),
+            "org.freedesktop.DBus.Error.InvalidArgs"
+        );
+    }
+}
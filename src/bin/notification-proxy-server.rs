@@ -1,39 +1,136 @@
-use bincode::Options;
 use futures_util::StreamExt;
-use notification_emitter::{merge_versions, NotificationEmitter};
-use notification_emitter::{
-    MessageWriter, ReplyMessage, MAJOR_VERSION, MAX_MESSAGE_SIZE, MINOR_VERSION,
-};
+use notification_emitter::actor::{self, Actor};
+use notification_emitter::admin;
+use notification_emitter::codec::{OutboundQueue, WireU32};
+use notification_emitter::config::{self, Config};
+use notification_emitter::control::{self, Control};
+use notification_emitter::crash;
+use notification_emitter::l10n::Catalog;
+use notification_emitter::{merge_versions, Capabilities, NotificationEmitter};
+use notification_emitter::{Features, NegotiatedFeatures, MAJOR_VERSION, MINOR_VERSION};
 use std::rc::Rc;
-use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+use zbus::fdo::NameOwnerChangedStream;
 
-async fn client_server(qube_name: String) {
-    let (emitter, mut server_name_owner_changed) = NotificationEmitter::new(
-        qube_name.to_owned() + ": ",
-        "Qubes VM ".to_owned() + &*qube_name,
-    )
-    .await
-    .expect("Cannot connect to notifcation daemon");
-    let (closed_stream, invoked_stream) =
-        futures_util::future::join(emitter.closed(), emitter.invocations()).await;
-    let emitter = Rc::new(emitter);
-    let options = bincode::DefaultOptions::new()
-        .with_fixint_encoding()
-        .with_native_endian()
-        .reject_trailing_bytes();
-    let mut stdin = tokio::io::stdin();
+/// Directory, under `$XDG_RUNTIME_DIR`, with a file for each qube that
+/// connected in the current session.
+const CONNECTED_DIR: &str = "qubes-notification-proxy/connected";
+
+/// Whether this is the first connection from `qube_name` in this session.
+/// Without `$XDG_RUNTIME_DIR`, which is cleared when the session ends, every
+/// connection counts as the first.
+fn first_connection(qube_name: &str) -> bool {
+    let Some(runtime_dir) = std::env::var_os("XDG_RUNTIME_DIR") else {
+        return true;
+    };
+    let dir = std::path::Path::new(&runtime_dir).join(CONNECTED_DIR);
+    if let Err(e) = std::fs::create_dir_all(&dir) {
+        eprintln!("Cannot create {}: {}", dir.display(), e);
+        return true;
+    }
+    // qrexec only allows qube names that are safe as file names.
+    match std::fs::File::options()
+        .write(true)
+        .create_new(true)
+        .open(dir.join(qube_name))
     {
-        let mut out = tokio::io::stdout();
-        out.write_u32_le(merge_versions(MAJOR_VERSION, MINOR_VERSION).to_le())
+        Ok(_) => true,
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => false,
+        Err(e) => {
+            eprintln!("Cannot record connection from {}: {}", qube_name, e);
+            true
+        }
+    }
+}
+
+/// Features offered to the qube: all of them, except inline replies if
+/// that capability is disabled for it, and images unless they are framed
+/// in the color of its label.
+fn offered_features(config: &Config) -> Features {
+    let mut features = Features::all();
+    features.set(Features::IMAGES, config.image_frame.is_some());
+    let replies = !config
+        .disable_capabilities
+        .contains(Capabilities::INLINE_REPLY);
+    features.set(Features::INLINE_REPLY, replies);
+    features
+}
+
+/// Connect to the notification daemons on the buses in `config`.  The first
+/// emitter is the main one, and with [`config::BusPolicy::All`] the others
+/// are for mirroring.
+async fn connect(
+    config: &Config,
+    prefix: String,
+    application_name: String,
+) -> Vec<(NotificationEmitter, NameOwnerChangedStream<'static>)> {
+    if config.session_buses.is_empty() {
+        return vec![NotificationEmitter::new(prefix, application_name)
             .await
-            .expect("Cannot write version for version negotiation");
-        out.flush().await.expect("flush failed");
+            .expect("Cannot connect to notifcation daemon")];
     }
-    let reply_version: u32 = stdin
-        .read_u32_le()
+    let mut res = vec![];
+    for address in &config.session_buses {
+        match NotificationEmitter::with_address(address, prefix.clone(), application_name.clone())
+            .await
+        {
+            Ok(emitter) => res.push(emitter),
+            Err(e) => {
+                eprintln!(
+                    "Cannot connect to notification daemon at {}: {}",
+                    address, e
+                );
+                continue;
+            }
+        }
+        if config.bus_policy == config::BusPolicy::First {
+            break;
+        }
+    }
+    if res.is_empty() {
+        eprintln!("Cannot connect to any notification daemon");
+        std::process::exit(1)
+    }
+    res
+}
+
+async fn client_server(qube_name: String, config: Config) {
+    let catalog = match config.locale.clone().or_else(Catalog::environment_locale) {
+        Some(locale) => Catalog::load(&locale),
+        None => Catalog::default(),
+    };
+    let mut emitters = connect(
+        &config,
+        catalog.format("prefix", &[("qube", &qube_name)]),
+        catalog.format("app-name", &[("qube", &qube_name)]),
+    )
+    .await
+    .into_iter();
+    let (mut emitter, server_name_owner_changed) = emitters
+        .next()
+        .expect("connect() returns at least one emitter");
+    let mut mirrors: Vec<_> = emitters.collect();
+    for (mirror, _) in &mut mirrors {
+        mirror.set_catalog(catalog.clone());
+        mirror.set_config(config.clone());
+        mirror.set_qube_name(qube_name.clone());
+    }
+    emitter.set_catalog(catalog);
+    emitter.set_qube_name(qube_name.clone());
+    let max_concurrent_calls = config.max_concurrent_calls;
+    let calls = Arc::new(Semaphore::new(max_concurrent_calls));
+    let announce = config.announce;
+    emitter.set_config(config.clone());
+    let mut stdin = tokio::io::stdin();
+    WireU32::new(merge_versions(MAJOR_VERSION, MINOR_VERSION))
+        .write(&mut tokio::io::stdout())
+        .await
+        .expect("Cannot write version for version negotiation");
+    let reply_version = WireU32::read(&mut stdin)
         .await
         .expect("Cannot read reply")
-        .to_le();
+        .get();
     let (reply_major, reply_minor) = notification_emitter::split_version(reply_version);
     if reply_major != MAJOR_VERSION {
         panic!(
@@ -47,117 +144,103 @@ async fn client_server(qube_name: String) {
 but this server only supports version {MINOR_VERSION}"
         );
     }
-    let stdout = MessageWriter::new();
-    let emitter_ = emitter.clone();
-    let mut closed_stream = closed_stream.expect("Cannot register for closed signals");
-    let mut invoked_stream = invoked_stream.expect("Cannot register for invoked signals");
-    let stdout_ = stdout.clone();
-    let _handle = tokio::task::spawn_local(async move {
-        while let Some(item) = server_name_owner_changed.next().await {
-            let item = item
-                .args()
-                .expect("Got invalid NameOwnerChanged message from bus daemon");
-            assert_eq!(
-                item.name, "org.freedesktop.Notifications",
-                "Bus daemon sent message for name we didn't register for"
-            );
-            emitter_.clear()
+    let features = if NegotiatedFeatures::exchanges_masks(reply_minor) {
+        let offered = offered_features(&config);
+        WireU32::new(offered.bits())
+            .write(&mut tokio::io::stdout())
+            .await
+            .expect("Cannot write feature mask");
+        let untrusted_mask = WireU32::read(&mut stdin)
+            .await
+            .expect("Cannot read feature mask")
+            .get();
+        NegotiatedFeatures::negotiate(reply_minor, offered, untrusted_mask)
+    } else {
+        NegotiatedFeatures::from_version(reply_minor)
+    };
+    eprintln!("Negotiated features: {}", features.names().join(", "));
+    crash::set_version(reply_major, reply_minor);
+    emitter.set_protocol_minor(reply_minor);
+    for (mirror, _) in &mut mirrors {
+        mirror.set_protocol_minor(reply_minor)
+    }
+    if announce && first_connection(&qube_name) {
+        if let Err(e) = emitter.announce(&qube_name).await {
+            eprintln!("Cannot announce connection from {}: {}", qube_name, e)
         }
-    });
-    let emitter_ = emitter.clone();
-    let _handle = tokio::task::spawn_local(async move {
-        while let Some(item) = closed_stream.next().await {
-            let item = match item.args() {
-                Ok(item) => item,
-                Err(e) => {
-                    eprintln!("Got invalid message from notification daemon: {}", e);
-                    continue;
-                }
-            };
-            let id = match emitter_.remove_host_id(item.id) {
-                None => continue,
-                Some(id) => id,
-            };
-            let data = options
-                .serialize(&ReplyMessage::Dismissed {
-                    id,
-                    reason: item.reason,
-                })
-                .expect("Serialization failed?");
-            stdout_.transmit(&*data).await
+    }
+    let muted = control::mute_file(&qube_name).is_some_and(|path| path.exists());
+    emitter.control_state().lock().expect("poisoned").muted = muted;
+    // Asking about a muted qube would be pointless.
+    let approval = config.approve_new_qubes
+        && !muted
+        && !control::approval_file(&qube_name).is_some_and(|path| path.exists());
+    {
+        let connection = emitter.connection();
+        connection
+            .object_server()
+            .at(
+                control::CONTROL_PATH,
+                Control::new(
+                    qube_name.clone(),
+                    (reply_major, reply_minor),
+                    emitter.control_state(),
+                )
+                .with_calls(calls.clone(), max_concurrent_calls),
+            )
+            .await
+            .expect("Cannot serve control interface");
+        if let Err(e) = connection
+            .request_name(control::control_name(&qube_name))
+            .await
+        {
+            eprintln!("Cannot acquire control interface name: {}", e)
         }
-    });
-    let stdout_ = stdout.clone();
-    let emitter_ = emitter.clone();
-    let _handle = tokio::task::spawn_local(async move {
-        while let Some(item) = invoked_stream.next().await {
-            let item = match item.args() {
-                Ok(item) => item,
-                Err(e) => {
-                    eprintln!("Got invalid message from notification daemon: {}", e);
-                    continue;
-                }
-            };
-            let id = match emitter_.translate_host_id(item.id) {
-                None => continue,
-                Some(id) => id,
-            };
-            let data = options
-                .serialize(&ReplyMessage::ActionInvoked {
-                    id,
-                    action: item.action_key,
-                })
-                .expect("Serialization failed?");
-            stdout_.transmit(&*data).await
+    }
+    let stdout = OutboundQueue::with_framing(features.framing());
+    let mut signals = vec![
+        actor::daemon_signals(&emitter, features.has(Features::INLINE_REPLY))
+            .await
+            .expect("Cannot register for signals"),
+        actor::owner_changes(0, server_name_owner_changed),
+        actor::termination().expect("Cannot handle SIGTERM"),
+        actor::resumes(),
+    ];
+    let mut mirror_daemons = vec![];
+    for (bus, (mirror, name_owner_changed)) in mirrors.into_iter().enumerate() {
+        signals.push(actor::owner_changes(bus + 1, name_owner_changed));
+        match actor::mirror_signals(bus + 1, &mirror).await {
+            Ok(closed) => signals.push(closed),
+            Err(e) => eprintln!("Cannot register for signals of mirror: {}", e),
         }
-    });
+        mirror_daemons.push(Rc::new(mirror));
+    }
+    let actor = Actor::new(
+        qube_name.clone(),
+        features,
+        &config,
+        Rc::new(emitter),
+        mirror_daemons,
+        calls,
+        stdout.clone(),
+    )
+    .with_approval(approval);
     eprintln!("Entering loop");
-    loop {
-        let size = match stdin.read_u32_le().await {
-            Ok(size) => size.to_le(),
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => break,
-                e => panic!("Error reading from stdin: {}", e),
-            },
-        };
-        if size > MAX_MESSAGE_SIZE {
-            panic!("Message too large ({} bytes)", size)
-        }
-        let mut bytes = vec![0; size as _];
-        match stdin.read_exact(&mut bytes[..]).await {
-            Ok(bytes_read) => assert_eq!(bytes_read, size as _),
-            Err(e) => match e.kind() {
-                std::io::ErrorKind::UnexpectedEof => break,
-                e => panic!("Error reading from stdin: {}", e),
-            },
-        };
-        let message: notification_emitter::Message = options
-            .deserialize(&bytes)
-            .expect("malformed input from client");
-        let sequence = message.id;
-        let emitter = emitter.clone();
-        let stdout = stdout.clone();
-        tokio::task::spawn_local(async move {
-            let out = emitter.send_notification(message.notification).await;
-            let data = options
-                .serialize(&match out {
-                    Ok(id) => ReplyMessage::Id {
-                        id: id.into(),
-                        sequence,
-                    },
-                    Err(zbus::Error::MethodError(name, message, _)) => ReplyMessage::DBusError {
-                        name: name.to_string(),
-                        message,
-                        sequence,
-                    },
-                    Err(e) => {
-                        eprintln!("Serialization failed for {:?}", e);
-                        ReplyMessage::UnknownError { sequence }
-                    }
-                })
-                .expect("Serialization failed?");
-            stdout.transmit(&*data).await
-        });
+    let mut writer = tokio::io::stdout();
+    let out = tokio::select! {
+        out = actor.run(
+            actor::frames(stdin, features.framing()),
+            futures_util::stream::select_all(signals).boxed_local(),
+        ) => out,
+        e = stdout.run(&mut writer) => Err(format!("cannot write: {}", e)),
+    };
+    if let Err(e) = out {
+        eprintln!("Closing connection from {}: {}", qube_name, e);
+        std::process::exit(1)
+    }
+    // Replies to calls that completed before the qube went away.
+    if let Err(e) = stdout.write_pending(&mut writer).await {
+        eprintln!("Cannot write to {}: {}", qube_name, e)
     }
 }
 
@@ -165,7 +248,82 @@ but this server only supports version {MINOR_VERSION}"
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let local_set = tokio::task::LocalSet::new();
 
-    let source = std::env::var("QREXEC_REMOTE_DOMAIN").expect("No remote domain in qrexec");
-    local_set.spawn_local(client_server(source));
-    Ok(local_set.await)
+    let mut dump_introspection = false;
+    let mut dump_schema = false;
+    let mut config_path = config::CONFIG_PATH.to_owned();
+    // Capability overrides from the command line take precedence over the
+    // configuration file.  Useful for troubleshooting.
+    let mut enable_capabilities = Capabilities::empty();
+    let mut disable_capabilities = Capabilities::empty();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--dump-introspection" => dump_introspection = true,
+            "--dump-schema" => dump_schema = true,
+            "--config" => config_path = args.next().expect("--config requires an argument"),
+            "--enable-cap" | "--disable-cap" => {
+                let name = args.next().expect("capability name required");
+                let capability = Capabilities::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown capability {:?}", name));
+                if arg == "--enable-cap" {
+                    enable_capabilities |= capability;
+                    disable_capabilities -= capability;
+                } else {
+                    disable_capabilities |= capability;
+                    enable_capabilities -= capability;
+                }
+            }
+            _ => panic!("Unknown argument {:?}", arg),
+        }
+    }
+    if dump_introspection {
+        print!(
+            "{}",
+            notification_emitter::introspection_xml(&[&Control::new(
+                String::new(),
+                (MAJOR_VERSION, MINOR_VERSION),
+                Default::default(),
+            )])
+        );
+        return Ok(());
+    }
+    if dump_schema {
+        print!("{}", notification_emitter::schema::json());
+        return Ok(());
+    }
+    crash::install("notification-proxy-server");
+    let untrusted_source =
+        std::env::var("QREXEC_REMOTE_DOMAIN").expect("No remote domain in qrexec");
+    let source = match notification_emitter::validate_qube_name(&untrusted_source) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Refusing to start: {} ({:?})", e, untrusted_source);
+            std::process::exit(1)
+        }
+    };
+    let mut config = match Config::load(&config_path, &source) {
+        Ok(mut config) => {
+            config.enable_capabilities =
+                (config.enable_capabilities - disable_capabilities) | enable_capabilities;
+            config.disable_capabilities =
+                (config.disable_capabilities - enable_capabilities) | disable_capabilities;
+            config
+        }
+        Err(e) => {
+            eprintln!("Refusing to start: invalid configuration: {}", e);
+            std::process::exit(1)
+        }
+    };
+    if config.label_frame {
+        match admin::label_color(config.admin_api, &source).await {
+            Ok(color) => config.image_frame = Some(color),
+            Err(e) => eprintln!(
+                "Cannot look up the label of {}, not showing images: {}",
+                source, e
+            ),
+        }
+    }
+    crash::set_qube(&source);
+    local_set.run_until(client_server(source, config)).await;
+    Ok(())
 }
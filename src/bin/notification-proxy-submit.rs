@@ -0,0 +1,606 @@
+//! Submit notifications to dom0 without a D-Bus session.
+//!
+//! Reads one JSON object per line from standard input, sends each as a
+//! notification over `qubes.Notifications`, and writes one JSON object per
+//! line to standard output for every reply.  This is for qubes that cannot
+//! run the D-Bus client, such as minimal templates or Windows qubes.
+//!
+//! Input objects take the keys `summary` (required), `body`, `actions` (an
+//! object mapping action keys to labels), `urgency` (`"low"`, `"normal"`, or
+//! `"critical"`), `category`, `expire_timeout`, `replaces_id`, `transient`,
+//! `resident`, and `suppress_sound`, with the meanings of the notification
+//! specification.
+//!
+//! Output objects are `{"line": N, "id": ID}` when the notification from
+//! input line N was shown, `{"line": N, "error": NAME, "message": TEXT}`
+//! when it was not, and `{"id": ID, "action": KEY}`, `{"id": ID, "url":
+//! URL}`, `{"id": ID, "reply": TEXT}`, or `{"id": ID, "closed": REASON}`
+//! for later events.  Replies come from an `inline-reply` action.  The tool
+//! exits once input has ended and every line has been answered.
+//!
+//! Options: `--qrexec-client PATH` (default `qrexec-client-vm`), the program
+//! that connects to the service.  It is run with the target and service as
+//! arguments, and must connect its standard input and output to the service.
+use bincode::Options as _;
+use notification_emitter::codec::WireU32;
+use notification_emitter::{
+    merge_versions, split_version, ExpireTimeout, Features, Message, NegotiatedFeatures,
+    NotificationBuilder, ReplyMessage, Request, Urgency, CLOSE_VERSION, MAJOR_VERSION,
+    MAX_MESSAGE_SIZE, MINOR_VERSION,
+};
+use std::collections::HashSet;
+use std::io::{BufRead as _, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// A parsed JSON value.  Objects keep their keys in order, so that actions
+/// are offered in the order they were given.
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+/// Nesting deeper than this is rejected, so that input cannot exhaust the
+/// stack.
+const MAX_DEPTH: usize = 16;
+
+struct Parser<'a> {
+    input: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Parse `input`, which must be exactly one JSON value.
+    fn parse(input: &'a str) -> Result<Json, String> {
+        let mut parser = Parser {
+            input: input.as_bytes(),
+            position: 0,
+        };
+        let value = parser.value(0)?;
+        parser.skip_whitespace();
+        if parser.position != parser.input.len() {
+            return Err(format!("trailing data at byte {}", parser.position));
+        }
+        Ok(value)
+    }
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.input.get(self.position) {
+            self.position += 1
+        }
+    }
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.input.get(self.position) != Some(&byte) {
+            return Err(format!(
+                "expected {:?} at byte {}",
+                byte as char, self.position
+            ));
+        }
+        self.position += 1;
+        Ok(())
+    }
+    fn keyword(&mut self, keyword: &str, value: Json) -> Result<Json, String> {
+        if !self.input[self.position..].starts_with(keyword.as_bytes()) {
+            return Err(format!("invalid token at byte {}", self.position));
+        }
+        self.position += keyword.len();
+        Ok(value)
+    }
+    fn value(&mut self, depth: usize) -> Result<Json, String> {
+        if depth > MAX_DEPTH {
+            return Err("nested too deeply".to_owned());
+        }
+        self.skip_whitespace();
+        match self.input.get(self.position) {
+            None => Err("unexpected end of input".to_owned()),
+            Some(b'n') => self.keyword("null", Json::Null),
+            Some(b't') => self.keyword("true", Json::Bool(true)),
+            Some(b'f') => self.keyword("false", Json::Bool(false)),
+            Some(b'"') => self.string().map(Json::String),
+            Some(b'[') => {
+                self.position += 1;
+                let mut elements = vec![];
+                self.skip_whitespace();
+                if self.input.get(self.position) == Some(&b']') {
+                    self.position += 1;
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value(depth + 1)?);
+                    self.skip_whitespace();
+                    match self.input.get(self.position) {
+                        Some(b',') => self.position += 1,
+                        Some(b']') => {
+                            self.position += 1;
+                            return Ok(Json::Array(elements));
+                        }
+                        _ => return Err(format!("expected ',' or ']' at byte {}", self.position)),
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let mut members = vec![];
+                self.skip_whitespace();
+                if self.input.get(self.position) == Some(&b'}') {
+                    self.position += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value(depth + 1)?));
+                    self.skip_whitespace();
+                    match self.input.get(self.position) {
+                        Some(b',') => self.position += 1,
+                        Some(b'}') => {
+                            self.position += 1;
+                            return Ok(Json::Object(members));
+                        }
+                        _ => return Err(format!("expected ',' or '}}' at byte {}", self.position)),
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.position;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') =
+                    self.input.get(self.position)
+                {
+                    self.position += 1
+                }
+                // The bytes are ASCII, so this cannot fail.
+                let text = std::str::from_utf8(&self.input[start..self.position]).unwrap();
+                text.parse()
+                    .map(Json::Number)
+                    .map_err(|_| format!("invalid number {:?}", text))
+            }
+            Some(_) => Err(format!("invalid token at byte {}", self.position)),
+        }
+    }
+    fn hex4(&mut self) -> Result<u32, String> {
+        let digits = self
+            .input
+            .get(self.position..self.position + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| format!("invalid escape at byte {}", self.position))?;
+        self.position += 4;
+        Ok(digits)
+    }
+    fn string(&mut self) -> Result<String, String> {
+        self.expect(b'"')?;
+        let mut bytes = vec![];
+        loop {
+            let byte = *self.input.get(self.position).ok_or("unterminated string")?;
+            self.position += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escape = *self.input.get(self.position).ok_or("unterminated string")?;
+                    self.position += 1;
+                    let c = match escape {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xD800..0xDC00).contains(&code)
+                                && self.input[self.position..].starts_with(b"\\u")
+                            {
+                                self.position += 2;
+                                let low = self.hex4()?;
+                                if !(0xDC00..0xE000).contains(&low) {
+                                    return Err("invalid surrogate pair".to_owned());
+                                }
+                                code = 0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00);
+                            }
+                            char::from_u32(code).ok_or("invalid code point")?
+                        }
+                        _ => return Err(format!("invalid escape at byte {}", self.position - 1)),
+                    };
+                    bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes())
+                }
+                0..=0x1F => return Err("control character in string".to_owned()),
+                _ => bytes.push(byte),
+            }
+        }
+        // The input came from a `&str` and escapes are encoded above.
+        Ok(String::from_utf8(bytes).expect("valid UTF-8"))
+    }
+}
+
+/// `s` as a JSON string.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Build a notification from one line of input.
+fn notification(line: &str) -> Result<NotificationBuilder, String> {
+    let mut members = match Parser::parse(line)? {
+        Json::Object(members) => members,
+        _ => return Err("not an object".to_owned()),
+    };
+    let string = |key: &str, value: Json| match value {
+        Json::String(s) => Ok(s),
+        _ => Err(format!("{} must be a string", key)),
+    };
+    let boolean = |key: &str, value: Json| match value {
+        Json::Bool(b) => Ok(b),
+        _ => Err(format!("{} must be true or false", key)),
+    };
+    let integer = |key: &str, value: Json, min: f64, max: f64| match value {
+        Json::Number(n) if n.fract() == 0.0 && (min..=max).contains(&n) => Ok(n),
+        _ => Err(format!(
+            "{} must be an integer from {} to {}",
+            key, min, max
+        )),
+    };
+    let summary = match members.iter().position(|(key, _)| key == "summary") {
+        Some(index) => string("summary", members.remove(index).1)?,
+        None => return Err("summary is required".to_owned()),
+    };
+    let mut builder = NotificationBuilder::new(summary);
+    for (key, value) in members {
+        builder = match &*key {
+            "summary" => return Err("duplicate summary".to_owned()),
+            "body" => builder.body(string(&key, value)?),
+            "category" => builder.category(string(&key, value)?),
+            "urgency" => builder.urgency(match &*string(&key, value)? {
+                "low" => Urgency::Low,
+                "normal" => Urgency::Normal,
+                "critical" => Urgency::Critical,
+                other => return Err(format!("unknown urgency {:?}", other)),
+            }),
+            "actions" => match value {
+                Json::Object(actions) => {
+                    let mut builder = builder;
+                    for (action, label) in actions {
+                        builder = builder.action(action, string("action label", label)?)
+                    }
+                    builder
+                }
+                _ => return Err("actions must be an object".to_owned()),
+            },
+            "expire_timeout" => {
+                let expire_timeout = integer(&key, value, -1.0, i32::MAX.into())? as i32;
+                builder
+                    .expire_timeout(ExpireTimeout::from_wire(expire_timeout).expect("at least -1"))
+            }
+            "replaces_id" => {
+                builder.replaces_id(integer(&key, value, 0.0, u32::MAX.into())? as u32)
+            }
+            "transient" => builder.transient(boolean(&key, value)?),
+            "resident" => builder.resident(boolean(&key, value)?),
+            "suppress_sound" => builder.suppress_sound(boolean(&key, value)?),
+            _ => return Err(format!("unknown key {:?}", key)),
+        }
+    }
+    Ok(builder)
+}
+
+/// Read one frame, or `None` at the end of input.
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 4];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let size = WireU32::from_bytes(header).get();
+    if size > MAX_MESSAGE_SIZE {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame too large ({} bytes)", size),
+        ));
+    }
+    let mut bytes = vec![0; size as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bytes))
+}
+
+/// Write `line` to standard output.
+fn output(line: String) {
+    let mut stdout = std::io::stdout().lock();
+    writeln!(stdout, "{}", line)
+        .and_then(|()| stdout.flush())
+        .expect("Error writing to stdout")
+}
+
+/// The reply to input line `line` that failed with `name`.
+fn error(line: u64, name: &str, message: &str) -> String {
+    format!(
+        "{{\"line\": {}, \"error\": {}, \"message\": {}}}",
+        line,
+        quote(name),
+        quote(message)
+    )
+}
+
+fn main() {
+    let mut qrexec_client = "qrexec-client-vm".to_owned();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match &*arg {
+            "--qrexec-client" => {
+                qrexec_client = args.next().expect("--qrexec-client requires an argument")
+            }
+            _ => panic!("Unknown argument {:?}", arg),
+        }
+    }
+
+    let mut child = Command::new(&qrexec_client)
+        .args(["", "qubes.Notifications"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap_or_else(|e| panic!("Cannot start {}: {}", qrexec_client, e));
+    let mut to_dom0 = child.stdin.take().expect("stdin is piped");
+    let mut from_dom0 = child.stdout.take().expect("stdout is piped");
+
+    // dom0 sends its version first.  Reply with the highest version both
+    // sides support.
+    let mut version = [0; 4];
+    from_dom0
+        .read_exact(&mut version)
+        .expect("Cannot read version from dom0");
+    let (major, minor) = split_version(WireU32::from_bytes(version).get());
+    if major != MAJOR_VERSION {
+        panic!("dom0 speaks protocol version {major}, not {MAJOR_VERSION}");
+    }
+    let minor = minor.min(MINOR_VERSION);
+    to_dom0
+        .write_all(&WireU32::new(merge_versions(MAJOR_VERSION, minor)).to_bytes())
+        .expect("Cannot write version to dom0");
+    // Nothing optional is needed to submit notifications.
+    if NegotiatedFeatures::exchanges_masks(minor) {
+        to_dom0
+            .write_all(&WireU32::new(Features::empty().bits()).to_bytes())
+            .expect("Cannot write feature mask to dom0");
+        from_dom0
+            .read_exact(&mut [0; 4])
+            .expect("Cannot read feature mask from dom0");
+    }
+
+    let options = bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_native_endian()
+        .reject_trailing_bytes();
+    // Lines sent to dom0 and not yet answered.
+    let pending = Arc::new((Mutex::new(HashSet::new()), Condvar::new()));
+    let reader_pending = pending.clone();
+    std::thread::spawn(move || {
+        let answered = |line: u64, reply: String| {
+            let (lock, condvar) = &*reader_pending;
+            if lock.lock().unwrap().remove(&line) {
+                output(reply);
+                condvar.notify_all()
+            }
+        };
+        while let Some(bytes) = read_frame(&mut from_dom0).expect("Cannot read from dom0") {
+            match options.deserialize(&bytes) {
+                Ok(ReplyMessage::Id { id, sequence }) => answered(
+                    sequence,
+                    format!("{{\"line\": {}, \"id\": {}}}", sequence, id),
+                ),
+                Ok(ReplyMessage::DBusError {
+                    name,
+                    message,
+                    sequence,
+                }) => answered(
+                    sequence,
+                    error(sequence, &name, message.as_deref().unwrap_or("")),
+                ),
+                Ok(ReplyMessage::UnknownError { sequence }) => answered(
+                    sequence,
+                    error(
+                        sequence,
+                        "org.freedesktop.DBus.Error.Failed",
+                        "Unknown error in dom0",
+                    ),
+                ),
+                Ok(ReplyMessage::ActionInvoked { id, action }) => output(format!(
+                    "{{\"id\": {}, \"action\": {}}}",
+                    id,
+                    quote(&action)
+                )),
+                Ok(ReplyMessage::OpenUrl { id, url }) => {
+                    output(format!("{{\"id\": {}, \"url\": {}}}", id, quote(&url)))
+                }
+                Ok(ReplyMessage::Replied { id, text }) => {
+                    output(format!("{{\"id\": {}, \"reply\": {}}}", id, quote(&text)))
+                }
+                Ok(ReplyMessage::Dismissed { id, reason }) => {
+                    output(format!("{{\"id\": {}, \"closed\": {}}}", id, reason))
+                }
+                Ok(
+                    ReplyMessage::ServerRestart
+                    | ReplyMessage::ExpireTimeout { .. }
+                    | ReplyMessage::Sanitized { .. }
+                    | ReplyMessage::Suppressed { .. }
+                    | ReplyMessage::Capabilities { .. }
+                    | ReplyMessage::DaemonCapabilities { .. }
+                    | ReplyMessage::Pong { .. }
+                    | ReplyMessage::Batch(_)
+                    | ReplyMessage::History { .. }
+                    | ReplyMessage::Draining
+                    | ReplyMessage::Drained { .. }
+                    | ReplyMessage::Reshown { .. },
+                ) => {}
+                Err(e) => panic!("Malformed reply from dom0: {}", e),
+            }
+        }
+        // dom0 went away: nothing more will be answered.
+        let (lock, condvar) = &*reader_pending;
+        for line in lock.lock().unwrap().drain() {
+            output(error(
+                line,
+                "org.freedesktop.DBus.Error.Disconnected",
+                "dom0 closed the connection",
+            ))
+        }
+        condvar.notify_all()
+    });
+
+    for (line, text) in (1..).zip(std::io::stdin().lock().lines()) {
+        let text = match text {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                output(error(
+                    line,
+                    "org.freedesktop.DBus.Error.InvalidArgs",
+                    "Input is not UTF-8",
+                ));
+                continue;
+            }
+            Err(e) => panic!("Error reading stdin: {}", e),
+        };
+        if text.trim().is_empty() {
+            continue;
+        }
+        let notification =
+            match notification(&text).and_then(|builder| builder.build().map_err(str::to_owned)) {
+                Ok(notification) => notification,
+                Err(e) => {
+                    output(error(line, "org.freedesktop.DBus.Error.InvalidArgs", &e));
+                    continue;
+                }
+            };
+        let message = Message {
+            id: line,
+            notification,
+        };
+        let data = if minor >= CLOSE_VERSION {
+            options.serialize(&Request::Notify(message))
+        } else {
+            options.serialize(&message)
+        }
+        .expect("Cannot serialize object?");
+        if data.len() > MAX_MESSAGE_SIZE as usize {
+            output(error(
+                line,
+                "org.freedesktop.DBus.Error.InvalidArgs",
+                "Notification too large",
+            ));
+            continue;
+        }
+        pending.0.lock().unwrap().insert(line);
+        to_dom0
+            .write_all(&WireU32::new(data.len() as u32).to_bytes())
+            .and_then(|()| to_dom0.write_all(&data))
+            .and_then(|()| to_dom0.flush())
+            .expect("Error writing to dom0");
+    }
+
+    let (lock, condvar) = &*pending;
+    drop(
+        condvar
+            .wait_while(lock.lock().unwrap(), |pending| !pending.is_empty())
+            .unwrap(),
+    );
+    drop(to_dom0);
+    let _ = child.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            Parser::parse(r#" {"a": [1, -2.5e1, true, null], "b": "\u00e9\ud83d\ude00\n"} "#),
+            Ok(Json::Object(vec![
+                (
+                    "a".to_owned(),
+                    Json::Array(vec![
+                        Json::Number(1.0),
+                        Json::Number(-25.0),
+                        Json::Bool(true),
+                        Json::Null
+                    ])
+                ),
+                ("b".to_owned(), Json::String("\u{e9}\u{1F600}\n".to_owned())),
+            ]))
+        );
+        for bad in [
+            "",
+            "{",
+            "{\"a\" 1}",
+            "[1,]",
+            "\"\\x\"",
+            "\"a\nb\"",
+            "1 2",
+            &"[".repeat(100),
+        ] {
+            assert!(Parser::parse(bad).is_err(), "{:?}", bad);
+        }
+        let text = "a\"b\\c\n\u{1}\u{e9}";
+        assert_eq!(
+            Parser::parse(&quote(text)),
+            Ok(Json::String(text.to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_notification() {
+        let built = notification(
+            r#"{"summary": "s", "body": "b", "urgency": "critical",
+                "actions": {"default": "Open", "later": "Later"},
+                "expire_timeout": 5000, "transient": true}"#,
+        )
+        .unwrap()
+        .build()
+        .unwrap();
+        assert_eq!(built.urgency(), Some(Urgency::Critical));
+        match built {
+            notification_emitter::Notification::V1 {
+                summary,
+                body,
+                actions,
+                expire_timeout,
+                transient: true,
+                ..
+            } => {
+                assert_eq!((&*summary, &*body), ("s", "b"));
+                assert_eq!(expire_timeout, ExpireTimeout::millis(5000));
+                assert_eq!(actions, ["default", "Open", "later", "Later"]);
+            }
+            other => panic!("{:?}", other),
+        }
+        for bad in [
+            "[]",
+            r#"{"body": "no summary"}"#,
+            r#"{"summary": 1}"#,
+            r#"{"summary": "s", "summary": "t"}"#,
+            r#"{"summary": "s", "urgency": "high"}"#,
+            r#"{"summary": "s", "expire_timeout": -2}"#,
+            r#"{"summary": "s", "replaces_id": 1.5}"#,
+            r#"{"summary": "s", "icon": "x"}"#,
+        ] {
+            assert!(notification(bad).is_err(), "{:?}", bad);
+        }
+    }
+}
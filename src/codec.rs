@@ -0,0 +1,379 @@
+//! Framing of the messages exchanged over qrexec.
+//!
+//! The connection starts with each side sending its protocol version as a
+//! [`WireU32`].  After that, each message is a [`WireU32`] length followed
+//! by that many bytes, encoded with the [`WireCodec`] the two sides agreed
+//! on, and, with [`Framing::Crc32`], a checksum.  A corrupted length cannot
+//! be recovered from, so any framing error ends the connection.
+
+use crate::MAX_MESSAGE_SIZE;
+use bincode::Options as _;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::rc::Rc;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio::sync::Notify;
+
+/// A `u32` as it is sent over the wire: little-endian on every host.  The
+/// value can only be reached through [`WireU32::get`], so it cannot
+/// accidentally be byte-swapped twice.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WireU32([u8; 4]);
+
+impl WireU32 {
+    pub const fn new(value: u32) -> Self {
+        Self(value.to_le_bytes())
+    }
+    pub const fn get(self) -> u32 {
+        u32::from_le_bytes(self.0)
+    }
+    pub const fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self(bytes)
+    }
+    pub const fn to_bytes(self) -> [u8; 4] {
+        self.0
+    }
+    /// Read one value.  Does not flush.
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Self> {
+        let mut bytes = [0; 4];
+        reader.read_exact(&mut bytes).await?;
+        Ok(Self(bytes))
+    }
+    /// Write one value and flush.
+    pub async fn write<W: AsyncWrite + Unpin>(self, writer: &mut W) -> std::io::Result<()> {
+        writer.write_all(&self.0).await?;
+        writer.flush().await
+    }
+}
+
+/// Why a frame could not be read.
+#[derive(Debug)]
+pub enum FrameError {
+    /// The stream ended in the middle of a frame.
+    Truncated,
+    /// The length prefix exceeds [`MAX_MESSAGE_SIZE`].
+    TooLarge(u32),
+    /// The checksum in the trailer does not match the frame.
+    BadChecksum { expected: u32, actual: u32 },
+    /// Reading failed.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for FrameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FrameError::Truncated => f.write_str("stream ended in the middle of a frame"),
+            FrameError::TooLarge(size) => write!(f, "frame too large ({} bytes)", size),
+            FrameError::BadChecksum { expected, actual } => write!(
+                f,
+                "frame corrupted (checksum {:08x}, expected {:08x})",
+                actual, expected
+            ),
+            FrameError::Io(e) => write!(f, "read failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// How messages are encoded in frames.
+pub trait WireCodec {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError>;
+    /// Decode one message, which must take up all of `bytes`.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError>;
+}
+
+/// Why a message could not be encoded or decoded.
+#[derive(Debug)]
+pub struct CodecError(String);
+
+impl std::fmt::Display for CodecError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+/// bincode with fixed-width native-endian integers, which every version
+/// speaks.  Nothing on the wire says which field is which, so both sides
+/// must agree on the layout of every message exactly.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Bincode;
+
+impl Bincode {
+    fn options() -> impl bincode::Options {
+        bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes()
+    }
+}
+
+impl WireCodec for Bincode {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        Self::options()
+            .serialize(value)
+            .map_err(|e| CodecError(e.to_string()))
+    }
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        Self::options()
+            .deserialize(bytes)
+            .map_err(|e| CodecError(e.to_string()))
+    }
+}
+
+/// CBOR, which names struct fields and enum variants, so that a message
+/// can be decoded without knowing its exact layout.  Used if both sides
+/// agreed on [`crate::Features::CBOR`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Cbor;
+
+impl WireCodec for Cbor {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(value, &mut bytes).map_err(|e| CodecError(e.to_string()))?;
+        Ok(bytes)
+    }
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        let mut rest = bytes;
+        let value = ciborium::de::from_reader(&mut rest).map_err(|e| CodecError(e.to_string()))?;
+        match rest.len() {
+            0 => Ok(value),
+            len => Err(CodecError(format!("{} trailing bytes", len))),
+        }
+    }
+}
+
+/// The [`WireCodec`] of a connection, chosen when it starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Bincode,
+    Cbor,
+}
+
+impl WireCodec for Encoding {
+    fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CodecError> {
+        match self {
+            Encoding::Bincode => Bincode.encode(value),
+            Encoding::Cbor => Cbor.encode(value),
+        }
+    }
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, CodecError> {
+        match self {
+            Encoding::Bincode => Bincode.decode(bytes),
+            Encoding::Cbor => Cbor.decode(bytes),
+        }
+    }
+}
+
+/// What follows the body of each frame, chosen when the connection starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Nothing.
+    #[default]
+    Plain,
+    /// The [`crc32`] of the length and the body, as a [`WireU32`].  Used if
+    /// both sides agreed on [`crate::Features::CHECKSUM`].
+    Crc32,
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = match crc & 1 {
+                0 => crc >> 1,
+                _ => (crc >> 1) ^ 0xEDB8_8320,
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// The CRC-32 of zlib and Ethernet over `parts`, one after the other.
+/// This catches corruption, not tampering: whoever can change a frame can
+/// fix up its checksum too.
+pub fn crc32(parts: &[&[u8]]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in parts.iter().copied().flatten() {
+        crc = CRC32_TABLE[usize::from(crc as u8 ^ byte)] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Read into `buf` until it is full or the stream ends, returning the number
+/// of bytes read.
+async fn read_full<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut [u8],
+) -> Result<usize, FrameError> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]).await {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(FrameError::Io(e)),
+        }
+    }
+    Ok(filled)
+}
+
+/// Read one frame and return its body.  Returns `Ok(None)` if the stream
+/// ends cleanly before the frame starts.
+pub async fn read_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    framing: Framing,
+) -> Result<Option<Vec<u8>>, FrameError> {
+    let mut header = [0; 4];
+    match read_full(reader, &mut header).await? {
+        0 => return Ok(None),
+        4 => {}
+        _ => return Err(FrameError::Truncated),
+    }
+    let size = WireU32::from_bytes(header).get();
+    if size > MAX_MESSAGE_SIZE {
+        return Err(FrameError::TooLarge(size));
+    }
+    let mut bytes = vec![0; size as usize];
+    if read_full(reader, &mut bytes).await? != bytes.len() {
+        return Err(FrameError::Truncated);
+    }
+    if framing == Framing::Crc32 {
+        let mut trailer = [0; 4];
+        if read_full(reader, &mut trailer).await? != trailer.len() {
+            return Err(FrameError::Truncated);
+        }
+        let expected = WireU32::from_bytes(trailer).get();
+        let actual = crc32(&[&header, &bytes]);
+        if actual != expected {
+            return Err(FrameError::BadChecksum { expected, actual });
+        }
+    }
+    Ok(Some(bytes))
+}
+
+/// Write one frame and flush.  Frames larger than [`MAX_MESSAGE_SIZE`] are
+/// refused with [`std::io::ErrorKind::InvalidInput`] before anything is
+/// written, since the peer would end the connection on them.
+///
+/// The length, body, and trailer are written with a single vectored write
+/// if the writer supports it, and copied into one buffer otherwise, so that
+/// a frame normally takes one system call.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    framing: Framing,
+    data: &[u8],
+) -> std::io::Result<()> {
+    let size = match u32::try_from(data.len()) {
+        Ok(size) if size <= MAX_MESSAGE_SIZE => size,
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("frame too large ({} bytes)", data.len()),
+            ))
+        }
+    };
+    let header = WireU32::new(size).to_bytes();
+    let trailer = match framing {
+        Framing::Plain => vec![],
+        Framing::Crc32 => WireU32::new(crc32(&[&header, data])).to_bytes().to_vec(),
+    };
+    if writer.is_write_vectored() {
+        let mut bufs = [
+            IoSlice::new(&header),
+            IoSlice::new(data),
+            IoSlice::new(&trailer),
+        ];
+        let mut bufs = &mut bufs[..];
+        while !bufs.is_empty() {
+            match writer.write_vectored(bufs).await {
+                Ok(0) => return Err(std::io::ErrorKind::WriteZero.into()),
+                Ok(n) => IoSlice::advance_slices(&mut bufs, n),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    } else {
+        let mut frame = Vec::with_capacity(header.len() + data.len() + trailer.len());
+        frame.extend_from_slice(&header);
+        frame.extend_from_slice(data);
+        frame.extend_from_slice(&trailer);
+        writer.write_all(&frame).await?;
+    }
+    writer.flush().await
+}
+
+/// Frames waiting to be written by a single writer task.  Frames are
+/// written in the order they were queued, whichever task queued them, and
+/// queueing never awaits.  Code that queues several frames without an
+/// `.await` in between therefore knows that no other frame can come
+/// between them, and that anything queued afterwards comes after them.
+#[derive(Clone, Default)]
+pub struct OutboundQueue(Rc<QueueInner>);
+
+#[derive(Default)]
+struct QueueInner {
+    framing: Framing,
+    frames: RefCell<VecDeque<Vec<u8>>>,
+    ready: Notify,
+}
+
+impl OutboundQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// A queue that writes frames with `framing`.
+    pub fn with_framing(framing: Framing) -> Self {
+        Self(Rc::new(QueueInner {
+            framing,
+            ..Default::default()
+        }))
+    }
+    /// Queue one frame body.
+    pub fn push(&self, data: Vec<u8>) {
+        self.0.frames.borrow_mut().push_back(data);
+        self.0.ready.notify_one();
+    }
+    /// Number of frames not yet written.
+    pub fn len(&self) -> usize {
+        self.0.frames.borrow().len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    /// Write the frames queued so far, including any queued while
+    /// writing.
+    pub async fn write_pending<W: AsyncWrite + Unpin>(
+        &self,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        loop {
+            // The borrow must end before the write awaits.
+            let data = self.0.frames.borrow_mut().pop_front();
+            match data {
+                Some(data) => write_frame(writer, self.0.framing, &data).await?,
+                None => return Ok(()),
+            }
+        }
+    }
+    /// Write frames as they are queued.  Only returns on error.
+    pub async fn run<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> std::io::Error {
+        loop {
+            if let Err(e) = self.write_pending(writer).await {
+                return e;
+            }
+            self.0.ready.notified().await
+        }
+    }
+}
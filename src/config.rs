@@ -0,0 +1,700 @@
+//! Configuration of the dom0 side of the proxy.
+//!
+//! The configuration file consists of `key = value` lines and `#`
+//! comments.  Settings before the first section apply to all qubes.  A
+//! `[name]` line starts a section whose settings apply only to the qube
+//! called `name`, overriding the global ones:
+//!
+//! ```text
+//! locale = de_DE.UTF-8
+//!
+//! [work]
+//! locale = en_US.UTF-8
+//! ```
+//!
+//! Unknown keys are ignored with a warning, so that newer configuration
+//! files can be used with older servers.  Invalid values are errors.
+
+use crate::admin::Transport;
+use crate::{Capabilities, ExpireTimeout, Urgency, MAX_EXPIRE_TIMEOUT};
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+/// Default location of the configuration file.
+pub const CONFIG_PATH: &str = "/etc/qubes/notification-proxy.conf";
+
+/// Default for [`Config::keepalive_timeout`]: three times the interval at
+/// which the guest pings by default.
+pub const DEFAULT_KEEPALIVE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Default for [`Config::expiry_grace`].
+pub const DEFAULT_EXPIRY_GRACE: Duration = Duration::from_secs(5);
+
+/// Default for [`Config::max_concurrent_calls`].
+pub const DEFAULT_MAX_CONCURRENT_CALLS: usize = 4;
+
+/// Default for [`Config::max_action_label`].
+pub const DEFAULT_MAX_ACTION_LABEL: usize = 60;
+
+/// Default for [`Config::dbus_retries`].
+pub const DEFAULT_DBUS_RETRIES: u32 = 2;
+
+/// Most retries [`Config::dbus_retries`] can be set to.
+pub const MAX_DBUS_RETRIES: u32 = 5;
+
+/// Default for [`Config::dbus_retry_delay`].
+pub const DEFAULT_DBUS_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Default for [`Config::max_signals_per_second`].
+pub const DEFAULT_MAX_SIGNALS_PER_SECOND: u32 = 20;
+
+/// Default for [`Config::signal_coalesce`].
+pub const DEFAULT_SIGNAL_COALESCE: Duration = Duration::from_millis(500);
+
+/// Where to show the time dom0 received a notification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timestamp {
+    /// After the summary, as local time of day.
+    Summary,
+    /// In the `x-qubes-received-at` hint, as seconds since the Unix epoch.
+    Hint,
+}
+
+/// Where notifications from a qube go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Route {
+    /// To the notification daemon.
+    Display,
+    /// Only to the log, after sanitization, for qubes that should be
+    /// silent but auditable.
+    Log,
+}
+
+/// How notifications are spread over [`Config::session_buses`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusPolicy {
+    /// To the first bus that can be reached.
+    First,
+    /// To every bus that can be reached.  Only the first one reports
+    /// dismissals and actions to the qube.
+    All,
+}
+
+/// Which daemon's quirks to work around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DaemonQuirks {
+    /// Those of the daemon that is running, as it identifies itself.
+    Detect,
+    /// None.
+    Off,
+    /// Those of the daemon with this name.
+    As(String),
+}
+
+/// Settings for one qube.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Locale used for strings rendered by the proxy.  If unset, the
+    /// locale is taken from the environment.
+    pub locale: Option<String>,
+    /// Text shown before the prefix of notifications with the corresponding
+    /// urgency, such as an emoji, so that urgency is visible even on daemons
+    /// that do not style it.  Indexed by urgency.
+    pub urgency_markers: [String; 3],
+    /// Capabilities assumed to be supported even if the daemon does not
+    /// advertise them.
+    pub enable_capabilities: Capabilities,
+    /// Capabilities assumed to be unsupported even if the daemon
+    /// advertises them.  This takes precedence over
+    /// [`Config::enable_capabilities`].
+    pub disable_capabilities: Capabilities,
+    /// Expire timeout, in milliseconds, used when the guest asks for the
+    /// daemon's default.
+    pub default_expire_timeout: Option<NonZeroU32>,
+    /// Maximum expire timeout, in milliseconds.  Notifications that would
+    /// never expire expire after this long instead.
+    pub max_expire_timeout: Option<NonZeroU32>,
+    /// What to do with the position hints.  If `None`, they are dropped.
+    /// Otherwise, they are clamped to a screen of the given width and
+    /// height and passed to the daemon.  Only useful for setups where the
+    /// qube is trusted to place its notifications.
+    pub position_hints: Option<(i32, i32)>,
+    /// If set, only notifications whose category matches one of these
+    /// patterns are shown.  Notifications without a category are then
+    /// rejected too.
+    pub allow_categories: Option<Vec<String>>,
+    /// Notifications whose category matches one of these patterns are
+    /// rejected.  This takes precedence over [`Config::allow_categories`].
+    pub deny_categories: Vec<String>,
+    /// How many notifications from the qube are passed to the daemon at
+    /// the same time.  Further notifications wait, and so does reading
+    /// from the qube, so a burst cannot use unbounded resources in dom0.
+    pub max_concurrent_calls: usize,
+    /// Longest action label passed to the daemon, in characters.  Longer
+    /// labels are cut short with an ellipsis, as buttons with long labels
+    /// break the layout of most daemons.
+    pub max_action_label: usize,
+    /// If set, the lines of the body are joined with this separator, for
+    /// daemons that only show the first line of the body.
+    pub fold_body: Option<String>,
+    /// If set, images with alpha are composited over this RGB color before
+    /// they are passed on, for daemons that draw them badly.
+    pub flatten_images: Option<[u8; 3]>,
+    /// Factor by which images are scaled up, for high-resolution displays.
+    /// If `None`, it is taken from the environment by [`display_scale`].
+    pub image_scale: Option<u32>,
+    /// If set, images are passed on inside a frame of this RGB color, such
+    /// as that of the label of the qube, so that they cannot pass for part
+    /// of the dom0 UI.  Without a frame, images are not passed on at all,
+    /// and with one they must leave room for it within
+    /// [`crate::MAX_WIDTH`] by [`crate::MAX_HEIGHT`].
+    pub image_frame: Option<[u8; 3]>,
+    /// Whether [`Config::image_frame`] is set to the color of the label of
+    /// the qube when it connects, as looked up through the Admin API.  If
+    /// the lookup fails, images are not passed on.
+    pub label_frame: bool,
+    /// How the Admin API is called: through qubesd in dom0, or over qrexec
+    /// when the server runs in a GUI domain.
+    pub admin_api: Transport,
+    /// Whether to flag URL-like text that mixes Latin, Greek, and Cyrillic
+    /// letters, as in a phishing link with a Cyrillic `а` in `paypal.com`.
+    pub flag_confusables: bool,
+    /// Where to show the time dom0 received the notification, if anywhere.
+    /// This tells the user when a notification that was held up somewhere
+    /// was actually sent, and cannot be forged by the qube.
+    pub timestamp: Option<Timestamp>,
+    /// How long after its expire timeout a notification that the daemon
+    /// did not close is taken to have expired anyway, so that the qube
+    /// learns about it even if the daemon crashed or ignores timeouts.  If
+    /// `None`, the qube is only told what the daemon reports.
+    pub expiry_grace: Option<Duration>,
+    /// How long a qube that pings may go without sending anything before
+    /// the connection is taken to be dead and closed.  Must be longer than
+    /// the interval at which the qube pings.  If `None`, the connection is
+    /// only closed when the qube closes it.
+    pub keepalive_timeout: Option<Duration>,
+    /// Where notifications go.
+    pub route: Route,
+    /// Whether to show resident notifications again when a daemon with
+    /// capabilities the previous one lacked takes over, so that they get
+    /// the hints that were left out.  Only for qubes that can be told of
+    /// their new IDs, with [`crate::ReplyMessage::Reshown`].
+    pub resend_on_upgrade: bool,
+    /// Which daemon's quirks to work around.
+    pub daemon_quirks: DaemonQuirks,
+    /// Whether to tell the qube when a notification was accepted but not
+    /// shown, for example because of quiet hours.  The qube can then tell
+    /// that the user has not seen it.
+    pub report_suppressed: bool,
+    /// Addresses of the session buses with the notification daemons, such
+    /// as one per seat.  If empty, the session bus of the server is used.
+    pub session_buses: Vec<String>,
+    /// How notifications are spread over [`Config::session_buses`].
+    pub bus_policy: BusPolicy,
+    /// Daily period, as minutes since local midnight from start
+    /// (inclusive) to end (exclusive), during which notifications that are
+    /// not critical are logged instead of shown.  The period may wrap
+    /// around midnight.
+    pub quiet_hours: Option<(u16, u16)>,
+    /// Unix socket or FIFO to which critical notifications are forwarded,
+    /// as described in the `relay` module.
+    pub relay: Option<std::path::PathBuf>,
+    /// Command run for the events in the `hooks` module.
+    pub hook: Option<std::path::PathBuf>,
+    /// How many rejected notifications within a minute run the hook.  If
+    /// `None`, rejections do not run it.
+    pub hook_rejections_per_minute: Option<NonZeroU32>,
+    /// Whether to show a notification the first time the qube connects in
+    /// a session, so that the user notices unexpected qubes sending
+    /// notifications.
+    pub announce: bool,
+    /// Whether the user must approve a qube before the first of its
+    /// notifications in a session is shown, so that an unexpected qube
+    /// sending notifications is noticed.
+    pub approve_new_qubes: bool,
+    /// How many times a `Notify` call is retried if it fails because no
+    /// daemon is running, as happens while the daemon is upgraded.
+    pub dbus_retries: u32,
+    /// Delay before the first retry.  Each further retry waits twice as
+    /// long, plus a random part of up to the same length.
+    pub dbus_retry_delay: Duration,
+    /// How many actions invoked in the daemon are reported to the qube
+    /// per second, as described in the `signals` module.  If `None`, there
+    /// is no limit.
+    pub max_signals_per_second: Option<NonZeroU32>,
+    /// Period within which the same action on the same notification is
+    /// only reported once.
+    pub signal_coalesce: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            locale: None,
+            urgency_markers: Default::default(),
+            enable_capabilities: Capabilities::empty(),
+            disable_capabilities: Capabilities::empty(),
+            default_expire_timeout: None,
+            max_expire_timeout: None,
+            position_hints: None,
+            allow_categories: None,
+            deny_categories: vec![],
+            max_concurrent_calls: DEFAULT_MAX_CONCURRENT_CALLS,
+            max_action_label: DEFAULT_MAX_ACTION_LABEL,
+            fold_body: None,
+            flatten_images: None,
+            image_scale: None,
+            image_frame: None,
+            label_frame: false,
+            admin_api: Transport::Auto,
+            flag_confusables: false,
+            timestamp: None,
+            expiry_grace: Some(DEFAULT_EXPIRY_GRACE),
+            keepalive_timeout: Some(DEFAULT_KEEPALIVE_TIMEOUT),
+            route: Route::Display,
+            resend_on_upgrade: false,
+            daemon_quirks: DaemonQuirks::Detect,
+            report_suppressed: false,
+            session_buses: vec![],
+            bus_policy: BusPolicy::First,
+            quiet_hours: None,
+            relay: None,
+            hook: None,
+            hook_rejections_per_minute: None,
+            announce: false,
+            approve_new_qubes: false,
+            dbus_retries: DEFAULT_DBUS_RETRIES,
+            dbus_retry_delay: DEFAULT_DBUS_RETRY_DELAY,
+            max_signals_per_second: NonZeroU32::new(DEFAULT_MAX_SIGNALS_PER_SECOND),
+            signal_coalesce: DEFAULT_SIGNAL_COALESCE,
+        }
+    }
+}
+
+impl Config {
+    /// Parse `text`, keeping the global settings and those in the section
+    /// for `qube_name`.  Errors include the (1-based) line number.
+    pub fn parse(text: &str, qube_name: &str) -> Result<Self, String> {
+        let mut config = Self::default();
+        let mut applies = true;
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |e: &str| format!("line {}: {}", number + 1, e);
+            if let Some(section) = line.strip_prefix('[') {
+                let section = section
+                    .strip_suffix(']')
+                    .ok_or_else(|| error("unterminated section header"))?;
+                applies = section.trim() == qube_name;
+                continue;
+            }
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| error("expected `key = value`"))?;
+            let (key, value) = (key.trim(), value.trim());
+            if !applies {
+                continue;
+            }
+            config.set(key, value).map_err(|e| error(&e))?
+        }
+        Ok(config)
+    }
+
+    /// Load the configuration from `path`.  A missing file is not an error
+    /// and results in the default configuration.
+    pub fn load(path: &str, qube_name: &str) -> Result<Self, String> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text, qube_name).map_err(|e| format!("{}: {}", path, e)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(format!("{}: {}", path, e)),
+        }
+    }
+
+    /// The marker for notifications with the given urgency.
+    pub fn urgency_marker(&self, urgency: Urgency) -> &str {
+        &self.urgency_markers[urgency as usize]
+    }
+
+    /// Whether `minute`, in minutes since local midnight, is within
+    /// [`Config::quiet_hours`].
+    pub fn quiet_at(&self, minute: u16) -> bool {
+        match self.quiet_hours {
+            None => false,
+            Some((start, end)) if start < end => (start..end).contains(&minute),
+            Some((start, end)) => minute >= start || minute < end,
+        }
+    }
+
+    /// The expire timeout to pass to the daemon when the guest asked for
+    /// `requested`.
+    pub fn effective_expire_timeout(&self, requested: ExpireTimeout) -> ExpireTimeout {
+        let requested = match (requested, self.default_expire_timeout) {
+            (ExpireTimeout::Default, Some(default)) => ExpireTimeout::millis(default.get()),
+            _ => requested,
+        };
+        match (requested, self.max_expire_timeout) {
+            (ExpireTimeout::Never, Some(max)) => ExpireTimeout::millis(max.get()),
+            (ExpireTimeout::Millis(millis), Some(max)) => {
+                ExpireTimeout::millis(millis.get().min(max.get()))
+            }
+            _ => requested,
+        }
+    }
+
+    /// Whether notifications with the given (already sanitized) category may
+    /// be shown.
+    pub fn category_allowed(&self, category: Option<&str>) -> bool {
+        let matches = |patterns: &[String]| {
+            category.is_some_and(|category| {
+                patterns
+                    .iter()
+                    .any(|pattern| category_matches(pattern, category))
+            })
+        };
+        if matches(&self.deny_categories) {
+            return false;
+        }
+        match self.allow_categories {
+            Some(ref allow) => matches(allow),
+            None => true,
+        }
+    }
+
+    /// Apply a single setting.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "locale" => self.locale = Some(value.to_owned()),
+            "low-marker" => self.urgency_markers[Urgency::Low as usize] = marker(value)?,
+            "normal-marker" => self.urgency_markers[Urgency::Normal as usize] = marker(value)?,
+            "critical-marker" => self.urgency_markers[Urgency::Critical as usize] = marker(value)?,
+            "enable-capabilities" => self.enable_capabilities = capabilities(value)?,
+            "disable-capabilities" => self.disable_capabilities = capabilities(value)?,
+            "default-expire-timeout" => self.default_expire_timeout = Some(milliseconds(value)?),
+            "max-expire-timeout" => self.max_expire_timeout = Some(milliseconds(value)?),
+            "position-hints" => self.position_hints = position_hints(value)?,
+            "allow-categories" => self.allow_categories = Some(category_patterns(value)?),
+            "deny-categories" => self.deny_categories = category_patterns(value)?,
+            "max-concurrent-calls" => self.max_concurrent_calls = count(value)?,
+            "max-action-label" => self.max_action_label = count(value)?,
+            "fold-body" => self.fold_body = separator(value)?,
+            "flatten-images" => self.flatten_images = color(value)?,
+            "image-scale" => self.image_scale = scale(value)?,
+            "image-frame" if value == "label" => {
+                self.image_frame = None;
+                self.label_frame = true
+            }
+            "image-frame" => {
+                self.image_frame = color(value)?;
+                self.label_frame = false
+            }
+            "admin-api" => self.admin_api = admin_api(value)?,
+            "flag-confusables" => self.flag_confusables = yes_no(value)?,
+            "timestamp" => self.timestamp = timestamp(value)?,
+            "expiry-grace" => self.expiry_grace = grace(value)?,
+            "keepalive-timeout" => self.keepalive_timeout = grace(value)?,
+            "route" => self.route = route(value)?,
+            "daemon-quirks" => self.daemon_quirks = daemon_quirks(value)?,
+            "report-suppressed" => self.report_suppressed = yes_no(value)?,
+            "session-buses" => self.session_buses = bus_addresses(value)?,
+            "bus-policy" => self.bus_policy = bus_policy(value)?,
+            "resend-on-upgrade" => self.resend_on_upgrade = yes_no(value)?,
+            "quiet-hours" => self.quiet_hours = quiet_hours(value)?,
+            "hook" => self.hook = absolute_path(value)?,
+            "relay" => self.relay = absolute_path(value)?,
+            "hook-rejections-per-minute" => self.hook_rejections_per_minute = threshold(value)?,
+            "announce" => self.announce = yes_no(value)?,
+            "approve-new-qubes" => self.approve_new_qubes = yes_no(value)?,
+            "dbus-retries" => self.dbus_retries = retries(value)?,
+            "dbus-retry-delay" => {
+                self.dbus_retry_delay = Duration::from_millis(milliseconds(value)?.get().into())
+            }
+            "max-signals-per-second" => self.max_signals_per_second = threshold(value)?,
+            "signal-coalesce" => {
+                self.signal_coalesce = Duration::from_millis(milliseconds(value)?.get().into())
+            }
+            _ => eprintln!("Ignoring unknown configuration key {:?}", key),
+        }
+        Ok(())
+    }
+}
+
+/// Parse an urgency marker.  Markers are short and are always followed by a
+/// space, so that they do not run into the qube name.
+fn marker(value: &str) -> Result<String, String> {
+    if value.is_empty() {
+        return Ok(String::new());
+    }
+    if value.chars().count() > 8 || value.chars().any(char::is_control) {
+        return Err(format!("invalid urgency marker {:?}", value));
+    }
+    Ok(value.to_owned() + " ")
+}
+
+/// Parse the body folding setting: `no`, or the separator to put between
+/// lines.  The separator is surrounded by spaces.
+fn separator(value: &str) -> Result<Option<String>, String> {
+    if value == "no" {
+        return Ok(None);
+    }
+    if value.is_empty() || value.chars().count() > 8 || value.chars().any(char::is_control) {
+        return Err(format!("invalid separator {:?}", value));
+    }
+    Ok(Some(format!(" {} ", value)))
+}
+
+/// Largest accepted [`Config::image_scale`].
+const MAX_IMAGE_SCALE: u32 = 8;
+
+/// The scale factor of the dom0 display, from `GDK_SCALE` or
+/// `QT_SCALE_FACTOR`, or 1 if neither is a whole number in range.
+pub fn display_scale() -> u32 {
+    ["GDK_SCALE", "QT_SCALE_FACTOR"]
+        .iter()
+        .filter_map(|name| std::env::var(name).ok()?.trim().parse().ok())
+        .find(|scale| (1..=MAX_IMAGE_SCALE).contains(scale))
+        .unwrap_or(1)
+}
+
+/// Parse the image scale: `auto`, or a factor from 1 to [`MAX_IMAGE_SCALE`].
+fn scale(value: &str) -> Result<Option<u32>, String> {
+    if value == "auto" {
+        return Ok(None);
+    }
+    match value.parse() {
+        Ok(scale) if (1..=MAX_IMAGE_SCALE).contains(&scale) => Ok(Some(scale)),
+        _ => Err(format!("invalid image scale {:?}", value)),
+    }
+}
+
+/// Parse `no` or an RGB color as `#rrggbb`.
+fn color(value: &str) -> Result<Option<[u8; 3]>, String> {
+    if value == "no" {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid color {:?}", value);
+    let hex = value.strip_prefix('#').ok_or_else(invalid)?;
+    if hex.len() != 6 || !hex.bytes().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+    let component = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok(Some([component(0)?, component(2)?, component(4)?]))
+}
+
+/// Parse the timestamp policy: `none`, `summary`, or `hint`.
+fn timestamp(value: &str) -> Result<Option<Timestamp>, String> {
+    match value {
+        "none" => Ok(None),
+        "summary" => Ok(Some(Timestamp::Summary)),
+        "hint" => Ok(Some(Timestamp::Hint)),
+        _ => Err(format!("invalid timestamp policy {:?}", value)),
+    }
+}
+
+/// Parse quiet hours: `no`, or `HH:MM-HH:MM` in local time.
+fn quiet_hours(value: &str) -> Result<Option<(u16, u16)>, String> {
+    if value == "no" {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid quiet hours {:?}", value);
+    let minute = |time: &str| -> Result<u16, String> {
+        let (hours, minutes) = time.trim().split_once(':').ok_or_else(invalid)?;
+        let digits = |s: &str| s.len() == 2 && s.bytes().all(|c| c.is_ascii_digit());
+        if !digits(hours) || !digits(minutes) {
+            return Err(invalid());
+        }
+        match (hours.parse::<u16>(), minutes.parse::<u16>()) {
+            (Ok(hours @ 0..=23), Ok(minutes @ 0..=59)) => Ok(hours * 60 + minutes),
+            _ => Err(invalid()),
+        }
+    };
+    let (start, end) = value.split_once('-').ok_or_else(invalid)?;
+    match (minute(start)?, minute(end)?) {
+        (start, end) if start == end => Err(invalid()),
+        period => Ok(Some(period)),
+    }
+}
+
+/// Parse a whitespace-separated list of D-Bus addresses.  Whitespace is
+/// always escaped within an address.
+fn bus_addresses(value: &str) -> Result<Vec<String>, String> {
+    value
+        .split_whitespace()
+        .map(|address| match address.parse::<zbus::Address>() {
+            Ok(_) => Ok(address.to_owned()),
+            Err(e) => Err(format!("invalid bus address {:?}: {}", address, e)),
+        })
+        .collect()
+}
+
+/// Parse a bus policy: `first` or `all`.
+fn bus_policy(value: &str) -> Result<BusPolicy, String> {
+    match value {
+        "first" => Ok(BusPolicy::First),
+        "all" => Ok(BusPolicy::All),
+        _ => Err(format!("invalid bus policy {:?}", value)),
+    }
+}
+
+/// Parse how the Admin API is called: `auto`, `qubesd`, or `qrexec`.
+fn admin_api(value: &str) -> Result<Transport, String> {
+    match value {
+        "auto" => Ok(Transport::Auto),
+        "qubesd" => Ok(Transport::Qubesd),
+        "qrexec" => Ok(Transport::Qrexec),
+        _ => Err(format!("invalid Admin API transport {:?}", value)),
+    }
+}
+
+/// Parse `no` or an absolute path.
+fn absolute_path(value: &str) -> Result<Option<std::path::PathBuf>, String> {
+    match value {
+        "no" => Ok(None),
+        _ if value.starts_with('/') => Ok(Some(value.into())),
+        _ => Err(format!("expected an absolute path, got {:?}", value)),
+    }
+}
+
+/// Parse `no` or a positive count.
+fn threshold(value: &str) -> Result<Option<NonZeroU32>, String> {
+    match value {
+        "no" => Ok(None),
+        _ => value
+            .parse()
+            .map(Some)
+            .map_err(|_| format!("invalid count {:?}", value)),
+    }
+}
+
+/// Parse a number of retries, `no` for none.
+fn retries(value: &str) -> Result<u32, String> {
+    match value {
+        "no" => Ok(0),
+        _ => match value.parse() {
+            Ok(v @ 0..=MAX_DBUS_RETRIES) => Ok(v),
+            _ => Err(format!(
+                "invalid number of retries {:?} (at most {})",
+                value, MAX_DBUS_RETRIES
+            )),
+        },
+    }
+}
+
+/// Parse the daemon whose quirks to work around: `auto`, `none`, or the
+/// name of a daemon with known quirks.
+fn daemon_quirks(value: &str) -> Result<DaemonQuirks, String> {
+    match value {
+        "auto" => Ok(DaemonQuirks::Detect),
+        "none" => Ok(DaemonQuirks::Off),
+        name if crate::quirks::known(name) => Ok(DaemonQuirks::As(name.to_owned())),
+        _ => Err(format!("no known quirks for daemon {:?}", value)),
+    }
+}
+
+/// Parse a route: `display` or `log`.
+fn route(value: &str) -> Result<Route, String> {
+    match value {
+        "display" => Ok(Route::Display),
+        "log" => Ok(Route::Log),
+        _ => Err(format!("invalid route {:?}", value)),
+    }
+}
+
+/// Parse a grace period or timeout: `never`, or a number of milliseconds.
+fn grace(value: &str) -> Result<Option<Duration>, String> {
+    match value {
+        "never" => Ok(None),
+        _ => Ok(Some(Duration::from_millis(
+            milliseconds(value)?.get().into(),
+        ))),
+    }
+}
+
+/// Parse `yes` or `no`.
+fn yes_no(value: &str) -> Result<bool, String> {
+    match value {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(format!("expected yes or no, got {:?}", value)),
+    }
+}
+
+/// Parse a comma-separated list of capability names.
+fn capabilities(value: &str) -> Result<Capabilities, String> {
+    let mut res = Capabilities::empty();
+    for name in value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        res |=
+            Capabilities::from_name(name).ok_or_else(|| format!("unknown capability {:?}", name))?
+    }
+    Ok(res)
+}
+
+/// Parse a positive number of milliseconds, at most [`MAX_EXPIRE_TIMEOUT`].
+fn milliseconds(value: &str) -> Result<NonZeroU32, String> {
+    match value.parse() {
+        Ok(v) if v <= MAX_EXPIRE_TIMEOUT => {
+            NonZeroU32::new(v).ok_or_else(|| format!("invalid number of milliseconds {:?}", value))
+        }
+        _ => Err(format!("invalid number of milliseconds {:?}", value)),
+    }
+}
+
+/// Parse a positive count.
+fn count(value: &str) -> Result<usize, String> {
+    match value.parse() {
+        Ok(v @ 1..) => Ok(v),
+        _ => Err(format!("invalid count {:?}", value)),
+    }
+}
+
+/// Parse the position hint policy: either `drop`, or `clamp` followed by
+/// the screen size, as in `clamp 1920x1080`.
+fn position_hints(value: &str) -> Result<Option<(i32, i32)>, String> {
+    let error = || format!("invalid position hint policy {:?}", value);
+    if value == "drop" {
+        return Ok(None);
+    }
+    let size = value.strip_prefix("clamp").ok_or_else(error)?.trim();
+    let (width, height) = size.split_once('x').ok_or_else(error)?;
+    match (width.parse(), height.parse()) {
+        (Ok(width @ 1..), Ok(height @ 1..)) => Ok(Some((width, height))),
+        _ => Err(error()),
+    }
+}
+
+/// Parse a comma-separated list of category patterns.  A pattern is either
+/// a category, which matches only itself, `class.*`, which matches `class`
+/// and every category in it, or `*`, which matches every category.
+fn category_patterns(value: &str) -> Result<Vec<String>, String> {
+    let mut res = vec![];
+    for pattern in value
+        .split(',')
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+    {
+        let category = pattern.strip_suffix(".*").unwrap_or(pattern);
+        let valid = pattern == "*"
+            || (category.starts_with(|c: char| c.is_ascii_lowercase())
+                && !category.ends_with('.')
+                && category.bytes().all(|c| matches!(c, b'a'..=b'z' | b'.')));
+        if !valid {
+            return Err(format!("invalid category pattern {:?}", pattern));
+        }
+        res.push(pattern.to_owned())
+    }
+    Ok(res)
+}
+
+/// Whether `category` matches `pattern`, as parsed by [`category_patterns`].
+fn category_matches(pattern: &str, category: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some("") => true,
+        Some(class) => category == &class[..class.len() - 1] || category.starts_with(class),
+        None => category == pattern,
+    }
+}
@@ -0,0 +1,356 @@
+//! Control interface served by the dom0 side of the proxy.
+//!
+//! Each qrexec connection is handled by its own server process, so each
+//! process serves one [`Control`] object on the session bus and owns a
+//! well-known name derived from the name of the qube it serves.  A tray
+//! widget can find all of them by listing the names that start with
+//! [`CONTROL_NAME_PREFIX`], and poll the counters of each for badges.
+//!
+//! Whether a qube is muted outlives the process: it is kept in a file per
+//! qube under `$XDG_STATE_HOME`, and read again when the qube reconnects.
+//! Whether the user approved a qube lasts for the session, in a file per
+//! qube under `$XDG_RUNTIME_DIR`.
+
+use crate::ExpireTimeout;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use zbus::dbus_interface;
+use zbus::zvariant::{OwnedValue, Value};
+
+/// Reason in `NotificationClosed` for a notification that expired.
+pub const CLOSED_EXPIRED: u32 = 1;
+/// Reason in `NotificationClosed` for a notification closed by a call to
+/// `CloseNotification`.
+pub const CLOSED_BY_CALL: u32 = 3;
+
+/// Reply to `DumpMaps`: the sizes of the ID maps and their live entries.
+type MapDump = ((u32, u32, u32, u64), Vec<(u32, u32, u64)>);
+
+/// Object path at which [`Control`] is served.
+pub const CONTROL_PATH: &str = "/org/qubes/NotificationProxy1";
+
+/// Prefix of the well-known bus name owned by each server process.
+pub const CONTROL_NAME_PREFIX: &str = "org.qubes.NotificationProxy1.Qube.";
+
+/// Escape a qube name so that it can be used as an element of a D-Bus
+/// name.  Bytes other than ASCII letters and digits are replaced by `_`
+/// followed by two lowercase hexadecimal digits.
+pub fn escape_qube_name(qube_name: &str) -> String {
+    let mut res = String::with_capacity(qube_name.len());
+    for &i in qube_name.as_bytes() {
+        match i {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => res.push(i.into()),
+            _ => res.push_str(&format!("_{:02x}", i)),
+        }
+    }
+    res
+}
+
+/// The correlation ID of call `sequence` from `qube_name`, which identifies
+/// a notification in the logs of both sides and in the control interface.
+/// Qube names cannot contain colons, so this is unambiguous.
+pub fn correlation_id(qube_name: &str, sequence: u64) -> String {
+    format!("{}:{}", qube_name, sequence)
+}
+
+/// The well-known bus name owned by the server process for `qube_name`.
+pub fn control_name(qube_name: &str) -> String {
+    CONTROL_NAME_PREFIX.to_owned() + &escape_qube_name(qube_name)
+}
+
+/// Directory, under `$XDG_STATE_HOME`, with a file for each muted qube.
+const MUTED_DIR: &str = "qubes-notification-proxy/muted";
+
+/// The file whose existence means that `qube_name` is muted, or `None` if
+/// neither `$XDG_STATE_HOME` nor `$HOME` is set.
+pub fn mute_file(qube_name: &str) -> Option<PathBuf> {
+    let state_home = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local/state"),
+    };
+    Some(state_home.join(MUTED_DIR).join(escape_qube_name(qube_name)))
+}
+
+/// Record in `path` whether the qube is muted.
+pub fn store_muted(path: &Path, muted: bool) -> std::io::Result<()> {
+    if muted {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?
+        }
+        std::fs::File::create(path).map(drop)
+    } else {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            res => res,
+        }
+    }
+}
+
+/// Directory, under `$XDG_RUNTIME_DIR`, with a file for each qube the user
+/// approved in the current session.
+const APPROVED_DIR: &str = "qubes-notification-proxy/approved";
+
+/// Directory, under `$XDG_RUNTIME_DIR`, with a lock file for each qube,
+/// locked while the user is asked whether to show it.
+const ASKING_DIR: &str = "qubes-notification-proxy/asking";
+
+/// The file for `qube_name` in `dir` under `$XDG_RUNTIME_DIR`, or `None` if
+/// that is not set.
+fn runtime_file(dir: &str, qube_name: &str) -> Option<PathBuf> {
+    let runtime_dir = std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty())?;
+    Some(
+        PathBuf::from(runtime_dir)
+            .join(dir)
+            .join(escape_qube_name(qube_name)),
+    )
+}
+
+/// The file whose existence means that the user approved `qube_name` in
+/// this session, or `None` if `$XDG_RUNTIME_DIR` is not set.
+pub fn approval_file(qube_name: &str) -> Option<PathBuf> {
+    runtime_file(APPROVED_DIR, qube_name)
+}
+
+/// The file locked while the user is asked whether to show `qube_name`, so
+/// that a qube with several connections is asked about once, or `None` if
+/// `$XDG_RUNTIME_DIR` is not set.
+pub fn asking_file(qube_name: &str) -> Option<PathBuf> {
+    runtime_file(ASKING_DIR, qube_name)
+}
+
+/// What the control interface knows about a live notification.
+#[derive(Debug, Clone)]
+pub struct NotificationInfo {
+    /// ID assigned by the notification daemon.
+    pub host_id: u32,
+    /// Expire timeout passed to the daemon, after policy was applied.
+    pub expire_timeout: ExpireTimeout,
+    /// When the guest ID was mapped to the host ID.
+    pub mapped_at: std::time::Instant,
+    /// Correlation ID of the call that showed the notification, if it was
+    /// shown by a call from the qube.
+    pub call: Option<String>,
+}
+
+/// State shared between the emitter and the control interface.  The
+/// control interface is served from other threads, so this uses a
+/// [`std::sync::Mutex`].  It must never be held across an `.await`.
+#[derive(Debug, Default)]
+pub struct State {
+    /// Live notifications, keyed by guest ID.
+    pub notifications: BTreeMap<u32, NotificationInfo>,
+    /// Sizes of the ID maps.
+    pub maps: crate::maps::Stats,
+    /// Notifications shown since the process started.
+    pub shown: u64,
+    /// Notifications that expired since the last call to
+    /// [`Control::mark_all_read`].  These were never dismissed by the user,
+    /// so they may not have been read.
+    pub unread: u32,
+    /// Whether notifications are logged instead of shown.
+    pub muted: bool,
+    /// Notifications rejected since the process started.
+    pub rejected: u64,
+    /// Why the last notification was rejected, with the correlation ID of
+    /// its call.
+    pub last_error: Option<String>,
+}
+
+impl State {
+    /// Record that the notification with guest ID `guest_id` was shown.
+    pub fn shown(&mut self, guest_id: u32, info: NotificationInfo) {
+        self.notifications.insert(guest_id, info);
+        self.shown = self.shown.saturating_add(1);
+    }
+    /// Record that the notification with guest ID `guest_id` was closed
+    /// for `reason`, as in the `NotificationClosed` signal.
+    pub fn closed(&mut self, guest_id: u32, reason: u32) {
+        if self.notifications.remove(&guest_id).is_some() && reason == CLOSED_EXPIRED {
+            self.unread = self.unread.saturating_add(1)
+        }
+    }
+    /// Record that call `call` was rejected with `error`.
+    pub fn rejected(&mut self, call: &str, error: &str) {
+        self.rejected = self.rejected.saturating_add(1);
+        self.last_error = Some(format!("[{}] {}", call, error));
+    }
+}
+
+pub type SharedState = Arc<Mutex<State>>;
+
+/// Per-qube control object.
+pub struct Control {
+    qube_name: String,
+    protocol_version: (u16, u16),
+    state: SharedState,
+    /// Permits for concurrent calls from the qube, and how many there are.
+    calls: Option<(Arc<Semaphore>, usize)>,
+}
+
+impl Control {
+    pub fn new(qube_name: String, protocol_version: (u16, u16), state: SharedState) -> Self {
+        Self {
+            qube_name,
+            protocol_version,
+            state,
+            calls: None,
+        }
+    }
+    /// Report the use of `calls`, which has `max` permits, in
+    /// [`Control::stats`].
+    pub fn with_calls(mut self, calls: Arc<Semaphore>, max: usize) -> Self {
+        self.calls = Some((calls, max));
+        self
+    }
+    /// Statistics for health checks, as returned by `GetVmStats`:
+    ///
+    /// - `active` (`u`), `shown` (`t`), `unread` (`u`), and `rejected`
+    ///   (`t`): counts of notifications;
+    /// - `active-ids` (`au`): guest IDs of the live notifications;
+    /// - `muted` (`b`);
+    /// - `calls-in-flight` (`u`) and `max-concurrent-calls` (`u`): calls
+    ///   being handled, and how many may be at once;
+    /// - `throttled` (`b`): whether calls from the qube are waiting for
+    ///   others to finish;
+    /// - `last-error` (`s`): why the last notification was rejected, or
+    ///   empty.
+    pub fn stats(&self) -> HashMap<String, OwnedValue> {
+        let state = self.state.lock().expect("poisoned");
+        let (in_flight, max) = match self.calls {
+            Some((ref calls, max)) => (max.saturating_sub(calls.available_permits()), max),
+            None => (0, 0),
+        };
+        let count = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+        let ids: Vec<u32> = state.notifications.keys().copied().collect();
+        let last_error = state.last_error.clone().unwrap_or_default();
+        [
+            ("active", count(state.notifications.len()).into()),
+            ("shown", state.shown.into()),
+            ("unread", state.unread.into()),
+            ("rejected", state.rejected.into()),
+            ("active-ids", Value::from(ids).into()),
+            ("muted", state.muted.into()),
+            ("calls-in-flight", count(in_flight).into()),
+            ("max-concurrent-calls", count(max).into()),
+            ("throttled", (max != 0 && in_flight >= max).into()),
+            ("last-error", Value::from(last_error).into()),
+        ]
+        .into_iter()
+        .map(|(key, value)| (key.to_owned(), value))
+        .collect()
+    }
+}
+
+#[dbus_interface(name = "org.qubes.NotificationProxy1.Control")]
+impl Control {
+    /// Name of the qube whose notifications this process forwards.
+    #[dbus_interface(property)]
+    fn qube_name(&self) -> String {
+        self.qube_name.clone()
+    }
+    /// Negotiated protocol version, as (major, minor).
+    #[dbus_interface(property)]
+    fn protocol_version(&self) -> (u16, u16) {
+        self.protocol_version
+    }
+    /// Live notifications, as (guest ID, host ID, effective expire
+    /// timeout) tuples.
+    fn list_notifications(&self) -> Vec<(u32, u32, i32)> {
+        let state = self.state.lock().expect("poisoned");
+        state
+            .notifications
+            .iter()
+            .map(|(&guest_id, info)| (guest_id, info.host_id, info.expire_timeout.into()))
+            .collect()
+    }
+    /// The ID maps, for bug reports: their sizes, as (live mappings,
+    /// tombstones, free guest IDs, age of the oldest tombstone in
+    /// milliseconds), and the live mappings, as (guest ID, host ID, age in
+    /// milliseconds) tuples.
+    fn dump_maps(&self) -> MapDump {
+        let state = self.state.lock().expect("poisoned");
+        let count = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+        let millis = |age: std::time::Duration| u64::try_from(age.as_millis()).unwrap_or(u64::MAX);
+        let stats = &state.maps;
+        let sizes = (
+            count(stats.live),
+            count(stats.tombstones),
+            count(stats.free_ids),
+            stats.oldest_tombstone.map_or(0, millis),
+        );
+        let entries = state
+            .notifications
+            .iter()
+            .map(|(&guest_id, info)| (guest_id, info.host_id, millis(info.mapped_at.elapsed())))
+            .collect();
+        (sizes, entries)
+    }
+    /// Calls that showed the live notifications, as (guest ID, correlation
+    /// ID) pairs.  Notifications the qube did not ask for, such as the
+    /// announcement of its first connection, are not listed.
+    fn list_calls(&self) -> Vec<(u32, String)> {
+        let state = self.state.lock().expect("poisoned");
+        state
+            .notifications
+            .iter()
+            .filter_map(|(&guest_id, info)| Some((guest_id, info.call.clone()?)))
+            .collect()
+    }
+    /// Number of notifications currently shown.
+    #[dbus_interface(property)]
+    fn active_count(&self) -> u32 {
+        let state = self.state.lock().expect("poisoned");
+        state.notifications.len().try_into().unwrap_or(u32::MAX)
+    }
+    /// Number of notifications that expired without being dismissed since
+    /// the last call to `MarkAllRead`.
+    #[dbus_interface(property)]
+    fn unread_count(&self) -> u32 {
+        self.state.lock().expect("poisoned").unread
+    }
+    /// Number of notifications shown since the process started.
+    #[dbus_interface(property)]
+    fn shown_count(&self) -> u64 {
+        self.state.lock().expect("poisoned").shown
+    }
+    /// Whether notifications from the qube are logged instead of shown.
+    /// This is remembered across connections.
+    #[dbus_interface(property)]
+    fn muted(&self) -> bool {
+        self.state.lock().expect("poisoned").muted
+    }
+    #[dbus_interface(property)]
+    fn set_muted(&mut self, muted: bool) {
+        self.state.lock().expect("poisoned").muted = muted;
+        eprintln!(
+            "{} qube {}",
+            if muted { "Muted" } else { "Unmuted" },
+            self.qube_name
+        );
+        if let Some(path) = mute_file(&self.qube_name) {
+            if let Err(e) = store_muted(&path, muted) {
+                eprintln!("Cannot save mute state to {}: {}", path.display(), e)
+            }
+        }
+    }
+    /// Statistics for the qube called `vm`, as described for
+    /// [`Control::stats`].  Each process only serves one qube, so this fails
+    /// for any other name; it exists so that scripts can check that they
+    /// reached the process they meant.
+    fn get_vm_stats(&self, vm: &str) -> zbus::fdo::Result<HashMap<String, OwnedValue>> {
+        if vm != self.qube_name {
+            return Err(zbus::fdo::Error::InvalidArgs(format!(
+                "This process serves {}, not {}",
+                self.qube_name, vm
+            )));
+        }
+        Ok(self.stats())
+    }
+    /// Reset the unread count, for example when the user opens the tray
+    /// widget.
+    fn mark_all_read(&self) {
+        self.state.lock().expect("poisoned").unread = 0
+    }
+}
@@ -0,0 +1,129 @@
+//! Panic hook for the binaries, so that a crash report from a user says
+//! which qube was involved and how far the connection got.  The report goes
+//! to standard error, which qrexec and systemd send to the journal, and the
+//! process exits afterwards: a panic in a spawned task would otherwise only
+//! end that task and leave a proxy that no longer works.
+//!
+//! The qube name comes from qrexec and the version has been negotiated by
+//! the time it is recorded.  The sequence number is chosen by the guest and
+//! is only an integer, so logging it cannot inject anything.
+
+use std::panic::PanicHookInfo;
+use std::sync::{Mutex, TryLockError};
+
+/// Number of backtrace lines logged.  Each frame takes up to two.
+const MAX_BACKTRACE_LINES: usize = 40;
+
+/// Exit status after a panic, as for an unhandled one.
+const PANIC_EXIT_STATUS: i32 = 101;
+
+/// What a process was doing when it crashed.
+#[derive(Debug, Clone, Default)]
+pub struct Context {
+    /// Name of the qube at the other end.
+    pub qube: Option<String>,
+    /// Negotiated protocol version, as (major, minor).
+    pub version: Option<(u16, u16)>,
+    /// Sequence number of the last call handled, as sent by the guest.
+    pub sequence: Option<u64>,
+}
+
+static CONTEXT: Mutex<Context> = Mutex::new(Context {
+    qube: None,
+    version: None,
+    sequence: None,
+});
+
+fn update(f: impl FnOnce(&mut Context)) {
+    f(&mut CONTEXT.lock().unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Record the name of the qube at the other end.
+pub fn set_qube(qube: &str) {
+    update(|context| context.qube = Some(qube.to_owned()))
+}
+
+/// Record the negotiated protocol version.
+pub fn set_version(major: u16, minor: u16) {
+    update(|context| context.version = Some((major, minor)))
+}
+
+/// Record the sequence number of the call being handled.
+pub fn set_sequence(sequence: u64) {
+    update(|context| context.sequence = Some(sequence))
+}
+
+/// The report for a panic with `message` at `location`.
+pub(crate) fn report(
+    program: &str,
+    context: &Context,
+    message: &str,
+    location: &str,
+    backtrace: &str,
+) -> String {
+    let mut report = format!("{} crashed at {}: {}\n", program, location, message);
+    if let Some(ref qube) = context.qube {
+        report += &format!("  qube: {}\n", qube);
+    }
+    if let Some((major, minor)) = context.version {
+        report += &format!("  protocol version: {}.{}\n", major, minor);
+    }
+    if let Some(sequence) = context.sequence {
+        report += &format!("  last sequence number: {}\n", sequence);
+    }
+    let lines: Vec<&str> = backtrace.lines().collect();
+    // The first frames are this hook and the panic machinery.
+    let is_frame = |line: &str| line.trim_start().starts_with(|c: char| c.is_ascii_digit());
+    let in_panic = |line: &str| line.contains("core::panicking::");
+    let start = match lines
+        .iter()
+        .position(|line| is_frame(line) && in_panic(line))
+    {
+        Some(first) => (first + 1..lines.len())
+            .find(|&i| is_frame(lines[i]) && !in_panic(lines[i]))
+            .unwrap_or(lines.len()),
+        None => 0,
+    };
+    let lines = &lines[start..];
+    report += "  backtrace:\n";
+    for line in lines.iter().take(MAX_BACKTRACE_LINES) {
+        report += &format!("  {}\n", line);
+    }
+    if lines.len() > MAX_BACKTRACE_LINES {
+        report += &format!("  ({} more lines)\n", lines.len() - MAX_BACKTRACE_LINES);
+    }
+    report
+}
+
+fn hook(program: &str, info: &PanicHookInfo<'_>) {
+    let payload = info.payload();
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message,
+            None => "unknown panic",
+        },
+    };
+    let location = match info.location() {
+        Some(location) => location.to_string(),
+        None => "unknown location".to_owned(),
+    };
+    // The panic may have happened while the context was being updated.
+    let context = match CONTEXT.try_lock() {
+        Ok(context) => context.clone(),
+        Err(TryLockError::Poisoned(e)) => e.into_inner().clone(),
+        Err(TryLockError::WouldBlock) => Context::default(),
+    };
+    let backtrace = std::backtrace::Backtrace::force_capture().to_string();
+    eprint!(
+        "{}",
+        report(program, &context, message, &location, &backtrace)
+    );
+    std::process::exit(PANIC_EXIT_STATUS)
+}
+
+/// Replace the default panic hook with one that logs a report for
+/// `program`, then exits.
+pub fn install(program: &'static str) {
+    std::panic::set_hook(Box::new(move |info| hook(program, info)))
+}
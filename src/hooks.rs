@@ -0,0 +1,114 @@
+//! Commands run in dom0 when something noteworthy happens with the
+//! notifications of a qube, so that administrators can connect the proxy to
+//! their own alerting without patching it.
+//!
+//! The command is run with an empty environment except for the variables
+//! set by [`Hooks::fire`], which are derived from trusted data only: the
+//! qube name comes from qrexec, and the rest are fixed strings and counts.
+//! Nothing the qube sent, such as the summary, is passed on.
+
+use crate::config::Config;
+use std::collections::VecDeque;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Window over which rejections are counted.
+const REJECTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// Something a hook is run for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The first critical notification from the qube since it connected.
+    FirstCritical,
+    /// This many notifications were rejected within a minute.
+    Rejections(u32),
+}
+
+impl Event {
+    /// Value of `QUBES_NOTIFICATION_EVENT` for this event.
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::FirstCritical => "first-critical",
+            Event::Rejections(_) => "rejections",
+        }
+    }
+}
+
+/// Tracks events for one qube and runs the hook for them.
+#[derive(Debug)]
+pub struct Hooks {
+    command: Option<PathBuf>,
+    qube_name: String,
+    rejections_per_minute: Option<NonZeroU32>,
+    critical_seen: bool,
+    /// When recent rejections happened, oldest first.
+    rejections: VecDeque<Instant>,
+}
+
+impl Hooks {
+    pub fn new(qube_name: String, config: &Config) -> Self {
+        Self {
+            command: config.hook.clone(),
+            qube_name,
+            rejections_per_minute: config.hook_rejections_per_minute,
+            critical_seen: false,
+            rejections: VecDeque::new(),
+        }
+    }
+    /// Record that a critical notification was shown.
+    pub fn critical(&mut self) -> Option<Event> {
+        if std::mem::replace(&mut self.critical_seen, true) {
+            return None;
+        }
+        Some(Event::FirstCritical)
+    }
+    /// Record that a notification was rejected at `now`.  Once the
+    /// threshold is reached, counting starts over, so that a qube that keeps
+    /// sending bad notifications does not run the hook for each of them.
+    pub fn rejected(&mut self, now: Instant) -> Option<Event> {
+        let threshold = self.rejections_per_minute?;
+        while let Some(&oldest) = self.rejections.front() {
+            if now.duration_since(oldest) < REJECTION_WINDOW {
+                break;
+            }
+            self.rejections.pop_front();
+        }
+        self.rejections.push_back(now);
+        if self.rejections.len() < threshold.get() as usize {
+            return None;
+        }
+        self.rejections.clear();
+        Some(Event::Rejections(threshold.get()))
+    }
+    /// Run the hook for `event`, if one is configured.  The hook runs in
+    /// the background and its exit status is only logged.
+    pub fn fire(&self, event: Event) {
+        let Some(ref command) = self.command else {
+            return;
+        };
+        let mut child = std::process::Command::new(command);
+        child
+            .env_clear()
+            .env("PATH", "/usr/local/bin:/usr/bin:/bin")
+            .env("QUBES_NOTIFICATION_EVENT", event.name())
+            .env("QUBES_NOTIFICATION_QUBE", &self.qube_name)
+            .stdin(std::process::Stdio::null());
+        if let Event::Rejections(count) = event {
+            child.env("QUBES_NOTIFICATION_COUNT", count.to_string());
+        }
+        let mut child = match child.spawn() {
+            Ok(child) => child,
+            Err(e) => {
+                eprintln!("Cannot run hook {}: {}", command.display(), e);
+                return;
+            }
+        };
+        let command = command.clone();
+        std::thread::spawn(move || match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => eprintln!("Hook {} failed: {}", command.display(), status),
+            Err(e) => eprintln!("Cannot wait for hook {}: {}", command.display(), e),
+        });
+    }
+}
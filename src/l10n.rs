@@ -0,0 +1,161 @@
+//! Localization of strings rendered by the proxy itself.
+//!
+//! Translations use a small subset of the Fluent syntax: one
+//! `id = value` message per line, `#` comments, and `{ $name }`
+//! placeables.  As in Fluent, whitespace around values is not significant;
+//! use a string literal such as `{ " " }` for significant whitespace.
+//! Catalogs live in [`CATALOG_DIR`] and are named after the
+//! language, for example `de.ftl` or `pt_BR.ftl`.  Messages missing from a
+//! catalog fall back to the built-in English text.
+
+use std::collections::HashMap;
+
+/// Directory containing the translation catalogs.
+pub const CATALOG_DIR: &str = "/usr/share/qubes-notification-proxy/locale";
+
+/// Built-in English messages.
+const ENGLISH: &[(&str, &str)] = &[
+    ("prefix", "{ $qube }: "),
+    ("app-name", "Qubes VM { $qube }"),
+    ("open-link", "Open link"),
+    ("open-link-numbered", "Open link { $number }"),
+    ("truncated", "… [truncated by Qubes]"),
+    ("confusable", "[⚠ mixed scripts]"),
+    ("received-at", "({ $time })"),
+    ("announce", "Notifications from { $qube } are now shown"),
+    ("approve", "Show notifications from { $qube }?"),
+    (
+        "approve-body",
+        "{ $qube } has not shown notifications in this session yet.",
+    ),
+    ("approve-allow", "Show"),
+    ("approve-deny", "Mute"),
+];
+
+/// A set of translated messages.
+#[derive(Debug, Default, Clone)]
+pub struct Catalog {
+    messages: HashMap<String, String>,
+}
+
+/// Check that `language` is something like `de` or `pt_BR`.  Anything else
+/// is rejected, so that a language cannot be used to escape
+/// [`CATALOG_DIR`].
+fn is_valid_language(language: &str) -> bool {
+    !language.is_empty()
+        && language.len() <= 32
+        && language
+            .bytes()
+            .all(|c| matches!(c, b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'-'))
+}
+
+impl Catalog {
+    /// Parse a catalog.  Errors include the (1-based) line number.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut messages = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once('=') {
+                Some((id, value)) if !id.trim().is_empty() => {
+                    messages.insert(id.trim().to_owned(), value.trim().to_owned());
+                }
+                _ => return Err(format!("line {}: expected `id = value`", number + 1)),
+            }
+        }
+        Ok(Self { messages })
+    }
+
+    /// Load the catalog for `locale`, which is a POSIX locale name such as
+    /// `de_DE.UTF-8`.  `de_DE` is tried first, then `de`.  If neither
+    /// exists, or the locale is `C` or `POSIX`, the empty catalog is
+    /// returned and the built-in English text is used.
+    pub fn load(locale: &str) -> Self {
+        let language = locale
+            .split(['.', '@'])
+            .next()
+            .expect("split always yields at least one item");
+        if matches!(language, "" | "C" | "POSIX") {
+            return Self::default();
+        }
+        let mut candidates = vec![language];
+        if let Some((short, _)) = language.split_once('_') {
+            candidates.push(short)
+        }
+        for candidate in candidates {
+            if !is_valid_language(candidate) {
+                eprintln!("Ignoring invalid locale {:?}", locale);
+                break;
+            }
+            let path = format!("{}/{}.ftl", CATALOG_DIR, candidate);
+            match std::fs::read_to_string(&path) {
+                Ok(text) => match Self::parse(&text) {
+                    Ok(catalog) => return catalog,
+                    Err(e) => eprintln!("Ignoring malformed catalog {}: {}", path, e),
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => eprintln!("Cannot read catalog {}: {}", path, e),
+            }
+        }
+        Self::default()
+    }
+
+    /// The locale from the environment, following the usual precedence of
+    /// `LC_ALL`, `LC_MESSAGES`, and `LANG`.
+    pub fn environment_locale() -> Option<String> {
+        ["LC_ALL", "LC_MESSAGES", "LANG"]
+            .iter()
+            .filter_map(|var| std::env::var(var).ok())
+            .find(|value| !value.is_empty())
+    }
+
+    /// Format the message `id`, substituting `{ $name }` placeables from
+    /// `args` and `{ "text" }` placeables with their text.  Unknown
+    /// placeables are left as they are.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not a built-in message.
+    pub fn format(&self, id: &str, args: &[(&str, &str)]) -> String {
+        let template = match self.messages.get(id) {
+            Some(template) => &**template,
+            None => {
+                ENGLISH
+                    .iter()
+                    .find(|(english_id, _)| *english_id == id)
+                    .unwrap_or_else(|| panic!("Unknown message {:?}", id))
+                    .1
+            }
+        };
+        let mut res = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            res.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = match rest.find('}') {
+                Some(end) => end,
+                None => break,
+            };
+            let name = rest[1..end].trim();
+            if let Some(literal) = name
+                .strip_prefix('"')
+                .and_then(|name| name.strip_suffix('"'))
+            {
+                res.push_str(literal)
+            } else {
+                match args
+                    .iter()
+                    .find(|(arg, _)| name.strip_prefix('$') == Some(*arg))
+                {
+                    Some((_, value)) => res.push_str(value),
+                    None => res.push_str(&rest[..=end]),
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        res.push_str(rest);
+        res
+    }
+}
@@ -1,9 +1,55 @@
+//! Notification proxy for Qubes OS.
+//!
+//! The guest side of the proxy implements `org.freedesktop.Notifications`
+//! and forwards every call over qrexec to dom0.  The dom0 side sanitizes the
+//! notifications and passes them to the real notification daemon using a
+//! [`NotificationEmitter`].
+//!
+//! The dom0 side can also run in a GUI domain, such as sys-gui, next to the
+//! notification daemon there.  It then looks up qube metadata, such as the
+//! label for `image-frame = label`, through the Admin API over qrexec
+//! instead of qubesd, and does without it if dom0 does not answer.
+//!
+//! # Supported API
+//!
+//! The following items are the supported public API of this crate and
+//! follow semantic versioning:
+//!
+//! - the emitter: [`NotificationEmitter`] and [`Capabilities`];
+//! - the protocol types: [`Message`], [`Notification`],
+//!   [`NotificationBuilder`], [`ReplyMessage`], [`Urgency`], [`Suppression`],
+//!   [`ImageParameters`], the version constants,
+//!   [`merge_versions`]/[`split_version`], [`Features`] and
+//!   [`NegotiatedFeatures`], and the size limits;
+//! - the sanitizers: [`sanitize_str`] and [`validate_qube_name`];
+//! - the transport: [`MessageWriter`] and the [`codec`] module.
+//!
+//! Everything else, including the `actor`, `admin`, `config`, `control`,
+//! `crash`, `l10n`, `resume`, and `schema` modules, exists to support the
+//! binaries in this package and may change in any release.  So may the
+//! `maps` module, which the `bench` feature makes public for the
+//! benchmarks.  See the `examples` directory for how to embed the emitter
+//! and how to speak the protocol.
+//!
+//! # Hints
+//!
+//! Besides the hints of the specification, the emitter passes these to the
+//! daemon, which can ignore them:
+//!
+//! - `x-qubes-prefix-length` (`u32`): how many characters at the start of
+//!   the summary come from dom0 rather than from the qube: the urgency
+//!   marker, if any, and the prefix naming the qube.  A daemon can style
+//!   them apart from the rest of the summary.
+//! - `desktop-entry` and `x-kde-origin-name`: derived from the name of the
+//!   qube, so that daemons such as Plasma group notifications by qube and
+//!   say where they came from.  The guest cannot set either.
 use bitflags::bitflags;
 use futures_util::TryFutureExt;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::num::NonZeroU32;
 use std::rc::Rc;
-use tokio::io::AsyncWriteExt as _;
+use std::time::SystemTime;
 use tokio::sync::Mutex;
 use zbus::{
     dbus_proxy,
@@ -12,8 +58,44 @@ use zbus::{
     zvariant::Value,
     Connection,
 };
+#[doc(hidden)]
+pub mod actor;
+#[doc(hidden)]
+pub mod admin;
+pub mod codec;
+#[doc(hidden)]
+pub mod config;
+#[doc(hidden)]
+pub mod control;
+#[doc(hidden)]
+pub mod crash;
+mod hooks;
+#[doc(hidden)]
+pub mod l10n;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod maps;
+#[cfg(not(feature = "bench"))]
 mod maps;
+mod protocol;
+mod quirks;
+mod relay;
+#[doc(hidden)]
+pub mod resume;
+#[doc(hidden)]
+pub mod schema;
+mod signals;
+#[cfg(any(test, not(feature = "qubes-pure")))]
+mod unicode;
 use maps::{GuestId, HostId, Maps};
+pub use protocol::{features, merge_versions, split_version, FIRST_PROVISIONAL_ID};
+pub use protocol::{Features, NegotiatedFeatures};
+pub use protocol::{BATCH_VERSION, CHECKSUM_VERSION, HISTORY_VERSION, LOCALE_VERSION};
+pub use protocol::{CBOR_VERSION, DAEMON_CAPABILITIES_VERSION, FEATURE_MASK_VERSION};
+pub use protocol::{CLOSE_VERSION, EXPIRE_TIMEOUT_VERSION, POSITION_VERSION, PROBE_VERSION};
+pub use protocol::{DRAIN_VERSION, PING_VERSION, RESHOWN_VERSION};
+pub use protocol::{MAJOR_VERSION, MINOR_VERSION, PROGRESS_VERSION, PROVISIONAL_IDS_VERSION};
+pub use protocol::{REPLY_VERSION, SANITIZED_VERSION, SUPPRESSED_VERSION, TAG_VERSION};
 #[dbus_proxy(
     interface = "org.freedesktop.Notifications",
     default_service = "org.freedesktop.Notifications",
@@ -43,8 +125,75 @@ pub trait Notifications {
     fn notification_replied(&self, id: u32, text: String) -> Result<()>;
 }
 
+/// Produce the introspection XML for an object implementing `interfaces`.
+///
+/// This is the same document a bus peer would get by calling
+/// `org.freedesktop.DBus.Introspectable.Introspect`, minus the standard
+/// interfaces that zbus implements for every object.
+#[doc(hidden)]
+pub fn introspection_xml(interfaces: &[&dyn zbus::Interface]) -> String {
+    let mut xml = String::from(
+        "<!DOCTYPE node PUBLIC \"-//freedesktop//DTD D-BUS Object Introspection 1.0//EN\"\n \
+         \"http://www.freedesktop.org/standards/dbus/1.0/introspect.dtd\">\n<node>\n",
+    );
+    for interface in interfaces {
+        interface.introspect_to_writer(&mut xml, 2);
+    }
+    xml.push_str("</node>\n");
+    xml
+}
+
 pub const MAX_MESSAGE_SIZE: u32 = 0x1_000_000; // max size in bytes
 
+/// Longest tag accepted in [`Extension::Tag`].
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// Whether `tag` is a valid [`Extension::Tag`]: 1 to [`MAX_TAG_LENGTH`]
+/// ASCII letters, digits, `-`, `.`, `_`, or `:`.
+pub fn is_valid_tag(tag: &str) -> bool {
+    (1..=MAX_TAG_LENGTH).contains(&tag.len())
+        && tag
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b':'))
+}
+
+/// Whether `tag` is a valid [`Extension::Locale`]: a BCP 47 language tag
+/// with a language, an optional script, and an optional region, and
+/// nothing else, in canonical case.  For example `ja`, `pt-BR`, or
+/// `zh-Hant-TW`.
+pub fn is_valid_language_tag(tag: &str) -> bool {
+    let mut parts = tag.split('-').peekable();
+    let language = parts.next().unwrap_or_default();
+    if !(2..=3).contains(&language.len()) || !language.bytes().all(|b| b.is_ascii_lowercase()) {
+        return false;
+    }
+    if let Some(script) = parts.next_if(|part| part.len() == 4) {
+        let (first, rest) = script.split_at(1);
+        if !first.bytes().all(|b| b.is_ascii_uppercase())
+            || !rest.bytes().all(|b| b.is_ascii_lowercase())
+        {
+            return false;
+        }
+    }
+    if let Some(region) = parts.next() {
+        let letters = region.len() == 2 && region.bytes().all(|b| b.is_ascii_uppercase());
+        let digits = region.len() == 3 && region.bytes().all(|b| b.is_ascii_digit());
+        if !letters && !digits {
+            return false;
+        }
+    }
+    parts.next().is_none()
+}
+
+/// The language tag for the POSIX locale `locale`: `pt-BR` for
+/// `pt_BR.UTF-8`.  `None` for `C` and `POSIX`, and for anything else
+/// [`is_valid_language_tag`] refuses.  Modifiers are dropped.
+pub fn language_tag_from_locale(locale: &str) -> Option<String> {
+    let name = locale.split(['.', '@']).next().unwrap_or_default();
+    let tag = name.replace('_', "-");
+    is_valid_language_tag(&tag).then_some(tag)
+}
+
 fn is_valid_action_name(action: &[u8]) -> bool {
     // 255 is arbitrary but should be more than enough
     if action.is_empty() {
@@ -66,7 +215,7 @@ fn is_valid_action_name(action: &[u8]) -> bool {
     return true;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 /// Messages sent by a notification server
 pub enum ReplyMessage {
     /// Notification successfully sent.  Since version 0
@@ -106,31 +255,291 @@ pub enum ReplyMessage {
     },
     /// Server restarted.
     ServerRestart,
+    /// The user asked to open a link found in a notification.  The guest
+    /// is responsible for opening it, so that the link is never opened in
+    /// dom0.  Since version 1.
+    OpenUrl {
+        /// ID of the notification containing the link.
+        id: u32,
+        /// The link to open.  This is the exact text the guest sent.
+        url: String,
+    },
+    /// The expire timeout used for a notification, after dom0 policy was
+    /// applied.  Sent just before the [`ReplyMessage::Id`] it applies to.
+    /// Since version 3.
+    ExpireTimeout {
+        /// ID of the notification.
+        id: u32,
+        /// The expire timeout.
+        expire_timeout: ExpireTimeout,
+    },
+    /// What the sanitizer did to a notification.  Sent before the
+    /// [`ReplyMessage::ExpireTimeout`] and [`ReplyMessage::Id`] it applies
+    /// to.  Since version 6.
+    Sanitized {
+        /// ID of the notification.
+        id: u32,
+        /// Whether the summary or body was cut short because it had too
+        /// many lines.  The text then ends with a marker saying so.
+        truncated: bool,
+    },
+    /// The notification was accepted but not shown.  Sent after the
+    /// [`ReplyMessage::Sanitized`] and before the [`ReplyMessage::Id`] it
+    /// applies to, and only if dom0 is configured to report this.  Since
+    /// version 8.
+    Suppressed {
+        /// ID of the notification.
+        id: u32,
+        /// Why it was not shown.
+        reason: Suppression,
+    },
+    /// Answer to [`Request::Probe`]: the capabilities dom0 now uses for
+    /// the guest's notifications, after configuration overrides.  Since
+    /// version 10.
+    Capabilities {
+        /// Names of the capabilities, as in `GetCapabilities`.
+        capabilities: Vec<String>,
+        /// The sequence number of this method call
+        sequence: u64,
+    },
+    /// The user replied to a notification with an [`INLINE_REPLY_ACTION`],
+    /// using the KDE `NotificationReplied` extension.  Since version 11.
+    Replied {
+        /// ID of the notification replied to.
+        id: u32,
+        /// What the user typed, at most [`MAX_REPLY_SIZE`] bytes.
+        text: String,
+    },
+    /// The capabilities dom0 uses for the guest's notifications, after
+    /// configuration overrides.  Sent right after version negotiation, and
+    /// again whenever another daemon takes over, so that the guest can
+    /// advertise what is actually supported.  Since version 12.
+    DaemonCapabilities {
+        /// The bits of [`Capabilities`].  Unknown bits are ignored.
+        capabilities: u32,
+    },
+    /// Answer to [`Request::Ping`].  Queued behind everything else dom0
+    /// has to send, so it also shows that replies get through.  Since
+    /// version 17.
+    Pong {
+        /// The sequence number of the ping.
+        sequence: u64,
+    },
+    /// Several messages, in the order they would have been sent one by one.
+    /// While a [`Request::Batch`] is being handled, everything dom0 has to
+    /// send is held back, and sent as one batch each time a batch request
+    /// completes.  Batches are never nested.  Since version 18.
+    Batch(Vec<ReplyMessage>),
+    /// Answer to [`Request::History`]: what dom0 remembers happening to the
+    /// notifications asked about.  Since version 19.
+    History {
+        /// The outcomes, oldest first.
+        entries: Vec<HistoryEntry>,
+        /// The sequence number of this method call
+        sequence: u64,
+    },
+    /// dom0 is shutting down.  The guest must not send any more
+    /// notifications, and should send a [`Request::Drain`] right away.
+    /// Since version 20.
+    Draining,
+    /// Answer to [`Request::Drain`], sent after the replies to every call
+    /// made before it.  Nothing follows, and dom0 closes the connection.
+    /// Since version 20.
+    Drained {
+        /// The sequence number of the drain request.
+        sequence: u64,
+    },
+    /// The daemon that showed a notification went away, and the one that
+    /// took over showed it again, with hints the old one lacked the
+    /// capabilities for.  Its old ID is no longer valid.  Since version 21.
+    Reshown {
+        /// The old ID of the notification.
+        id: u32,
+        /// The ID it was shown again with, which may be the old one.
+        new_id: u32,
+    },
+}
+
+/// Why dom0 did not show a notification it accepted.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Suppression {
+    /// The qube is muted.
+    Muted,
+    /// The notification arrived during quiet hours.
+    QuietHours,
+    /// Notifications from the qube are only logged.
+    LogOnly,
+}
+
+impl Suppression {
+    /// Name of the reason, for the guest's D-Bus interface.
+    pub fn name(self) -> &'static str {
+        match self {
+            Suppression::Muted => "muted",
+            Suppression::QuietHours => "quiet-hours",
+            Suppression::LogOnly => "log-only",
+        }
+    }
+}
+
+/// Something that happened to a notification of the guest, as reported in
+/// [`ReplyMessage::History`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    /// ID of the notification.
+    pub id: u32,
+    pub outcome: Outcome,
+}
+
+/// What happened to a notification.  Only metadata: never any text the
+/// user typed or the notification showed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    /// The notification was closed, as reported with
+    /// [`ReplyMessage::Dismissed`].  Since version 19.
+    Dismissed {
+        /// Reason the notification was closed.
+        reason: u32,
+    },
+    /// An action was invoked, including one that opened a link.  Since
+    /// version 19.
+    ActionInvoked {
+        /// The key of the action, as the guest sent it.
+        action: String,
+    },
+    /// The user replied inline.  The text is not kept.  Since version 19.
+    Replied,
+}
+
+impl Outcome {
+    /// Name of the outcome, for the guest's D-Bus interface.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Dismissed { .. } => "dismissed",
+            Outcome::ActionInvoked { .. } => "action-invoked",
+            Outcome::Replied => "replied",
+        }
+    }
+}
+
+/// How many outcomes dom0 remembers for [`Request::History`].
+pub const MAX_HISTORY: usize = 256;
+
+/// The longest expire timeout passed to the daemon: one day.  Longer
+/// timeouts are clamped to this.
+pub const MAX_EXPIRE_TIMEOUT: u32 = 24 * 60 * 60 * 1000;
+
+/// An expire timeout.  On the wire this is the `i32` of the notification
+/// specification, so that the message formats stay the same: -1 is
+/// [`ExpireTimeout::Default`], 0 is [`ExpireTimeout::Never`], and positive
+/// values are milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "i32", into = "i32")]
+pub enum ExpireTimeout {
+    /// Let the daemon decide.
+    Default,
+    /// Never expire.
+    Never,
+    /// Expire after this many milliseconds.
+    Millis(Millis),
+}
+
+/// The milliseconds of an [`ExpireTimeout::Millis`], between 1 and
+/// [`MAX_EXPIRE_TIMEOUT`] so that they are positive on the wire.  Only
+/// [`ExpireTimeout::millis`] makes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Millis(NonZeroU32);
+
+impl Millis {
+    /// The number of milliseconds.
+    pub fn get(self) -> u32 {
+        self.0.get()
+    }
+}
+
+impl ExpireTimeout {
+    /// Convert from the representation in the specification.  Values below
+    /// -1 are invalid, and values above [`MAX_EXPIRE_TIMEOUT`] are clamped.
+    pub fn from_wire(value: i32) -> Option<Self> {
+        Some(match value {
+            ..=-2 => return None,
+            -1 => ExpireTimeout::Default,
+            0 => ExpireTimeout::Never,
+            millis => ExpireTimeout::millis(millis.unsigned_abs()),
+        })
+    }
+    /// A timeout of `millis` milliseconds, clamped to between 1 and
+    /// [`MAX_EXPIRE_TIMEOUT`].
+    pub fn millis(millis: u32) -> Self {
+        ExpireTimeout::Millis(Millis(
+            NonZeroU32::new(millis.clamp(1, MAX_EXPIRE_TIMEOUT)).expect("clamped to at least 1"),
+        ))
+    }
+    /// Convert to the representation in the specification.
+    pub fn to_wire(self) -> i32 {
+        match self {
+            ExpireTimeout::Default => -1,
+            ExpireTimeout::Never => 0,
+            // MAX_EXPIRE_TIMEOUT fits in an i32
+            ExpireTimeout::Millis(millis) => millis.get() as i32,
+        }
+    }
+}
+
+impl TryFrom<i32> for ExpireTimeout {
+    type Error = String;
+    fn try_from(value: i32) -> Result<Self, String> {
+        ExpireTimeout::from_wire(value).ok_or_else(|| format!("invalid expire timeout {}", value))
+    }
+}
+
+impl From<ExpireTimeout> for i32 {
+    fn from(expire_timeout: ExpireTimeout) -> i32 {
+        expire_timeout.to_wire()
+    }
+}
+
+impl ReplyMessage {
+    /// The reply to call `sequence`, which tried to close `id` but there is
+    /// no notification with that ID.
+    pub fn invalid_id(id: u32, sequence: u64) -> Self {
+        ReplyMessage::DBusError {
+            name: INVALID_ID_ERROR.to_owned(),
+            message: Some(format!("No notification with ID {}", id)),
+            sequence,
+        }
+    }
 }
 
 #[repr(u8)]
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Urgency {
     Low = 0,
     Normal = 1,
     Critical = 2,
 }
 
+impl TryFrom<Value<'_>> for Urgency {
+    type Error = zbus::zvariant::Error;
+    /// Parse the `urgency` hint, which must be a byte from 0 to 2.
+    fn try_from(value: Value<'_>) -> Result<Self, Self::Error> {
+        match value {
+            Value::U8(0) => Ok(Urgency::Low),
+            Value::U8(1) => Ok(Urgency::Normal),
+            Value::U8(2) => Ok(Urgency::Critical),
+            Value::U8(n) => Err(zbus::zvariant::Error::Message(format!(
+                "unknown urgency {}",
+                n
+            ))),
+            _ => Err(zbus::zvariant::Error::IncorrectType),
+        }
+    }
+}
+
 pub const MAX_SIZE: usize = 1usize << 21; // This is 2MiB, more than enough
 pub const MAX_WIDTH: i32 = 255;
 pub const MAX_HEIGHT: i32 = 255;
 
-pub const MAJOR_VERSION: u16 = 1;
-pub const MINOR_VERSION: u16 = 0;
-
-pub const fn merge_versions(major: u16, minor: u16) -> u32 {
-    (major as u32) << 16 | (minor as u32)
-}
-
-pub const fn split_version(combined: u32) -> (u16, u16) {
-    ((combined >> 16) as _, combined as _)
-}
-
 #[derive(Serialize, Deserialize, Debug, Value, Type, Clone)]
 /// Image parameters
 pub struct ImageParameters {
@@ -150,10 +559,203 @@ pub struct ImageParameters {
     pub untrusted_data: Vec<u8>,
 }
 
+/// Maximum length of a qube name.  This is the same limit qubesd enforces.
+pub const MAX_QUBE_NAME_LENGTH: usize = 31;
+
+/// Validate the name of a qube, as provided by qrexec.
+///
+/// The name ends up in every notification shown by the daemon, so it must
+/// be something qubesd would have accepted: an ASCII letter followed by
+/// ASCII letters, digits, `_`, `.`, or `-`, at most 31 bytes long.  A single
+/// trailing newline (as left by some shell wrappers) is removed.
+pub fn validate_qube_name(untrusted_name: &str) -> Result<String, &'static str> {
+    let untrusted_name = untrusted_name
+        .strip_suffix('\n')
+        .unwrap_or(untrusted_name)
+        .as_bytes();
+    if untrusted_name.is_empty() {
+        return Err("Empty qube name");
+    }
+    if untrusted_name.len() > MAX_QUBE_NAME_LENGTH {
+        return Err("Qube name too long");
+    }
+    match untrusted_name[0] {
+        b'a'..=b'z' | b'A'..=b'Z' => {}
+        _ => return Err("Qube name does not start with an ASCII letter"),
+    }
+    for i in &untrusted_name[1..] {
+        match i {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b'_' | b'.' | b'-' => {}
+            _ => return Err("Forbidden character in qube name"),
+        }
+    }
+    // sanitize end
+    Ok(String::from_utf8(untrusted_name.to_owned()).expect("checked to be ASCII above"))
+}
+
+/// Prefix of the action names used to open links in the guest.  Guests may
+/// not use action names starting with this prefix.
+const OPEN_URL_ACTION_PREFIX: &str = "qubes-open-url-";
+
+/// Action that makes daemons with [`Capabilities::INLINE_REPLY`] show a
+/// field for replying to the notification.
+pub const INLINE_REPLY_ACTION: &str = "inline-reply";
+
+/// Action invoked by clicking the notification itself instead of a button.
+/// Reserved by the specification.
+pub const DEFAULT_ACTION: &str = "default";
+
+/// Action that opens the notification settings of the application.
+/// Reserved by the draft of the next version of the specification.
+pub const SETTINGS_ACTION: &str = "settings";
+
+/// Whether `key` is one of the action keys the specification reserves.
+/// These are passed on unchanged like any other action, so that the daemon
+/// gives them their special meaning.  Their labels may be empty, and are
+/// never taken to be icon names: the proxy does not pass on the
+/// `action-icons` hint.
+pub fn is_reserved_action(key: &str) -> bool {
+    key == DEFAULT_ACTION || key == SETTINGS_ACTION
+}
+
+/// Check the keys of `untrusted_actions`, alternating keys and labels as
+/// `Notify` takes them, and sanitize the labels, cutting them short after
+/// `max_label` characters.  Keys are passed on unchanged, reserved ones
+/// included, but the prefix of the "Open link" actions is refused.
+fn sanitize_actions(untrusted_actions: &[String], max_label: usize) -> zbus::Result<Vec<String>> {
+    let mut actions = Vec::with_capacity(untrusted_actions.len());
+    for (count, s) in untrusted_actions.iter().enumerate() {
+        if count & 1 == 0 {
+            if !is_valid_action_name(s.as_bytes()) || s.starts_with(OPEN_URL_ACTION_PREFIX) {
+                return Err(zbus::Error::Failure("Invalid action name".to_owned()));
+            }
+            // Sanitized by is_valid_action_name()
+            actions.push(s.to_owned())
+        } else {
+            actions.push(limit_label(&sanitize_str(s), max_label))
+        }
+    }
+    Ok(actions)
+}
+
+/// Longest [`ReplyMessage::Replied`] text passed on to the guest, in bytes.
+pub const MAX_REPLY_SIZE: usize = 1 << 16;
+
+/// Maximum number of links for which an "Open link" action is added.
+const MAX_URLS: usize = 3;
+
+/// Maximum length of a link, in bytes.
+const MAX_URL_LENGTH: usize = 2048;
+
+/// Find `http` and `https` links in `text`.  Only links consisting entirely
+/// of printable ASCII are recognized.  Trailing punctuation is assumed to
+/// belong to the surrounding text.
+pub(crate) fn find_urls(text: &str) -> Vec<&str> {
+    let mut res = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("http") {
+        let candidate = &rest[start..];
+        let scheme_len = if candidate.starts_with("https://") {
+            8
+        } else if candidate.starts_with("http://") {
+            7
+        } else {
+            rest = &candidate[4..];
+            continue;
+        };
+        let len = candidate
+            .bytes()
+            .position(|c| !matches!(c, b'!'..=b'~') || matches!(c, b'<' | b'>' | b'"' | b'\''))
+            .unwrap_or(candidate.len());
+        let url = candidate[..len].trim_end_matches(['.', ',', ';', ':', '!', '?', ')']);
+        if url.len() > scheme_len && url.len() <= MAX_URL_LENGTH {
+            res.push(url)
+        }
+        rest = &candidate[len..];
+    }
+    res
+}
+
+/// Scripts whose letters are easily confused with each other.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Script {
+    Latin,
+    FullwidthLatin,
+    Greek,
+    Cyrillic,
+}
+
+/// The script of `c`, if it is a letter in one of the scripts in
+/// [`Script`].  Letters of other scripts are not confused with these and
+/// are ignored.
+fn confusable_script(c: char) -> Option<Script> {
+    if !c.is_alphabetic() {
+        return None;
+    }
+    Some(match u32::from(c) {
+        0x41..=0x5A | 0x61..=0x7A | 0xC0..=0x24F | 0x1E00..=0x1EFF => Script::Latin,
+        0xFF21..=0xFF3A | 0xFF41..=0xFF5A => Script::FullwidthLatin,
+        0x370..=0x3FF | 0x1F00..=0x1FFF => Script::Greek,
+        0x400..=0x52F => Script::Cyrillic,
+        _ => return None,
+    })
+}
+
+/// Whether `word` looks like a link or a domain name: it contains `://`,
+/// or a dot between two letters or digits.
+fn looks_like_url(word: &str) -> bool {
+    let chars: Vec<char> = word.chars().collect();
+    word.contains("://")
+        || chars
+            .windows(3)
+            .any(|w| w[0].is_alphanumeric() && w[1] == '.' && w[2].is_alphanumeric())
+}
+
+/// Whether `word` mixes letters from more than one [`Script`].
+fn mixes_scripts(word: &str) -> bool {
+    let mut scripts = word.chars().filter_map(confusable_script);
+    match scripts.next() {
+        Some(first) => scripts.any(|script| script != first),
+        None => false,
+    }
+}
+
+/// Put `marker` after every link-like word in sanitized `text` that mixes
+/// scripts, such as `pаypal.com` with a Cyrillic `а`.  Notifications cross
+/// the trust boundary, so they are an obvious place for phishing links.
+pub(crate) fn flag_confusables(text: &str, marker: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for piece in text.split_inclusive(char::is_whitespace) {
+        let word = piece.trim_end_matches(char::is_whitespace);
+        res.push_str(word);
+        if looks_like_url(word) && mixes_scripts(word) {
+            res.push(' ');
+            res.push_str(marker);
+        }
+        res.push_str(&piece[word.len()..]);
+    }
+    res
+}
+
 const MAX_LINES: usize = 500;
 const MAX_CHARS_PER_LINE: usize = 1000;
 
-fn serialize_image(
+/// Width, in pixels before scaling, of the frame around forwarded images.
+const IMAGE_FRAME_WIDTH: usize = 2;
+
+/// [`serialize_image_with`], without flattening or a frame.
+#[cfg(test)]
+fn serialize_image(image: ImageParameters) -> Result<Value<'static>, &'static str> {
+    serialize_image_with(image, None, None, 1)
+}
+
+/// Validate `image` and convert it to an `image-data` hint.  If
+/// `background`, an RGB color, is given, images with alpha are composited
+/// over it.  If `frame`, another RGB color, is given, the image is put in
+/// a frame of that color, and must leave room for it within [`MAX_WIDTH`]
+/// and [`MAX_HEIGHT`].  The image is then scaled up by `scale`, or by as
+/// much as fits in [`MAX_WIDTH`] and [`MAX_HEIGHT`].
+fn serialize_image_with(
     ImageParameters {
         untrusted_width,
         untrusted_height,
@@ -163,6 +765,9 @@ fn serialize_image(
         untrusted_channels,
         untrusted_data,
     }: ImageParameters,
+    background: Option<[u8; 3]>,
+    frame: Option<[u8; 3]>,
+    scale: u32,
 ) -> Result<Value<'static>, &'static str> {
     // sanitize start
 
@@ -195,26 +800,69 @@ fn serialize_image(
         return Err("Too small width, height, or stride");
     }
 
-    // check that the image is not too large
-    if untrusted_width > MAX_WIDTH || untrusted_height > MAX_HEIGHT {
+    // check that the image is not too large, once framed
+    let border = frame.map_or(0, |_| IMAGE_FRAME_WIDTH as i32 * 2);
+    if untrusted_width > MAX_WIDTH - border || untrusted_height > MAX_HEIGHT - border {
         return Err("Width or height too large");
     }
 
-    // check that the image fits in the buffer
-    if data.len() as i32 / untrusted_height < untrusted_rowstride {
-        return Err("Image too large");
+    // check that the image fits in the buffer.  All values are positive
+    // here, and the products are computed without wrapping, so a huge stride
+    // cannot make a short buffer look long enough.
+    match (untrusted_height as usize).checked_mul(untrusted_rowstride as usize) {
+        Some(size) if size <= data.len() => {}
+        _ => return Err("Image too large"),
     }
 
     // check that the rows fit in the stride
-    if untrusted_rowstride / channels < untrusted_width {
-        return Err("Row stride too small");
+    match untrusted_width.checked_mul(channels) {
+        Some(row_size) if row_size <= untrusted_rowstride => {}
+        _ => return Err("Row stride too small"),
     }
 
-    let height = untrusted_height;
-    let width = untrusted_width;
-    let rowstride = untrusted_rowstride;
+    let mut height = untrusted_height;
+    let mut width = untrusted_width;
+    let mut rowstride = untrusted_rowstride;
     // sanitize end
 
+    let (mut has_alpha, mut channels, mut data) = (has_alpha, channels, data);
+    if let (true, Some(background)) = (has_alpha, background) {
+        data = flatten_alpha(
+            width as usize,
+            height as usize,
+            rowstride as usize,
+            &data,
+            background,
+        );
+        (has_alpha, channels, rowstride) = (false, 3, width * 3);
+    }
+    if let Some(color) = frame {
+        data = frame_pixels(
+            width as usize,
+            height as usize,
+            rowstride as usize,
+            channels as usize,
+            &data,
+            color,
+        );
+        (width, height) = (width + border, height + border);
+        rowstride = width * channels;
+    }
+    let scale = (scale as i32)
+        .min(MAX_WIDTH / width)
+        .min(MAX_HEIGHT / height);
+    if scale > 1 {
+        data = scale_pixels(
+            (width * channels) as usize,
+            height as usize,
+            rowstride as usize,
+            channels as usize,
+            &data,
+            scale as usize,
+        );
+        (width, height) = (width * scale, height * scale);
+        rowstride = width * channels;
+    }
     return Ok(Value::from((
         width,
         height,
@@ -226,11 +874,103 @@ fn serialize_image(
     )));
 }
 
+/// Scale pixel data up by `scale` in both directions, repeating each pixel,
+/// which keeps icons sharp.  Rows of the result are `row_size * scale`
+/// bytes.  The caller has checked that the rows fit in `rowstride` and
+/// that `data` holds `height` rows.
+fn scale_pixels(
+    row_size: usize,
+    height: usize,
+    rowstride: usize,
+    channels: usize,
+    data: &[u8],
+    scale: usize,
+) -> Vec<u8> {
+    let mut res = Vec::with_capacity(row_size * height * scale * scale);
+    for row in data.chunks(rowstride).take(height) {
+        let start = res.len();
+        for pixel in row[..row_size].chunks_exact(channels) {
+            for _ in 0..scale {
+                res.extend_from_slice(pixel)
+            }
+        }
+        for _ in 1..scale {
+            res.extend_from_within(start..start + row_size * scale)
+        }
+    }
+    res
+}
+
+/// Put pixel data in an opaque frame of `color`, [`IMAGE_FRAME_WIDTH`]
+/// pixels wide, giving rows of `(width + 2 * IMAGE_FRAME_WIDTH) * channels`
+/// bytes.  The caller has checked that the rows fit in `rowstride` and that
+/// `data` holds `height` rows.
+fn frame_pixels(
+    width: usize,
+    height: usize,
+    rowstride: usize,
+    channels: usize,
+    data: &[u8],
+    color: [u8; 3],
+) -> Vec<u8> {
+    let mut pixel = color.to_vec();
+    pixel.resize(channels, 255);
+    let side = pixel.repeat(IMAGE_FRAME_WIDTH);
+    let edge = pixel.repeat(width + 2 * IMAGE_FRAME_WIDTH);
+    let mut res = Vec::with_capacity(edge.len() * (height + 2 * IMAGE_FRAME_WIDTH));
+    for _ in 0..IMAGE_FRAME_WIDTH {
+        res.extend_from_slice(&edge)
+    }
+    for row in data.chunks(rowstride).take(height) {
+        res.extend_from_slice(&side);
+        res.extend_from_slice(&row[..width * channels]);
+        res.extend_from_slice(&side);
+    }
+    for _ in 0..IMAGE_FRAME_WIDTH {
+        res.extend_from_slice(&edge)
+    }
+    res
+}
+
+/// Composite RGBA pixel data over `background`, giving RGB data with rows
+/// of `width * 3` bytes.  The caller has checked that the rows fit in
+/// `rowstride` and that `data` holds `height` rows.
+fn flatten_alpha(
+    width: usize,
+    height: usize,
+    rowstride: usize,
+    data: &[u8],
+    background: [u8; 3],
+) -> Vec<u8> {
+    let mut res = Vec::with_capacity(width * height * 3);
+    for row in data.chunks(rowstride).take(height) {
+        for pixel in row[..width * 4].chunks_exact(4) {
+            let alpha = u16::from(pixel[3]);
+            for (&color, &background) in pixel[..3].iter().zip(&background) {
+                let color = u16::from(color) * alpha + u16::from(background) * (255 - alpha);
+                res.push(((color + 127) / 255) as u8)
+            }
+        }
+    }
+    res
+}
+
+#[cfg(feature = "qubes-pure")]
 #[link(kind = "dylib", name = "qubes-pure")]
 extern "C" {
     fn qubes_pure_code_point_safe_for_display(code_point: u32) -> bool;
 }
 
+/// Whether `code_point` is safe to display, according to libqubes-pure or,
+/// without the `qubes-pure` feature, to [`unicode`].
+fn code_point_safe_for_display(code_point: u32) -> bool {
+    #[cfg(feature = "qubes-pure")]
+    // SAFETY: this function is not actually unsafe
+    return unsafe { qubes_pure_code_point_safe_for_display(code_point) };
+    #[cfg(not(feature = "qubes-pure"))]
+    return unicode::code_point_safe_for_display(code_point);
+}
+
 /// This imposes the following restrictions:
 ///
 /// - Characters are limited to a safe subset of Unicode.
@@ -239,42 +979,266 @@ extern "C" {
 ///
 /// Too many lines in particular is known to make xfce4-notifyd spin and consume 100% CPU.
 pub fn sanitize_str(arg: &str) -> String {
+    sanitize_str_with(arg, code_point_safe_for_display).0
+}
+
+/// [`sanitize_str`], also returning whether the text was truncated.
+pub(crate) fn sanitize_str_truncated(arg: &str) -> (String, bool) {
+    sanitize_str_with(arg, code_point_safe_for_display)
+}
+
+/// [`sanitize_str`], with the given check for code points.  This is the
+/// seam that lets the line handling be tested independently of
+/// libqubes-pure.
+fn sanitize_str_with(arg: &str, safe_for_display: impl Fn(u32) -> bool) -> (String, bool) {
     let mut res = String::with_capacity(arg.len());
     let mut iter = arg.chars().peekable();
     let mut counter = 0;
     let mut lines = 0;
     while let Some(c) = iter.next() {
-        res.push(
-            // SAFETY: this function is not actually unsafe
-            if unsafe { qubes_pure_code_point_safe_for_display(c.into()) } || c == '\t' {
-                counter += 1;
-                c
-            } else if c == '\n' {
-                counter = 0;
-                lines += 1;
-                c
-            } else if c == '\r' {
-                if iter.peek() == Some(&'\n') {
-                    continue;
-                }
-                counter = 0;
-                lines += 1;
-                '\n'
-            } else {
-                // This is U+FFFD REPLACEMENT CHARACTER
-                counter += 1;
-                '\u{FFFD}'
-            },
-        );
-        if counter >= MAX_CHARS_PER_LINE {
+        res.push(if safe_for_display(c.into()) || c == '\t' {
+            counter += 1;
+            c
+        } else if c == '\n' {
+            counter = 0;
+            lines += 1;
+            c
+        } else if c == '\r' {
+            if iter.peek() == Some(&'\n') {
+                continue;
+            }
+            counter = 0;
+            lines += 1;
+            '\n'
+        } else {
+            // This is U+FFFD REPLACEMENT CHARACTER
+            counter += 1;
+            '\u{FFFD}'
+        });
+        // A line break that follows anyway ends the line, so that
+        // sanitizing sanitized text changes nothing.
+        if counter >= MAX_CHARS_PER_LINE && !matches!(iter.peek(), Some('\n' | '\r')) {
             res.push('\n');
             counter = 0;
             lines += 1;
         }
         if lines >= MAX_LINES {
-            break; // notification daemon will hang if there are too many lines
+            // notification daemon will hang if there are too many lines
+            return (res, iter.peek().is_some());
         }
     }
+    (res, false)
+}
+
+/// The trusted start of the summary: the urgency `marker`, then the
+/// `prefix` naming the qube.  Also returns its length for the
+/// `x-qubes-prefix-length` hint, in characters rather than bytes so that
+/// it does not depend on the encoding the daemon uses internally.
+pub(crate) fn summary_prefix(marker: &str, prefix: &str) -> (String, u32) {
+    let res = marker.to_owned() + prefix;
+    let length = res.chars().count().try_into().unwrap_or(u32::MAX);
+    (res, length)
+}
+
+/// The `desktop-entry` hint for notifications from `qube_name`.  Qube names
+/// are valid in desktop file IDs.  No desktop file has this name, so
+/// daemons fall back to the application name, but they group the
+/// notifications of each qube together.
+pub(crate) fn desktop_entry(qube_name: &str) -> String {
+    format!("org.qubes-os.qube.{}", qube_name)
+}
+
+/// Compose the summary shown by the daemon from the trusted prefix and the
+/// sanitized, untrusted summary.
+///
+/// The untrusted part is wrapped in U+2068 FIRST STRONG ISOLATE and U+2069
+/// POP DIRECTIONAL ISOLATE.  Without this, a right-to-left summary can
+/// reorder the text around it, making the prefix render in the middle of
+/// the string.  `sanitize_str` never lets directional formatting characters
+/// through, so the guest cannot terminate the isolate early.
+pub(crate) fn compose_summary(prefix: &str, sanitized_summary: &str) -> String {
+    let mut res = String::with_capacity(prefix.len() + sanitized_summary.len() + 6);
+    res.push_str(prefix);
+    res.push('\u{2068}');
+    res.push_str(sanitized_summary);
+    res.push('\u{2069}');
+    res
+}
+
+/// `time` as local time of day, `HH:MM:SS`, in the time zone of dom0.
+pub(crate) fn local_time_of_day(time: SystemTime) -> String {
+    let tm = local_tm(time);
+    format!("{:02}:{:02}:{:02}", tm.tm_hour, tm.tm_min, tm.tm_sec)
+}
+
+/// Minutes since local midnight at `time`, in the time zone of dom0.
+pub(crate) fn local_minute_of_day(time: SystemTime) -> u16 {
+    let tm = local_tm(time);
+    (tm.tm_hour * 60 + tm.tm_min) as u16
+}
+
+/// `time` broken down in the time zone of dom0.
+fn local_tm(time: SystemTime) -> nix::libc::tm {
+    let seconds = match time.duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as nix::libc::time_t,
+        Err(_) => 0,
+    };
+    // SAFETY: localtime_r only reads `seconds` and writes `tm`, and all-zero
+    // is a valid `tm`
+    let mut tm: nix::libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { nix::libc::localtime_r(&seconds, &mut tm) }.is_null() {
+        // Only possible for years that do not fit in an int; fall back
+        // to UTC.
+        tm.tm_hour = (seconds / 3600 % 24) as _;
+        tm.tm_min = (seconds / 60 % 60) as _;
+        tm.tm_sec = (seconds % 60) as _;
+    }
+    tm
+}
+
+/// Runs of at least this many U+FFFD REPLACEMENT CHARACTER are collapsed
+/// by [`collapse_replacements`].
+const MIN_COLLAPSED_RUN: usize = 4;
+
+/// Collapse runs of U+FFFD REPLACEMENT CHARACTER in sanitized text into a
+/// single one with the length of the run, as in `[\u{FFFD}×1000]`, so that
+/// text that is mostly unsafe characters stays legible but still shows
+/// that something was replaced.  The sanitizer has already broken long
+/// lines, so a long run is collapsed one line at a time.
+pub(crate) fn collapse_replacements(sanitized: &str) -> String {
+    let mut res = String::with_capacity(sanitized.len());
+    let mut run = 0;
+    let end_run = |res: &mut String, run: usize| {
+        if run >= MIN_COLLAPSED_RUN {
+            res.push_str(&format!("[\u{FFFD}×{}]", run))
+        } else {
+            res.extend(std::iter::repeat_n('\u{FFFD}', run))
+        }
+    };
+    for c in sanitized.chars() {
+        if c == '\u{FFFD}' {
+            run += 1;
+            continue;
+        }
+        end_run(&mut res, run);
+        run = 0;
+        res.push(c)
+    }
+    end_run(&mut res, run);
+    res
+}
+
+/// Cut the sanitized `text` after `max_lines` lines, also returning
+/// whether anything was cut.  Like [`sanitize_str`], truncated text ends
+/// with a line break.
+pub(crate) fn limit_lines(text: &str, max_lines: usize) -> (&str, bool) {
+    match text.match_indices('\n').nth(max_lines.saturating_sub(1)) {
+        Some((end, _)) if max_lines > 0 => (&text[..=end], end + 1 < text.len()),
+        _ if max_lines == 0 => ("", !text.is_empty()),
+        _ => (text, false),
+    }
+}
+
+/// Cut the sanitized `text` after `max_chars` characters, also returning
+/// whether anything was cut.  Like [`sanitize_str`], truncated text ends
+/// with a line break.
+pub(crate) fn limit_chars(text: &str, max_chars: usize) -> (String, bool) {
+    match text.char_indices().nth(max_chars) {
+        None => (text.to_owned(), false),
+        Some((end, _)) => {
+            let mut res = text[..end].to_owned();
+            if !res.ends_with('\n') {
+                res.push('\n')
+            }
+            (res, true)
+        }
+    }
+}
+
+/// Replace the last line of the truncated, sanitized `text` with
+/// `marker`, so that the marked text has no more lines than the limit it
+/// was cut to.
+pub(crate) fn mark_truncated(mut text: String, marker: &str) -> String {
+    let kept = text
+        .strip_suffix('\n')
+        .unwrap_or(&text)
+        .rfind('\n')
+        .map_or(0, |end| end + 1);
+    text.truncate(kept);
+    text.push_str(marker);
+    text
+}
+
+/// Join the non-empty lines of the sanitized body with `separator`, for
+/// daemons that only show the first line.  The separator comes from the
+/// configuration, so it is trusted.
+pub(crate) fn fold_lines(sanitized_body: &str, separator: &str) -> String {
+    sanitized_body
+        .lines()
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join(separator)
+}
+
+/// Cut the sanitized `label` to `max_chars` characters, ending it with an
+/// ellipsis if anything was cut.
+pub(crate) fn limit_label(label: &str, max_chars: usize) -> String {
+    match label.char_indices().nth(max_chars) {
+        None => label.to_owned(),
+        Some(_) => {
+            let mut res: String = label.chars().take(max_chars.saturating_sub(1)).collect();
+            res.push('\u{2026}');
+            res
+        }
+    }
+}
+
+/// Move the first non-blank line of the sanitized body into the sanitized
+/// summary if the summary is blank.  Some applications put all of their
+/// text in the body, which would otherwise leave nothing but the prefix in
+/// the summary.
+pub(crate) fn promote_first_line(
+    sanitized_summary: String,
+    sanitized_body: String,
+) -> (String, String) {
+    if !sanitized_summary.trim().is_empty() {
+        return (sanitized_summary, sanitized_body);
+    }
+    let mut rest = &*sanitized_body;
+    while !rest.is_empty() {
+        let (line, tail) = rest.split_once('\n').unwrap_or((rest, ""));
+        if !line.trim().is_empty() {
+            return (line.to_owned(), tail.to_owned());
+        }
+        rest = tail;
+    }
+    (sanitized_summary, sanitized_body)
+}
+
+/// Separates the summary from the body that was moved into it.
+const SUMMARY_BODY_SEPARATOR: &str = " \u{2014} ";
+
+/// Append the first non-empty line of the sanitized body to the sanitized
+/// summary, for daemons that do not show bodies.  The line is cut short,
+/// with an ellipsis, so that the last line of the summary stays within the
+/// limit [`sanitize_str`] imposes.
+pub(crate) fn fold_body_into_summary(sanitized_summary: &str, sanitized_body: &str) -> String {
+    let mut res = sanitized_summary.to_owned();
+    let Some(line) = sanitized_body.lines().find(|line| !line.is_empty()) else {
+        return res;
+    };
+    let used = res.rsplit('\n').next().unwrap_or("").chars().count()
+        + SUMMARY_BODY_SEPARATOR.chars().count();
+    let Some(available) = MAX_CHARS_PER_LINE.checked_sub(used).filter(|&n| n > 0) else {
+        return res;
+    };
+    res.push_str(SUMMARY_BODY_SEPARATOR);
+    if line.chars().count() <= available {
+        res.push_str(line)
+    } else {
+        res.extend(line.chars().take(available - 1));
+        res.push('\u{2026}')
+    }
     res
 }
 
@@ -295,23 +1259,430 @@ bitflags! {
    }
 }
 
-pub struct NotificationEmitter {
-    notification_proxy: NotificationsProxy<'static>,
-    capabilities: Capabilities,
-    prefix: String,
-    application_name: String,
-    maps: std::cell::RefCell<Maps>,
+/// Names of the capabilities, as used by `GetCapabilities`.
+const CAPABILITY_NAMES: &[(&str, Capabilities)] = &[
+    ("action-icons", Capabilities::ACTION_ICONS),
+    ("actions", Capabilities::ACTIONS),
+    ("body", Capabilities::BODY),
+    ("body-hyperlinks", Capabilities::BODY_HYPERLINKS),
+    ("body-images", Capabilities::BODY_IMAGES),
+    ("body-markup", Capabilities::BODY_MARKUP),
+    ("icon-multi", Capabilities::ICON_MULTI),
+    ("icon-static", Capabilities::ICON_STATIC),
+    ("inline-reply", Capabilities::INLINE_REPLY),
+    ("persistence", Capabilities::PERSISTENCE),
+    ("sound", Capabilities::SOUND),
+];
+
+/// Capabilities the client advertises to applications in the guest.
+///
+/// This must not include [`Capabilities::BODY_MARKUP`]: the body is always
+/// taken to be plain text, and escaped by [`escape_markup`] if the daemon
+/// in dom0 supports markup.  Advertising markup would make applications
+/// send markup that is then shown literally.
+///
+/// [`Capabilities::ACTIONS`] includes the reserved [`DEFAULT_ACTION`] and
+/// [`SETTINGS_ACTION`], which reach the daemon unchanged.  Since
+/// [`Capabilities::ACTION_ICONS`] is never advertised, applications need
+/// not give icon names for them, or for any other action.
+pub const GUEST_CAPABILITIES: Capabilities = Capabilities::PERSISTENCE.union(Capabilities::ACTIONS);
+
+/// Capabilities the client advertises with minor version `minor`:
+/// [`GUEST_CAPABILITIES`], plus inline replies if dom0 passes them on.
+/// Whether the daemon in dom0 supports them is not known, so applications
+/// may get an [`INLINE_REPLY_ACTION`] action instead of a reply.
+pub fn guest_capabilities(minor: u16) -> Capabilities {
+    match minor {
+        REPLY_VERSION.. => GUEST_CAPABILITIES | Capabilities::INLINE_REPLY,
+        _ => GUEST_CAPABILITIES,
+    }
 }
 
-impl NotificationEmitter {
-    pub fn capabilities(&self) -> Capabilities {
-        self.capabilities
+/// Capabilities the client advertises once dom0 reported with
+/// [`ReplyMessage::DaemonCapabilities`] that its daemon has `daemon`: those
+/// of [`guest_capabilities`] that the daemon actually supports.  The rest
+/// are either not passed on, or rewritten by dom0, whatever the daemon can
+/// do.
+pub fn forwarded_capabilities(daemon: Capabilities, minor: u16) -> Capabilities {
+    daemon & guest_capabilities(minor)
+}
+
+impl Capabilities {
+    /// The capability with the given name, as used by `GetCapabilities`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        CAPABILITY_NAMES
+            .iter()
+            .find(|&&(n, _)| n == name)
+            .map(|&(_, capability)| capability)
     }
-    pub async fn new(
-        prefix: String,
-        application_name: String,
+    /// The names of the capabilities in `self`, as used by
+    /// `GetCapabilities`.
+    pub fn names(self) -> Vec<&'static str> {
+        CAPABILITY_NAMES
+            .iter()
+            .filter(|&&(_, capability)| self.contains(capability))
+            .map(|&(name, _)| name)
+            .collect()
+    }
+}
+
+impl From<&[String]> for Capabilities {
+    /// The capabilities named in the result of `GetCapabilities`.  Unknown
+    /// names are ignored.
+    fn from(names: &[String]) -> Self {
+        names
+            .iter()
+            .filter_map(|name| Capabilities::from_name(name))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Capabilities {
+    /// Comma-separated names, as taken by the `enable-capabilities` and
+    /// `disable-capabilities` settings.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.names().join(", "))
+    }
+}
+
+/// Escape `body` for a daemon that supports body markup, so that it is
+/// shown as plain text.
+pub(crate) fn escape_markup(body: &str) -> String {
+    let mut escaped_body = String::with_capacity(body.len());
+    // this is slow and can easily be made much faster with trivially
+    // correct `unsafe`, but the D-Bus call (which actually renders text on
+    // screen!) will be orders of magnitude slower so we do not care.
+    for i in body.chars() {
+        match i {
+            '<' => escaped_body.push_str("&lt;"),
+            '>' => escaped_body.push_str("&gt;"),
+            '&' => escaped_body.push_str("&amp;"),
+            '\'' => escaped_body.push_str("&apos;"),
+            '"' => escaped_body.push_str("&quot;"),
+            x => escaped_body.push(x),
+        }
+    }
+    escaped_body
+}
+
+/// Parse the result of `GetCapabilities`.
+fn parse_capabilities(names: Vec<String>) -> Capabilities {
+    for capability_str in names.iter() {
+        if Capabilities::from_name(capability_str).is_none() {
+            eprintln!("Unknown capability {} detected", capability_str)
+        }
+    }
+    Capabilities::from(&names[..])
+}
+
+/// D-Bus errors after which `Notify` is retried.  They mean that the call
+/// never reached a daemon, so a retry cannot show a notification twice.
+/// `NoReply` is not one of them: the daemon may have shown the notification
+/// and then stopped answering.
+const RETRYABLE_ERRORS: &[&str] = &[
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.NameHasNoOwner",
+    "org.freedesktop.DBus.Error.Spawn.ChildExited",
+    "org.freedesktop.DBus.Error.Spawn.Failed",
+];
+
+/// Most `Notify` calls retried per minute, so that a daemon that stays
+/// away does not hold up every notification.
+const MAX_RETRIES_PER_MINUTE: usize = 10;
+
+/// How long to wait before retry number `attempt`, counting from 1: `base`,
+/// doubled for each earlier retry, plus up to `base` chosen by `random`.
+pub(crate) fn retry_delay(
+    base: std::time::Duration,
+    attempt: u32,
+    random: u64,
+) -> std::time::Duration {
+    let backoff = base.saturating_mul(1 << attempt.saturating_sub(1).min(16));
+    // The top 53 bits, as a fraction in [0, 1).
+    let jitter = (random >> 11) as f64 / (1u64 << 53) as f64;
+    backoff.saturating_add(base.mul_f64(jitter))
+}
+
+/// Mutable state shared by the tasks using one emitter.  It is only
+/// reachable from the closure passed to [`Scoped::with`], which cannot
+/// `.await`, so no borrow is ever held across an await point, where another
+/// task could run and need the state too.
+///
+/// Locking order: the control state may be locked inside the closure, but
+/// [`Scoped::with`] must not be called while it is locked, nor from inside
+/// another call to it.
+struct Scoped<T>(std::cell::RefCell<T>);
+
+impl<T> Scoped<T> {
+    fn new(value: T) -> Self {
+        Self(std::cell::RefCell::new(value))
+    }
+    fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R {
+        f(&mut self
+            .0
+            .try_borrow_mut()
+            .expect("emitter state used re-entrantly"))
+    }
+}
+
+/// When notifications the daemon has not closed are taken to have expired,
+/// by guest ID and in order, so that one timer on the earliest serves them
+/// all.
+#[derive(Default)]
+struct Deadlines {
+    by_id: HashMap<u32, tokio::time::Instant>,
+    order: std::collections::BTreeSet<(tokio::time::Instant, u32)>,
+}
+
+impl Deadlines {
+    fn insert(&mut self, guest_id: u32, deadline: tokio::time::Instant) {
+        self.remove(guest_id);
+        self.by_id.insert(guest_id, deadline);
+        self.order.insert((deadline, guest_id));
+    }
+    fn remove(&mut self, guest_id: u32) -> Option<tokio::time::Instant> {
+        let deadline = self.by_id.remove(&guest_id)?;
+        self.order.remove(&(deadline, guest_id));
+        Some(deadline)
+    }
+    /// The earliest deadline, and the guest ID it is for.
+    fn first(&self) -> Option<(tokio::time::Instant, u32)> {
+        self.order.first().copied()
+    }
+    fn clear(&mut self) {
+        self.by_id.clear();
+        self.order.clear();
+    }
+}
+
+/// The mutable state of a [`NotificationEmitter`], behind [`Scoped`].
+#[derive(Default)]
+struct Tracked {
+    /// The name and specification version the daemon returned from
+    /// `GetServerInformation`.
+    server_information: (String, String),
+    maps: Maps,
+    urls: HashMap<u32, Vec<String>>,
+    /// When notifications the daemon has not closed are taken to have
+    /// expired.
+    deadlines: Deadlines,
+    /// Last host ID made up for a notification that was only logged.  These
+    /// count down from `u32::MAX`, away from the IDs daemons hand out.
+    last_logged_id: u32,
+    /// Made-up host IDs of live notifications that were only logged.
+    logged: std::collections::HashSet<u32>,
+    /// Resident notifications shown without some hints because the daemon
+    /// lacked the capabilities for them, keyed by guest ID, with when they
+    /// were received and the missing capabilities.  Only kept if
+    /// [`config::Config::resend_on_upgrade`] is set.
+    stripped: HashMap<u32, (Notification, SystemTime, Capabilities)>,
+    /// What was in `stripped` when the daemon went away, keyed by the guest
+    /// IDs the notifications had, which are no longer valid.
+    orphaned: HashMap<u32, (Notification, SystemTime, Capabilities)>,
+    /// Guest IDs of live notifications, keyed by their [`Extension::Tag`].
+    tags: HashMap<String, u32>,
+    /// The reverse of `tags`: tags of live notifications, keyed by guest ID.
+    tag_of: HashMap<u32, String>,
+    /// When `Notify` calls were retried within the last minute.
+    retries: std::collections::VecDeque<std::time::Instant>,
+}
+
+impl Tracked {
+    /// Set the tag of the live notification with guest ID `guest_id`,
+    /// replacing the one it had.  A tag names one notification at a time.
+    fn set_tag(&mut self, guest_id: u32, tag: Option<String>) {
+        if let Some(old) = self.tag_of.remove(&guest_id) {
+            if self.tags.get(&old) == Some(&guest_id) {
+                self.tags.remove(&old);
+            }
+        }
+        if let Some(tag) = tag {
+            if let Some(previous) = self.tags.insert(tag.clone(), guest_id) {
+                self.tag_of.remove(&previous);
+            }
+            self.tag_of.insert(guest_id, tag);
+        }
+    }
+}
+
+pub struct NotificationEmitter {
+    notification_proxy: NotificationsProxy<'static>,
+    capabilities: std::cell::Cell<Capabilities>,
+    /// The capabilities the daemon advertised.
+    advertised: std::cell::Cell<Capabilities>,
+    prefix: String,
+    application_name: String,
+    /// Name of the qube this emitter serves, once known.
+    qube_name: Option<String>,
+    protocol_minor: u16,
+    catalog: l10n::Catalog,
+    config: config::Config,
+    control: control::SharedState,
+    state: Scoped<Tracked>,
+}
+
+impl NotificationEmitter {
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities.get()
+    }
+    /// Set the minor protocol version negotiated with the guest.  Features
+    /// the guest does not understand are disabled.
+    #[doc(hidden)]
+    pub fn set_protocol_minor(&mut self, minor: u16) {
+        self.protocol_minor = minor
+    }
+    /// Set the catalog used for strings rendered by the proxy itself.
+    #[doc(hidden)]
+    pub fn set_catalog(&mut self, catalog: l10n::Catalog) {
+        self.catalog = catalog
+    }
+    /// Set the name of the qube this emitter serves, as validated from
+    /// qrexec.  It names the origin of notifications in hints.
+    #[doc(hidden)]
+    pub fn set_qube_name(&mut self, qube_name: String) {
+        self.qube_name = Some(qube_name)
+    }
+    /// Set the configuration for the qube this emitter serves.
+    #[doc(hidden)]
+    pub fn set_config(&mut self, config: config::Config) {
+        self.config = config;
+        self.set_capabilities(self.advertised.get())
+    }
+    /// Whether another `Notify` call may be retried now, counting it if so.
+    fn take_retry(&self) -> bool {
+        let now = std::time::Instant::now();
+        self.state.with(|state| {
+            let retries = &mut state.retries;
+            while retries.front().is_some_and(|&oldest| {
+                now.duration_since(oldest) >= std::time::Duration::from_secs(60)
+            }) {
+                retries.pop_front();
+            }
+            if retries.len() >= MAX_RETRIES_PER_MINUTE {
+                return false;
+            }
+            retries.push_back(now);
+            true
+        })
+    }
+    /// The quirks of the daemon to work around.
+    pub fn quirks(&self) -> quirks::Quirks {
+        self.state.with(|state| {
+            let (ref name, ref spec_version) = state.server_information;
+            match self.config.daemon_quirks {
+                config::DaemonQuirks::Detect => quirks::lookup(name, spec_version),
+                config::DaemonQuirks::Off => quirks::Quirks::default(),
+                config::DaemonQuirks::As(ref name) => quirks::lookup(name, spec_version),
+            }
+        })
+    }
+    /// Set the capabilities from those the daemon `advertised`, working
+    /// around its quirks and applying the overrides from the configuration.
+    fn set_capabilities(&self, advertised: Capabilities) {
+        self.advertised.set(advertised);
+        let quirks = self.quirks();
+        if advertised.intersects(quirks.disable_capabilities) {
+            eprintln!(
+                "Ignoring capabilities the daemon does not handle properly: {:?}",
+                advertised & quirks.disable_capabilities
+            );
+        }
+        let capabilities = ((advertised - quirks.disable_capabilities)
+            | self.config.enable_capabilities)
+            - self.config.disable_capabilities;
+        if capabilities != advertised - quirks.disable_capabilities {
+            eprintln!(
+                "Capabilities overridden by configuration: {:?} (daemon advertised {:?})",
+                capabilities, advertised
+            );
+        }
+        self.capabilities.set(capabilities)
+    }
+    /// Ask the daemon for its capabilities and identity again, after it
+    /// was replaced.
+    pub async fn refresh_capabilities(&self) -> zbus::Result<()> {
+        let advertised = parse_capabilities(self.notification_proxy.get_capabilities().await?.0);
+        let (name, _, _, spec_version) = self.notification_proxy.get_server_information().await?;
+        eprintln!(
+            "New daemon {} (specification {}), capabilities: {:?}",
+            name, spec_version, advertised
+        );
+        self.state
+            .with(|state| state.server_information = (name, spec_version));
+        self.set_capabilities(advertised);
+        Ok(())
+    }
+    /// Show the resident notifications that lost hints because the daemon
+    /// lacked capabilities again, if the current daemon has any of them.
+    /// This is called when another daemon takes over, or gains
+    /// capabilities, after [`NotificationEmitter::refresh_capabilities`].
+    /// The notifications the previous daemon showed are gone along with it,
+    /// and so are their guest IDs, so they are shown under new ones, and
+    /// the old and new guest IDs are returned for the qube to be told.  The
+    /// others are kept for a daemon that has what they lack.
+    pub async fn resend_upgradable(&self) -> Vec<(u32, u32)> {
+        let capabilities = self.capabilities.get();
+        let orphaned = self.state.with(|state| {
+            let (upgradable, rest): (HashMap<_, _>, _) = state
+                .orphaned
+                .drain()
+                .partition(|(_, (_, _, missing))| missing.intersects(capabilities));
+            state.orphaned = rest;
+            upgradable
+        });
+        let mut reshown = vec![];
+        for (guest_id, (mut notification, received, missing)) in orphaned {
+            notification.set_replaces_id(0);
+            match self.send_notification(notification, received).await {
+                Ok(new_id) => {
+                    eprintln!(
+                        "Showed notification {} again as {}, now with {:?}",
+                        guest_id,
+                        u32::from(new_id),
+                        missing & capabilities
+                    );
+                    reshown.push((guest_id, new_id.into()))
+                }
+                Err(e) => eprintln!("Cannot show notification {} again: {}", guest_id, e),
+            }
+        }
+        reshown
+    }
+    /// The state shown by the control interface.
+    #[doc(hidden)]
+    pub fn control_state(&self) -> control::SharedState {
+        self.control.clone()
+    }
+    /// The expire timeout that will be passed to the daemon when the guest
+    /// asks for `requested`.
+    pub fn effective_expire_timeout(&self, requested: ExpireTimeout) -> ExpireTimeout {
+        self.config.effective_expire_timeout(requested)
+    }
+    /// The session bus connection used to talk to the notification daemon.
+    pub fn connection(&self) -> &Connection {
+        self.notification_proxy.connection()
+    }
+    pub async fn new(
+        prefix: String,
+        application_name: String,
+    ) -> zbus::Result<(Self, NameOwnerChangedStream<'static>)> {
+        Self::on_connection(Connection::session().await?, prefix, application_name).await
+    }
+    /// [`NotificationEmitter::new`], for the daemon on the session bus at
+    /// `address` instead of the one of this process.
+    pub async fn with_address(
+        address: &str,
+        prefix: String,
+        application_name: String,
+    ) -> zbus::Result<(Self, NameOwnerChangedStream<'static>)> {
+        let connection = zbus::ConnectionBuilder::address(address)?.build().await?;
+        Self::on_connection(connection, prefix, application_name).await
+    }
+    async fn on_connection(
+        connection: Connection,
+        prefix: String,
+        application_name: String,
     ) -> zbus::Result<(Self, NameOwnerChangedStream<'static>)> {
-        let connection = Connection::session().await?;
         let (dbus_proxy, notification_proxy) = futures_util::future::join(
             DBusProxy::new(&connection).and_then(move |proxy| async move {
                 proxy
@@ -320,31 +1691,18 @@ impl NotificationEmitter {
             }),
             NotificationsProxy::new(&connection).and_then(move |proxy| async move {
                 let caps = proxy.get_capabilities().await?.0;
-                Ok((proxy, caps))
+                let (name, _, _, spec_version) = proxy.get_server_information().await?;
+                Ok((proxy, caps, (name, spec_version)))
             }),
         )
         .await;
-        let (dbus_proxy, (notification_proxy, capabilities_list)) =
+        let (dbus_proxy, (notification_proxy, capabilities_list, server_information)) =
             (dbus_proxy?, notification_proxy?);
-        let mut capabilities = Capabilities::default();
-        for capability_str in capabilities_list.into_iter() {
-            match &*capability_str {
-                "action-icons" => capabilities |= Capabilities::ACTION_ICONS,
-                "persistence" => capabilities |= Capabilities::PERSISTENCE,
-                "body-markup" => capabilities |= Capabilities::BODY_MARKUP,
-                "sound" => capabilities |= Capabilities::SOUND,
-                "body" => capabilities |= Capabilities::BODY,
-                "body-hyperlinks" => capabilities |= Capabilities::BODY_HYPERLINKS,
-                "body-images" => capabilities |= Capabilities::BODY_IMAGES,
-                "icon-static" => capabilities |= Capabilities::ICON_STATIC,
-                "actions" => capabilities |= Capabilities::ACTIONS,
-                "icon-multi" => capabilities |= Capabilities::ICON_MULTI,
-                "inline-reply" => capabilities |= Capabilities::INLINE_REPLY,
-                _ => eprintln!("Unknown capability {} detected", capability_str),
-            }
-        }
+        let capabilities = parse_capabilities(capabilities_list);
         eprintln!(
-            "Server capabilities: body markup {}, persistence {}",
+            "Server {} (specification {}) capabilities: body markup {}, persistence {}",
+            server_information.0,
+            server_information.1,
             capabilities.contains(Capabilities::BODY_MARKUP),
             capabilities.contains(Capabilities::PERSISTENCE),
         );
@@ -352,45 +1710,171 @@ impl NotificationEmitter {
             Self {
                 notification_proxy,
 
-                capabilities,
+                capabilities: capabilities.into(),
+                advertised: capabilities.into(),
                 prefix,
                 application_name,
-                maps: Default::default(),
+                qube_name: None,
+                protocol_minor: 0,
+                catalog: Default::default(),
+                config: Default::default(),
+                control: Default::default(),
+                state: Scoped::new(Tracked {
+                    server_information,
+                    ..Default::default()
+                }),
             },
             dbus_proxy,
         ))
     }
 }
 
+/// Actions of the question asked by [`NotificationEmitter::ask_approval`].
+const APPROVE_ACTION: &str = "approve";
+const MUTE_ACTION: &str = "mute";
+
+/// How long the question asked by [`NotificationEmitter::ask_approval`]
+/// waits for an answer.
+const APPROVAL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(300);
+
+/// Wait until nothing else holds the lock file at `path`, such as another
+/// connection asking the user about the same qube, and take it.  The lock
+/// is released when the returned file is closed, even if the process dies.
+/// Returns `None` if the file cannot be locked.
+async fn lock_file(path: &std::path::Path) -> Option<std::fs::File> {
+    use std::os::unix::io::AsRawFd as _;
+    let file = std::fs::create_dir_all(path.parent()?).and_then(|()| {
+        std::fs::File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+    });
+    let file = match file {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Cannot open {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    loop {
+        let flags = nix::libc::LOCK_EX | nix::libc::LOCK_NB;
+        if unsafe { nix::libc::flock(file.as_raw_fd(), flags) } == 0 {
+            return Some(file);
+        }
+        match std::io::Error::last_os_error() {
+            e if e.kind() == std::io::ErrorKind::WouldBlock => {
+                tokio::time::sleep(std::time::Duration::from_millis(500)).await
+            }
+            e => {
+                eprintln!("Cannot lock {}: {}", path.display(), e);
+                return None;
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
-pub struct MessageWriter(Rc<Mutex<tokio::io::Stdout>>);
+pub struct MessageWriter(Rc<Mutex<tokio::io::Stdout>>, codec::Framing);
 
 impl MessageWriter {
     pub fn new() -> Self {
-        Self(Rc::new(Mutex::new(tokio::io::stdout())))
+        Self::with_framing(Default::default())
+    }
+    /// A writer that writes frames with `framing`.
+    pub fn with_framing(framing: codec::Framing) -> Self {
+        Self(Rc::new(Mutex::new(tokio::io::stdout())), framing)
     }
     pub async fn transmit(&self, data: &[u8]) {
-        let len: u32 = data.len().try_into().unwrap();
         let mut guard = self.0.lock().await;
-        guard
-            .write_u32_le(len.to_le())
-            .await
-            .expect("error writing to stdout");
-        guard
-            .write_all(&*data)
+        codec::write_frame(&mut *guard, self.1, data)
             .await
             .expect("error writing to stdout");
-        guard.flush().await.expect("error writing to stdout");
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Message {
     pub id: u64,
     pub notification: Notification,
 }
 
+/// D-Bus error for a `CloseNotification` call with an ID that does not
+/// belong to any notification of the caller.
+pub const INVALID_ID_ERROR: &str = "org.freedesktop.Notifications.Error.InvalidId";
+
+/// A request from the guest.  Since version 7; older guests send a bare
+/// [`Message`] instead.
 #[derive(Serialize, Deserialize, Debug)]
+pub enum Request {
+    /// Show a notification.
+    Notify(Message),
+    /// Close a notification.  Answered with a [`ReplyMessage::Id`] for
+    /// the closed notification, or an error.  The error is
+    /// [`INVALID_ID_ERROR`] if there is no such notification.
+    Close {
+        /// Sequence number of the call.
+        sequence: u64,
+        /// The ID dom0 gave the notification.  Not trusted.
+        untrusted_id: u32,
+    },
+    /// Ask the notification daemon for its capabilities again, for
+    /// instance after the user switched daemons in a way dom0 did not
+    /// notice.  Answered with a [`ReplyMessage::Capabilities`], or an
+    /// error.  Since version 10.
+    Probe {
+        /// Sequence number of the call.
+        sequence: u64,
+    },
+    /// Check that dom0 is still there.  Answered with a
+    /// [`ReplyMessage::Pong`] right away, even when calls are waiting,
+    /// unless a [`Request::Batch`] holds replies back.  Since version 17.
+    Ping {
+        /// Sequence number of the ping.
+        sequence: u64,
+    },
+    /// Show several notifications, as if each had been sent with
+    /// [`Request::Notify`], in one frame.  The calls are made one after the
+    /// other, and take up a single call slot in dom0.  The replies come in
+    /// a [`ReplyMessage::Batch`].  Since version 18.
+    Batch(Vec<Message>),
+    /// Ask what happened to notifications of the guest, so that it can
+    /// catch up on signals it missed, for instance while suspended.  dom0
+    /// only remembers the last [`MAX_HISTORY`] outcomes.  Answered with a
+    /// [`ReplyMessage::History`].  Since version 19.
+    History {
+        /// Sequence number of the call.
+        sequence: u64,
+        /// The notifications asked about, as IDs dom0 gave them, or all of
+        /// them if empty.  Not trusted.
+        untrusted_ids: Vec<u32>,
+    },
+    /// End the connection cleanly, because the guest is shutting down or
+    /// dom0 sent [`ReplyMessage::Draining`].  dom0 reads nothing after
+    /// this, finishes the calls in flight, and answers with
+    /// [`ReplyMessage::Drained`] after their replies.  Since version 20.
+    Drain {
+        /// Sequence number of the request.
+        sequence: u64,
+    },
+}
+
+impl Request {
+    /// Sequence number of the call, or of the first call of a batch.
+    pub fn sequence(&self) -> u64 {
+        match *self {
+            Request::Notify(ref message) => message.id,
+            Request::Batch(ref messages) => messages.first().map_or(0, |message| message.id),
+            Request::Close { sequence, .. }
+            | Request::Probe { sequence }
+            | Request::Ping { sequence }
+            | Request::History { sequence, .. }
+            | Request::Drain { sequence } => sequence,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Notification {
     V1 {
         suppress_sound: bool,
@@ -404,37 +1888,279 @@ pub enum Notification {
         body: String,
         actions: Vec<String>,
         category: Option<String>,
-        expire_timeout: i32,
+        expire_timeout: ExpireTimeout,
+        image: Option<ImageParameters>,
+    },
+    /// Like [`Notification::V1`], plus optional extensions.  Since version 4.
+    V2 {
+        suppress_sound: bool,
+        transient: bool,
+        resident: bool,
+        urgency: Option<Urgency>,
+        replaces_id: u32,
+        summary: String,
+        body: String,
+        actions: Vec<String>,
+        category: Option<String>,
+        expire_timeout: ExpireTimeout,
         image: Option<ImageParameters>,
+        /// Optional information.  Each kind of extension is only sent if
+        /// the negotiated version supports it, so that older servers can
+        /// still decode the message.
+        extensions: Vec<Extension>,
     },
 }
 
+/// Optional information attached to a [`Notification::V2`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum Extension {
+    /// The `x` and `y` hints.  Not trusted.  Since version 4.
+    Position { untrusted_x: i32, untrusted_y: i32 },
+    /// The `value` hint, a percentage.  Not trusted.  Since version 5.
+    Progress { untrusted_value: i32 },
+    /// The `x-qubes-tag` or `x-dunst-stack-tag` hint.  A notification with
+    /// the same tag as a live one from the same qube replaces it.  Not
+    /// trusted.  Since version 9.
+    Tag { untrusted_tag: String },
+    /// The `x-qubes-locale` hint, or the locale of the guest, as a
+    /// language tag.  Passed on as `x-qubes-locale` if
+    /// [`is_valid_language_tag`] accepts it, so that the daemon can pick
+    /// fonts and line breaking for the language.  Not trusted.  Since
+    /// version 15.
+    Locale { untrusted_language: String },
+}
+
+impl Notification {
+    /// Convert to the latest version of the notification format.
+    pub fn upgrade(self) -> Self {
+        match self {
+            Notification::V1 {
+                suppress_sound,
+                transient,
+                resident,
+                urgency,
+                replaces_id,
+                summary,
+                body,
+                actions,
+                category,
+                expire_timeout,
+                image,
+            } => Notification::V2 {
+                suppress_sound,
+                transient,
+                resident,
+                urgency,
+                replaces_id,
+                summary,
+                body,
+                actions,
+                category,
+                expire_timeout,
+                image,
+                extensions: vec![],
+            },
+            v2 => v2,
+        }
+    }
+    /// The ID of the notification this one replaces, or 0.
+    pub fn replaces_id(&self) -> u32 {
+        match *self {
+            Notification::V1 { replaces_id, .. } | Notification::V2 { replaces_id, .. } => {
+                replaces_id
+            }
+        }
+    }
+    /// Make this notification replace the one with ID `id`, or none if 0.
+    pub fn set_replaces_id(&mut self, id: u32) {
+        match *self {
+            Notification::V1 {
+                ref mut replaces_id,
+                ..
+            }
+            | Notification::V2 {
+                ref mut replaces_id,
+                ..
+            } => *replaces_id = id,
+        }
+    }
+    /// The urgency the guest asked for.
+    pub fn urgency(&self) -> Option<Urgency> {
+        match *self {
+            Notification::V1 { urgency, .. } | Notification::V2 { urgency, .. } => urgency,
+        }
+    }
+    /// The expire timeout the guest asked for.
+    pub fn expire_timeout(&self) -> ExpireTimeout {
+        match *self {
+            Notification::V1 { expire_timeout, .. } | Notification::V2 { expire_timeout, .. } => {
+                expire_timeout
+            }
+        }
+    }
+}
+
+/// Builds a [`Notification`].  Everything but the summary is optional, and
+/// defaults to what `Notify` would do with an empty value: no body, no
+/// actions, no hints, and the daemon's default expire timeout.
+///
+/// ```
+/// use notification_emitter::{NotificationBuilder, Urgency};
+/// let notification = NotificationBuilder::new("Backup done")
+///     .body("No errors")
+///     .urgency(Urgency::Low)
+///     .action("default", "Show log")
+///     .build()
+///     .unwrap();
+/// assert_eq!(notification.urgency(), Some(Urgency::Low));
+/// ```
+#[derive(Debug, Clone)]
+pub struct NotificationBuilder {
+    suppress_sound: bool,
+    transient: bool,
+    resident: bool,
+    urgency: Option<Urgency>,
+    replaces_id: u32,
+    summary: String,
+    body: String,
+    actions: Vec<String>,
+    category: Option<String>,
+    expire_timeout: ExpireTimeout,
+    image: Option<ImageParameters>,
+    extensions: Vec<Extension>,
+}
+
+impl NotificationBuilder {
+    pub fn new(summary: impl Into<String>) -> Self {
+        Self {
+            suppress_sound: false,
+            transient: false,
+            resident: false,
+            urgency: None,
+            replaces_id: 0,
+            summary: summary.into(),
+            body: String::new(),
+            actions: vec![],
+            category: None,
+            expire_timeout: ExpireTimeout::Default,
+            image: None,
+            extensions: vec![],
+        }
+    }
+    pub fn body(mut self, body: impl Into<String>) -> Self {
+        self.body = body.into();
+        self
+    }
+    /// Add an action with the given key and label.
+    pub fn action(mut self, key: impl Into<String>, label: impl Into<String>) -> Self {
+        self.actions.push(key.into());
+        self.actions.push(label.into());
+        self
+    }
+    /// Set the actions as `Notify` takes them: keys and labels, alternating.
+    pub fn actions(mut self, actions: Vec<String>) -> Self {
+        self.actions = actions;
+        self
+    }
+    pub fn urgency(mut self, urgency: Urgency) -> Self {
+        self.urgency = Some(urgency);
+        self
+    }
+    /// Replace the notification with ID `id`, or none if 0.
+    pub fn replaces_id(mut self, id: u32) -> Self {
+        self.replaces_id = id;
+        self
+    }
+    pub fn category(mut self, category: impl Into<String>) -> Self {
+        self.category = Some(category.into());
+        self
+    }
+    pub fn expire_timeout(mut self, expire_timeout: ExpireTimeout) -> Self {
+        self.expire_timeout = expire_timeout;
+        self
+    }
+    pub fn suppress_sound(mut self, suppress_sound: bool) -> Self {
+        self.suppress_sound = suppress_sound;
+        self
+    }
+    pub fn transient(mut self, transient: bool) -> Self {
+        self.transient = transient;
+        self
+    }
+    pub fn resident(mut self, resident: bool) -> Self {
+        self.resident = resident;
+        self
+    }
+    pub fn image(mut self, image: ImageParameters) -> Self {
+        self.image = Some(image);
+        self
+    }
+    /// Add an extension.  The result is then a [`Notification::V2`], which
+    /// needs version [`POSITION_VERSION`] or later.
+    pub fn extension(mut self, extension: Extension) -> Self {
+        self.extensions.push(extension);
+        self
+    }
+    /// Check the notification and build it.  It is a [`Notification::V1`]
+    /// unless it has extensions, so that dom0 need not be newer than
+    /// necessary.
+    pub fn build(self) -> Result<Notification, &'static str> {
+        if self.actions.len() & 1 != 0 {
+            return Err("Actions must come in pairs of key and label");
+        }
+        let notification = Notification::V1 {
+            suppress_sound: self.suppress_sound,
+            transient: self.transient,
+            resident: self.resident,
+            urgency: self.urgency,
+            replaces_id: self.replaces_id,
+            summary: self.summary,
+            body: self.body,
+            actions: self.actions,
+            category: self.category,
+            expire_timeout: self.expire_timeout,
+            image: self.image,
+        };
+        if self.extensions.is_empty() {
+            return Ok(notification);
+        }
+        let mut notification = notification.upgrade();
+        if let Notification::V2 {
+            ref mut extensions, ..
+        } = notification
+        {
+            *extensions = self.extensions
+        }
+        Ok(notification)
+    }
+}
+
 impl NotificationEmitter {
     #[inline]
     /// Whether the server supports persistence
     pub fn persistence(&self) -> bool {
-        self.capabilities.contains(Capabilities::PERSISTENCE)
+        self.capabilities.get().contains(Capabilities::PERSISTENCE)
     }
     #[inline]
     /// Whether the server supports sound
     pub fn sound(&self) -> bool {
-        self.capabilities.contains(Capabilities::SOUND)
+        self.capabilities.get().contains(Capabilities::SOUND)
     }
     #[inline]
     /// Whether the server supports actions
     pub fn actions(&self) -> bool {
-        self.capabilities.contains(Capabilities::ACTIONS)
+        self.capabilities.get().contains(Capabilities::ACTIONS)
     }
 
     #[inline]
     /// Whether the server supports body markup
     pub fn body_markup(&self) -> bool {
-        self.capabilities.contains(Capabilities::BODY_MARKUP)
+        self.capabilities.get().contains(Capabilities::BODY_MARKUP)
     }
     #[inline]
     /// Whether the server supports notification bodies
     pub fn body(&self) -> bool {
-        self.capabilities.contains(Capabilities::BODY)
+        self.capabilities.get().contains(Capabilities::BODY)
     }
     pub async fn closed(&self) -> zbus::Result<NotificationClosedStream<'static>> {
         self.notification_proxy.receive_notification_closed().await
@@ -445,10 +2171,15 @@ impl NotificationEmitter {
     pub async fn replies(&self) -> zbus::Result<NotificationRepliedStream<'static>> {
         self.notification_proxy.receive_notification_replied().await
     }
+    /// Translate a host ID to a guest ID.  Mappings removed shortly before
+    /// are still found, so that late signals reach the guest.
     pub fn translate_host_id(&self, id: u32) -> Option<u32> {
         match HostId::new_less_safe(id) {
             None => Some(0),
-            Some(a) => match self.maps.borrow().lookup_host_id(a) {
+            Some(a) => match self
+                .state
+                .with(|state| state.maps.lookup_host_id_or_tombstone(a))
+            {
                 None => {
                     eprintln!("ID {} not found!", u32::from(a));
                     None
@@ -457,16 +2188,286 @@ impl NotificationEmitter {
             },
         }
     }
+    /// If `action` is one of the "Open link" actions added to the
+    /// notification with host ID `id`, returns the link to open.
+    pub fn url_for_action(&self, id: u32, action: &str) -> Option<String> {
+        let index: usize = action.strip_prefix(OPEN_URL_ACTION_PREFIX)?.parse().ok()?;
+        self.state
+            .with(|state| state.urls.get(&id)?.get(index).cloned())
+    }
     pub fn clear(&self) {
-        self.maps.borrow_mut().clear()
+        self.state.with(|state| {
+            state.maps.clear();
+            state.urls.clear();
+            state.deadlines.clear();
+            state.logged.clear();
+            state.orphaned.extend(state.stripped.drain());
+            state.tags.clear();
+            state.tag_of.clear();
+            let mut control = self.control.lock().expect("poisoned");
+            control.notifications.clear();
+            control.maps = state.maps.stats()
+        })
+    }
+    /// Forget the notification with host ID `id`, returning its guest ID.
+    fn forget_host_id(&self, id: u32) -> Option<u32> {
+        self.state.with(|state| {
+            state.urls.remove(&id);
+            let guest_id = HostId::new_less_safe(id)
+                .and_then(|a| state.maps.remove_host_id(a).map(u32::from))?;
+            state.deadlines.remove(guest_id);
+            state.logged.remove(&id);
+            state.stripped.remove(&guest_id);
+            state.set_tag(guest_id, None);
+            Some(guest_id)
+        })
     }
     pub fn remove_host_id(&self, id: u32) -> Option<u32> {
-        HostId::new_less_safe(id)
-            .and_then(|a| self.maps.borrow_mut().remove_host_id(a).map(From::from))
+        let guest_id = self.forget_host_id(id)?;
+        let stats = self.state.with(|state| state.maps.stats());
+        let mut control = self.control.lock().expect("poisoned");
+        control.notifications.remove(&guest_id);
+        control.maps = stats;
+        Some(guest_id)
+    }
+    /// Handle the `NotificationClosed` signal for host ID `id`.  Like
+    /// [`NotificationEmitter::remove_host_id`], but also updates the
+    /// counters of the control interface.
+    pub fn notification_closed(&self, id: u32, reason: u32) -> Option<u32> {
+        let guest_id = self.forget_host_id(id)?;
+        let stats = self.state.with(|state| state.maps.stats());
+        let mut control = self.control.lock().expect("poisoned");
+        control.closed(guest_id, reason);
+        control.maps = stats;
+        Some(guest_id)
+    }
+    /// Tell the user that notifications from `qube_name` are shown.
+    pub async fn announce(&self, qube_name: &str) -> zbus::Result<()> {
+        let summary = self.catalog.format("announce", &[("qube", qube_name)]);
+        self.notification_proxy
+            .notify(
+                self.application_name.clone(),
+                0,
+                "",
+                &summary,
+                "",
+                &[],
+                &HashMap::new(),
+                -1,
+            )
+            .await
+            .map(drop)
+    }
+    /// Ask the user whether notifications from `qube_name` may be shown,
+    /// and wait for the answer.  Approval lasts until the session ends.  If
+    /// the user mutes the qube instead, that is remembered as if done
+    /// through the control interface.  If the question is dismissed, goes
+    /// unanswered for [`APPROVAL_TIMEOUT`], or cannot be asked, the qube is
+    /// muted until it reconnects.  A daemon without actions cannot ask, so
+    /// then the qube is announced and shown instead.
+    ///
+    /// Only one connection from a qube asks at a time.  The others wait,
+    /// and take its answer if it was recorded.
+    pub async fn ask_approval(&self, qube_name: &str) {
+        if !self.actions() {
+            eprintln!(
+                "The notification daemon cannot show actions, \
+                so notifications from {} are shown WITHOUT APPROVAL",
+                qube_name
+            );
+            if let Err(e) = self.announce(qube_name).await {
+                eprintln!("Cannot announce connection from {}: {}", qube_name, e)
+            }
+            return;
+        }
+        let _asking = match control::asking_file(qube_name) {
+            Some(path) => lock_file(&path).await,
+            None => None,
+        };
+        let recorded = |file: Option<std::path::PathBuf>| file.is_some_and(|path| path.exists());
+        let approved = if recorded(control::approval_file(qube_name)) {
+            Some(true)
+        } else if recorded(control::mute_file(qube_name)) {
+            Some(false)
+        } else {
+            match self.ask(qube_name).await {
+                Ok(approved) => approved,
+                Err(e) => {
+                    eprintln!("Cannot ask whether to show {}: {}", qube_name, e);
+                    None
+                }
+            }
+        };
+        let stored = match approved {
+            Some(true) => control::approval_file(qube_name).map(|path| {
+                let dir = path.parent().expect("approval file is in a directory");
+                let out = std::fs::create_dir_all(dir).and_then(|()| std::fs::File::create(&path));
+                (path, out.map(drop))
+            }),
+            Some(false) => control::mute_file(qube_name)
+                .map(|path| (path.clone(), control::store_muted(&path, true))),
+            None => None,
+        };
+        if let Some((path, Err(e))) = stored {
+            eprintln!("Cannot record answer in {}: {}", path.display(), e)
+        }
+        eprintln!("Notifications from {} approved: {:?}", qube_name, approved);
+        self.control.lock().expect("poisoned").muted = approved != Some(true);
+    }
+    /// Show the question for [`NotificationEmitter::ask_approval`], and
+    /// return whether the user chose to show notifications, or `None` if
+    /// the question was closed without an answer.
+    async fn ask(&self, qube_name: &str) -> zbus::Result<Option<bool>> {
+        use futures_util::StreamExt as _;
+        if !self.actions() {
+            return Err(zbus::Error::Failure("daemon has no actions".to_owned()));
+        }
+        // Subscribed first, so that a quick answer is not missed.
+        let invoked = self.invocations().await?.filter_map(|signal| async move {
+            let args = signal.args().ok()?;
+            Some((args.id, Some(args.action_key == APPROVE_ACTION)))
+        });
+        let closed = self
+            .closed()
+            .await?
+            .filter_map(|signal| async move { Some((signal.args().ok()?.id, None)) });
+        let qube = [("qube", qube_name)];
+        let actions = [
+            APPROVE_ACTION.to_owned(),
+            self.catalog.format("approve-allow", &[]),
+            MUTE_ACTION.to_owned(),
+            self.catalog.format("approve-deny", &[]),
+        ];
+        let hints = HashMap::from([
+            ("urgency", Value::U8(Urgency::Critical as u8)),
+            ("resident", Value::Bool(true)),
+        ]);
+        let id = self
+            .notification_proxy
+            .notify(
+                self.application_name.clone(),
+                0,
+                "",
+                &self.catalog.format("approve", &qube),
+                &self.catalog.format("approve-body", &qube),
+                &actions,
+                &hints,
+                0,
+            )
+            .await?;
+        let mut answers = futures_util::stream::select(invoked, closed)
+            .filter(|&(answered, _)| std::future::ready(answered == id))
+            .boxed_local();
+        match tokio::time::timeout(APPROVAL_TIMEOUT, answers.next()).await {
+            Ok(answer) => Ok(answer.and_then(|(_, approved)| approved)),
+            Err(_) => {
+                eprintln!("No answer whether to show {}", qube_name);
+                self.notification_proxy.close_notification(id).await?;
+                Ok(None)
+            }
+        }
+    }
+    /// When the earliest deadline of a live notification passes, if any.
+    /// See [`NotificationEmitter::expire_overdue`].
+    pub fn next_deadline(&self) -> Option<tokio::time::Instant> {
+        self.state
+            .with(|state| state.deadlines.first().map(|(deadline, _)| deadline))
+    }
+    /// Forget the notifications past their deadline as expired, and return
+    /// their guest IDs.  The daemon should have closed them by then, so they
+    /// are closed in case the daemon ignores timeouts.
+    pub async fn expire_overdue(&self) -> Vec<u32> {
+        let now = tokio::time::Instant::now();
+        let mut expired = vec![];
+        loop {
+            let overdue = self.state.with(|state| {
+                let (deadline, guest_id) = state.deadlines.first()?;
+                if deadline > now {
+                    return None;
+                }
+                state.deadlines.remove(guest_id);
+                Some(state.maps.resolve_guest_id(guest_id).map(u32::from))
+            });
+            let Some(overdue) = overdue else {
+                return expired;
+            };
+            let Some(host_id) = overdue else { continue };
+            let Some(guest_id) = self.notification_closed(host_id, control::CLOSED_EXPIRED) else {
+                continue;
+            };
+            eprintln!("Notification {} did not expire in time", guest_id);
+            // The daemon's NotificationClosed signal for this is ignored,
+            // since the notification has been forgotten already.
+            if let Err(e) = self.notification_proxy.close_notification(host_id).await {
+                eprintln!("Cannot close notification {}: {}", guest_id, e)
+            }
+            expired.push(guest_id)
+        }
+    }
+    /// Close the notification the guest knows as `untrusted_id`.  The ID is
+    /// only looked up among the IDs given to this guest, and never taken to
+    /// be a host ID, so a qube cannot close notifications of other qubes or
+    /// of dom0 applications.  Returns `None` if the guest has no
+    /// notification with that ID.  Otherwise, also returns whether the
+    /// notification was only logged, in which case no daemon reports that
+    /// it was closed, so the caller must.
+    pub async fn close_notification(&self, untrusted_id: u32) -> zbus::Result<Option<(u32, bool)>> {
+        let resolved = self.state.with(|state| {
+            let host_id = u32::from(state.maps.resolve_guest_id(untrusted_id)?);
+            Some((host_id, state.logged.contains(&host_id)))
+        });
+        let Some((host_id, logged)) = resolved else {
+            return Ok(None);
+        };
+        // sanitize end
+        if logged {
+            let guest_id = self.notification_closed(host_id, control::CLOSED_BY_CALL);
+            return Ok(guest_id.map(|id| (id, true)));
+        }
+        self.notification_proxy.close_notification(host_id).await?;
+        Ok(Some((untrusted_id, false)))
     }
+    /// Show `notification`, which dom0 received at `received`, returning
+    /// the ID to report to the guest.
     pub async fn send_notification(
         &self,
-        Notification::V1 {
+        notification: Notification,
+        received: SystemTime,
+    ) -> zbus::Result<GuestId> {
+        Ok(self.send(notification, received).await?.id)
+    }
+    /// [`NotificationEmitter::send_notification`], also returning what was
+    /// done to the notification on the way.
+    pub async fn send(
+        &self,
+        notification: Notification,
+        received: SystemTime,
+    ) -> zbus::Result<Sent> {
+        self.send_call(notification, received, None).await
+    }
+    /// [`NotificationEmitter::send`], for the call with
+    /// correlation ID `call`, which is added to the log and the control
+    /// interface.
+    #[doc(hidden)]
+    pub async fn send_call(
+        &self,
+        notification: Notification,
+        received: SystemTime,
+        call: Option<String>,
+    ) -> zbus::Result<Sent> {
+        let tag = call
+            .as_deref()
+            .map_or(String::new(), |call| format!("[{}] ", call));
+        let notification = notification.upgrade();
+        let original = match notification {
+            Notification::V2 { resident: true, .. }
+                if self.config.resend_on_upgrade && self.protocol_minor >= RESHOWN_VERSION =>
+            {
+                Some(notification.clone())
+            }
+            _ => None,
+        };
+        let Notification::V2 {
             suppress_sound,
             transient,
             resident,
@@ -478,16 +2479,63 @@ impl NotificationEmitter {
             category: untrusted_category,
             expire_timeout,
             image,
-        }: Notification,
-    ) -> zbus::Result<GuestId> {
+            extensions,
+        } = notification.upgrade()
+        else {
+            unreachable!("upgrade() returns the latest version")
+        };
+        let wanted = [
+            (resident || transient, Capabilities::PERSISTENCE),
+            (suppress_sound, Capabilities::SOUND),
+            (!untrusted_actions.is_empty(), Capabilities::ACTIONS),
+            (
+                untrusted_actions
+                    .iter()
+                    .step_by(2)
+                    .any(|a| a == INLINE_REPLY_ACTION),
+                Capabilities::INLINE_REPLY,
+            ),
+        ]
+        .into_iter()
+        .filter(|&(wanted, _)| wanted)
+        .fold(Capabilities::empty(), |res, (_, capability)| {
+            res | capability
+        });
+        let missing = wanted - self.capabilities.get();
+        let quirks = self.quirks();
+        let stack_tag = extensions.iter().find_map(|extension| match extension {
+            Extension::Tag { untrusted_tag } => Some(untrusted_tag),
+            _ => None,
+        });
+        let stack_tag = match stack_tag {
+            Some(untrusted_tag) if !is_valid_tag(untrusted_tag) => {
+                eprintln!("Ignoring invalid tag {:?}", untrusted_tag);
+                None
+            }
+            // sanitize end
+            Some(tag) => Some(tag.clone()),
+            None => None,
+        };
+        // A tagged notification replaces the live one with the same tag,
+        // unless the qube asked to replace a specific one.
+        let replaces_id = match stack_tag {
+            Some(ref stack_tag) if replaces_id == 0 => {
+                let live = self.state.with(|state| {
+                    state.tags.get(stack_tag).copied().filter(|&id| {
+                        maps::GuestId::new_less_safe(id)
+                            .is_some_and(|id| state.maps.lookup_guest_id(id).is_some())
+                    })
+                });
+                live.unwrap_or(0)
+            }
+            _ => replaces_id,
+        };
         let guest_id = maps::GuestId::new_less_safe(replaces_id);
         let host_id = match guest_id {
             None => None,
-            Some(id) => self.maps.borrow().lookup_guest_id(id),
+            Some(id) => self.state.with(|state| state.maps.lookup_guest_id(id)),
         };
-        if expire_timeout < -1 {
-            return Err(zbus::Error::Unsupported);
-        }
+        let expire_timeout = self.effective_expire_timeout(expire_timeout);
 
         if untrusted_actions.len() & 1 != 0 {
             return Err(zbus::Error::Failure(format!(
@@ -505,26 +2553,17 @@ impl NotificationEmitter {
         // However, there is no good way to do that in practice, so just pass
         // an empty string to indicate "no icon".
         let icon = "";
-        let actions = if self.actions() {
-            let mut actions = Vec::with_capacity(untrusted_actions.len());
-            for (count, s) in untrusted_actions.iter().enumerate() {
-                if count & 1 == 0 {
-                    if !is_valid_action_name(s.as_bytes()) {
-                        return Err(zbus::Error::Failure("Invalid action name".to_owned()));
-                    }
-                    // Sanitized by is_valid_action_name()
-                    actions.push(s.to_owned())
-                } else {
-                    actions.push(sanitize_str(&*s))
-                }
-            }
-            actions
+        let mut actions = if self.actions() {
+            sanitize_actions(&untrusted_actions, self.config.max_action_label)?
         } else {
             vec![]
         };
 
         // this is slow but I don't care, the D-Bus call is orders of magnitude slower
         // Set up the hints
+        let stack_tag_hint = stack_tag
+            .as_ref()
+            .map(|stack_tag| format!("{}{}", self.prefix, stack_tag));
         let mut hints = HashMap::new();
         if let Some(urgency) = urgency {
             // this is a hack to appease the borrow checker
@@ -538,15 +2577,63 @@ impl NotificationEmitter {
                 <zbus::zvariant::Value<'_> as From<&'_ u8>>::from(urgency),
             );
         }
-        if resident && self.capabilities.contains(Capabilities::PERSISTENCE) {
+        if resident && self.capabilities.get().contains(Capabilities::PERSISTENCE) {
             hints.insert("resident", Value::from(&true));
         }
-        if suppress_sound && self.capabilities.contains(Capabilities::SOUND) {
+        if suppress_sound && self.capabilities.get().contains(Capabilities::SOUND) {
             hints.insert("suppress-sound", Value::from(&true));
         }
         if transient && self.persistence() {
             hints.insert("transient", Value::from(&true));
         }
+        for extension in &extensions {
+            match *extension {
+                Extension::Position {
+                    untrusted_x,
+                    untrusted_y,
+                } => match self.config.position_hints {
+                    None => eprintln!("Ignoring coordinate hints {} {}", untrusted_x, untrusted_y),
+                    Some((width, height)) => {
+                        // the configuration only allows positive sizes
+                        let x = untrusted_x.clamp(0, width.saturating_sub(1).max(0));
+                        let y = untrusted_y.clamp(0, height.saturating_sub(1).max(0));
+                        // sanitize end
+                        hints.insert("x", Value::I32(x));
+                        hints.insert("y", Value::I32(y));
+                    }
+                },
+                Extension::Progress { untrusted_value } => {
+                    let value = untrusted_value.clamp(0, 100);
+                    // sanitize end
+                    hints.insert("value", Value::I32(value));
+                }
+                Extension::Locale {
+                    ref untrusted_language,
+                } => {
+                    if !is_valid_language_tag(untrusted_language) {
+                        eprintln!("Ignoring invalid locale {:?}", untrusted_language);
+                        continue;
+                    }
+                    // sanitize end
+                    hints.insert("x-qubes-locale", Value::from(&**untrusted_language));
+                }
+                // Handled above.
+                Extension::Tag { .. } => {}
+            }
+        }
+        // Daemons that stack by tag then replace notifications even across
+        // restarts of the proxy.  The tag is prefixed like the summary, so
+        // that qubes cannot replace each other's notifications.
+        if let Some(ref stack_tag) = stack_tag_hint {
+            hints.insert("x-dunst-stack-tag", Value::from(&**stack_tag));
+        }
+        if self.config.timestamp == Some(config::Timestamp::Hint) {
+            let seconds = match received.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(duration) => duration.as_secs() as i64,
+                Err(_) => 0,
+            };
+            hints.insert("x-qubes-received-at", Value::I64(seconds));
+        }
         if let Some(ref untrusted_category) = untrusted_category {
             let category = untrusted_category.as_bytes();
             if category.len() > 64 {
@@ -569,108 +2656,3691 @@ impl NotificationEmitter {
             // sanitize end
             hints.insert("category", Value::from(category));
         }
-        // Temporarily disabled due to lack of image processing
-        if false {
-            if let Some(image) = image {
-                match serialize_image(image) {
-                    Ok(value) => hints.insert("image-data", value),
-                    Err(e) => return Err(zbus::Error::MissingParameter(e)),
-                };
-            }
+        let category = untrusted_category.as_deref();
+        if !self.config.category_allowed(category) {
+            eprintln!("{}Rejecting notification with category {:?}", tag, category);
+            return Err(zbus::Error::MissingParameter("Category not allowed"));
         }
-        let mut escaped_body;
-        if self.body_markup() {
-            let body = sanitize_str(&*untrusted_body);
-            // Body markup must be escaped.  FIXME: validate it instead.
-            escaped_body = String::with_capacity(body.as_bytes().len());
-            // this is slow and can easily be made much faster with
-            // trivially correct `unsafe`, but the D-Bus call (which
-            // actually renders text on screen!) will be orders of
-            // magnitude slower so we do not care.
-            for i in body.chars() {
-                match i {
-                    '<' => escaped_body.push_str("&lt;"),
-                    '>' => escaped_body.push_str("&gt;"),
-                    '&' => escaped_body.push_str("&amp;"),
-                    '\'' => escaped_body.push_str("&apos;"),
-                    '"' => escaped_body.push_str("&quot;"),
-                    x => escaped_body.push(x),
-                }
-            }
+        // Images are only passed on inside a frame, as anything else
+        // could mimic the dom0 UI.
+        if let (Some(image), Some(frame)) = (image, self.config.image_frame) {
+            let scale = self
+                .config
+                .image_scale
+                .unwrap_or_else(config::display_scale);
+            match serialize_image_with(image, self.config.flatten_images, Some(frame), scale) {
+                Ok(value) => hints.insert(quirks.image_hint, value),
+                Err(e) => return Err(zbus::Error::MissingParameter(e)),
+            };
+        }
+        // Links must never be opened in dom0, so instead of letting the
+        // daemon handle them, add actions that ask the guest to open them.
+        let urls: Vec<String> = if self.actions() && self.protocol_minor >= 1 {
+            find_urls(&sanitize_str(&untrusted_body))
+                .into_iter()
+                .take(MAX_URLS)
+                .map(str::to_owned)
+                .collect()
         } else {
-            escaped_body = sanitize_str(&*untrusted_body)
+            vec![]
+        };
+        for i in 0..urls.len() {
+            actions.push(format!("{}{}", OPEN_URL_ACTION_PREFIX, i));
+            actions.push(match i {
+                0 => self.catalog.format("open-link", &[]),
+                i => self
+                    .catalog
+                    .format("open-link-numbered", &[("number", &(i + 1).to_string())]),
+            });
         }
-        let host_id_num = match host_id {
-            None => 0,
-            Some(i) => i.into(),
-        };
-        let id = HostId::new_less_safe(
-            self.notification_proxy
-                .notify(
-                    application_name,
-                    host_id_num,
-                    icon,
-                    &*(self.prefix.clone() + &*sanitize_str(&*untrusted_summary)),
-                    &*escaped_body,
-                    &*actions,
-                    &hints,
-                    expire_timeout,
-                )
-                .await?,
-        )
-        .expect("Notification daemon sent a zero ID?");
+        // The marker takes the place of the last line kept.
+        let mark = |text: String| mark_truncated(text, &self.catalog.format("truncated", &[]));
+        let flag = |text: String| {
+            if self.config.flag_confusables {
+                flag_confusables(&text, &self.catalog.format("confusable", &[]))
+            } else {
+                text
+            }
+        };
+        let (summary, summary_truncated) = sanitize_str_truncated(&untrusted_summary);
+        let mut summary = flag(collapse_replacements(&summary));
+        if summary_truncated {
+            summary = mark(summary)
+        }
+        if quirks.single_line_summary {
+            summary = fold_lines(&summary, " ")
+        }
+        let (body, mut body_truncated) = sanitize_str_truncated(&untrusted_body);
+        let body = match quirks.max_lines {
+            Some(max_lines) => {
+                let (limited, cut) = limit_lines(&body, max_lines);
+                body_truncated |= cut;
+                limited.to_owned()
+            }
+            None => body,
+        };
+        let body = match quirks.max_body_chars {
+            Some(max_chars) => {
+                let (limited, cut) = limit_chars(&body, max_chars);
+                body_truncated |= cut;
+                limited
+            }
+            None => body,
+        };
+        let mut body = flag(collapse_replacements(&body));
+        if body_truncated {
+            body = mark(body)
+        }
+        let (summary, mut body) = promote_first_line(summary, body);
+        if let Some(ref separator) = self.config.fold_body {
+            body = fold_lines(&body, separator);
+        }
+        let relay = match (urgency, &self.config.relay) {
+            (Some(Urgency::Critical), Some(path)) => Some((
+                path.clone(),
+                relay::record(&[&self.application_name, &summary, &body]),
+            )),
+            _ => None,
+        };
+        // Daemons without body support may drop it silently, so the start
+        // of it goes in the summary instead.
+        let (summary, body) = if self.body() {
+            (summary, body)
+        } else {
+            (fold_body_into_summary(&summary, &body), String::new())
+        };
+        let escaped_body = if self.body_markup() {
+            // Body markup must be escaped.  FIXME: validate it instead.
+            escape_markup(&body)
+        } else {
+            body
+        };
+        let marker = self
+            .config
+            .urgency_marker(urgency.unwrap_or(Urgency::Normal));
+        let (prefix, prefix_length) = summary_prefix(marker, &self.prefix);
+        let mut summary = compose_summary(&prefix, &summary);
+        hints.insert("x-qubes-prefix-length", Value::U32(prefix_length));
+        // Plasma groups notifications by desktop entry and shows the
+        // origin name next to the application name.  Both come from the
+        // qube name, never from the guest.
+        if let Some(ref qube_name) = self.qube_name {
+            hints.insert("desktop-entry", Value::from(desktop_entry(qube_name)));
+            hints.insert("x-kde-origin-name", Value::from(&**qube_name));
+        }
+        if self.config.timestamp == Some(config::Timestamp::Summary) {
+            let time = local_time_of_day(received);
+            summary.push(' ');
+            summary.push_str(&self.catalog.format("received-at", &[("time", &time)]));
+        }
+        // A notification that was only logged is replaced by a new one.
+        for hint in quirks.ignored_hints {
+            hints.remove(hint);
+        }
+        let logged =
+            host_id.filter(|&id| self.state.with(|state| state.logged.contains(&id.into())));
+        let host_id_num = match host_id {
+            Some(i) if logged.is_none() => i.into(),
+            _ => 0,
+        };
+        let muted = self.control.lock().expect("poisoned").muted;
+        let quiet = urgency != Some(Urgency::Critical)
+            && self.config.quiet_at(local_minute_of_day(received));
+        let suppressed = if muted {
+            Some(Suppression::Muted)
+        } else if quiet {
+            Some(Suppression::QuietHours)
+        } else if self.config.route == config::Route::Log {
+            Some(Suppression::LogOnly)
+        } else {
+            None
+        };
+        let route = match suppressed {
+            Some(_) => config::Route::Log,
+            None => config::Route::Display,
+        };
+        let id = match route {
+            config::Route::Display => {
+                let mut attempt = 0;
+                let id = loop {
+                    let out = self
+                        .notification_proxy
+                        .notify(
+                            application_name.clone(),
+                            host_id_num,
+                            icon,
+                            &summary,
+                            &*escaped_body,
+                            &*actions,
+                            &hints,
+                            expire_timeout.into(),
+                        )
+                        .await;
+                    match out {
+                        Err(zbus::Error::MethodError(ref name, _, _))
+                            if attempt < self.config.dbus_retries
+                                && RETRYABLE_ERRORS.contains(&name.as_str())
+                                && self.take_retry() =>
+                        {
+                            attempt += 1;
+                            let random = {
+                                use std::hash::{BuildHasher as _, Hasher as _};
+                                std::collections::hash_map::RandomState::new()
+                                    .build_hasher()
+                                    .finish()
+                            };
+                            let delay = retry_delay(self.config.dbus_retry_delay, attempt, random);
+                            eprintln!(
+                                "{}Notify failed with {}, retry {} of {} in {} ms",
+                                tag,
+                                name,
+                                attempt,
+                                self.config.dbus_retries,
+                                delay.as_millis()
+                            );
+                            tokio::time::sleep(delay).await
+                        }
+                        out => break out?,
+                    }
+                };
+                HostId::new_less_safe(id).expect("Notification daemon sent a zero ID?")
+            }
+            config::Route::Log => {
+                eprintln!(
+                    "{}Not showing notification: summary {:?}, body {:?}",
+                    tag, summary, escaped_body
+                );
+                // No daemon hands out IDs, so they are made up here.
+                self.state.with(|state| {
+                    let id = logged.unwrap_or_else(|| {
+                        let id = match state.last_logged_id.wrapping_sub(1) {
+                            0 => u32::MAX,
+                            id => id,
+                        };
+                        state.last_logged_id = id;
+                        HostId::new_less_safe(id).expect("not zero")
+                    });
+                    state.logged.insert(id.into());
+                    id
+                })
+            }
+        };
+        // Only what the user would have been shown is forwarded, and the
+        // reply does not wait for the relay.
+        if let (Some((path, record)), None | Some(Suppression::LogOnly)) = (relay, suppressed) {
+            tokio::task::spawn_local(async move {
+                if let Err(e) = relay::send(&path, &record).await {
+                    eprintln!("Cannot forward notification to {}: {}", path.display(), e)
+                }
+            });
+        }
+
+        let next_id = self.state.with(|state| {
+            if urls.is_empty() {
+                state.urls.remove(&id.into());
+            } else {
+                state.urls.insert(id.into(), urls);
+            }
+            state.maps.next_id(id, guest_id)
+        });
+        let Some(guest_id) = next_id else {
+            eprintln!(
+                "{}No free notification ID, closing host ID {}",
+                tag,
+                u32::from(id)
+            );
+            let logged = self.state.with(|state| {
+                state.urls.remove(&id.into());
+                state.logged.remove(&id.into())
+            });
+            if !logged {
+                if let Err(e) = self.notification_proxy.close_notification(id.into()).await {
+                    eprintln!("{}Cannot close host ID {}: {}", tag, u32::from(id), e)
+                }
+            }
+            return Err(zbus::Error::Failure("No free notification ID".to_owned()));
+        };
+        self.state.with(|state| {
+            state.set_tag(guest_id.into(), stack_tag);
+            match original {
+                Some(original) if !missing.is_empty() => state
+                    .stripped
+                    .insert(guest_id.into(), (original, received, missing)),
+                _ => state.stripped.remove(&guest_id.into()),
+            };
+        });
+        eprintln!(
+            "{}Guest ID {} maps to host ID {}, expire timeout {:?}",
+            tag,
+            u32::from(guest_id),
+            u32::from(id),
+            expire_timeout
+        );
+        let stats = self.state.with(|state| state.maps.stats());
+        let mut control = self.control.lock().expect("poisoned");
+        control.shown(
+            guest_id.into(),
+            control::NotificationInfo {
+                host_id: id.into(),
+                expire_timeout,
+                mapped_at: std::time::Instant::now(),
+                call,
+            },
+        );
+        control.maps = stats;
+        drop(control);
+        let deadline = match (expire_timeout, self.config.expiry_grace) {
+            _ if route == config::Route::Log => None,
+            (ExpireTimeout::Millis(millis), Some(grace)) => {
+                let timeout = std::time::Duration::from_millis(millis.get().into());
+                Some(tokio::time::Instant::now() + timeout + grace)
+            }
+            _ => None,
+        };
+        self.state.with(|state| match deadline {
+            Some(deadline) => state.deadlines.insert(guest_id.into(), deadline),
+            None => drop(state.deadlines.remove(guest_id.into())),
+        });
+        Ok(Sent {
+            id: guest_id,
+            truncated: summary_truncated || body_truncated,
+            deadline,
+            suppressed,
+        })
+    }
+}
+
+/// A notification shown by [`NotificationEmitter::send`].
+#[derive(Debug, Clone, Copy)]
+pub struct Sent {
+    /// The ID to report to the guest.
+    pub id: GuestId,
+    /// Whether the summary or body was truncated.
+    pub truncated: bool,
+    /// When to call [`NotificationEmitter::expire_overdue`], if the
+    /// notification expires.  Replacing the notification moves its deadline.
+    pub deadline: Option<tokio::time::Instant>,
+    /// Why the notification was only logged, if it was.
+    pub suppressed: Option<Suppression>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use codec::Framing;
+    #[test]
+    fn test_discriminant_serialized() {
+        use bincode::Options as _;
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes();
+        let v = options
+            .serialize(&Notification::V1 {
+                suppress_sound: true,
+                transient: false,
+                resident: false,
+                urgency: None,
+                replaces_id: 0,
+                summary: "".to_owned(),
+                body: "".to_owned(),
+                actions: vec![],
+                category: None,
+                expire_timeout: ExpireTimeout::Never,
+                image: None,
+            })
+            .unwrap();
+        assert_eq!(&v[..4], &[0, 0, 0, 0][..])
+    }
+    #[test]
+    fn test_enum_extensibility() {
+        #[derive(Serialize, Deserialize)]
+        enum A {
+            B { x: bool },
+        }
+        #[derive(Serialize, Deserialize)]
+        enum D {
+            B { x: bool },
+            C { x: u32 },
+        }
+        use bincode::Options as _;
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes();
+        let serialized = options.serialize(&A::B { x: true }).unwrap();
+        let deserialized: D = options.deserialize(&serialized).unwrap();
+        assert!(matches!(deserialized, D::B { x: true }));
+        assert_eq!(serialized, options.serialize(&D::B { x: true }).unwrap());
+    }
+
+    #[test]
+    fn test_control_name() {
+        assert_eq!(
+            control::control_name("sys-net"),
+            "org.qubes.NotificationProxy1.Qube.sys_2dnet"
+        );
+        assert_eq!(control::escape_qube_name("a.b_c"), "a_2eb_5fc");
+        let xml = introspection_xml(&[&control::Control::new(
+            "work".to_owned(),
+            (1, 0),
+            Default::default(),
+        )]);
+        assert!(xml.contains("<interface name=\"org.qubes.NotificationProxy1.Control\">"));
+        assert!(xml.contains("<method name=\"DumpMaps\">"));
+        assert!(xml.ends_with("</node>\n"));
+        assert!(xml.contains("<property name=\"UnreadCount\" type=\"u\" access=\"read\"/>"));
+        assert!(xml.contains("<method name=\"MarkAllRead\">"));
+    }
+
+    #[test]
+    fn test_correlation_ids() {
+        assert_eq!(control::correlation_id("work", 12), "work:12");
+        let mut state = control::State::default();
+        let info = |call: Option<&str>| control::NotificationInfo {
+            host_id: 1,
+            expire_timeout: ExpireTimeout::Default,
+            mapped_at: std::time::Instant::now(),
+            call: call.map(str::to_owned),
+        };
+        state.shown(1, info(Some("work:12")));
+        state.shown(2, info(None));
+        assert_eq!(state.notifications[&1].call.as_deref(), Some("work:12"));
+        assert_eq!(state.notifications[&2].call, None);
+        let xml = introspection_xml(&[&control::Control::new(
+            "work".to_owned(),
+            (MAJOR_VERSION, MINOR_VERSION),
+            Default::default(),
+        )]);
+        assert!(xml.contains("<method name=\"ListCalls\">"), "{}", xml);
+    }
+
+    #[test]
+    fn test_control_counters() {
+        let info = || control::NotificationInfo {
+            host_id: 1,
+            expire_timeout: ExpireTimeout::Default,
+            mapped_at: std::time::Instant::now(),
+            call: None,
+        };
+        let mut state = control::State::default();
+        for guest_id in 1..=4 {
+            state.shown(guest_id, info());
+        }
+        // Replacing a notification shows it again.
+        state.shown(4, info());
+        assert_eq!((state.notifications.len(), state.shown), (4, 5));
+        state.closed(1, control::CLOSED_EXPIRED);
+        state.closed(2, 2);
+        state.closed(3, control::CLOSED_EXPIRED);
+        // Unknown IDs are not counted.
+        state.closed(3, control::CLOSED_EXPIRED);
+        state.closed(9, control::CLOSED_EXPIRED);
+        assert_eq!((state.notifications.len(), state.unread), (1, 2));
+    }
+
+    #[test]
+    fn test_vm_stats() {
+        let state = control::SharedState::default();
+        let calls = std::sync::Arc::new(tokio::sync::Semaphore::new(2));
+        let control = control::Control::new("work".to_owned(), (1, 0), state.clone())
+            .with_calls(calls.clone(), 2);
+        {
+            let mut state = state.lock().unwrap();
+            for guest_id in [3, 7] {
+                let info = control::NotificationInfo {
+                    host_id: guest_id + 100,
+                    expire_timeout: ExpireTimeout::Default,
+                    mapped_at: std::time::Instant::now(),
+                    call: None,
+                };
+                state.shown(guest_id, info);
+            }
+            state.rejected("work:4", "invalid image");
+        }
+        let stats = control.stats();
+        assert_eq!(&*stats["active"], &Value::U32(2));
+        assert_eq!(&*stats["shown"], &Value::U64(2));
+        assert_eq!(&*stats["rejected"], &Value::U64(1));
+        assert_eq!(&*stats["active-ids"], &Value::from(vec![3u32, 7]));
+        assert_eq!(
+            &*stats["last-error"],
+            &Value::from("[work:4] invalid image")
+        );
+        assert_eq!(&*stats["throttled"], &Value::Bool(false));
+
+        let _permits = calls.try_acquire_many(2).unwrap();
+        let stats = control.stats();
+        assert_eq!(&*stats["calls-in-flight"], &Value::U32(2));
+        assert_eq!(&*stats["throttled"], &Value::Bool(true));
+        let xml = introspection_xml(&[&control]);
+        assert!(xml.contains("<method name=\"GetVmStats\">"), "{}", xml);
+    }
+
+    #[test]
+    fn test_mute_state() {
+        let dir =
+            std::env::temp_dir().join(format!("notification-proxy-test-{}", std::process::id()));
+        let path = dir.join("muted").join(control::escape_qube_name("work"));
+        assert!(!path.exists());
+        control::store_muted(&path, true).unwrap();
+        assert!(path.exists());
+        // Muting twice is fine, and so is unmuting twice.
+        control::store_muted(&path, true).unwrap();
+        control::store_muted(&path, false).unwrap();
+        assert!(!path.exists());
+        control::store_muted(&path, false).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_validate_qube_name() {
+        assert_eq!(validate_qube_name("work").unwrap(), "work");
+        assert_eq!(validate_qube_name("sys-net\n").unwrap(), "sys-net");
+        assert_eq!(validate_qube_name("a.b_c-1").unwrap(), "a.b_c-1");
+        assert_eq!(validate_qube_name(&"a".repeat(31)).unwrap(), "a".repeat(31));
+        assert_eq!(validate_qube_name("").unwrap_err(), "Empty qube name");
+        assert_eq!(validate_qube_name("\n").unwrap_err(), "Empty qube name");
+        assert_eq!(
+            validate_qube_name(&"a".repeat(32)).unwrap_err(),
+            "Qube name too long"
+        );
+        assert_eq!(
+            validate_qube_name("1work").unwrap_err(),
+            "Qube name does not start with an ASCII letter"
+        );
+        for bad in ["wo rk", "work\n\n", "w\u{202e}k", "w:k", "w/k"] {
+            assert_eq!(
+                validate_qube_name(bad).unwrap_err(),
+                "Forbidden character in qube name"
+            );
+        }
+    }
+
+    #[test]
+    fn test_catalog() {
+        let english = l10n::Catalog::default();
+        assert_eq!(english.format("prefix", &[("qube", "work")]), "work: ");
+        let catalog = l10n::Catalog::parse(
+            "# comment\n\
+             prefix = { $qube }:{ \" \" }\n\
+             open-link-numbered = Link { $number } öffnen { $unknown }\n",
+        )
+        .unwrap();
+        assert_eq!(catalog.format("prefix", &[("qube", "work")]), "work: ");
+        assert_eq!(
+            catalog.format("open-link-numbered", &[("number", "2")]),
+            "Link 2 öffnen { $unknown }"
+        );
+        assert_eq!(catalog.format("open-link", &[]), "Open link");
+        assert_eq!(
+            l10n::Catalog::parse("a\nb = c").unwrap_err(),
+            "line 1: expected `id = value`"
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let text = "locale = de\n[work]\nlocale = fr\nnew-key = 1\n[personal]\nlocale = it\n";
+        let config = config::Config::parse(text, "work").unwrap();
+        assert_eq!(config.locale.as_deref(), Some("fr"));
+        let config = config::Config::parse(text, "vault").unwrap();
+        assert_eq!(config.locale.as_deref(), Some("de"));
+        assert_eq!(
+            config::Config::parse("[work\n", "work").unwrap_err(),
+            "line 1: unterminated section header"
+        );
+        assert_eq!(
+            config::Config::parse("\nlocale de\n", "work").unwrap_err(),
+            "line 2: expected `key = value`"
+        );
+    }
+
+    #[test]
+    fn test_urgency_markers() {
+        let config =
+            config::Config::parse("critical-marker = \u{26a0}\nlow-marker =\n", "a").unwrap();
+        assert_eq!(config.urgency_marker(Urgency::Critical), "\u{26a0} ");
+        assert_eq!(config.urgency_marker(Urgency::Low), "");
+        assert_eq!(config.urgency_marker(Urgency::Normal), "");
+        assert!(config::Config::parse("low-marker = a\tb", "a").is_err());
+        assert!(config::Config::parse("low-marker = 123456789", "a").is_err());
+    }
+
+    #[test]
+    fn test_expire_timeout_policy() {
+        let config = config::Config::default();
+        for i in [-1, 0, 1, MAX_EXPIRE_TIMEOUT as i32] {
+            let timeout = ExpireTimeout::from_wire(i).unwrap();
+            assert_eq!(config.effective_expire_timeout(timeout), timeout);
+        }
+        let config = config::Config::parse(
+            "default-expire-timeout = 5000\nmax-expire-timeout=60000",
+            "a",
+        )
+        .unwrap();
+        let effective = |i| {
+            config
+                .effective_expire_timeout(ExpireTimeout::from_wire(i).unwrap())
+                .to_wire()
+        };
+        assert_eq!(effective(-1), 5000);
+        assert_eq!(effective(0), 60000);
+        assert_eq!(effective(1), 1);
+        assert_eq!(effective(60001), 60000);
+        assert!(config::Config::parse("max-expire-timeout = 0", "a").is_err());
+        assert!(config::Config::parse("max-expire-timeout = -1", "a").is_err());
+        assert!(config::Config::parse("max-expire-timeout = 86400001", "a").is_err());
+    }
+
+    #[test]
+    fn test_position_hints() {
+        let config = config::Config::parse("position-hints = clamp 1920x1080", "a").unwrap();
+        assert_eq!(config.position_hints, Some((1920, 1080)));
+        let config = config::Config::parse("position-hints = drop", "a").unwrap();
+        assert_eq!(config.position_hints, None);
+        for bad in ["clamp", "clamp 0x1080", "clamp 1920", "clamp -1x5", "keep"] {
+            let text = format!("position-hints = {}", bad);
+            assert!(config::Config::parse(&text, "a").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_categories() {
+        let config = config::Config::parse(
+            "deny-categories = presence.*\n[work]\nallow-categories = email.arrived, im.*",
+            "work",
+        )
+        .unwrap();
+        assert!(config.category_allowed(Some("email.arrived")));
+        assert!(config.category_allowed(Some("im")));
+        assert!(config.category_allowed(Some("im.received")));
+        assert!(!config.category_allowed(Some("email.bounced")));
+        assert!(!config.category_allowed(Some("imp")));
+        assert!(!config.category_allowed(None));
+        let config = config::Config::parse("deny-categories = presence.*", "a").unwrap();
+        assert!(!config.category_allowed(Some("presence.online")));
+        assert!(!config.category_allowed(Some("presence")));
+        assert!(config.category_allowed(Some("presencex")));
+        assert!(config.category_allowed(None));
+        for bad in ["Email", "email.", "*.arrived", "email.*.x", ".email"] {
+            let text = format!("deny-categories = {}", bad);
+            assert!(config::Config::parse(&text, "a").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_max_concurrent_calls() {
+        let config = config::Config::parse("", "a").unwrap();
+        assert_eq!(
+            config.max_concurrent_calls,
+            config::DEFAULT_MAX_CONCURRENT_CALLS
+        );
+        let config = config::Config::parse("max-concurrent-calls = 16", "a").unwrap();
+        assert_eq!(config.max_concurrent_calls, 16);
+        assert!(config::Config::parse("max-concurrent-calls = 0", "a").is_err());
+    }
+
+    #[test]
+    fn test_capability_overrides() {
+        let config = config::Config::parse(
+            "disable-capabilities = body-markup, sound\nenable-capabilities = actions,",
+            "a",
+        )
+        .unwrap();
+        assert_eq!(
+            config.disable_capabilities,
+            Capabilities::BODY_MARKUP | Capabilities::SOUND
+        );
+        assert_eq!(config.enable_capabilities, Capabilities::ACTIONS);
+        assert_eq!(
+            config::Config::parse("enable-capabilities = bogus", "a").unwrap_err(),
+            "line 1: unknown capability \"bogus\""
+        );
+    }
+
+    #[test]
+    fn test_keepalive_timeout() {
+        let config = config::Config::parse(
+            "keepalive-timeout = 5000\n[a]\nkeepalive-timeout = never",
+            "b",
+        );
+        assert_eq!(
+            config.unwrap().keepalive_timeout,
+            Some(std::time::Duration::from_secs(5))
+        );
+        let config = config::Config::parse(
+            "keepalive-timeout = 5000\n[a]\nkeepalive-timeout = never",
+            "a",
+        );
+        assert_eq!(config.unwrap().keepalive_timeout, None);
+    }
+
+    #[test]
+    fn test_image_frame_config() {
+        let config = config::Config::parse("[a]\nimage-frame = #cc0000", "a");
+        assert_eq!(config.unwrap().image_frame, Some([204, 0, 0]));
+        let config = config::Config::parse("[a]\nimage-frame = #cc0000", "b");
+        assert_eq!(config.unwrap().image_frame, None);
+    }
+
+    #[test]
+    fn test_session_buses() {
+        let config = config::Config::default();
+        assert!(config.session_buses.is_empty());
+        assert_eq!(config.bus_policy, config::BusPolicy::First);
+        let text = "session-buses = unix:path=/run/user/1000/bus \
+            unix:path=/run/user/1001/bus,guid=0123456789abcdef0123456789abcdef\n\
+            [a]\nbus-policy = all";
+        let config = config::Config::parse(text, "a").unwrap();
+        assert_eq!(
+            config.session_buses,
+            [
+                "unix:path=/run/user/1000/bus",
+                "unix:path=/run/user/1001/bus,guid=0123456789abcdef0123456789abcdef"
+            ]
+        );
+        assert_eq!(config.bus_policy, config::BusPolicy::All);
+        assert!(config::Config::parse("session-buses = /run/user/1000/bus", "a").is_err());
+        assert!(config::Config::parse("bus-policy = some", "a").is_err());
+    }
+
+    #[test]
+    fn test_tombstones() {
+        let host = |id| HostId::new_less_safe(id).unwrap();
+        let mut maps = maps::Maps::default();
+        let first = u32::from(maps.next_id(host(7), None).unwrap());
+        let second = u32::from(maps.next_id(host(8), None).unwrap());
+        assert_eq!(maps.remove_host_id(host(7)).map(u32::from), Some(first));
+        assert!(maps.lookup_host_id(host(7)).is_none());
+        let found = maps.lookup_host_id_or_tombstone(host(7));
+        assert_eq!(found.map(u32::from), Some(first));
+        // Tombstoned guest IDs are not reused, and live mappings win.
+        let third = u32::from(maps.next_id(host(7), None).unwrap());
+        assert!(third != first && third != second);
+        let found = maps.lookup_host_id_or_tombstone(host(7));
+        assert_eq!(found.map(u32::from), Some(third));
+        maps.remove_host_id(host(8));
+        let found = maps.lookup_host_id_or_tombstone(host(8));
+        assert_eq!(found.map(u32::from), Some(second));
+        // A restarted daemon reuses host IDs, so nothing survives a clear.
+        maps.clear();
+        assert!(maps.lookup_host_id_or_tombstone(host(7)).is_none());
+        assert!(maps.lookup_host_id_or_tombstone(host(8)).is_none());
+        assert_eq!(maps.stats().tombstones, 0);
+    }
+
+    #[test]
+    fn test_map_stats() {
+        let host = |id| HostId::new_less_safe(id).unwrap();
+        let mut maps = maps::Maps::default();
+        assert_eq!(maps.stats(), Default::default());
+        for id in 1..=3 {
+            maps.next_id(host(id), None);
+        }
+        maps.remove_host_id(host(2));
+        let stats = maps.stats();
+        assert_eq!((stats.live, stats.tombstones, stats.free_ids), (2, 1, 0));
+        assert!(stats.oldest_tombstone.is_some());
+        maps.tombstone_grace = std::time::Duration::ZERO;
+        maps.remove_host_id(host(3));
+        let stats = maps.stats();
+        assert_eq!((stats.live, stats.tombstones, stats.free_ids), (1, 1, 1));
+    }
+
+    #[test]
+    fn test_close_policy() {
+        let host = |id| HostId::new_less_safe(id).unwrap();
+        let resolve = |maps: &maps::Maps, id| maps.resolve_guest_id(id).map(u32::from);
+        // Each qube has its own server process, and so its own maps.
+        let (mut work, mut personal) = (maps::Maps::default(), maps::Maps::default());
+        let work_id = u32::from(work.next_id(host(10), None).unwrap());
+        let personal_id = u32::from(personal.next_id(host(11), None).unwrap());
+        assert_eq!(resolve(&work, work_id), Some(10));
+        assert_eq!(resolve(&personal, personal_id), Some(11));
+        // Guest IDs are handed out from 1 in every qube, so the same
+        // number refers to a different notification in each.
+        assert_eq!(work_id, personal_id);
+        let other_id = u32::from(personal.next_id(host(12), None).unwrap());
+        assert_eq!(resolve(&work, other_id), None);
+        // Host IDs are never accepted: not of the qube's own notifications,
+        // not of other qubes', and not of dom0 applications'.
+        for host_id in [10, 11, 12, 13, u32::MAX] {
+            assert_eq!(resolve(&work, host_id), None, "host ID {}", host_id);
+        }
+        assert_eq!(resolve(&work, 0), None);
+        // Closed notifications cannot be closed again, even while the
+        // tombstone lets signals for them through.
+        work.remove_host_id(host(10));
+        assert_eq!(resolve(&work, work_id), None);
+        assert!(work.lookup_host_id_or_tombstone(host(10)).is_some());
+        work.clear();
+        assert_eq!(resolve(&personal, personal_id), Some(11));
+    }
+
+    #[test]
+    fn test_language_tags() {
+        for tag in ["ja", "pt-BR", "zh-Hant-TW", "es-419"] {
+            assert!(is_valid_language_tag(tag), "{tag:?} should be valid");
+        }
+        for tag in ["EN", "en_US", "en-us", "zh-hant", "en-US-x", "C", ""] {
+            assert!(!is_valid_language_tag(tag), "{tag:?} should be invalid");
+        }
+        assert_eq!(
+            language_tag_from_locale("pt_BR.UTF-8").as_deref(),
+            Some("pt-BR")
+        );
+        assert_eq!(
+            language_tag_from_locale("sr_RS@latin").as_deref(),
+            Some("sr-RS")
+        );
+        assert_eq!(language_tag_from_locale("C"), None);
+        assert_eq!(language_tag_from_locale("POSIX"), None);
+    }
+
+    #[test]
+    fn test_reserved_actions() {
+        assert!(is_reserved_action("default") && is_reserved_action("settings"));
+        assert!(!is_reserved_action("Default") && !is_reserved_action("later"));
+        // Passed on unchanged, even without labels.
+        let actions = ["default", "", "settings", "", "later", "Later"].map(str::to_owned);
+        assert_eq!(sanitize_actions(&actions, 60).unwrap(), actions);
+        let actions = ["qubes-open-url-0", "Open"].map(str::to_owned);
+        assert!(sanitize_actions(&actions, 60).is_err());
+        // And so are the signals for them.
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("hello").action("settings", ""));
+            let received = sim.after(60).await;
+            let Some(&ReplyMessage::Id { id, sequence: 0 }) = received.last() else {
+                panic!("unexpected replies {:?}", received)
+            };
+            sim.signal(actor::Event::Invoked {
+                host_id: sim.daemon.host_id(id),
+                action: SETTINGS_ACTION.to_owned(),
+            });
+            assert!(matches!(
+                &sim.after(10).await[..],
+                [ReplyMessage::ActionInvoked { action, .. }] if action == "settings"
+            ));
+        });
+    }
+
+    #[test]
+    fn test_invalid_id_reply() {
+        use bincode::Options as _;
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes();
+        let bytes = options.serialize(&ReplyMessage::invalid_id(7, 3)).unwrap();
+        match options.deserialize(&bytes).unwrap() {
+            ReplyMessage::DBusError {
+                name,
+                message: Some(message),
+                sequence: 3,
+            } => {
+                assert_eq!(name, INVALID_ID_ERROR);
+                assert!(zbus::names::ErrorName::try_from(&*name).is_ok());
+                assert!(message.contains('7'));
+            }
+            reply => panic!("unexpected {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn test_free_ids() {
+        let host = |id| HostId::new_less_safe(id).unwrap();
+        let mut maps = maps::Maps::default();
+        maps.tombstone_grace = std::time::Duration::ZERO;
+        let ids: Vec<u32> = (1..=5)
+            .map(|i| maps.next_id(host(i), None).unwrap().into())
+            .collect();
+        assert_eq!(ids, [2, 3, 4, 5, 6]);
+        maps.remove_host_id(host(4));
+        maps.remove_host_id(host(2));
+        // Released IDs are reused in the order they were released.
+        assert_eq!(u32::from(maps.next_id(host(6), None).unwrap()), 5);
+        assert_eq!(u32::from(maps.next_id(host(7), None).unwrap()), 3);
+        assert_eq!(u32::from(maps.next_id(host(8), None).unwrap()), 7);
+        // A released ID the guest took back is not handed out again.
+        maps.remove_host_id(host(8));
+        maps.next_id(host(9), GuestId::new_less_safe(7));
+        assert_eq!(u32::from(maps.next_id(host(10), None).unwrap()), 8);
+
+        // Random allocations and releases never hand out a live ID.
+        let mut maps = maps::Maps::default();
+        maps.tombstone_grace = std::time::Duration::ZERO;
+        let mut live = std::collections::HashMap::new();
+        let mut state = 1u32;
+        for next_host in 1..10_000 {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            if state >> 30 != 0 || live.is_empty() {
+                let guest = u32::from(maps.next_id(host(next_host), None).unwrap());
+                assert!(live.insert(guest, next_host).is_none(), "{} reused", guest);
+            } else {
+                let &guest = live.keys().next().unwrap();
+                let host_id = live.remove(&guest).unwrap();
+                assert_eq!(
+                    maps.remove_host_id(host(host_id)).map(u32::from),
+                    Some(guest)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_guest_id_exhaustion() {
+        let host = |id| HostId::new_less_safe(id).unwrap();
+        let guest = |id| GuestId::new_less_safe(id);
+        let mut maps = maps::Maps::default();
+        // The guest holds a long run of IDs ahead of the counter.
+        let run = 100_000;
+        for id in 2..2 + run {
+            maps.next_id(host(id), guest(id));
+        }
+        // Allocation skips the run, once.
+        assert_eq!(
+            maps.next_id(host(1_000_000), None).map(u32::from),
+            Some(2 + run)
+        );
+        assert_eq!(
+            maps.next_id(host(1_000_001), None).map(u32::from),
+            Some(3 + run)
+        );
+        // Released IDs of the run are reused once their tombstone expires.
+        maps.tombstone_grace = std::time::Duration::ZERO;
+        maps.remove_host_id(host(5));
+        assert_eq!(maps.next_id(host(1_000_002), None).map(u32::from), Some(5));
+    }
+
+    #[test]
+    fn test_read_frame() {
+        use codec::{read_frame, FrameError};
+        fn read_with(framing: Framing, mut input: &[u8]) -> Result<Option<Vec<u8>>, FrameError> {
+            tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap()
+                .block_on(read_frame(&mut input, framing))
+        }
+        let read = |input: &[u8]| read_with(Framing::Plain, input);
+        assert!(matches!(read(b""), Ok(None)));
+        assert_eq!(read(b"\x02\0\0\0ab").unwrap().unwrap(), b"ab");
+        assert_eq!(read(b"\0\0\0\0").unwrap().unwrap(), b"");
+        // Truncated in the length and in the body
+        assert!(matches!(read(b"\x02\0"), Err(FrameError::Truncated)));
+        assert!(matches!(read(b"\x02\0\0\0a"), Err(FrameError::Truncated)));
+        // Oversized frames are rejected before anything is allocated.
+        let size = MAX_MESSAGE_SIZE + 1;
+        assert!(matches!(
+            read(&size.to_le_bytes()),
+            Err(FrameError::TooLarge(s)) if s == size
+        ));
+        assert!(matches!(
+            read(&u32::MAX.to_le_bytes()),
+            Err(FrameError::TooLarge(u32::MAX))
+        ));
+        let mut frame = MAX_MESSAGE_SIZE.to_le_bytes().to_vec();
+        frame.resize(4 + MAX_MESSAGE_SIZE as usize, 0);
+        assert_eq!(
+            read(&frame).unwrap().unwrap().len(),
+            MAX_MESSAGE_SIZE as usize
+        );
+        // The checksum covers the length and the body.
+        let checksum = codec::crc32(&[b"\x02\0\0\0ab"]).to_le_bytes();
+        let frame = [&b"\x02\0\0\0ab"[..], &checksum].concat();
+        assert_eq!(read_with(Framing::Crc32, &frame).unwrap().unwrap(), b"ab");
+        assert!(matches!(
+            read_with(Framing::Crc32, &frame[..7]),
+            Err(FrameError::Truncated)
+        ));
+        for corrupted in [0, 4, 7] {
+            let mut frame = frame.clone();
+            frame[corrupted] ^= 1;
+            assert!(matches!(
+                read_with(Framing::Crc32, &frame),
+                Err(FrameError::BadChecksum { .. } | FrameError::Truncated)
+            ));
+        }
+        let mut frame = frame.clone();
+        frame[5] ^= 0x80;
+        assert!(matches!(
+            read_with(Framing::Crc32, &frame),
+            Err(FrameError::BadChecksum { expected, actual })
+                if expected == u32::from_le_bytes(checksum) && actual != expected
+        ));
+    }
+
+    #[test]
+    fn test_crc32() {
+        // The check value of CRC-32/ISO-HDLC.
+        assert_eq!(codec::crc32(&[b"123456789"]), 0xCBF4_3926);
+        assert_eq!(codec::crc32(&[b"1234", b"", b"56789"]), 0xCBF4_3926);
+        assert_eq!(codec::crc32(&[]), 0);
+    }
+
+    #[test]
+    fn test_wire_u32() {
+        use codec::WireU32;
+        // The byte order is fixed, whatever the host's is.
+        assert_eq!(WireU32::new(0x0403_0201).to_bytes(), [1, 2, 3, 4]);
+        assert_eq!(WireU32::from_bytes([1, 2, 3, 4]).get(), 0x0403_0201);
+        for value in [0, 1, 0xFF, 0x100, MAX_MESSAGE_SIZE, u32::MAX] {
+            assert_eq!(WireU32::new(value).get(), value);
+        }
+        let version = WireU32::new(merge_versions(1, 2));
+        assert_eq!(version.to_bytes(), [2, 0, 1, 0]);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut out = vec![];
+        runtime.block_on(version.write(&mut out)).unwrap();
+        assert_eq!(out, [2, 0, 1, 0]);
+        let read = runtime.block_on(WireU32::read(&mut &out[..])).unwrap();
+        assert_eq!(split_version(read.get()), (1, 2));
+    }
+
+    #[test]
+    fn test_write_frame() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut out = vec![];
+        runtime
+            .block_on(codec::write_frame(&mut out, Framing::Plain, b"abc"))
+            .unwrap();
+        runtime
+            .block_on(codec::write_frame(&mut out, Framing::Plain, b""))
+            .unwrap();
+        assert_eq!(out, b"\x03\0\0\0abc\0\0\0\0");
+        let mut input = &out[..];
+        let frame = runtime.block_on(codec::read_frame(&mut input, Framing::Plain));
+        assert_eq!(frame.unwrap().unwrap(), b"abc");
+        let mut out = vec![];
+        runtime
+            .block_on(codec::write_frame(&mut out, Framing::Crc32, b"abc"))
+            .unwrap();
+        let checksum = codec::crc32(&[b"\x03\0\0\0abc"]).to_le_bytes();
+        assert_eq!(out, [&b"\x03\0\0\0abc"[..], &checksum].concat());
+        let frame = runtime.block_on(codec::read_frame(&mut &out[..], Framing::Crc32));
+        assert_eq!(frame.unwrap().unwrap(), b"abc");
+        // Frames the peer would reject are not written at all.
+        let mut out = vec![];
+        let big = vec![0; MAX_MESSAGE_SIZE as usize + 1];
+        let e = runtime
+            .block_on(codec::write_frame(&mut out, Framing::Plain, &big))
+            .unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(out.is_empty());
+    }
+
+    /// Resident set size of this process, in kB.
+    fn rss_kb() -> u64 {
+        let status = std::fs::read_to_string("/proc/self/status").unwrap();
+        let line = status.lines().find(|l| l.starts_with("VmRSS:")).unwrap();
+        line.split_whitespace().nth(1).unwrap().parse().unwrap()
+    }
+
+    /// Pushes notifications, expiries, dismissals, and restarts of the main
+    /// daemon and a mirror through an [`actor::Actor`] with [`FakeDaemon`]s,
+    /// and checks that nothing grows without bound.  Takes a while, and
+    /// measures the whole process, so run it on its own:
+    /// `cargo test --lib -- --ignored test_soak`.
+    #[test]
+    #[ignore]
+    fn test_soak() {
+        const ROUNDS: u64 = 300_000;
+        const BATCH: u64 = 1000;
+        const LIVE: usize = 1000;
+        const RESTART_EVERY: u64 = 50_000;
+        let fds = || std::fs::read_dir("/proc/self/fd").unwrap().count();
+        let fds_before = fds();
+        simulate_with(MINOR_VERSION, 1, false, 1, |sim| async move {
+            let mirror = sim.mirrors[0].clone();
+            let mut live = std::collections::VecDeque::new();
+            // Replies, dismissals, and capability reports.
+            let mut counts = [0u64; 3];
+            let mut tally =
+                |received: Vec<ReplyMessage>, live: &mut std::collections::VecDeque<u32>| {
+                    for reply in received {
+                        match reply {
+                            ReplyMessage::Id { id, .. } => {
+                                counts[0] += 1;
+                                live.push_back(id)
+                            }
+                            // Calls in flight when the daemon goes away fail.
+                            ReplyMessage::DBusError { .. } => counts[0] += 1,
+                            ReplyMessage::Dismissed { .. } => counts[1] += 1,
+                            ReplyMessage::DaemonCapabilities { .. } => counts[2] += 1,
+                            ReplyMessage::Sanitized { .. } | ReplyMessage::ExpireTimeout { .. } => {
+                            }
+                            reply => panic!("unexpected {:?}", reply),
+                        }
+                    }
+                };
+            let mut rss_after_warmup = 0;
+            for start in (0..ROUNDS).step_by(BATCH as usize) {
+                if start > 0 && start % RESTART_EVERY == 0 {
+                    for bus in [0, 1] {
+                        for new_owner in [false, true] {
+                            sim.signal(actor::Event::OwnerChanged { bus, new_owner })
+                        }
+                    }
+                }
+                for sequence in start..start + BATCH {
+                    let notification =
+                        NotificationBuilder::new(format!("Notification {}", sequence));
+                    match sequence % 2 {
+                        0 => sim.notify(
+                            sequence,
+                            notification.expire_timeout(ExpireTimeout::millis(500)),
+                        ),
+                        _ => sim.notify(sequence, notification),
+                    }
+                }
+                tally(sim.after(1000).await, &mut live);
+                // The user dismisses the oldest notifications, and the
+                // mirror reports the copies it was told to close.
+                while live.len() > LIVE {
+                    let id = live.pop_front().unwrap();
+                    let host_id = sim.daemon.maps.borrow().resolve_guest_id(id);
+                    if let Some(host_id) = host_id {
+                        sim.signal(actor::Event::Closed {
+                            host_id: host_id.into(),
+                            reason: 2,
+                        })
+                    }
+                }
+                tally(sim.after(100).await, &mut live);
+                for id in std::mem::take(&mut *mirror.closes.borrow_mut()) {
+                    let host_id = mirror.maps.borrow().resolve_guest_id(id);
+                    if let Some(host_id) = host_id {
+                        sim.signal(actor::Event::MirrorClosed {
+                            bus: 1,
+                            host_id: host_id.into(),
+                        })
+                    }
+                }
+                tally(sim.after(100).await, &mut live);
+                // Tombstones are kept for 10 s, in which time 10 batches
+                // are closed.
+                let bound = 4 * (LIVE + 11 * BATCH as usize);
+                assert!(sim.daemon.maps.borrow().footprint() <= bound);
+                assert!(mirror.maps.borrow().footprint() <= bound);
+                assert!(sim.daemon.deadlines.borrow().by_id.len() <= LIVE);
+                if start == RESTART_EVERY {
+                    rss_after_warmup = rss_kb();
+                }
+            }
+            tally(sim.after(1000).await, &mut live);
+            let [replies, dismissals, reports] = counts;
+            assert_eq!(replies, ROUNDS);
+            assert!(dismissals > 0);
+            assert_eq!(reports, ROUNDS / RESTART_EVERY);
+            assert!(
+                rss_kb() < rss_after_warmup + 32 * 1024,
+                "RSS grew from {} kB to {} kB",
+                rss_after_warmup,
+                rss_kb()
+            );
+        });
+        assert_eq!(fds(), fds_before);
+    }
+
+    #[test]
+    fn test_outbound_queue_order() {
+        use bincode::Options as _;
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes();
+        let serialize = move |message: &ReplyMessage| options.serialize(message).unwrap();
+        let queue = codec::OutboundQueue::new();
+        let local_set = tokio::task::LocalSet::new();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        // A reply and the signals for the notification it created, queued
+        // by different tasks.  The reply task queues both of its messages
+        // without awaiting, so nothing can come between them.
+        let replies = queue.clone();
+        local_set.spawn_local(async move {
+            tokio::task::yield_now().await;
+            replies.push(serialize(&ReplyMessage::ExpireTimeout {
+                id: 5,
+                expire_timeout: ExpireTimeout::millis(1000),
+            }));
+            replies.push(serialize(&ReplyMessage::Id { id: 5, sequence: 0 }));
+        });
+        let signals = queue.clone();
+        local_set.spawn_local(async move {
+            signals.push(serialize(&ReplyMessage::ActionInvoked {
+                id: 4,
+                action: "default".to_owned(),
+            }));
+            tokio::task::yield_now().await;
+            tokio::task::yield_now().await;
+            signals.push(serialize(&ReplyMessage::Dismissed { id: 5, reason: 2 }));
+        });
+        runtime.block_on(local_set);
+        assert_eq!(queue.len(), 4);
+        let mut out = vec![];
+        runtime.block_on(queue.write_pending(&mut out)).unwrap();
+        assert!(queue.is_empty());
+        let mut input = &out[..];
+        let mut received = vec![];
+        while let Some(frame) = runtime
+            .block_on(codec::read_frame(&mut input, Framing::Plain))
+            .unwrap()
+        {
+            received.push(options.deserialize::<ReplyMessage>(&frame).unwrap());
+        }
+        assert!(matches!(
+            &received[..],
+            [
+                ReplyMessage::ActionInvoked { id: 4, .. },
+                ReplyMessage::ExpireTimeout { id: 5, .. },
+                ReplyMessage::Id { id: 5, sequence: 0 },
+                ReplyMessage::Dismissed { id: 5, reason: 2 },
+            ]
+        ));
+    }
+
+    /// A notification daemon for [`actor::Actor`] that answers every call
+    /// after `latency` of simulated time.  Clearing it, as the actor does
+    /// when the daemon is replaced, fails the calls in flight the way a
+    /// daemon that went away would.
+    struct FakeDaemon {
+        latency: std::time::Duration,
+        capabilities: std::cell::Cell<Capabilities>,
+        /// Bumped every time the daemon is replaced.
+        generation: std::cell::Cell<u32>,
+        last_host_id: std::cell::Cell<u32>,
+        maps: std::cell::RefCell<Maps>,
+        deadlines: std::cell::RefCell<Deadlines>,
+        control: control::SharedState,
+        /// How the user answers whether to show the qube, and how often
+        /// they were asked.
+        approve: std::cell::Cell<bool>,
+        asked: std::cell::Cell<u32>,
+        /// Whether calls that do not show notifications succeed.
+        reachable: std::cell::Cell<bool>,
+        /// The IDs `close_notification` was called with.
+        closes: std::cell::RefCell<Vec<u32>>,
+        /// Guest IDs of notifications shown without sound, and of those
+        /// that were when the daemon went away.
+        stripped: std::cell::RefCell<Vec<u32>>,
+        orphaned: std::cell::RefCell<Vec<u32>>,
+    }
+
+    impl FakeDaemon {
+        fn new(latency: u64) -> Self {
+            Self {
+                latency: std::time::Duration::from_millis(latency),
+                capabilities: std::cell::Cell::new(Capabilities::BODY | Capabilities::ACTIONS),
+                generation: Default::default(),
+                last_host_id: Default::default(),
+                maps: Default::default(),
+                deadlines: Default::default(),
+                control: Default::default(),
+                approve: std::cell::Cell::new(true),
+                asked: Default::default(),
+                reachable: std::cell::Cell::new(true),
+                closes: Default::default(),
+                stripped: Default::default(),
+                orphaned: Default::default(),
+            }
+        }
+        fn next_host_id(&self) -> HostId {
+            let host_id = self.last_host_id.get() + 1;
+            self.last_host_id.set(host_id);
+            HostId::new_less_safe(host_id).unwrap()
+        }
+        /// The host ID of the notification the guest knows as `guest_id`.
+        fn host_id(&self, guest_id: u32) -> u32 {
+            self.maps
+                .borrow()
+                .resolve_guest_id(guest_id)
+                .unwrap()
+                .into()
+        }
+    }
+
+    impl actor::Daemon for FakeDaemon {
+        async fn send_call(
+            &self,
+            notification: Notification,
+            _received: SystemTime,
+            _call: Option<String>,
+        ) -> zbus::Result<Sent> {
+            let generation = self.generation.get();
+            tokio::time::sleep(self.latency).await;
+            if self.generation.get() != generation {
+                return Err(zbus::Error::Failure("daemon went away".to_owned()));
+            }
+            // A live notification is replaced in place, keeping its ID.
+            let replaced = notification.replaces_id();
+            let host_id = self.maps.borrow().resolve_guest_id(replaced);
+            let (host_id, guest_id) = match host_id {
+                Some(host_id) => (host_id, GuestId::new_less_safe(replaced)),
+                None => (self.next_host_id(), None),
+            };
+            let id = self.maps.borrow_mut().next_id(host_id, guest_id).unwrap();
+            let deadline = match notification.expire_timeout() {
+                ExpireTimeout::Millis(millis) => Some(
+                    tokio::time::Instant::now()
+                        + std::time::Duration::from_millis(millis.get().into()),
+                ),
+                _ => None,
+            };
+            match deadline {
+                Some(deadline) => self.deadlines.borrow_mut().insert(id.into(), deadline),
+                None => drop(self.deadlines.borrow_mut().remove(id.into())),
+            }
+            let muted = self.control.lock().unwrap().muted;
+            Ok(Sent {
+                id,
+                truncated: false,
+                deadline,
+                suppressed: muted.then_some(Suppression::Muted),
+            })
+        }
+        async fn close_notification(&self, untrusted_id: u32) -> zbus::Result<Option<(u32, bool)>> {
+            tokio::time::sleep(self.latency).await;
+            self.closes.borrow_mut().push(untrusted_id);
+            let found = self.maps.borrow().resolve_guest_id(untrusted_id).is_some();
+            Ok(found.then_some((untrusted_id, false)))
+        }
+        async fn refresh_capabilities(&self) -> zbus::Result<()> {
+            tokio::time::sleep(self.latency).await;
+            match self.reachable.get() {
+                true => Ok(()),
+                false => Err(zbus::Error::Failure("daemon went away".to_owned())),
+            }
+        }
+        async fn resend_upgradable(&self) -> Vec<(u32, u32)> {
+            if !self.capabilities.get().contains(Capabilities::SOUND) {
+                return vec![];
+            }
+            let orphaned = std::mem::take(&mut *self.orphaned.borrow_mut());
+            let mut reshown = vec![];
+            for id in orphaned {
+                tokio::time::sleep(self.latency).await;
+                let host_id = self.next_host_id();
+                let new_id = self.maps.borrow_mut().next_id(host_id, None).unwrap();
+                reshown.push((id, new_id.into()))
+            }
+            reshown
+        }
+        async fn ask_approval(&self, _qube_name: &str) {
+            // The user takes a second to answer.
+            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            self.asked.set(self.asked.get() + 1);
+            self.control.lock().unwrap().muted = !self.approve.get();
+        }
+        async fn expire_overdue(&self) -> Vec<u32> {
+            let mut expired = vec![];
+            loop {
+                let first = self.deadlines.borrow().first();
+                let Some((deadline, guest_id)) = first else {
+                    break;
+                };
+                if deadline > tokio::time::Instant::now() {
+                    break;
+                }
+                let host_id = self.host_id(guest_id);
+                expired.extend(self.notification_closed(host_id, control::CLOSED_EXPIRED))
+            }
+            expired
+        }
+        fn capabilities(&self) -> Capabilities {
+            self.capabilities.get()
+        }
+        fn next_deadline(&self) -> Option<tokio::time::Instant> {
+            self.deadlines
+                .borrow()
+                .first()
+                .map(|(deadline, _)| deadline)
+        }
+        fn notification_closed(&self, id: u32, _reason: u32) -> Option<u32> {
+            let id = self
+                .maps
+                .borrow_mut()
+                .remove_host_id(HostId::new_less_safe(id)?)?;
+            self.deadlines.borrow_mut().remove(id.into());
+            Some(id.into())
+        }
+        fn remove_host_id(&self, id: u32) -> Option<u32> {
+            self.notification_closed(id, 0)
+        }
+        fn translate_host_id(&self, id: u32) -> Option<u32> {
+            let maps = self.maps.borrow();
+            maps.lookup_host_id_or_tombstone(HostId::new_less_safe(id)?)
+                .map(u32::from)
+        }
+        fn url_for_action(&self, _id: u32, _action: &str) -> Option<String> {
+            None
+        }
+        fn effective_expire_timeout(&self, requested: ExpireTimeout) -> ExpireTimeout {
+            requested
+        }
+        fn control_state(&self) -> control::SharedState {
+            self.control.clone()
+        }
+        fn clear(&self) {
+            self.maps.borrow_mut().clear();
+            self.deadlines.borrow_mut().clear();
+            let stripped = std::mem::take(&mut *self.stripped.borrow_mut());
+            self.orphaned.borrow_mut().extend(stripped);
+            self.generation.set(self.generation.get() + 1);
+        }
+    }
+
+    /// An [`actor::Actor`] with a [`FakeDaemon`], fed by a test script.
+    struct Sim {
+        features: NegotiatedFeatures,
+        daemon: Rc<FakeDaemon>,
+        mirrors: Vec<Rc<FakeDaemon>>,
+        requests: futures_channel::mpsc::UnboundedSender<actor::Event>,
+        signals: futures_channel::mpsc::UnboundedSender<actor::Event>,
+        out: codec::OutboundQueue,
+        actor: tokio::task::JoinHandle<Result<(), String>>,
+    }
+
+    impl Sim {
+        /// Send `message`, a [`Request`] or, before [`CLOSE_VERSION`], a
+        /// bare [`Message`].
+        fn send(&self, message: &impl Serialize) {
+            use codec::WireCodec as _;
+            let bytes = self.features.encoding().encode(message).unwrap();
+            self.requests
+                .unbounded_send(actor::Event::Frame(bytes))
+                .unwrap()
+        }
+        fn notify(&self, sequence: u64, notification: NotificationBuilder) {
+            let message = Message {
+                id: sequence,
+                notification: notification.build().unwrap(),
+            };
+            match self.features.minor() >= CLOSE_VERSION {
+                true => self.send(&Request::Notify(message)),
+                false => self.send(&message),
+            }
+        }
+        fn signal(&self, event: actor::Event) {
+            self.signals.unbounded_send(event).unwrap()
+        }
+        /// Let `millis` of simulated time pass, and return what was sent to
+        /// the qube meanwhile.
+        async fn after(&self, millis: u64) -> Vec<ReplyMessage> {
+            use codec::WireCodec as _;
+            tokio::time::sleep(std::time::Duration::from_millis(millis)).await;
+            let mut out = vec![];
+            self.out.write_pending(&mut out).await.unwrap();
+            let mut input = &out[..];
+            let mut received = vec![];
+            while let Some(frame) = codec::read_frame(&mut input, self.features.framing())
+                .await
+                .unwrap()
+            {
+                received.push(self.features.encoding().decode(&frame).unwrap());
+            }
+            received
+        }
+    }
+
+    /// Run `script` against an actor for a qube with minor version
+    /// `minor`, whose daemon answers after `latency` milliseconds.  The
+    /// clock is paused, and only moves when every task waits for it, so
+    /// each run sees the same interleaving however slow the machine is.
+    fn simulate<F: std::future::Future<Output = ()>>(
+        minor: u16,
+        latency: u64,
+        script: impl FnOnce(Sim) -> F,
+    ) {
+        simulate_with(minor, latency, false, 0, script)
+    }
+
+    /// [`simulate`], with the user asked to approve the qube if
+    /// `approval`, and notifications copied to `mirrors` mirrors.
+    fn simulate_with<F: std::future::Future<Output = ()>>(
+        minor: u16,
+        latency: u64,
+        approval: bool,
+        mirrors: usize,
+        script: impl FnOnce(Sim) -> F,
+    ) {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let local_set = tokio::task::LocalSet::new();
+        local_set.block_on(&runtime, async move {
+            use futures_util::StreamExt as _;
+            let config = config::Config::default();
+            let features = NegotiatedFeatures::from_version(minor);
+            let daemon = Rc::new(FakeDaemon::new(latency));
+            let mirrors: Vec<_> = (0..mirrors)
+                .map(|_| Rc::new(FakeDaemon::new(latency)))
+                .collect();
+            let (requests, request_events) = futures_channel::mpsc::unbounded();
+            let (signals, signal_events) = futures_channel::mpsc::unbounded();
+            let out = codec::OutboundQueue::with_framing(features.framing());
+            let calls =
+                std::sync::Arc::new(tokio::sync::Semaphore::new(config.max_concurrent_calls));
+            let actor = actor::Actor::new(
+                "work".to_owned(),
+                features,
+                &config,
+                daemon.clone(),
+                mirrors.clone(),
+                calls,
+                out.clone(),
+            )
+            .with_approval(approval);
+            let actor = tokio::task::spawn_local(
+                actor.run(request_events.boxed_local(), signal_events.boxed_local()),
+            );
+            script(Sim {
+                features,
+                daemon,
+                mirrors,
+                requests,
+                signals,
+                out,
+                actor,
+            })
+            .await
+        })
+    }
+
+    #[test]
+    fn test_simulated_notify() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(
+                0,
+                NotificationBuilder::new("hello").expire_timeout(ExpireTimeout::millis(1000)),
+            );
+            assert!(matches!(
+                &sim.after(40).await[..],
+                [ReplyMessage::DaemonCapabilities { capabilities }]
+                    if *capabilities == u32::from((Capabilities::BODY | Capabilities::ACTIONS).bits())
+            ));
+            let received = sim.after(20).await;
+            let Some(&ReplyMessage::Id { id, sequence: 0 }) = received.last() else {
+                panic!("unexpected replies {:?}", received)
+            };
+            assert!(matches!(
+                &received[..],
+                [
+                    ReplyMessage::Sanitized { id: sanitized, truncated: false },
+                    ReplyMessage::ExpireTimeout { id: expiring, .. },
+                    ReplyMessage::Id { .. },
+                ] if *sanitized == id && *expiring == id
+            ));
+            // Shown at 50ms, so it expires at 1050ms.
+            assert!(sim.after(980).await.is_empty());
+            assert!(matches!(
+                &sim.after(20).await[..],
+                &[ReplyMessage::Dismissed { id: dismissed, reason: control::CLOSED_EXPIRED }]
+                    if dismissed == id
+            ));
+        });
+        // An old client only gets the reply, and still learns of the
+        // expiry.
+        simulate(PROVISIONAL_IDS_VERSION, 50, |sim| async move {
+            sim.notify(
+                3,
+                NotificationBuilder::new("hello").expire_timeout(ExpireTimeout::millis(1000)),
+            );
+            let received = sim.after(60).await;
+            let &[ReplyMessage::Id { id, sequence: 3 }] = &received[..] else {
+                panic!("unexpected replies {:?}", received)
+            };
+            assert!(matches!(
+                &sim.after(1000).await[..],
+                &[ReplyMessage::Dismissed { id: dismissed, .. }] if dismissed == id
+            ));
+        });
+    }
+
+    #[test]
+    fn test_simulated_expiry() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(
+                0,
+                NotificationBuilder::new("hello").expire_timeout(ExpireTimeout::millis(1000)),
+            );
+            sim.notify(
+                1,
+                NotificationBuilder::new("other").expire_timeout(ExpireTimeout::millis(3000)),
+            );
+            let received = sim.after(60).await;
+            let ids: Vec<u32> = received
+                .iter()
+                .filter_map(|message| match *message {
+                    ReplyMessage::Id { id, .. } => Some(id),
+                    _ => None,
+                })
+                .collect();
+            let &[id, other] = &ids[..] else {
+                panic!("unexpected replies {:?}", received)
+            };
+            // Replaced at 550ms with a longer timeout, it no longer expires
+            // at 1050ms.
+            sim.after(440).await;
+            sim.notify(
+                2,
+                NotificationBuilder::new("again")
+                    .replaces_id(id)
+                    .expire_timeout(ExpireTimeout::millis(5000)),
+            );
+            assert!(matches!(
+                &sim.after(60).await[..],
+                [.., ReplyMessage::Id { id: replaced, sequence: 2 }] if *replaced == id
+            ));
+            assert!(sim.after(1000).await.is_empty());
+            // The other one expires at 3050ms, and the replacement at
+            // 5550ms, each once.
+            assert!(matches!(
+                &sim.after(1500).await[..],
+                &[ReplyMessage::Dismissed { id: dismissed, reason: control::CLOSED_EXPIRED }]
+                    if dismissed == other
+            ));
+            assert!(sim.after(2400).await.is_empty());
+            assert!(matches!(
+                &sim.after(100).await[..],
+                &[ReplyMessage::Dismissed { id: dismissed, reason: control::CLOSED_EXPIRED }]
+                    if dismissed == id
+            ));
+            assert_eq!(sim.daemon.deadlines.borrow().first(), None);
+            assert!(sim.after(1000).await.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_simulated_reshown() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("hello"));
+            sim.notify(1, NotificationBuilder::new("other"));
+            let received = sim.after(60).await;
+            let ids: Vec<u32> = received
+                .iter()
+                .filter_map(|message| match *message {
+                    ReplyMessage::Id { id, .. } => Some(id),
+                    _ => None,
+                })
+                .collect();
+            let &[id, _] = &ids[..] else {
+                panic!("unexpected replies {:?}", received)
+            };
+            sim.daemon.stripped.borrow_mut().push(id);
+            // A daemon that plays sounds takes over, and shows the first
+            // notification again, under an ID the qube is told of.
+            let capabilities = sim.daemon.capabilities.get() | Capabilities::SOUND;
+            sim.daemon.capabilities.set(capabilities);
+            for new_owner in [false, true] {
+                sim.signal(actor::Event::OwnerChanged { bus: 0, new_owner })
+            }
+            let received = sim.after(200).await;
+            let &[ReplyMessage::Reshown { id: old, new_id }, ReplyMessage::DaemonCapabilities {
+                capabilities: reported,
+            }] = &received[..]
+            else {
+                panic!("unexpected replies {:?}", received)
+            };
+            assert_eq!(old, id);
+            assert_eq!(reported, u32::from(capabilities.bits()));
+            assert_eq!(sim.daemon.maps.borrow().stats().live, 1);
+            sim.daemon.host_id(new_id);
+            // Nothing is left to show again.
+            sim.signal(actor::Event::OwnerChanged {
+                bus: 0,
+                new_owner: true,
+            });
+            assert!(matches!(
+                &sim.after(200).await[..],
+                [ReplyMessage::DaemonCapabilities { .. }]
+            ));
+        });
+    }
+
+    #[test]
+    fn test_simulated_probe() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("hello"));
+            let received = sim.after(60).await;
+            let Some(&ReplyMessage::Id { id, .. }) = received.last() else {
+                panic!("unexpected replies {:?}", received)
+            };
+            // The daemon goes away, and nothing takes over yet.
+            sim.daemon.stripped.borrow_mut().push(id);
+            sim.signal(actor::Event::OwnerChanged {
+                bus: 0,
+                new_owner: false,
+            });
+            // Probing a daemon that did not change shows nothing again.
+            sim.send(&Request::Probe { sequence: 1 });
+            assert!(matches!(
+                &sim.after(60).await[..],
+                [ReplyMessage::Capabilities { sequence: 1, .. }]
+            ));
+            assert_eq!(*sim.daemon.orphaned.borrow(), [id]);
+            // Nor does probing again within the interval, which is answered
+            // right away.
+            sim.send(&Request::Probe { sequence: 2 });
+            assert!(matches!(
+                &sim.after(1).await[..],
+                [ReplyMessage::Capabilities { sequence: 2, .. }]
+            ));
+            assert_eq!(*sim.daemon.orphaned.borrow(), [id]);
+            // Once the daemon gained capabilities, it does.
+            sim.after(5000).await;
+            sim.send(&Request::Probe { sequence: 3 });
+            sim.after(10).await;
+            sim.daemon
+                .capabilities
+                .set(Capabilities::BODY | Capabilities::SOUND);
+            let received = sim.after(150).await;
+            assert!(
+                matches!(
+                    &received[..],
+                    [
+                        ReplyMessage::Capabilities { sequence: 3, .. },
+                        ReplyMessage::Reshown { id: old, .. },
+                    ] if *old == id
+                ),
+                "{:?}",
+                received
+            );
+            assert!(sim.daemon.orphaned.borrow().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_simulated_mirror() {
+        simulate_with(MINOR_VERSION, 50, false, 1, |sim| async move {
+            sim.notify(
+                0,
+                NotificationBuilder::new("hello").expire_timeout(ExpireTimeout::millis(1000)),
+            );
+            // Shown at 50ms, and on the mirror at 100ms.  Both daemons
+            // start out with the same IDs.
+            let received = sim.after(200).await;
+            let Some(&ReplyMessage::Id { id, .. }) = received.last() else {
+                panic!("unexpected replies {:?}", received)
+            };
+            let mirror = &sim.mirrors[0];
+            assert_eq!(mirror.maps.borrow().stats().live, 1);
+            let host_id = mirror.host_id(id);
+            // The copy is closed when the notification expires at 1050ms,
+            // which takes the mirror until 1100ms.
+            sim.after(950).await;
+            assert_eq!(*mirror.closes.borrow(), [id]);
+            // The mirror forgets it once its daemon reports it closed.
+            sim.signal(actor::Event::MirrorClosed { bus: 1, host_id });
+            sim.after(1).await;
+            assert_eq!(mirror.maps.borrow().stats().live, 0);
+            // Notifications closed in the mirror's daemon only are forgotten
+            // by the mirror too.
+            sim.notify(1, NotificationBuilder::new("again"));
+            let received = sim.after(200).await;
+            let Some(&ReplyMessage::Id { id, .. }) = received.last() else {
+                panic!("unexpected replies {:?}", received)
+            };
+            let host_id = mirror.host_id(id);
+            sim.signal(actor::Event::MirrorClosed { bus: 1, host_id });
+            assert!(sim.after(1).await.is_empty());
+            assert_eq!(mirror.maps.borrow().stats().live, 0);
+        });
+    }
+
+    #[test]
+    fn test_simulated_mirror_dismissal() {
+        simulate_with(MINOR_VERSION, 50, false, 1, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("hello"));
+            let received = sim.after(200).await;
+            let Some(&ReplyMessage::Id { id, .. }) = received.last() else {
+                panic!("unexpected replies {:?}", received)
+            };
+            // The user dismisses the notification on the main daemon, and
+            // the copy is closed too.
+            let host_id = sim.daemon.host_id(id);
+            sim.signal(actor::Event::Closed { host_id, reason: 2 });
+            assert!(matches!(
+                sim.after(100).await[..],
+                [ReplyMessage::Dismissed { reason: 2, .. }]
+            ));
+            let mirror = &sim.mirrors[0];
+            assert_eq!(*mirror.closes.borrow(), [id]);
+        });
+    }
+
+    #[test]
+    fn test_simulated_daemon_restart() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("lost"));
+            assert!(matches!(
+                &sim.after(20).await[..],
+                [ReplyMessage::DaemonCapabilities { .. }]
+            ));
+            // The daemon is replaced by one with more capabilities while
+            // the call is in flight.
+            sim.daemon
+                .capabilities
+                .set(Capabilities::BODY | Capabilities::ACTIONS | Capabilities::INLINE_REPLY);
+            sim.signal(actor::Event::OwnerChanged {
+                bus: 0,
+                new_owner: true,
+            });
+            assert!(matches!(
+                &sim.after(40).await[..],
+                [ReplyMessage::UnknownError { sequence: 0 }]
+            ));
+            assert!(matches!(
+                &sim.after(40).await[..],
+                [ReplyMessage::DaemonCapabilities { capabilities }]
+                    if *capabilities & u32::from(Capabilities::INLINE_REPLY.bits()) != 0
+            ));
+            sim.notify(1, NotificationBuilder::new("shown"));
+            assert!(matches!(
+                &sim.after(60).await[..],
+                [
+                    ReplyMessage::Sanitized { .. },
+                    ReplyMessage::ExpireTimeout { .. },
+                    ReplyMessage::Id { sequence: 1, .. }
+                ]
+            ));
+        });
+    }
+
+    #[test]
+    fn test_simulated_late_action() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(
+                0,
+                NotificationBuilder::new("hello").action("default", "Open"),
+            );
+            let received = sim.after(60).await;
+            let Some(&ReplyMessage::Id { id, sequence: 0 }) = received.last() else {
+                panic!("unexpected replies {:?}", received)
+            };
+            let host_id = sim.daemon.host_id(id);
+            sim.signal(actor::Event::Closed { host_id, reason: 2 });
+            // The action was invoked just before the notification was
+            // closed, but the signal arrives afterwards.
+            sim.signal(actor::Event::Invoked {
+                host_id,
+                action: "default".to_owned(),
+            });
+            // Not a notification of this qube.
+            sim.signal(actor::Event::Invoked {
+                host_id: host_id + 1,
+                action: "default".to_owned(),
+            });
+            assert!(matches!(
+                &sim.after(10).await[..],
+                [ReplyMessage::Dismissed { id: dismissed, reason: 2 },
+                 ReplyMessage::ActionInvoked { id: invoked, action }]
+                    if *dismissed == id && *invoked == id && action == "default"
+            ));
+        });
+    }
+
+    #[test]
+    fn test_simulated_eof() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("hello"));
+            assert_eq!(sim.after(10).await.len(), 1);
+            sim.requests.unbounded_send(actor::Event::Eof).unwrap();
+            // The call in flight is dropped along with the actor, so its
+            // reply is never sent.
+            assert!(sim.after(100).await.is_empty());
+            assert_eq!(sim.actor.await.unwrap(), Ok(()));
+        });
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.requests
+                .unbounded_send(actor::Event::Frame(vec![0xff; 3]))
+                .unwrap();
+            assert!(sim
+                .actor
+                .await
+                .unwrap()
+                .unwrap_err()
+                .starts_with("malformed message"));
+        });
+    }
+
+    #[test]
+    fn test_simulated_keepalive() {
+        let timeout = config::DEFAULT_KEEPALIVE_TIMEOUT.as_millis() as u64;
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.send(&Request::Ping { sequence: 7 });
+            let out = sim.after(10).await;
+            assert!(matches!(
+                out.last(),
+                Some(ReplyMessage::Pong { sequence: 7 })
+            ));
+            sim.after(timeout - 10).await;
+            sim.send(&Request::Ping { sequence: 8 });
+            assert_eq!(sim.after(timeout - 10).await.len(), 1);
+            assert!(!sim.actor.is_finished());
+            sim.after(20).await;
+            let e = sim.actor.await.unwrap().unwrap_err();
+            assert!(e.starts_with("nothing received"), "{}", e);
+        });
+        // While every slot is taken by a slow call, pings are still
+        // answered, but the silence of the qube does not count.
+        simulate(MINOR_VERSION, 2 * timeout, |sim| async move {
+            let slots = config::DEFAULT_MAX_CONCURRENT_CALLS as u64;
+            for sequence in 0..slots {
+                sim.notify(sequence, NotificationBuilder::new("slow"));
+            }
+            sim.send(&Request::Ping { sequence: slots });
+            let pong = |out: &[ReplyMessage]| matches!(out.last(), Some(ReplyMessage::Pong { sequence }) if *sequence == slots);
+            assert!(pong(&sim.after(10).await));
+            sim.after(2 * timeout).await;
+            assert!(!sim.actor.is_finished());
+        });
+        simulate(PING_VERSION - 1, 50, |sim| async move {
+            sim.send(&Request::Ping { sequence: 0 });
+            let e = sim.actor.await.unwrap().unwrap_err();
+            assert!(e.starts_with("ping not supported"), "{}", e);
+        });
+    }
+
+    #[test]
+    fn test_simulated_batch() {
+        let batch = |sequences: std::ops::Range<u64>| {
+            Request::Batch(
+                sequences
+                    .map(|id| Message {
+                        id,
+                        notification: NotificationBuilder::new("burst").build().unwrap(),
+                    })
+                    .collect(),
+            )
+        };
+        // The calls are made one at a time, and everything sent meanwhile
+        // comes with their replies, in order.
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.after(10).await;
+            sim.send(&batch(0..3));
+            sim.send(&Request::Ping { sequence: 3 });
+            assert!(sim.after(140).await.is_empty());
+            let out = sim.after(20).await;
+            let [ReplyMessage::Batch(replies)] = &out[..] else {
+                panic!("{:?}", out)
+            };
+            let order: Vec<_> = replies
+                .iter()
+                .filter_map(|reply| match *reply {
+                    ReplyMessage::Id { sequence, .. } | ReplyMessage::Pong { sequence } => {
+                        Some(sequence)
+                    }
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(order, [3, 0, 1, 2]);
+            // Nothing is held back once the batch is done.
+            sim.send(&Request::Ping { sequence: 4 });
+            assert!(matches!(
+                &sim.after(10).await[..],
+                [ReplyMessage::Pong { sequence: 4 }]
+            ));
+            sim.send(&Request::Batch(vec![]));
+            assert!(sim.after(10).await.is_empty());
+            assert!(!sim.actor.is_finished());
+        });
+        simulate(BATCH_VERSION - 1, 50, |sim| async move {
+            sim.send(&batch(0..2));
+            let e = sim.actor.await.unwrap().unwrap_err();
+            assert!(e.starts_with("batch not supported"), "{}", e);
+        });
+    }
+
+    #[test]
+    fn test_simulated_history() {
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("first"));
+            sim.notify(1, NotificationBuilder::new("second"));
+            let ids: Vec<u32> = sim
+                .after(60)
+                .await
+                .into_iter()
+                .filter_map(|reply| match reply {
+                    ReplyMessage::Id { id, .. } => Some(id),
+                    _ => None,
+                })
+                .collect();
+            let [first, second] = ids[..] else {
+                panic!("{:?}", ids)
+            };
+            sim.signal(actor::Event::Invoked {
+                host_id: sim.daemon.host_id(first),
+                action: "default".to_owned(),
+            });
+            sim.signal(actor::Event::Replied {
+                host_id: sim.daemon.host_id(second),
+                text: "secret".to_owned(),
+            });
+            sim.signal(actor::Event::Closed {
+                host_id: sim.daemon.host_id(first),
+                reason: 2,
+            });
+            // Not a notification of this qube.
+            sim.signal(actor::Event::Closed {
+                host_id: 1000,
+                reason: 2,
+            });
+            sim.after(10).await;
+            let history = |untrusted_ids: Vec<u32>| async {
+                sim.send(&Request::History {
+                    sequence: 9,
+                    untrusted_ids,
+                });
+                match &sim.after(10).await[..] {
+                    [ReplyMessage::History {
+                        entries,
+                        sequence: 9,
+                    }] => entries.clone(),
+                    out => panic!("{:?}", out),
+                }
+            };
+            assert_eq!(
+                history(vec![first]).await,
+                [
+                    HistoryEntry {
+                        id: first,
+                        outcome: Outcome::ActionInvoked {
+                            action: "default".to_owned()
+                        }
+                    },
+                    HistoryEntry {
+                        id: first,
+                        outcome: Outcome::Dismissed { reason: 2 }
+                    },
+                ]
+            );
+            assert_eq!(
+                history(vec![])
+                    .await
+                    .iter()
+                    .map(|entry| entry.id)
+                    .collect::<Vec<_>>(),
+                [first, second, first]
+            );
+            assert_eq!(history(vec![second]).await[0].outcome, Outcome::Replied);
+            assert!(history(vec![1000]).await.is_empty());
+        });
+        simulate(HISTORY_VERSION - 1, 50, |sim| async move {
+            sim.send(&Request::History {
+                sequence: 0,
+                untrusted_ids: vec![],
+            });
+            let e = sim.actor.await.unwrap().unwrap_err();
+            assert!(e.starts_with("history not supported"), "{}", e);
+        });
+    }
+
+    #[test]
+    fn test_simulated_drain() {
+        let ids = |out: &[ReplyMessage]| {
+            out.iter()
+                .filter(|reply| matches!(reply, ReplyMessage::Id { .. }))
+                .count()
+        };
+        // The calls in flight are answered first, and nothing sent after
+        // the drain is read.
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.after(10).await;
+            sim.notify(0, NotificationBuilder::new("first"));
+            sim.send(&Request::Drain { sequence: 1 });
+            sim.notify(2, NotificationBuilder::new("late"));
+            let out = sim.after(60).await;
+            assert_eq!(ids(&out), 1);
+            assert!(matches!(
+                out.last(),
+                Some(ReplyMessage::Drained { sequence: 1 })
+            ));
+            assert_eq!(sim.actor.await.unwrap(), Ok(()));
+        });
+        // When dom0 shuts down, the qube is asked to drain.
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.after(10).await;
+            sim.signal(actor::Event::Shutdown);
+            assert!(matches!(&sim.after(10).await[..], [ReplyMessage::Draining]));
+            sim.send(&Request::Drain { sequence: 0 });
+            assert!(matches!(
+                &sim.after(10).await[..],
+                [ReplyMessage::Drained { sequence: 0 }]
+            ));
+            assert_eq!(sim.actor.await.unwrap(), Ok(()));
+        });
+        // A qube that does not answer is drained anyway.
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.after(10).await;
+            sim.signal(actor::Event::Shutdown);
+            sim.notify(0, NotificationBuilder::new("first"));
+            assert_eq!(ids(&sim.after(4990).await), 1);
+            assert!(!sim.actor.is_finished());
+            assert!(sim.after(20).await.is_empty());
+            assert_eq!(sim.actor.await.unwrap(), Ok(()));
+        });
+        // Older qubes just see the connection end once the calls are done.
+        simulate(DRAIN_VERSION - 1, 50, |sim| async move {
+            sim.after(10).await;
+            sim.notify(0, NotificationBuilder::new("first"));
+            sim.after(10).await;
+            sim.signal(actor::Event::Shutdown);
+            assert_eq!(ids(&sim.after(50).await), 1);
+            assert_eq!(sim.actor.await.unwrap(), Ok(()));
+        });
+        simulate(DRAIN_VERSION - 1, 50, |sim| async move {
+            sim.send(&Request::Drain { sequence: 0 });
+            let e = sim.actor.await.unwrap().unwrap_err();
+            assert!(e.starts_with("drain not supported"), "{}", e);
+        });
+    }
+
+    #[test]
+    fn test_simulated_resume() {
+        use resume::ClockWatch;
+        use std::time::Duration;
+        let wall = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let monotonic = std::time::Instant::now();
+        let at = |secs: u64| Duration::from_secs(secs);
+        let mut watch = ClockWatch::at(wall, monotonic);
+        assert_eq!(watch.suspended_at(wall + at(10), monotonic + at(10)), None);
+        assert_eq!(
+            watch.suspended_at(wall + at(80), monotonic + at(20)),
+            Some(at(60))
+        );
+        // Small adjustments, and a clock set backwards, are not suspends.
+        assert_eq!(watch.suspended_at(wall + at(92), monotonic + at(30)), None);
+        assert_eq!(watch.suspended_at(wall, monotonic + at(40)), None);
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.after(10).await;
+            sim.signal(actor::Event::Resumed(at(60)));
+            assert!(sim.after(60).await.is_empty());
+            // The daemon changed while suspended.
+            sim.signal(actor::Event::Resumed(at(60)));
+            sim.after(10).await;
+            sim.daemon.capabilities.set(Capabilities::BODY);
+            assert!(matches!(
+                &sim.after(50).await[..],
+                [ReplyMessage::DaemonCapabilities { capabilities }]
+                    if *capabilities == u32::from(Capabilities::BODY.bits())
+            ));
+            // The daemon is gone.
+            sim.daemon.reachable.set(false);
+            sim.signal(actor::Event::Resumed(at(60)));
+            assert!(matches!(
+                &sim.after(60).await[..],
+                [ReplyMessage::ServerRestart]
+            ));
+            assert_eq!(sim.actor.await.unwrap(), Ok(()));
+        });
+    }
+
+    #[test]
+    fn test_simulated_approval() {
+        let config =
+            config::Config::parse("approve-new-qubes = yes\n[a]\napprove-new-qubes = no", "b");
+        assert!(config.unwrap().approve_new_qubes);
+        let config =
+            config::Config::parse("approve-new-qubes = yes\n[a]\napprove-new-qubes = no", "a");
+        assert!(!config.unwrap().approve_new_qubes);
+        assert!(!config::Config::default().approve_new_qubes);
+        let ids = |out: &[ReplyMessage]| {
+            out.iter()
+                .filter(|reply| matches!(reply, ReplyMessage::Id { .. }))
+                .count()
+        };
+        // Every notification waits for the answer, which is asked for once.
+        simulate_with(MINOR_VERSION, 50, true, 0, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("first"));
+            sim.notify(1, NotificationBuilder::new("second"));
+            assert_eq!(ids(&sim.after(900).await), 0);
+            sim.notify(2, NotificationBuilder::new("third"));
+            let out = sim.after(200).await;
+            assert_eq!(ids(&out), 3);
+            assert!(!out
+                .iter()
+                .any(|reply| matches!(reply, ReplyMessage::Suppressed { .. })));
+            sim.notify(3, NotificationBuilder::new("fourth"));
+            assert_eq!(ids(&sim.after(60).await), 1);
+            assert_eq!(sim.daemon.asked.get(), 1);
+        });
+        // Once the user declines, the qube is muted.
+        simulate_with(MINOR_VERSION, 50, true, 0, |sim| async move {
+            sim.daemon.approve.set(false);
+            sim.notify(0, NotificationBuilder::new("first"));
+            assert_eq!(ids(&sim.after(1100).await), 1);
+            assert!(sim.daemon.control.lock().unwrap().muted);
+        });
+        // Pings are answered while every call waits for the answer, and a
+        // call read meanwhile is made in order once it comes.
+        simulate_with(MINOR_VERSION, 50, true, 0, |sim| async move {
+            let calls = config::DEFAULT_MAX_CONCURRENT_CALLS as u64;
+            for sequence in 0..calls {
+                sim.notify(sequence, NotificationBuilder::new("waiting"));
+            }
+            sim.send(&Request::Ping { sequence: 99 });
+            sim.notify(calls, NotificationBuilder::new("stalled"));
+            let out = sim.after(100).await;
+            assert!(out
+                .iter()
+                .any(|reply| matches!(reply, ReplyMessage::Pong { sequence: 99 })));
+            assert_eq!(ids(&out), 0);
+            let out = sim.after(1100).await;
+            let sequences: Vec<_> = out
+                .iter()
+                .filter_map(|reply| match *reply {
+                    ReplyMessage::Id { sequence, .. } => Some(sequence),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(sequences, (0..=calls).collect::<Vec<_>>());
+        });
+        simulate(MINOR_VERSION, 50, |sim| async move {
+            sim.notify(0, NotificationBuilder::new("first"));
+            assert_eq!(ids(&sim.after(60).await), 1);
+            assert_eq!(sim.daemon.asked.get(), 0);
+        });
+    }
+
+    #[test]
+    fn test_lock_file() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap();
+        let dir =
+            std::env::temp_dir().join(format!("notification-lock-test-{}", std::process::id()));
+        let path = dir.join("asking/work");
+        runtime.block_on(async {
+            let held = lock_file(&path).await.unwrap();
+            let waiting =
+                tokio::time::timeout(std::time::Duration::from_secs(10), lock_file(&path));
+            assert!(waiting.await.is_err());
+            drop(held);
+            assert!(lock_file(&path).await.is_some());
+        });
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_compose_summary() {
+        assert_eq!(
+            compose_summary("work: ", "hello"),
+            "work: \u{2068}hello\u{2069}"
+        );
+        let rtl = compose_summary("work: ", "\u{5e9}\u{5dc}\u{5d5}\u{5dd}");
+        assert!(rtl.starts_with("work: \u{2068}"));
+        assert!(rtl.ends_with("\u{2069}"));
+        // Directional controls from the guest are replaced before composition.
+        let sanitized = sanitize_str("\u{2069}\u{202e}evil");
+        assert!(!sanitized.contains(['\u{2069}', '\u{202e}']));
+        assert_eq!(
+            compose_summary("work: ", &sanitized)
+                .matches('\u{2069}')
+                .count(),
+            1
+        );
+        // The length hint covers the marker and the qube name, in
+        // characters, and nothing from the guest.
+        let config = config::Config::parse("critical-marker = \u{26a0}", "a").unwrap();
+        let marker = config.urgency_marker(Urgency::Critical);
+        let (prefix, length) = summary_prefix(marker, "w\u{f6}rk: ");
+        assert_eq!(prefix, "\u{26a0} w\u{f6}rk: ");
+        assert_eq!(length, 8);
+        let summary = compose_summary(&prefix, "hello");
+        let start: String = summary.chars().take(length as usize).collect();
+        assert_eq!(start, prefix);
+        assert_eq!(summary.chars().nth(length as usize), Some('\u{2068}'));
+        assert_eq!(summary_prefix("", "work: "), ("work: ".to_owned(), 6));
+    }
+
+    #[test]
+    fn test_collapse_replacements() {
+        assert_eq!(collapse_replacements(""), "");
+        assert_eq!(collapse_replacements("a\u{FFFD}b"), "a\u{FFFD}b");
+        let short = str::repeat("\u{FFFD}", MIN_COLLAPSED_RUN - 1);
+        assert_eq!(collapse_replacements(&short), short);
+        assert_eq!(
+            collapse_replacements("a\u{FFFD}\u{FFFD}\u{FFFD}\u{FFFD}b\u{FFFD}"),
+            "a[\u{FFFD}×4]b\u{FFFD}"
+        );
+        // The count cannot run into digits that follow the run.
+        assert_eq!(
+            collapse_replacements(&(str::repeat("\u{FFFD}", 5) + "12")),
+            "[\u{FFFD}×5]12"
+        );
+        // Runs end at line breaks, including those the sanitizer adds.
+        let flood = sanitize_ascii(&str::repeat("\x15", MAX_CHARS_PER_LINE + 10));
+        assert_eq!(
+            collapse_replacements(&flood),
+            format!("[\u{FFFD}×{}]\n[\u{FFFD}×10]", MAX_CHARS_PER_LINE)
+        );
+    }
+
+    #[test]
+    fn test_flag_confusables() {
+        let flag = |text: &str| flag_confusables(text, "[!]");
+        // Cyrillic а, Greek ο, fullwidth ａ
+        for url in [
+            "p\u{430}ypal.com",
+            "https://www.g\u{3bf}\u{3bf}gle.com/login",
+            "\u{ff41}mazon.com",
+        ] {
+            assert_eq!(
+                flag(&format!("Log in at {}, now\n", url)),
+                format!("Log in at {}, [!] now\n", url)
+            );
+        }
+        for text in [
+            "Log in at paypal.com now",
+            "https://www.qubes-os.org/",
+            // Not link-like
+            "p\u{430}ypal com",
+            // One script, and scripts that are not confused with Latin
+            "\u{43f}\u{440}\u{438}\u{43c}\u{435}\u{440}.\u{440}\u{444}",
+            "\u{4f8b}\u{3048}.jp",
+            "",
+        ] {
+            assert_eq!(flag(text), text);
+        }
+        assert_eq!(flag("a\u{430}.b\tc"), "a\u{430}.b [!]\tc");
+        let config = config::Config::parse("flag-confusables = yes", "a").unwrap();
+        assert!(config.flag_confusables);
+        assert!(!config::Config::default().flag_confusables);
+        assert!(config::Config::parse("flag-confusables = 1", "a").is_err());
+    }
+
+    #[test]
+    fn test_timestamp() {
+        let time = local_time_of_day(SystemTime::now());
+        assert_eq!(time.len(), 8, "{}", time);
+        assert!(time.bytes().enumerate().all(|(i, c)| if i % 3 == 2 {
+            c == b':'
+        } else {
+            c.is_ascii_digit()
+        }));
+        let config = config::Config::parse("timestamp = summary\n[a]\ntimestamp = hint", "b");
+        assert_eq!(config.unwrap().timestamp, Some(config::Timestamp::Summary));
+        let config = config::Config::parse("timestamp = summary\n[a]\ntimestamp = hint", "a");
+        assert_eq!(config.unwrap().timestamp, Some(config::Timestamp::Hint));
+        let config = config::Config::parse("timestamp = none", "a");
+        assert_eq!(config.unwrap().timestamp, None);
+        assert!(config::Config::parse("timestamp = body", "a").is_err());
+    }
+
+    #[test]
+    fn test_expiry_grace() {
+        let config = config::Config::parse("", "a").unwrap();
+        assert_eq!(config.expiry_grace, Some(config::DEFAULT_EXPIRY_GRACE));
+        let config = config::Config::parse("expiry-grace = 250\n[a]\nexpiry-grace = never", "b");
+        assert_eq!(
+            config.unwrap().expiry_grace,
+            Some(std::time::Duration::from_millis(250))
+        );
+        let config = config::Config::parse("expiry-grace = 250\n[a]\nexpiry-grace = never", "a");
+        assert_eq!(config.unwrap().expiry_grace, None);
+        assert!(config::Config::parse("expiry-grace = 0", "a").is_err());
+        assert!(config::Config::parse("expiry-grace = soon", "a").is_err());
+    }
+
+    #[test]
+    fn test_route() {
+        let text = "route = log\n[a]\nroute = display";
+        let config = config::Config::parse(text, "b");
+        assert_eq!(config.unwrap().route, config::Route::Log);
+        let config = config::Config::parse(text, "a");
+        assert_eq!(config.unwrap().route, config::Route::Display);
+        let config = config::Config::parse("", "a");
+        assert_eq!(config.unwrap().route, config::Route::Display);
+        assert!(config::Config::parse("route = nowhere", "a").is_err());
+    }
+
+    #[test]
+    fn test_dbus_retries() {
+        let config = config::Config::default();
+        assert_eq!(config.dbus_retries, config::DEFAULT_DBUS_RETRIES);
+        let config =
+            config::Config::parse("dbus-retries = no\n[a]\ndbus-retries = 5", "b").unwrap();
+        assert_eq!(config.dbus_retries, 0);
+        let config = config::Config::parse("dbus-retry-delay = 100", "a").unwrap();
+        assert_eq!(
+            config.dbus_retry_delay,
+            std::time::Duration::from_millis(100)
+        );
+        assert!(config::Config::parse("dbus-retries = 6", "a").is_err());
+        assert!(config::Config::parse("dbus-retry-delay = 0", "a").is_err());
+
+        let base = std::time::Duration::from_millis(100);
+        assert_eq!(retry_delay(base, 1, 0), base);
+        assert_eq!(retry_delay(base, 3, 0), base * 4);
+        let longest = retry_delay(base, 3, u64::MAX);
+        assert!(longest > base * 4 && longest <= base * 5);
+        assert!(retry_delay(std::time::Duration::MAX, 5, u64::MAX) == std::time::Duration::MAX);
+        assert!(!RETRYABLE_ERRORS.contains(&"org.freedesktop.DBus.Error.NoReply"));
+    }
+
+    #[test]
+    fn test_announce() {
+        let config = config::Config::parse("announce = yes\n[a]\nannounce = no", "b");
+        assert!(config.unwrap().announce);
+        let config = config::Config::parse("announce = yes\n[a]\nannounce = no", "a");
+        assert!(!config.unwrap().announce);
+        assert!(!config::Config::default().announce);
+        assert_eq!(
+            l10n::Catalog::default().format("announce", &[("qube", "work")]),
+            "Notifications from work are now shown"
+        );
+    }
+
+    #[test]
+    fn test_resend_on_upgrade() {
+        let text = "resend-on-upgrade = yes\n[a]\nresend-on-upgrade = no";
+        assert!(config::Config::parse(text, "b").unwrap().resend_on_upgrade);
+        assert!(!config::Config::parse(text, "a").unwrap().resend_on_upgrade);
+        assert!(!config::Config::default().resend_on_upgrade);
+        assert!(config::Config::parse("resend-on-upgrade = maybe", "a").is_err());
+    }
+
+    #[test]
+    fn test_tags() {
+        for tag in ["volume", "org.example.app:status", "a-b_c.1"] {
+            assert!(is_valid_tag(tag), "{:?}", tag);
+        }
+        let long = "a".repeat(MAX_TAG_LENGTH + 1);
+        for tag in ["", "with space", "new\nline", "caf\u{e9}", &long] {
+            assert!(!is_valid_tag(tag), "{:?}", tag);
+        }
+        assert!(is_valid_tag(&long[1..]));
+        use bincode::Options as _;
+        let notification = NotificationBuilder::new("volume")
+            .extension(Extension::Tag {
+                untrusted_tag: "volume".to_owned(),
+            })
+            .build()
+            .unwrap();
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes();
+        let bytes = options.serialize(&notification).unwrap();
+        match options.deserialize(&bytes).unwrap() {
+            Notification::V2 { extensions, .. } => assert_eq!(
+                extensions,
+                [Extension::Tag {
+                    untrusted_tag: "volume".to_owned()
+                }]
+            ),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_tag_map() {
+        let mut state = Tracked::default();
+        state.set_tag(1, Some("a".to_owned()));
+        assert_eq!(state.tags.get("a"), Some(&1));
+        // Replacing a notification replaces its tag.
+        state.set_tag(1, Some("b".to_owned()));
+        assert_eq!(state.tags.get("a"), None);
+        assert_eq!(state.tags.get("b"), Some(&1));
+        // A tag names one notification: the one that had it loses it.
+        state.set_tag(2, Some("b".to_owned()));
+        assert_eq!(state.tags.get("b"), Some(&2));
+        assert_eq!(state.tag_of.get(&1), None);
+        state.set_tag(1, None);
+        assert_eq!(state.tags.get("b"), Some(&2));
+        state.set_tag(2, None);
+        assert!(state.tags.is_empty() && state.tag_of.is_empty());
+    }
+
+    #[test]
+    fn test_deadlines() {
+        let now = tokio::time::Instant::now();
+        let at = |ms| now + std::time::Duration::from_millis(ms);
+        let mut deadlines = Deadlines::default();
+        deadlines.insert(1, at(300));
+        deadlines.insert(2, at(200));
+        assert_eq!(deadlines.first(), Some((at(200), 2)));
+        // Replacing a notification moves its deadline.
+        deadlines.insert(2, at(400));
+        assert_eq!(deadlines.first(), Some((at(300), 1)));
+        assert_eq!(deadlines.remove(1), Some(at(300)));
+        assert_eq!(deadlines.remove(1), None);
+        assert_eq!(deadlines.first(), Some((at(400), 2)));
+        deadlines.clear();
+        assert_eq!(deadlines.first(), None);
+    }
+
+    #[test]
+    fn test_urgency_hint() {
+        assert_eq!(Urgency::try_from(Value::U8(0)).unwrap(), Urgency::Low);
+        assert_eq!(Urgency::try_from(Value::U8(1)).unwrap(), Urgency::Normal);
+        assert_eq!(Urgency::try_from(Value::U8(2)).unwrap(), Urgency::Critical);
+        assert!(Urgency::try_from(Value::U8(3)).is_err());
+        assert!(Urgency::try_from(Value::U32(1)).is_err());
+        assert!(Urgency::try_from(Value::from("low")).is_err());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        for &(name, capability) in CAPABILITY_NAMES {
+            assert_eq!(Capabilities::from_name(name), Some(capability));
+            assert_eq!(capability.names(), [name]);
+        }
+        assert_eq!(Capabilities::all().names().len(), CAPABILITY_NAMES.len());
+        assert_eq!(Capabilities::from_name("x-unknown"), None);
+        let names = [
+            "body".to_owned(),
+            "x-unknown".to_owned(),
+            "actions".to_owned(),
+        ];
+        let capabilities = Capabilities::from(&names[..]);
+        assert_eq!(capabilities, Capabilities::BODY | Capabilities::ACTIONS);
+        assert_eq!(capabilities.to_string(), "actions, body");
+        assert_eq!(Capabilities::empty().to_string(), "");
+        let config = config::Config::parse(
+            &format!("enable-capabilities = {}", Capabilities::all()),
+            "a",
+        )
+        .unwrap();
+        assert_eq!(config.enable_capabilities, Capabilities::all());
+        // The body is plain text, and escaped if the daemon takes markup.
+        assert!(!GUEST_CAPABILITIES.contains(Capabilities::BODY_MARKUP));
+        // Only what the daemon has is passed on, and never markup.
+        let forwarded = forwarded_capabilities(Capabilities::all(), MINOR_VERSION);
+        assert_eq!(forwarded, guest_capabilities(MINOR_VERSION));
+        let forwarded = forwarded_capabilities(
+            Capabilities::BODY_MARKUP | Capabilities::ACTIONS,
+            MINOR_VERSION,
+        );
+        assert_eq!(forwarded, Capabilities::ACTIONS);
+        let forwarded = forwarded_capabilities(Capabilities::INLINE_REPLY, REPLY_VERSION - 1);
+        assert_eq!(forwarded, Capabilities::empty());
+        assert_eq!(
+            escape_markup("<b>\"Tom\" & 'Jerry'</b>"),
+            "&lt;b&gt;&quot;Tom&quot; &amp; &apos;Jerry&apos;&lt;/b&gt;"
+        );
+    }
+
+    #[test]
+    fn test_flatten_images() {
+        // Opaque, transparent, and half-transparent pixels, then padding.
+        let data = vec![10, 20, 30, 255, 10, 20, 30, 0, 255, 255, 255, 128, 99, 99];
+        assert_eq!(
+            flatten_alpha(3, 1, 14, &data, [0, 0, 100]),
+            [10, 20, 30, 0, 0, 100, 128, 128, 178]
+        );
+        let image = ImageParameters {
+            untrusted_width: 3,
+            untrusted_height: 1,
+            untrusted_rowstride: 14,
+            untrusted_has_alpha: true,
+            untrusted_bits_per_sample: 8,
+            untrusted_channels: 4,
+            untrusted_data: data,
+        };
+        let value = serialize_image_with(image, Some([0, 0, 0]), None, 1).unwrap();
+        let (width, height, rowstride, has_alpha, bits, channels, data): (
+            i32,
+            i32,
+            i32,
+            bool,
+            i32,
+            i32,
+            Vec<u8>,
+        ) = value.try_into().unwrap();
+        assert_eq!((width, height, rowstride, has_alpha), (3, 1, 9, false));
+        assert_eq!((bits, channels, data.len()), (8, 3, 9));
+        let config =
+            config::Config::parse("flatten-images = #1E1e00\n[a]\nflatten-images = no", "b");
+        assert_eq!(config.unwrap().flatten_images, Some([30, 30, 0]));
+        let config =
+            config::Config::parse("flatten-images = #1E1e00\n[a]\nflatten-images = no", "a");
+        assert_eq!(config.unwrap().flatten_images, None);
+        for bad in ["black", "#12345", "#1234567", "#12345g", "#+1+2+3"] {
+            let text = format!("flatten-images = {}", bad);
+            assert!(config::Config::parse(&text, "a").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_image_frame() {
+        // One RGBA pixel per row, then padding.
+        let data = [1, 2, 3, 4, 0, 5, 6, 7, 8, 0];
+        let framed = frame_pixels(1, 2, 5, 4, &data, [9, 9, 9]);
+        let width = 1 + 2 * IMAGE_FRAME_WIDTH;
+        assert_eq!(framed.len(), width * (2 + 2 * IMAGE_FRAME_WIDTH) * 4);
+        let pixel = |x: usize, y: usize| &framed[(y * width + x) * 4..][..4];
+        let inner = IMAGE_FRAME_WIDTH;
+        assert_eq!(pixel(inner, inner), [1, 2, 3, 4]);
+        assert_eq!(pixel(inner, inner + 1), [5, 6, 7, 8]);
+        // The frame is opaque, even around transparent images.
+        assert_eq!(pixel(0, 0), [9, 9, 9, 255]);
+        assert_eq!(pixel(inner - 1, inner), [9, 9, 9, 255]);
+        assert_eq!(pixel(width - 1, inner + 2), [9, 9, 9, 255]);
+        // The frame is scaled with the image, so it stays visible.
+        let image = ImageParameters {
+            untrusted_width: 4,
+            untrusted_height: 2,
+            untrusted_rowstride: 12,
+            untrusted_has_alpha: false,
+            untrusted_bits_per_sample: 8,
+            untrusted_channels: 3,
+            untrusted_data: vec![0; 24],
+        };
+        let value = serialize_image_with(image, None, Some([255, 0, 0]), 2).unwrap();
+        let (width, height, rowstride, _, _, _, data): (i32, i32, i32, bool, i32, i32, Vec<u8>) =
+            value.try_into().unwrap();
+        let border = 2 * IMAGE_FRAME_WIDTH as i32;
+        assert_eq!((width, height), (2 * (4 + border), 2 * (2 + border)));
+        assert_eq!(data.len(), (rowstride * height) as usize);
+        assert_eq!(data[..3], [255, 0, 0]);
+        // The largest images only fit without their frame.
+        let image = |width: i32, height: i32| ImageParameters {
+            untrusted_width: width,
+            untrusted_height: height,
+            untrusted_rowstride: width * 3,
+            untrusted_has_alpha: false,
+            untrusted_bits_per_sample: 8,
+            untrusted_channels: 3,
+            untrusted_data: vec![0; (width * height * 3) as usize],
+        };
+        let frame = Some([255, 0, 0]);
+        let largest = image(MAX_WIDTH, MAX_HEIGHT);
+        assert!(serialize_image_with(largest.clone(), None, None, 1).is_ok());
+        assert_eq!(
+            serialize_image_with(largest, None, frame, 1).unwrap_err(),
+            "Width or height too large"
+        );
+        let value = serialize_image_with(image(MAX_WIDTH - border, 1), None, frame, 1).unwrap();
+        let (width, height, ..): (i32, i32, i32, bool, i32, i32, Vec<u8>) =
+            value.try_into().unwrap();
+        assert_eq!((width, height), (MAX_WIDTH, 1 + border));
+        assert!(serialize_image_with(image(1, MAX_HEIGHT - border + 1), None, frame, 1).is_err());
+    }
+
+    #[test]
+    fn test_admin_api() {
+        use admin::{parse_color, parse_property, parse_reply};
+        let reply = b"0\0default=False type=label red";
+        let property = parse_property(parse_reply(reply).unwrap()).unwrap();
+        assert_eq!(property, "red");
+        assert_eq!(
+            parse_color(parse_reply(b"0\x000xcc0000").unwrap()),
+            Ok([204, 0, 0])
+        );
+        let reply = b"2\0QubesVMNotFoundError\0\0No such domain: 'x'\0";
+        let error = parse_reply(reply).unwrap_err();
+        assert_eq!(error, "QubesVMNotFoundError: No such domain: 'x'");
+        assert!(parse_reply(b"").is_err());
+        assert!(parse_reply(b"1\0").is_err());
+        assert!(parse_property(b"red").is_err());
+        for bad in ["cc0000", "0xcc00", "0x+c+c+c", "0xcc00zz"] {
+            assert!(parse_color(bad.as_bytes()).is_err(), "{}", bad);
+        }
+        let config = config::Config::parse("image-frame = label\nadmin-api = qrexec", "a");
+        let config = config.unwrap();
+        assert!(config.label_frame && config.image_frame.is_none());
+        assert_eq!(config.admin_api, admin::Transport::Qrexec);
+        let config = config::Config::parse("image-frame = label\n[a]\nimage-frame = no", "a");
+        assert!(!config.unwrap().label_frame);
+        assert!(config::Config::parse("admin-api = qubesd", "a").is_ok());
+        assert!(config::Config::parse("admin-api = http", "a").is_err());
+    }
+
+    #[test]
+    fn test_image_scale() {
+        // Two RGB pixels per row, then padding.
+        let data = [1, 1, 1, 2, 2, 2, 0, 3, 3, 3, 4, 4, 4, 0];
+        assert_eq!(
+            scale_pixels(6, 2, 7, 3, &data, 2),
+            [
+                [1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2],
+                [1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 2, 2],
+                [3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4],
+                [3, 3, 3, 3, 3, 3, 4, 4, 4, 4, 4, 4],
+            ]
+            .concat()
+        );
+        let image = |width: i32| ImageParameters {
+            untrusted_width: width,
+            untrusted_height: 1,
+            untrusted_rowstride: width * 3,
+            untrusted_has_alpha: false,
+            untrusted_bits_per_sample: 8,
+            untrusted_channels: 3,
+            untrusted_data: vec![0; width as usize * 3],
+        };
+        let size = |image, scale| {
+            let value = serialize_image_with(image, None, None, scale).unwrap();
+            let (width, height, rowstride, _, _, _, data): (
+                i32,
+                i32,
+                i32,
+                bool,
+                i32,
+                i32,
+                Vec<u8>,
+            ) = value.try_into().unwrap();
+            assert_eq!(data.len(), (rowstride * height) as usize);
+            (width, height)
+        };
+        assert_eq!(size(image(10), 3), (30, 3));
+        // Never beyond the size caps.
+        assert_eq!(size(image(100), 3), (200, 2));
+        assert_eq!(size(image(MAX_WIDTH), 3), (MAX_WIDTH, 1));
+        let config = config::Config::parse("image-scale = 2\n[a]\nimage-scale = auto", "b");
+        assert_eq!(config.unwrap().image_scale, Some(2));
+        let config = config::Config::parse("image-scale = 2\n[a]\nimage-scale = auto", "a");
+        assert_eq!(config.unwrap().image_scale, None);
+        for bad in ["0", "1.5", "9", "big"] {
+            let text = format!("image-scale = {}", bad);
+            assert!(config::Config::parse(&text, "a").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_quiet_hours() {
+        let minute = local_minute_of_day(SystemTime::now());
+        assert!(minute < 24 * 60);
+        let config = config::Config::parse("quiet-hours = 22:00-08:30\n[a]\nquiet-hours = no", "b");
+        let config = config.unwrap();
+        assert_eq!(config.quiet_hours, Some((22 * 60, 8 * 60 + 30)));
+        for (minute, quiet) in [
+            (0, true),
+            (509, true),
+            (510, false),
+            (1319, false),
+            (1320, true),
+        ] {
+            assert_eq!(config.quiet_at(minute), quiet, "{}", minute);
+        }
+        let config = config::Config::parse("quiet-hours = 12:00 - 13:00", "b").unwrap();
+        assert!(!config.quiet_at(719) && config.quiet_at(720) && !config.quiet_at(780));
+        let config = config::Config::parse("quiet-hours = 22:00-08:30\n[a]\nquiet-hours = no", "a");
+        assert!(!config.unwrap().quiet_at(0));
+        for bad in [
+            "22:00",
+            "22:00-22:00",
+            "24:00-08:00",
+            "22:60-08:00",
+            "2:00-08:00",
+            "+1:00-08:00",
+        ] {
+            let text = format!("quiet-hours = {}", bad);
+            assert!(config::Config::parse(&text, "a").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        use hooks::Event;
+        let text = "hook = /usr/local/bin/alert\nhook-rejections-per-minute = 3";
+        let config = config::Config::parse(text, "a").unwrap();
+        assert_eq!(
+            config.hook.as_deref(),
+            Some(std::path::Path::new("/usr/local/bin/alert"))
+        );
+        let mut hooks = hooks::Hooks::new("a".to_owned(), &config);
+        assert_eq!(hooks.critical(), Some(Event::FirstCritical));
+        assert_eq!(hooks.critical(), None);
+        let start = std::time::Instant::now();
+        let at = |seconds| start + std::time::Duration::from_secs(seconds);
+        assert_eq!(hooks.rejected(at(0)), None);
+        assert_eq!(hooks.rejected(at(30)), None);
+        // The first rejection is a minute old by now.
+        assert_eq!(hooks.rejected(at(60)), None);
+        assert_eq!(hooks.rejected(at(61)), Some(Event::Rejections(3)));
+        // Counting starts over.
+        assert_eq!(hooks.rejected(at(62)), None);
+        let mut hooks = hooks::Hooks::new("a".to_owned(), &config::Config::default());
+        assert!((0..100).all(|_| hooks.rejected(start).is_none()));
+        for bad in ["hook = alert", "hook-rejections-per-minute = 0"] {
+            assert!(config::Config::parse(bad, "a").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_crash_report() {
+        let context = crash::Context {
+            qube: Some("work".to_owned()),
+            version: Some((1, MINOR_VERSION)),
+            sequence: Some(42),
+        };
+        let mut backtrace =
+            "   0: notification_emitter::crash::hook\n  at src/crash.rs\n".to_owned();
+        backtrace += "   1: core::panicking::panic_fmt\n  at panicking.rs\n";
+        backtrace.extend((2..102).map(|i| format!("  {}: frame\n", i)));
+        let report = crash::report("server", &context, "boom", "src/x.rs:1:2", &backtrace);
+        let mut lines = report.lines();
+        assert_eq!(lines.next(), Some("server crashed at src/x.rs:1:2: boom"));
+        assert_eq!(lines.next(), Some("  qube: work"));
+        assert_eq!(
+            lines.next(),
+            Some(&*format!("  protocol version: 1.{}", MINOR_VERSION))
+        );
+        assert_eq!(lines.next(), Some("  last sequence number: 42"));
+        assert_eq!(lines.next(), Some("  backtrace:"));
+        // Without the frames of the hook.
+        assert_eq!(lines.next(), Some("    2: frame"));
+        assert_eq!(lines.last(), Some("  (60 more lines)"));
+        // Nothing is known before the connection is set up.
+        let report = crash::report("client", &Default::default(), "boom", "x", "");
+        assert_eq!(report, "client crashed at x: boom\n  backtrace:\n");
+    }
+
+    #[test]
+    fn test_signal_limiter() {
+        use std::time::Duration;
+        let text = "max-signals-per-second = 3\nsignal-coalesce = 100";
+        let config = config::Config::parse(text, "a").unwrap();
+        assert_eq!(config.signal_coalesce, Duration::from_millis(100));
+        let mut limiter = signals::SignalLimiter::new(&config);
+        let start = std::time::Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        assert!(limiter.action(1, "default", at(0)));
+        // The same action again is coalesced, another one is not.
+        assert!(!limiter.action(1, "default", at(50)));
+        assert!(limiter.action(1, "other", at(60)));
+        assert!(limiter.action(2, "default", at(70)));
+        // Three actions within a second.
+        assert!(!limiter.action(3, "default", at(80)));
+        assert!(!limiter.action(1, "default", at(900)));
+        assert!(limiter.action(1, "default", at(1000)));
+        assert_eq!(limiter.dropped(), 3);
+        // A dismissed notification starts over.
+        limiter.dismissed(1);
+        assert!(limiter.action(1, "default", at(1075)));
+        let config = config::Config::parse("max-signals-per-second = no", "a").unwrap();
+        let mut limiter = signals::SignalLimiter::new(&config);
+        assert!((0..1000).all(|id| limiter.action(id, "default", start)));
+        for bad in ["max-signals-per-second = 0", "signal-coalesce = 0"] {
+            assert!(config::Config::parse(bad, "a").is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_relay() {
+        use std::io::Read;
+        use std::os::unix::fs::OpenOptionsExt as _;
+        let record = relay::record(&["Qubes VM work", "a\tb", "c\nd\r\n"]);
+        assert_eq!(record, "Qubes VM work\ta b\tc d  \n");
+        let dir =
+            std::env::temp_dir().join(format!("notification-relay-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("socket");
+        let listener = std::os::unix::net::UnixListener::bind(&socket).unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_io()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(relay::send(&socket, &record)).unwrap();
+        let mut received = String::new();
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.read_to_string(&mut received).unwrap();
+        assert_eq!(received, record);
+        // Nothing reads the FIFO, so the record is dropped at once.
+        let fifo = dir.join("fifo");
+        let path = std::ffi::CString::new(std::os::unix::ffi::OsStrExt::as_bytes(fifo.as_os_str()))
+            .unwrap();
+        // SAFETY: `path` is a valid C string
+        assert_eq!(unsafe { nix::libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        let e = runtime.block_on(relay::send(&fifo, &record)).unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(nix::libc::ENXIO));
+        // A record that fits in the pipe is written whole.
+        let mut reader = std::fs::File::options()
+            .read(true)
+            .custom_flags(nix::libc::O_NONBLOCK)
+            .open(&fifo)
+            .unwrap();
+        let long = relay::record(&["a", &"\u{e9}".repeat(relay::MAX_RECORD)]);
+        assert!(long.len() <= relay::MAX_RECORD && long.ends_with("\u{e9}\n"));
+        runtime.block_on(relay::send(&fifo, &long)).unwrap();
+        let mut received = String::new();
+        reader.read_to_string(&mut received).unwrap();
+        assert_eq!(received, long);
+        let missing = runtime.block_on(relay::send(&dir.join("missing"), &record));
+        assert!(missing.is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        let config = config::Config::parse("relay = /run/relay\n[a]\nrelay = no", "b");
+        assert_eq!(
+            config.unwrap().relay.as_deref(),
+            Some(std::path::Path::new("/run/relay"))
+        );
+        let config = config::Config::parse("relay = /run/relay\n[a]\nrelay = no", "a");
+        assert_eq!(config.unwrap().relay, None);
+        assert!(config::Config::parse("relay = relay", "a").is_err());
+    }
+
+    #[test]
+    fn test_features() {
+        assert!(features(0).is_empty());
+        assert_eq!(
+            features(PROVISIONAL_IDS_VERSION),
+            ["open-url", "provisional-ids"]
+        );
+        assert_eq!(features(MINOR_VERSION).len(), protocol::FEATURES.len());
+        assert!(protocol::FEATURES.windows(2).all(|w| w[0].1 <= w[1].1));
+        assert!(features(TAG_VERSION - 1).iter().all(|&f| f != "tag"));
+        assert!(features(TAG_VERSION).contains(&"tag"));
+        assert!(features(PROBE_VERSION - 1).iter().all(|&f| f != "probe"));
+        assert!(features(PROBE_VERSION).contains(&"probe"));
+    }
 
-        Ok(self.maps.borrow_mut().next_id(id, guest_id))
+    #[test]
+    fn test_guest_capabilities() {
+        // Inline replies are offered to applications once dom0 passes
+        // them on.
+        let inline_reply = Capabilities::INLINE_REPLY;
+        assert!(!guest_capabilities(REPLY_VERSION - 1).contains(inline_reply));
+        assert!(guest_capabilities(REPLY_VERSION).contains(inline_reply));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
     #[test]
-    fn test_discriminant_serialized() {
+    fn test_feature_negotiation() {
+        let old = NegotiatedFeatures::from_version(CLOSE_VERSION);
+        assert!(old.has(Features::IMAGES | Features::CLOSE));
+        assert!(!old.has(Features::INLINE_REPLY));
+        assert!(!NegotiatedFeatures::exchanges_masks(
+            FEATURE_MASK_VERSION - 1
+        ));
+        // Only what both sides offered, within what the version implies.
+        let ours = Features::IMAGES | Features::INLINE_REPLY;
+        let negotiated = NegotiatedFeatures::negotiate(MINOR_VERSION, ours, u32::MAX);
+        assert_eq!(negotiated.features(), ours);
+        let negotiated = NegotiatedFeatures::negotiate(REPLY_VERSION - 1, ours, u32::MAX);
+        assert_eq!(negotiated.features(), Features::IMAGES);
+        let negotiated = NegotiatedFeatures::negotiate(MINOR_VERSION, Features::all(), 0b101);
+        assert_eq!(negotiated.features(), ours);
+        assert_eq!(negotiated.minor(), MINOR_VERSION);
+        let names = negotiated.names();
+        assert!(names.contains(&"inline-reply") && !names.contains(&"close"));
+        assert_eq!(names[0], "images");
+        assert_eq!(names.len(), 1 + features(MINOR_VERSION).len() - 3);
+        assert_eq!(negotiated.encoding(), codec::Encoding::Bincode);
+        assert_eq!(negotiated.framing(), Framing::Plain);
+        let checksummed =
+            NegotiatedFeatures::negotiate(MINOR_VERSION, Features::CHECKSUM, u32::MAX);
+        assert_eq!(checksummed.framing(), Framing::Crc32);
+        // A guest that turned closing off cannot close anyway.
+        let features = NegotiatedFeatures::negotiate(MINOR_VERSION, Features::all(), 0);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let local_set = tokio::task::LocalSet::new();
+        let config = config::Config::default();
+        let mut actor = actor::Actor::new(
+            "work".to_owned(),
+            features,
+            &config,
+            Rc::new(FakeDaemon::new(0)),
+            vec![],
+            std::sync::Arc::new(tokio::sync::Semaphore::new(1)),
+            codec::OutboundQueue::new(),
+        );
         use bincode::Options as _;
-        let options = bincode::DefaultOptions::new()
+        let close = bincode::DefaultOptions::new()
             .with_fixint_encoding()
             .with_native_endian()
-            .reject_trailing_bytes();
-        let v = options
-            .serialize(&Notification::V1 {
-                suppress_sound: true,
-                transient: false,
-                resident: false,
-                urgency: None,
-                replaces_id: 0,
-                summary: "".to_owned(),
-                body: "".to_owned(),
-                actions: vec![],
-                category: None,
-                expire_timeout: 0,
-                image: None,
+            .reject_trailing_bytes()
+            .serialize(&Request::Close {
+                sequence: 0,
+                untrusted_id: 1,
             })
             .unwrap();
-        assert_eq!(&v[..4], &[0, 0, 0, 0][..])
+        let out = local_set.block_on(
+            &runtime,
+            async move { actor.handle(actor::Event::Frame(close)) },
+        );
+        assert_eq!(out, Err("close not negotiated".to_owned()));
     }
+
     #[test]
-    fn test_enum_extensibility() {
-        #[derive(Serialize, Deserialize)]
-        enum A {
-            B { x: bool },
-        }
-        #[derive(Serialize, Deserialize)]
-        enum D {
-            B { x: bool },
-            C { x: u32 },
+    fn test_wire_codecs() {
+        use codec::WireCodec as _;
+        let request = Request::Notify(Message {
+            id: 7,
+            notification: NotificationBuilder::new("hello")
+                .action("default", "Open")
+                .extension(Extension::Tag {
+                    untrusted_tag: "backup".to_owned(),
+                })
+                .build()
+                .unwrap(),
+        });
+        for encoding in [codec::Encoding::Bincode, codec::Encoding::Cbor] {
+            let bytes = encoding.encode(&request).unwrap();
+            let Request::Notify(message) = encoding.decode(&bytes).unwrap() else {
+                panic!("{:?} changed the request", encoding)
+            };
+            assert_eq!(message.id, 7);
+            assert_eq!(message.notification.replaces_id(), 0);
+            let mut longer = bytes.clone();
+            longer.push(0);
+            assert!(encoding.decode::<Request>(&longer).is_err());
+            assert!(encoding
+                .decode::<Request>(&bytes[..bytes.len() - 1])
+                .is_err());
         }
+        // CBOR names the variants and fields, and bincode does not.
+        let reply = ReplyMessage::Dismissed { id: 5, reason: 2 };
+        let cbor = codec::Cbor.encode(&reply).unwrap();
+        assert!(cbor.windows(9).any(|w| w == b"Dismissed"));
+        assert!(cbor.windows(6).any(|w| w == b"reason"));
+        assert!(matches!(
+            codec::Cbor.decode(&cbor).unwrap(),
+            ReplyMessage::Dismissed { id: 5, reason: 2 }
+        ));
+        let bincode = codec::Bincode.encode(&reply).unwrap();
+        assert!(!bincode.windows(6).any(|w| w == b"reason"));
+        // Used whenever both sides can.
+        let features = NegotiatedFeatures::from_version(CBOR_VERSION);
+        assert_eq!(features.encoding(), codec::Encoding::Cbor);
+        let features = NegotiatedFeatures::negotiate(CBOR_VERSION, Features::all(), 0b111);
+        assert_eq!(features.encoding(), codec::Encoding::Bincode);
+    }
+
+    #[test]
+    fn test_suppression() {
+        assert_eq!(features(SUPPRESSED_VERSION).last(), Some(&"suppressed"));
+        assert_eq!(Suppression::QuietHours.name(), "quiet-hours");
+        let text = "report-suppressed = yes\n[a]\nreport-suppressed = no";
+        assert!(config::Config::parse(text, "b").unwrap().report_suppressed);
+        assert!(!config::Config::parse(text, "a").unwrap().report_suppressed);
+        assert!(!config::Config::default().report_suppressed);
         use bincode::Options as _;
         let options = bincode::DefaultOptions::new()
             .with_fixint_encoding()
             .with_native_endian()
             .reject_trailing_bytes();
-        let serialized = options.serialize(&A::B { x: true }).unwrap();
-        let deserialized: D = options.deserialize(&serialized).unwrap();
-        assert!(matches!(deserialized, D::B { x: true }));
-        assert_eq!(serialized, options.serialize(&D::B { x: true }).unwrap());
+        let reply = ReplyMessage::Suppressed {
+            id: 5,
+            reason: Suppression::LogOnly,
+        };
+        let bytes = options.serialize(&reply).unwrap();
+        assert!(matches!(
+            options.deserialize(&bytes).unwrap(),
+            ReplyMessage::Suppressed {
+                id: 5,
+                reason: Suppression::LogOnly
+            }
+        ));
+    }
+
+    #[test]
+    fn test_protocol_consistency() {
+        // Each minor version adds one named feature, so that the version
+        // cannot be bumped without saying what it adds, or the reverse.
+        let versions = protocol::FEATURES.iter().map(|&(_, version)| version);
+        assert!(versions.eq(1..=MINOR_VERSION));
+        // The documentation of each variant agrees with SINCE.
+        let mut checked = 0;
+        let mut name = "";
+        let mut doc = String::new();
+        for line in include_str!("lib.rs").lines() {
+            if let Some(text) = line.trim().strip_prefix("///") {
+                doc = doc + " " + text.trim();
+                continue;
+            }
+            if let Some(rest) = line.strip_prefix("pub enum ") {
+                name = rest.split(' ').next().unwrap();
+            } else if let (Some(variant), Some((_, since))) = (
+                line.strip_prefix("    ")
+                    .filter(|rest| rest.starts_with(|c: char| c.is_ascii_uppercase())),
+                doc.split_once("Since version "),
+            ) {
+                let variant = variant.split([' ', '(', ',']).next().unwrap();
+                let since: String = since.chars().take_while(char::is_ascii_digit).collect();
+                assert_eq!(
+                    schema::since(name, variant),
+                    Some(since.parse().unwrap()),
+                    "{}::{}",
+                    name,
+                    variant
+                );
+                checked += 1;
+            }
+            if !line.trim().starts_with("#[") {
+                doc.clear()
+            }
+        }
+        assert!(checked >= 11, "only {} variants documented", checked);
+        // The crate is released with the packages built from it.
+        assert_eq!(env!("CARGO_PKG_VERSION"), include_str!("../version").trim());
+    }
+
+    #[test]
+    fn test_schema() {
+        let mut schema = schema::Schema::default();
+        schema.trace::<Request>();
+        schema.trace::<ReplyMessage>();
+        let mut variants = 0;
+        for (name, container) in &schema.containers {
+            if let schema::Container::Enum(v) = container {
+                for (variant, shape) in v {
+                    assert!(shape.is_some(), "{}::{} not traced", name, variant);
+                    let since = schema::since(name, variant)
+                        .unwrap_or_else(|| panic!("{}::{} missing from SINCE", name, variant));
+                    assert!(since <= MINOR_VERSION);
+                    variants += 1;
+                }
+            }
+        }
+        // Nothing in SINCE that is not in the protocol.
+        assert_eq!(variants, schema::SINCE.len());
+        match &schema.containers["Notification"] {
+            schema::Container::Enum(v) => assert!(matches!(
+                &v[1],
+                ("V2", Some(schema::Variant::Struct(fields)))
+                    if fields.last() == Some(&("extensions", schema::Format::Seq(
+                        Box::new(schema::Format::Named("Extension"))
+                    )))
+            )),
+            other => panic!("Notification is not an enum: {:?}", other),
+        }
+        // Expire timeouts are plain i32 on the wire.
+        assert!(matches!(
+            &schema.containers["ReplyMessage"],
+            schema::Container::Enum(v) if v[7] == ("ExpireTimeout", Some(schema::Variant::Struct(vec![
+                ("id", schema::Format::U32),
+                ("expire_timeout", schema::Format::I32),
+            ])))
+        ));
+        let json = schema::json();
+        assert!(json.contains("\"ImageParameters\": {\"struct\""));
+        assert_eq!(json.matches('{').count(), json.matches('}').count());
+    }
+
+    #[test]
+    fn test_notification_builder() {
+        let notification = NotificationBuilder::new("summary").build().unwrap();
+        match notification {
+            Notification::V1 {
+                ref summary,
+                ref body,
+                ref actions,
+                replaces_id: 0,
+                expire_timeout: ExpireTimeout::Default,
+                urgency: None,
+                ..
+            } => {
+                assert_eq!(summary, "summary");
+                assert!(body.is_empty() && actions.is_empty());
+            }
+            _ => panic!("wrong defaults: {:?}", notification),
+        }
+        let notification = NotificationBuilder::new("summary")
+            .action("default", "Open")
+            .extension(Extension::Progress {
+                untrusted_value: 50,
+            })
+            .replaces_id(3)
+            .build()
+            .unwrap();
+        match notification {
+            Notification::V2 {
+                ref actions,
+                ref extensions,
+                replaces_id: 3,
+                ..
+            } => {
+                assert_eq!(actions, &["default", "Open"]);
+                assert_eq!(extensions.len(), 1);
+            }
+            _ => panic!("extensions need V2: {:?}", notification),
+        }
+        assert!(NotificationBuilder::new("summary")
+            .actions(vec!["default".to_owned()])
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn test_replaces_id() {
+        let mut notification = Notification::V1 {
+            suppress_sound: false,
+            transient: false,
+            resident: false,
+            urgency: None,
+            replaces_id: 3,
+            summary: String::new(),
+            body: String::new(),
+            actions: vec![],
+            category: None,
+            expire_timeout: ExpireTimeout::Default,
+            image: None,
+        };
+        assert_eq!(notification.replaces_id(), 3);
+        notification.set_replaces_id(4);
+        assert_eq!(notification.upgrade().replaces_id(), 4);
+    }
+
+    #[test]
+    fn test_fold_body_into_summary() {
+        assert_eq!(fold_body_into_summary("title", ""), "title");
+        assert_eq!(fold_body_into_summary("title", "\n\n"), "title");
+        assert_eq!(
+            fold_body_into_summary("title", "\nfirst\nsecond"),
+            "title \u{2014} first"
+        );
+        // The last line of the summary stays within the limit.
+        let summary = "a".repeat(MAX_CHARS_PER_LINE - 10);
+        let folded = fold_body_into_summary(&summary, &"b".repeat(20));
+        assert_eq!(folded.chars().count(), MAX_CHARS_PER_LINE);
+        assert!(folded.ends_with("bbbbb\u{2026}"));
+        let summary = "a".repeat(MAX_CHARS_PER_LINE);
+        assert_eq!(fold_body_into_summary(&summary, "b"), summary);
+        let summary = "a".repeat(MAX_CHARS_PER_LINE) + "\nc";
+        assert_eq!(
+            fold_body_into_summary(&summary, "b"),
+            summary.clone() + " \u{2014} b"
+        );
+    }
+
+    #[test]
+    fn test_promote_first_line() {
+        let promote =
+            |summary: &str, body: &str| promote_first_line(summary.to_owned(), body.to_owned());
+        assert_eq!(promote("title", "text"), ("title".into(), "text".into()));
+        assert_eq!(
+            promote("", "\n \nfirst\nsecond\n"),
+            ("first".into(), "second\n".into())
+        );
+        assert_eq!(promote(" ", "only"), ("only".into(), "".into()));
+        // Nothing to promote.
+        assert_eq!(promote("", "\n\t\n"), ("".into(), "\n\t\n".into()));
+        assert_eq!(promote("", ""), ("".into(), "".into()));
+    }
+
+    #[test]
+    fn test_action_labels() {
+        assert_eq!(limit_label("Reply", 5), "Reply");
+        assert_eq!(limit_label("Replies", 5), "Repl\u{2026}");
+        assert_eq!(limit_label("\u{e9}t\u{e9}s", 2), "\u{e9}\u{2026}");
+        assert_eq!(limit_label("", 1), "");
+        let long = "x".repeat(1000);
+        let actions = ["later", &*long].map(str::to_owned);
+        let limit = config::DEFAULT_MAX_ACTION_LABEL;
+        let sanitized = sanitize_actions(&actions, limit).unwrap();
+        assert_eq!(sanitized[0], "later");
+        assert_eq!(sanitized[1].chars().count(), limit);
+        assert!(sanitized[1].ends_with("x\u{2026}"));
+        let config = config::Config::parse("max-action-label = 20", "a").unwrap();
+        assert_eq!(config.max_action_label, 20);
+        assert!(config::Config::parse("max-action-label = 0", "a").is_err());
+    }
+
+    #[test]
+    fn test_daemon_quirks() {
+        assert_eq!(limit_lines("a\nb\nc", 2), ("a\nb\n", true));
+        assert_eq!(limit_lines("a\nb\n", 2), ("a\nb\n", false));
+        assert_eq!(limit_lines("a\nb", 2), ("a\nb", false));
+        assert_eq!(limit_lines("a", 0), ("", true));
+        assert_eq!(mark_truncated("a\nb\n".to_owned(), "cut"), "a\ncut");
+        assert_eq!(mark_truncated("a\n\n".to_owned(), "cut"), "a\ncut");
+        assert_eq!(mark_truncated("a\n".to_owned(), "cut"), "cut");
+        let quirks = quirks::lookup("xfce4-notifyd", "1.2");
+        assert_eq!(quirks.max_lines, Some(20));
+        assert_eq!(quirks.image_hint, "image-data");
+        let unknown = "notification-daemon";
+        assert_eq!(quirks::lookup(unknown, "1.2"), quirks::Quirks::default());
+        assert_eq!(quirks::lookup(unknown, "1.1").image_hint, "image_data");
+        assert_eq!(quirks::lookup(unknown, "1.0").image_hint, "icon_data");
+        assert_eq!(quirks::lookup(unknown, "bogus").image_hint, "image-data");
+        let text = "daemon-quirks = none\n[a]\ndaemon-quirks = xfce4-notifyd";
+        let config = config::Config::parse(text, "b").unwrap();
+        assert_eq!(config.daemon_quirks, config::DaemonQuirks::Off);
+        let config = config::Config::parse(text, "a").unwrap();
+        assert_eq!(
+            config.daemon_quirks,
+            config::DaemonQuirks::As("xfce4-notifyd".to_owned())
+        );
+        assert_eq!(
+            config::Config::default().daemon_quirks,
+            config::DaemonQuirks::Detect
+        );
+        assert!(config::Config::parse("daemon-quirks = unknown", "a").is_err());
+    }
+
+    #[test]
+    fn test_dunst_quirks() {
+        let quirks = quirks::lookup("dunst", "1.2");
+        assert!(quirks.single_line_summary);
+        assert_eq!(quirks.max_lines, None);
+        assert_eq!(quirks.disable_capabilities, Capabilities::PERSISTENCE);
+        // What dunst advertises; resident notifications would otherwise
+        // be sent with a hint it ignores.
+        let advertised = Capabilities::ACTIONS
+            | Capabilities::BODY
+            | Capabilities::BODY_HYPERLINKS
+            | Capabilities::BODY_MARKUP
+            | Capabilities::ICON_STATIC
+            | Capabilities::PERSISTENCE;
+        assert!(!(advertised - quirks.disable_capabilities).contains(Capabilities::PERSISTENCE));
+        // Nothing after the first line break of the summary is lost.
+        let (summary, truncated) = sanitize_str_truncated("Build\r\nfailed\n");
+        assert!(!truncated);
+        assert_eq!(fold_lines(&summary, " "), "Build failed");
+        assert!(config::Config::parse("daemon-quirks = dunst", "a").is_ok());
+    }
+
+    #[test]
+    fn test_gnome_quirks() {
+        let quirks = quirks::lookup("gnome-shell", "1.2");
+        assert_eq!(quirks.max_lines, Some(6));
+        assert_eq!(quirks.max_body_chars, Some(300));
+        assert!(!quirks.single_line_summary);
+        assert!(quirks.ignored_hints.contains(&"value"));
+        assert!(!quirks.ignored_hints.contains(&"urgency"));
+        assert_eq!(limit_chars("abc", 3), ("abc".to_owned(), false));
+        assert_eq!(limit_chars("abcd", 3), ("abc\n".to_owned(), true));
+        assert_eq!(limit_chars("ab\ncd", 3), ("ab\n".to_owned(), true));
+        assert_eq!(
+            limit_chars("\u{5e9}\u{5dc}\u{5d5}", 2),
+            ("\u{5e9}\u{5dc}\n".to_owned(), true)
+        );
+        assert!(config::Config::parse("daemon-quirks = gnome-shell", "a").is_ok());
+    }
+
+    #[test]
+    fn test_plasma_capabilities() {
+        // What Plasma advertises.  The KDE extensions need no capability
+        // bits: x-kde-origin-name is always set, and the others are not
+        // used.  Inline replies are only passed on as NotificationReplied.
+        let advertised = [
+            "body",
+            "body-hyperlinks",
+            "body-markup",
+            "body-images",
+            "icon-static",
+            "actions",
+            "persistence",
+            "inline-reply",
+            "x-kde-urls",
+            "x-kde-origin-name",
+            "x-kde-display-appname",
+            "inhibitions",
+        ];
+        let capabilities = parse_capabilities(advertised.map(str::to_owned).to_vec());
+        assert_eq!(
+            capabilities,
+            Capabilities::BODY
+                | Capabilities::BODY_HYPERLINKS
+                | Capabilities::BODY_MARKUP
+                | Capabilities::BODY_IMAGES
+                | Capabilities::ICON_STATIC
+                | Capabilities::ACTIONS
+                | Capabilities::PERSISTENCE
+                | Capabilities::INLINE_REPLY
+        );
+        assert_eq!(quirks::lookup("Plasma", "1.2"), quirks::Quirks::default());
+    }
+
+    /// The actions and hints of each `Notify` call a [`MockDaemon`] got.
+    type Notified = std::sync::Arc<
+        std::sync::Mutex<Vec<(Vec<String>, HashMap<String, zbus::zvariant::OwnedValue>)>>,
+    >;
+
+    /// A notification daemon that records the calls made to it, on a
+    /// peer-to-peer connection, so that tests need no session bus.
+    struct MockDaemon {
+        capabilities: Vec<String>,
+        name: String,
+        notified: Notified,
+    }
+
+    #[zbus::dbus_interface(name = "org.freedesktop.Notifications")]
+    impl MockDaemon {
+        fn get_capabilities(&self) -> Vec<String> {
+            self.capabilities.clone()
+        }
+        fn get_server_information(&self) -> (String, String, String, String) {
+            let name = self.name.clone();
+            (name, "KDE".to_owned(), "5.27".to_owned(), "1.2".to_owned())
+        }
+        #[allow(clippy::too_many_arguments)]
+        fn notify(
+            &self,
+            _app_name: String,
+            _replaces_id: u32,
+            _app_icon: String,
+            _summary: String,
+            _body: String,
+            actions: Vec<String>,
+            hints: HashMap<String, zbus::zvariant::OwnedValue>,
+            _expire_timeout: i32,
+        ) -> u32 {
+            let mut notified = self.notified.lock().unwrap();
+            notified.push((actions, hints));
+            notified.len() as u32
+        }
+        fn close_notification(&self, _id: u32) {}
+    }
+
+    /// An emitter for the qube `work`, connected to a [`MockDaemon`] with
+    /// `capabilities` and `name`, and the calls made to the daemon.
+    async fn mock_emitter(
+        capabilities: &[&str],
+        name: &str,
+    ) -> (NotificationEmitter, Notified, Connection) {
+        let notified = std::sync::Arc::default();
+        let daemon = MockDaemon {
+            capabilities: capabilities.iter().map(|&c| c.to_owned()).collect(),
+            name: name.to_owned(),
+            notified: std::sync::Arc::clone(&notified),
+        };
+        let (daemon_end, emitter_end) = tokio::net::UnixStream::pair().unwrap();
+        let guid = zbus::Guid::generate();
+        let daemon = zbus::ConnectionBuilder::unix_stream(daemon_end)
+            .server(&guid)
+            .p2p()
+            .serve_at("/org/freedesktop/Notifications", daemon)
+            .unwrap()
+            .build();
+        let emitter = zbus::ConnectionBuilder::unix_stream(emitter_end)
+            .p2p()
+            .build();
+        let (daemon, emitter) = futures_util::future::join(daemon, emitter).await;
+        let (mut emitter, _) = NotificationEmitter::on_connection(
+            emitter.unwrap(),
+            "work: ".to_owned(),
+            "Qubes VM work".to_owned(),
+        )
+        .await
+        .unwrap();
+        emitter.set_protocol_minor(MINOR_VERSION);
+        emitter.set_qube_name("work".to_owned());
+        (emitter, notified, daemon.unwrap())
+    }
+
+    #[test]
+    fn test_plasma_end_to_end() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let local_set = tokio::task::LocalSet::new();
+        local_set.block_on(&runtime, async {
+            let plasma = [
+                "body",
+                "body-hyperlinks",
+                "body-markup",
+                "body-images",
+                "icon-static",
+                "actions",
+                "persistence",
+                "inline-reply",
+                "x-kde-urls",
+                "x-kde-origin-name",
+                "x-kde-display-appname",
+                "inhibitions",
+            ];
+            let (emitter, notified, _daemon) = mock_emitter(&plasma, "Plasma").await;
+            // The guest is told it can ask for inline replies.
+            let capabilities = forwarded_capabilities(emitter.capabilities(), MINOR_VERSION);
+            assert!(capabilities.contains(Capabilities::INLINE_REPLY));
+            let notification = NotificationBuilder::new("Message from Alice")
+                .action(INLINE_REPLY_ACTION, "Reply")
+                .build()
+                .unwrap();
+            emitter
+                .send_notification(notification, SystemTime::now())
+                .await
+                .unwrap();
+            let notified = notified.lock().unwrap();
+            let (actions, hints) = &notified[0];
+            assert_eq!(actions[0], INLINE_REPLY_ACTION);
+            let hint = |name: &str| hints[name].downcast_ref::<str>().map(str::to_owned);
+            assert_eq!(hint("desktop-entry").unwrap(), "org.qubes-os.qube.work");
+            assert_eq!(hint("x-kde-origin-name").unwrap(), "work");
+        });
+    }
+
+    #[test]
+    fn test_fold_lines() {
+        assert_eq!(fold_lines("", " / "), "");
+        assert_eq!(fold_lines("one line", " / "), "one line");
+        assert_eq!(fold_lines("a\n\nb\n", " / "), "a / b");
+        // Lines the sanitizer broke up are folded too.
+        let long = sanitize_str(&"a".repeat(MAX_CHARS_PER_LINE + 1));
+        assert_eq!(
+            fold_lines(&long, " / "),
+            "a".repeat(MAX_CHARS_PER_LINE) + " / a"
+        );
+        let config = config::Config::parse("fold-body = \u{23ce}\n[a]\nfold-body = no", "b");
+        assert_eq!(config.unwrap().fold_body.as_deref(), Some(" \u{23ce} "));
+        let config = config::Config::parse("fold-body = \u{23ce}\n[a]\nfold-body = no", "a");
+        assert_eq!(config.unwrap().fold_body, None);
+        assert!(config::Config::parse("fold-body = a\tb", "a").is_err());
+        assert!(config::Config::parse("fold-body =", "a").is_err());
+    }
+
+    #[test]
+    fn test_find_urls() {
+        assert_eq!(
+            find_urls("see https://example.com/a?b=c&d, or (http://x.org)."),
+            vec!["https://example.com/a?b=c&d", "http://x.org"]
+        );
+        assert!(find_urls("http:// https:// httpx://a.b ftp://a.b").is_empty());
+        assert_eq!(find_urls("<https://a.b/'x'>"), vec!["https://a.b/"]);
+        assert_eq!(find_urls("https://a.b/\u{430}"), vec!["https://a.b/"]);
+        let long = "https://".to_owned() + &str::repeat("a", MAX_URL_LENGTH);
+        assert!(find_urls(&long).is_empty());
+    }
+
+    /// Random strings for the sanitizer tests.  Mostly ASCII, with code
+    /// points from all planes and the troublesome ranges mixed in.
+    fn random_strings(count: usize) -> Vec<String> {
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        (0..count)
+            .map(|_| {
+                let len = next() % 64;
+                (0..len)
+                    .filter_map(|_| {
+                        let code_point = match next() % 4 {
+                            0 => next() % 0x80,
+                            1 => next() % 0x3000,
+                            2 => 0x2000 + next() % 0x100,
+                            _ => next() % 0x110000,
+                        };
+                        char::from_u32(code_point as u32)
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_unicode_table() {
+        let safe = unicode::code_point_safe_for_display;
+        for c in ['a', ' ', '~', 'é', 'Ж', '中', '€', '\u{1F600}'] {
+            assert!(safe(c.into()), "{:?}", c);
+        }
+        for c in [
+            '\0',
+            '\t',
+            '\n',
+            '\x7F',
+            '\u{AD}',
+            '\u{301}',
+            '\u{200B}',
+            '\u{2028}',
+            '\u{202E}',
+            '\u{2067}',
+            '\u{E000}',
+            '\u{FEFF}',
+            '\u{FFFF}',
+            '\u{30000}',
+            '\u{10FFFD}',
+        ] {
+            assert!(!safe(c.into()), "{:?}", c);
+        }
+        for s in random_strings(1000) {
+            assert!(sanitize_str_with(&s, safe)
+                .0
+                .chars()
+                .all(|c| safe(c.into()) || matches!(c, '\t' | '\n' | '\u{FFFD}')));
+        }
+    }
+
+    /// The pure-Rust table must never accept a code point libqubes-pure
+    /// rejects.  Only meaningful when linked against the real library.
+    #[cfg(feature = "qubes-pure")]
+    #[test]
+    fn test_sanitizer_differential() {
+        let accepted_by_rust_only: Vec<u32> = (0..=0x10FFFF)
+            .filter(|&c| unicode::code_point_safe_for_display(c) && !code_point_safe_for_display(c))
+            .collect();
+        assert!(
+            accepted_by_rust_only.is_empty(),
+            "accepted only by the Rust table: {:X?}",
+            accepted_by_rust_only
+        );
+        for s in random_strings(10_000) {
+            let c = sanitize_str(&s);
+            let rust = sanitize_str_with(&s, unicode::code_point_safe_for_display).0;
+            assert_eq!(c.chars().count(), rust.chars().count(), "{:?}", s);
+            for (c, rust) in c.chars().zip(rust.chars()) {
+                assert!(c == rust || rust == '\u{FFFD}', "{:?}", s);
+            }
+        }
+    }
+
+    /// Random strings with long lines and many lines, around the limits.
+    fn random_long_strings(count: usize) -> Vec<String> {
+        random_strings(count)
+            .into_iter()
+            .enumerate()
+            .map(|(i, s)| {
+                let s = if i % 3 == 0 { s + "\n" } else { s };
+                s.repeat(1 + i * 37 % (MAX_CHARS_PER_LINE * 2))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_sanitizer_properties() {
+        for s in random_strings(1000)
+            .into_iter()
+            .chain(random_long_strings(200))
+        {
+            let (once, truncated) = sanitize_str_truncated(&s);
+            assert_eq!(
+                sanitize_str_truncated(&once),
+                (once.clone(), false),
+                "{:?}",
+                s
+            );
+            assert!(once.lines().count() <= MAX_LINES, "{:?}", s);
+            assert!(
+                once.lines()
+                    .all(|line| line.chars().count() <= MAX_CHARS_PER_LINE),
+                "{:?}",
+                s
+            );
+            assert!(once
+                .chars()
+                .all(|c| code_point_safe_for_display(c.into())
+                    || matches!(c, '\t' | '\n' | '\u{FFFD}')));
+            assert!(
+                truncated || once.chars().count() >= s.chars().count() - s.matches("\r\n").count()
+            );
+        }
+        // Exactly at the limit, before a line break of any kind.
+        for end in ["\n", "\r", "\r\n"] {
+            let s = "a".repeat(MAX_CHARS_PER_LINE) + end + "b";
+            assert_eq!(sanitize_ascii(&s), "a".repeat(MAX_CHARS_PER_LINE) + "\nb");
+        }
+        // Every char, without panicking.
+        let all: Vec<char> = (0..=0x10FFFF).filter_map(char::from_u32).collect();
+        for chunk in all.chunks(MAX_CHARS_PER_LINE / 2) {
+            let s: String = chunk.iter().collect();
+            let (once, _) = sanitize_str_truncated(&s);
+            assert_eq!(sanitize_str(&once), once);
+        }
+    }
+
+    /// No markup is forwarded yet: the allowed tag set is empty, so
+    /// whatever the guest sends must reach the daemon as text.
+    #[test]
+    fn test_markup_escaping() {
+        const SOUP: &[&str] = &[
+            "<",
+            ">",
+            "&",
+            "/",
+            "\"",
+            "'",
+            "=",
+            "b",
+            "i",
+            "a",
+            "amp;",
+            "lt;",
+            "#60;",
+            "#x3c;",
+            "<b>",
+            "</b>",
+            "<i>",
+            "<a href=\"",
+            "<img src='",
+            "<!--",
+            "-->",
+            "<![CDATA[",
+            "]]>",
+            "\u{FFFD}",
+            "\n",
+            " ",
+        ];
+        const ENTITIES: &[(&str, char)] = &[
+            ("&lt;", '<'),
+            ("&gt;", '>'),
+            ("&amp;", '&'),
+            ("&apos;", '\''),
+            ("&quot;", '"'),
+        ];
+        for (i, noise) in random_strings(2000).into_iter().enumerate() {
+            let mut state = i as u64;
+            let mut body = noise;
+            for _ in 0..i % 40 {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                body.push_str(SOUP[(state >> 33) as usize % SOUP.len()]);
+            }
+            let body = sanitize_str(&body);
+            let escaped = escape_markup(&body);
+            assert!(!escaped.contains(['<', '>', '"', '\'']), "{:?}", escaped);
+            // Every & starts an entity, and decoding gives back the text.
+            let mut decoded = String::new();
+            let mut rest = &*escaped;
+            while let Some(start) = rest.find('&') {
+                decoded.push_str(&rest[..start]);
+                rest = &rest[start..];
+                let &(entity, c) = ENTITIES
+                    .iter()
+                    .find(|(entity, _)| rest.starts_with(entity))
+                    .unwrap_or_else(|| panic!("bare & in {:?}", escaped));
+                decoded.push(c);
+                rest = &rest[entity.len()..];
+            }
+            decoded.push_str(rest);
+            assert_eq!(decoded, body);
+        }
     }
 
     #[test]
@@ -685,20 +6355,50 @@ mod tests {
         assert_eq!(sanitize_str("a\x15\n"), "a\u{FFFD}\n".to_owned());
     }
 
+    /// [`sanitize_str`] with a stand-in for the code point check, for tests
+    /// of everything else it does.  These do not depend on which code
+    /// points libqubes-pure or the Rust table accept.
+    fn sanitize_ascii(arg: &str) -> String {
+        sanitize_str_with(arg, |c| (0x20..0x7F).contains(&c)).0
+    }
+
+    #[test]
+    fn test_sanitize_line_breaks() {
+        assert_eq!(sanitize_ascii("a\r\nb\rc\n"), "a\nb\nc\n");
+        assert_eq!(sanitize_ascii("\r\r\n"), "\n\n");
+        assert_eq!(sanitize_ascii("a\x15\u{e9}\t"), "a\u{FFFD}\u{FFFD}\t");
+        // Replacement characters count towards the line length.
+        let line = str::repeat("\x15", MAX_CHARS_PER_LINE);
+        assert_eq!(
+            sanitize_ascii(&line),
+            str::repeat("\u{FFFD}", MAX_CHARS_PER_LINE) + "\n"
+        );
+    }
+
     #[test]
     fn test_too_many_lines() {
         let max_lines = str::repeat("a\n", 500);
-        assert_eq!(&sanitize_str(&*max_lines), &max_lines, "500 lines are fine");
         assert_eq!(
-            sanitize_str(&*(max_lines.clone() + &"a\n"[..])),
+            &sanitize_ascii(&*max_lines),
+            &max_lines,
+            "500 lines are fine"
+        );
+        assert_eq!(
+            sanitize_ascii(&*(max_lines.clone() + &"a\n"[..])),
             max_lines,
             "501 lines are not"
         );
+        let ascii = |c| (0x20..0x7F).contains(&c);
+        assert!(!sanitize_str_with(&max_lines, ascii).1);
+        assert!(sanitize_str_with(&(max_lines.clone() + "a"), ascii).1);
+        let long = str::repeat("a", MAX_LINES * MAX_CHARS_PER_LINE);
+        assert!(!sanitize_str_with(&long, ascii).1, "wrapping loses nothing");
+        assert!(sanitize_str_with(&(long + "a"), ascii).1);
     }
     #[test]
     fn test_too_long_lines() {
         let really_really_long = str::repeat("a", MAX_LINES * MAX_CHARS_PER_LINE);
-        let long_sanitized = sanitize_str(&*really_really_long);
+        let long_sanitized = sanitize_ascii(&*really_really_long);
         assert_eq!(long_sanitized.len(), (MAX_CHARS_PER_LINE + 1) * MAX_LINES);
         let cmp = vec![str::repeat("a", MAX_CHARS_PER_LINE); MAX_LINES].join("\n") + "\n";
         assert_eq!(long_sanitized.len(), cmp.len());
@@ -708,7 +6408,7 @@ mod tests {
     #[test]
     fn test_gigunda() {
         let really_really_long = str::repeat("a", MAX_LINES * 2 * MAX_CHARS_PER_LINE);
-        let long_sanitized = sanitize_str(&*really_really_long);
+        let long_sanitized = sanitize_ascii(&*really_really_long);
         assert_eq!(long_sanitized.len(), (MAX_CHARS_PER_LINE + 1) * MAX_LINES);
         let cmp = vec![str::repeat("a", MAX_CHARS_PER_LINE); MAX_LINES].join("\n") + "\n";
         assert_eq!(long_sanitized.len(), cmp.len());
@@ -818,4 +6518,233 @@ mod tests {
             "Image too large"
         );
     }
+
+    /// Extreme and random values for untrusted integers.  Tests are built
+    /// with overflow checks, so any arithmetic that wraps panics here.
+    fn untrusted_i32s(count: usize) -> Vec<i32> {
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut res = vec![i32::MIN, i32::MIN + 1, -2, -1, 0, 1, 2, 3, 4];
+        res.extend([i32::MAX - 1, i32::MAX, MAX_WIDTH, MAX_WIDTH + 1]);
+        res.extend([MAX_SIZE as i32 - 1, MAX_SIZE as i32, MAX_SIZE as i32 + 1]);
+        res.extend((0..count).map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as i32 >> (state >> 59)
+        }));
+        res
+    }
+
+    #[test]
+    fn test_untrusted_arithmetic() {
+        let values = untrusted_i32s(64);
+        // Buffers stay small: the checks must reject the huge sizes on their
+        // own, and images that pass are slow to convert.
+        for &width in &values {
+            for &rowstride in &values {
+                for &height in &values[..16] {
+                    for size in [0, 4, 1024] {
+                        let _ = serialize_image(ImageParameters {
+                            untrusted_width: width,
+                            untrusted_height: height,
+                            untrusted_rowstride: rowstride,
+                            untrusted_has_alpha: true,
+                            untrusted_bits_per_sample: 8,
+                            untrusted_channels: 4,
+                            untrusted_data: vec![0; size],
+                        });
+                    }
+                }
+            }
+        }
+        let mut config = config::Config::default();
+        for &max in &values {
+            config.default_expire_timeout = NonZeroU32::new(max.max(0).unsigned_abs());
+            config.max_expire_timeout = config.default_expire_timeout;
+            for &requested in &values {
+                let Some(requested) = ExpireTimeout::from_wire(requested) else {
+                    assert!(requested < -1);
+                    continue;
+                };
+                let timeout = config.effective_expire_timeout(requested).to_wire();
+                assert!(timeout >= -1 && timeout <= MAX_EXPIRE_TIMEOUT as i32);
+                if max > 0 {
+                    assert!(timeout > 0 && timeout <= max, "{} > {}", timeout, max)
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_expire_timeout() {
+        use bincode::Options as _;
+        let options = bincode::DefaultOptions::new()
+            .with_fixint_encoding()
+            .with_native_endian()
+            .reject_trailing_bytes();
+        assert_eq!(ExpireTimeout::from_wire(i32::MIN), None);
+        assert_eq!(ExpireTimeout::from_wire(-2), None);
+        assert_eq!(ExpireTimeout::from_wire(-1), Some(ExpireTimeout::Default));
+        assert_eq!(ExpireTimeout::from_wire(0), Some(ExpireTimeout::Never));
+        assert_eq!(
+            ExpireTimeout::millis(u32::MAX).to_wire(),
+            MAX_EXPIRE_TIMEOUT as i32
+        );
+        assert_eq!(ExpireTimeout::millis(0).to_wire(), 1);
+        for (requested, clamped) in [
+            (1, 1),
+            (60000, 60000),
+            (MAX_EXPIRE_TIMEOUT as i32, MAX_EXPIRE_TIMEOUT as i32),
+            (MAX_EXPIRE_TIMEOUT as i32 + 1, MAX_EXPIRE_TIMEOUT as i32),
+            (i32::MAX, MAX_EXPIRE_TIMEOUT as i32),
+        ] {
+            let timeout = ExpireTimeout::from_wire(requested).unwrap();
+            assert_eq!(timeout.to_wire(), clamped);
+        }
+        // The wire format is the i32 of the specification.
+        for timeout in [
+            ExpireTimeout::Default,
+            ExpireTimeout::Never,
+            ExpireTimeout::millis(1000),
+        ] {
+            let reply = ReplyMessage::ExpireTimeout {
+                id: 1,
+                expire_timeout: timeout,
+            };
+            let bytes = options.serialize(&reply).unwrap();
+            assert_eq!(&bytes[8..], &timeout.to_wire().to_ne_bytes()[..]);
+            match options.deserialize(&bytes).unwrap() {
+                ReplyMessage::ExpireTimeout { expire_timeout, .. } => {
+                    assert_eq!(expire_timeout, timeout)
+                }
+                reply => panic!("unexpected {:?}", reply),
+            }
+        }
+        let mut bytes = options
+            .serialize(&ReplyMessage::ExpireTimeout {
+                id: 1,
+                expire_timeout: ExpireTimeout::Default,
+            })
+            .unwrap();
+        bytes[8..].copy_from_slice(&(-2i32).to_ne_bytes());
+        assert!(options.deserialize::<ReplyMessage>(&bytes).is_err());
+        // So is the one a qube asks for, before dom0 looks at it.
+        let notification = NotificationBuilder::new("summary").build().unwrap();
+        let mut bytes = options.serialize(&notification).unwrap();
+        // The timeout is followed by the tag of the missing image.
+        let timeout = bytes.len() - 5..bytes.len() - 1;
+        assert_eq!(&bytes[timeout.clone()], &(-1i32).to_ne_bytes()[..]);
+        bytes[timeout].copy_from_slice(&(-2i32).to_ne_bytes());
+        assert!(options.deserialize::<Notification>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_image_overflow() {
+        let image = ImageParameters {
+            untrusted_width: 1,
+            untrusted_height: 1,
+            untrusted_rowstride: 4,
+            untrusted_has_alpha: true,
+            untrusted_bits_per_sample: 8,
+            untrusted_channels: 4,
+            untrusted_data: vec![0, 0, 0, 0],
+        };
+        let check = |image: ImageParameters| serialize_image(image).map(|_| ());
+        for channels in [-4, -1, i32::MIN, i32::MAX] {
+            assert_eq!(
+                check(ImageParameters {
+                    untrusted_channels: channels,
+                    ..image.clone()
+                }),
+                Err("Wrong number of channels")
+            );
+        }
+        for (width, height, rowstride) in [
+            (i32::MIN, 1, 4),
+            (1, i32::MIN, 4),
+            (1, 1, i32::MIN),
+            (-1, -1, -1),
+        ] {
+            assert_eq!(
+                check(ImageParameters {
+                    untrusted_width: width,
+                    untrusted_height: height,
+                    untrusted_rowstride: rowstride,
+                    ..image.clone()
+                }),
+                Err("Too small width, height, or stride")
+            );
+        }
+        // A stride that does not fit in the buffer, however large
+        for height in [1, 2, MAX_HEIGHT] {
+            assert_eq!(
+                check(ImageParameters {
+                    untrusted_height: height,
+                    untrusted_rowstride: i32::MAX,
+                    untrusted_data: vec![0; MAX_SIZE],
+                    ..image.clone()
+                }),
+                Err("Image too large")
+            );
+        }
+        // A single row may use all of the data
+        check(ImageParameters {
+            untrusted_width: MAX_WIDTH,
+            untrusted_rowstride: MAX_SIZE as i32,
+            untrusted_data: vec![0; MAX_SIZE],
+            ..image.clone()
+        })
+        .unwrap();
+        assert_eq!(
+            check(ImageParameters {
+                untrusted_width: MAX_WIDTH,
+                untrusted_rowstride: MAX_SIZE as i32 + 1,
+                untrusted_data: vec![0; MAX_SIZE],
+                ..image.clone()
+            }),
+            Err("Image too large")
+        );
+        // Exactly height * rowstride bytes are needed, one less is not enough
+        let (width, height) = (MAX_WIDTH, MAX_HEIGHT);
+        let rowstride = width * 4 + 3;
+        let size = (height * rowstride) as usize;
+        check(ImageParameters {
+            untrusted_width: width,
+            untrusted_height: height,
+            untrusted_rowstride: rowstride,
+            untrusted_data: vec![0; size],
+            ..image.clone()
+        })
+        .unwrap();
+        assert_eq!(
+            check(ImageParameters {
+                untrusted_width: width,
+                untrusted_height: height,
+                untrusted_rowstride: rowstride,
+                untrusted_data: vec![0; size - 1],
+                ..image.clone()
+            }),
+            Err("Image too large")
+        );
+        // A row must fit in the stride exactly
+        for (has_alpha, channels) in [(false, 3), (true, 4)] {
+            let image = ImageParameters {
+                untrusted_width: MAX_WIDTH,
+                untrusted_height: 1,
+                untrusted_rowstride: MAX_WIDTH * channels,
+                untrusted_has_alpha: has_alpha,
+                untrusted_channels: channels,
+                untrusted_data: vec![0; (MAX_WIDTH * channels) as usize],
+                ..image.clone()
+            };
+            check(image.clone()).unwrap();
+            assert_eq!(
+                check(ImageParameters {
+                    untrusted_rowstride: MAX_WIDTH * channels - 1,
+                    ..image
+                }),
+                Err("Row stride too small")
+            );
+        }
+    }
 }
@@ -1,10 +1,36 @@
+//! Translation between guest and host notification IDs.
+//!
+//! This module is public only so that it can be benchmarked.
+
 use core::num::NonZeroU32;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::Duration;
+// Follows the paused clock of simulation tests.
+use tokio::time::Instant;
+
+/// How long a removed mapping can still be used to translate host IDs.
+/// Signals from the daemon can arrive after the notification was closed,
+/// and should still reach the guest.
+pub const TOMBSTONE_GRACE: Duration = Duration::from_secs(10);
+
+/// Sizes of the maps, for the control interface.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Live mappings.
+    pub live: usize,
+    /// Removed mappings still used to translate late signals.
+    pub tombstones: usize,
+    /// Guest IDs waiting to be reused.
+    pub free_ids: usize,
+    /// How long ago the oldest tombstone was made.
+    pub oldest_tombstone: Option<Duration>,
+}
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 #[repr(transparent)]
 pub struct HostId(NonZeroU32);
 #[repr(transparent)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct GuestId(NonZeroU32);
 
 impl HostId {
@@ -31,10 +57,32 @@ impl From<HostId> for u32 {
     }
 }
 
-pub(super) struct Maps {
-    guest_to_host_map: std::collections::BTreeMap<NonZeroU32, NonZeroU32>,
-    host_to_guest_map: std::collections::BTreeMap<NonZeroU32, NonZeroU32>,
+/// Bidirectional map between live guest and host IDs.  Both directions are
+/// hash maps, so lookups are O(1) no matter how many notifications are
+/// live.  Released guest IDs are reused first, oldest first, once their
+/// tombstone expires.  Otherwise IDs are allocated sequentially, skipping
+/// the ones the guest chose itself by replacing notifications.  The
+/// counter never goes back, so each ID is skipped at most once, and
+/// allocation takes amortized constant time.
+///
+/// Once the counter reaches [`crate::FIRST_PROVISIONAL_ID`], the ID of the
+/// oldest tombstone is reused early, at the cost of no longer translating
+/// late signals for it.  If there is no tombstone either, allocation
+/// fails.
+#[derive(Debug)]
+pub struct Maps {
+    guest_to_host_map: HashMap<NonZeroU32, NonZeroU32>,
+    host_to_guest_map: HashMap<NonZeroU32, NonZeroU32>,
     last_id: NonZeroU32,
+    /// Recently removed (host ID, guest ID) pairs and when they were
+    /// removed, oldest first.
+    tombstones: VecDeque<(NonZeroU32, NonZeroU32, Instant)>,
+    /// Guest IDs in `tombstones`, so that allocation need not scan it.
+    tombstoned_guest_ids: HashSet<NonZeroU32>,
+    /// Guest IDs whose tombstone expired, in the order they were released.
+    free_ids: VecDeque<NonZeroU32>,
+    /// How long tombstones are kept.  Defaults to [`TOMBSTONE_GRACE`].
+    pub tombstone_grace: Duration,
 }
 
 impl Default for Maps {
@@ -43,32 +91,24 @@ impl Default for Maps {
             guest_to_host_map: Default::default(),
             host_to_guest_map: Default::default(),
             last_id: 1.try_into().expect("constant value"),
+            tombstones: VecDeque::new(),
+            tombstoned_guest_ids: HashSet::new(),
+            free_ids: VecDeque::new(),
+            tombstone_grace: TOMBSTONE_GRACE,
         }
     }
 }
 
-fn next(t: NonZeroU32) -> NonZeroU32 {
-    match t.into() {
-        u32::MAX => 1,
-        e => e + 1,
-    }
-    .try_into()
-    .expect("always produces nonzero value")
-}
-
 impl Maps {
-    pub(super) fn next_id(&mut self, id: HostId, guest_id: Option<GuestId>) -> GuestId {
+    /// Map host ID `id` to `guest_id`, or to a new guest ID if `None`.
+    /// Returns `None` if no guest ID is free.
+    pub fn next_id(&mut self, id: HostId, guest_id: Option<GuestId>) -> Option<GuestId> {
         if let Some(guest_id) = guest_id {
             self.guest_to_host_map.insert(guest_id.0, id.0);
             self.host_to_guest_map.insert(id.0, guest_id.0);
-            return guest_id;
+            return Some(guest_id);
         }
-        self.last_id = next(self.last_id);
-        while self.guest_to_host_map.contains_key(&self.last_id) {
-            self.last_id = next(self.last_id);
-        }
-        let last_id = self.last_id;
-        eprintln!("Next ID is {}, mapping to host ID {}", last_id, id.0);
+        let last_id = self.allocate()?;
         assert!(self
             .guest_to_host_map
             .insert(last_id, id.0.into())
@@ -79,32 +119,126 @@ impl Maps {
                 .is_none(),
             "notification daemon reused an ID without telling us"
         );
-        GuestId(
+        Some(GuestId(
             last_id
                 .try_into()
                 .expect("last ID set to a nonzero value above"),
-        )
+        ))
+    }
+
+    /// Pick an unused guest ID.
+    fn allocate(&mut self) -> Option<NonZeroU32> {
+        self.expire_tombstones();
+        // Do not reuse live or tombstoned IDs.  The guest can choose its own
+        // IDs by replacing a notification that no longer exists, so IDs on
+        // the free list might be live again.
+        let in_use = |this: &Self, id| {
+            this.guest_to_host_map.contains_key(&id) || this.tombstoned_guest_ids.contains(&id)
+        };
+        while let Some(id) = self.free_ids.pop_front() {
+            if !in_use(self, id) {
+                return Some(id);
+            }
+        }
+        // Every ID below the counter that is not in use is on the free
+        // list.  IDs from crate::FIRST_PROVISIONAL_ID up are reserved for
+        // the guest.
+        while u32::from(self.last_id) + 1 < crate::FIRST_PROVISIONAL_ID {
+            self.last_id = self.last_id.checked_add(1).expect("below the limit");
+            if !in_use(self, self.last_id) {
+                return Some(self.last_id);
+            }
+        }
+        while let Some((_, id, _)) = self.tombstones.pop_front() {
+            self.tombstoned_guest_ids.remove(&id);
+            if !in_use(self, id) {
+                return Some(id);
+            }
+        }
+        None
     }
 
-    pub(super) fn lookup_guest_id(&self, id: GuestId) -> Option<HostId> {
+    pub fn lookup_guest_id(&self, id: GuestId) -> Option<HostId> {
         self.guest_to_host_map.get(&id.0.into()).map(|&e| HostId(e))
     }
 
-    pub(super) fn lookup_host_id(&self, id: HostId) -> Option<GuestId> {
+    /// Translate an ID received from the guest.  Only IDs that map to a
+    /// live notification resolve: removed ones do not, and neither do
+    /// numbers that happen to be host IDs.
+    pub fn resolve_guest_id(&self, untrusted_id: u32) -> Option<HostId> {
+        self.lookup_guest_id(GuestId::new_less_safe(untrusted_id)?)
+    }
+
+    pub fn lookup_host_id(&self, id: HostId) -> Option<GuestId> {
         self.host_to_guest_map
             .get(&id.0.into())
             .map(|&e| GuestId(e))
     }
 
-    pub(super) fn remove_host_id(&mut self, id: HostId) -> Option<GuestId> {
+    /// Like [`Maps::lookup_host_id`], but also finds mappings removed less
+    /// than [`Maps::tombstone_grace`] ago.
+    pub fn lookup_host_id_or_tombstone(&self, id: HostId) -> Option<GuestId> {
+        self.lookup_host_id(id).or_else(|| {
+            let now = Instant::now();
+            self.tombstones
+                .iter()
+                .rev()
+                .find(|&&(h, _, time)| h == id.0 && now.duration_since(time) < self.tombstone_grace)
+                .map(|&(_, g, _)| GuestId(g))
+        })
+    }
+
+    pub fn remove_host_id(&mut self, id: HostId) -> Option<GuestId> {
+        self.expire_tombstones();
         self.host_to_guest_map.remove(&id.0.into()).map(|g| {
             assert_eq!(self.guest_to_host_map.remove(&g.into()), id.0.into());
+            self.tombstones.push_back((id.0, g, Instant::now()));
+            self.tombstoned_guest_ids.insert(g);
             GuestId(g)
         })
     }
 
-    pub(super) fn clear(&mut self) {
-        self.guest_to_host_map.clear();
+    /// Total number of entries kept, live or not, for leak tests.
+    #[cfg(test)]
+    pub fn footprint(&self) -> usize {
+        self.guest_to_host_map.len()
+            + self.host_to_guest_map.len()
+            + self.tombstones.len()
+            + self.tombstoned_guest_ids.len()
+            + self.free_ids.len()
+    }
+
+    /// Forget every mapping, for when the daemon restarted.  No tombstones
+    /// are kept: a new daemon reuses host IDs, and late signals for them
+    /// would reach the guest as signals for its old notifications.
+    pub fn clear(&mut self) {
+        self.tombstoned_guest_ids.clear();
+        let tombstones = self.tombstones.drain(..).map(|(_, g, _)| g);
+        self.free_ids.extend(tombstones);
         self.host_to_guest_map.clear();
+        self.free_ids
+            .extend(self.guest_to_host_map.drain().map(|(g, _)| g));
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            live: self.guest_to_host_map.len(),
+            tombstones: self.tombstones.len(),
+            free_ids: self.free_ids.len(),
+            oldest_tombstone: self.tombstones.front().map(|&(_, _, time)| time.elapsed()),
+        }
+    }
+
+    fn expire_tombstones(&mut self) {
+        let now = Instant::now();
+        while let Some(&(_, _, time)) = self.tombstones.front() {
+            if now.duration_since(time) < self.tombstone_grace {
+                break;
+            }
+            if let Some((_, g, _)) = self.tombstones.pop_front() {
+                self.tombstoned_guest_ids.remove(&g);
+                self.free_ids.push_back(g);
+            }
+        }
     }
 }
@@ -0,0 +1,292 @@
+//! The protocol version and what each minor version added.  Everything
+//! that depends on the version is derived from the constants here, so that
+//! the server, the client, and the schema cannot disagree about it.  The
+//! constants are also exported from the crate root.
+
+#[cfg(doc)]
+use crate::codec::{crc32, Bincode, Cbor, WireU32};
+use crate::codec::{Encoding, Framing};
+#[cfg(doc)]
+use crate::{Extension, Message, Notification, ReplyMessage, Request};
+use bitflags::bitflags;
+
+pub const MAJOR_VERSION: u16 = 1;
+pub const MINOR_VERSION: u16 = 21;
+
+/// Guest IDs at or above this value are never assigned by dom0.  The guest
+/// uses them as provisional IDs when a reply from dom0 is late.  Since
+/// version 2.
+pub const FIRST_PROVISIONAL_ID: u32 = 0xF000_0000;
+
+/// First minor version in which dom0 respects [`FIRST_PROVISIONAL_ID`].
+pub const PROVISIONAL_IDS_VERSION: u16 = 2;
+
+/// First minor version with [`ReplyMessage::ExpireTimeout`].
+pub const EXPIRE_TIMEOUT_VERSION: u16 = 3;
+
+/// First minor version with [`Notification::V2`] and
+/// [`Extension::Position`].
+pub const POSITION_VERSION: u16 = 4;
+
+/// First minor version with [`Extension::Progress`].
+pub const PROGRESS_VERSION: u16 = 5;
+
+/// First minor version with [`ReplyMessage::Sanitized`].
+pub const SANITIZED_VERSION: u16 = 6;
+
+/// First minor version in which the guest sends [`Request`]s instead of
+/// bare [`Message`]s, and can close notifications.
+pub const CLOSE_VERSION: u16 = 7;
+
+/// First minor version with [`ReplyMessage::Suppressed`].
+pub const SUPPRESSED_VERSION: u16 = 8;
+
+/// First minor version with [`Extension::Tag`].
+pub const TAG_VERSION: u16 = 9;
+
+/// First minor version with [`Request::Probe`].
+pub const PROBE_VERSION: u16 = 10;
+
+/// First minor version with [`ReplyMessage::Replied`].
+pub const REPLY_VERSION: u16 = 11;
+
+/// First minor version with [`ReplyMessage::DaemonCapabilities`].
+pub const DAEMON_CAPABILITIES_VERSION: u16 = 12;
+
+/// First minor version in which both sides send a [`Features`] mask right
+/// after the version.
+pub const FEATURE_MASK_VERSION: u16 = 13;
+
+/// First minor version with [`Features::CBOR`].
+pub const CBOR_VERSION: u16 = 14;
+
+/// First minor version with [`Extension::Locale`].
+pub const LOCALE_VERSION: u16 = 15;
+
+/// First minor version with [`Features::CHECKSUM`].
+pub const CHECKSUM_VERSION: u16 = 16;
+
+/// First minor version with [`Request::Ping`] and [`ReplyMessage::Pong`].
+pub const PING_VERSION: u16 = 17;
+
+/// First minor version with [`Request::Batch`] and [`ReplyMessage::Batch`].
+pub const BATCH_VERSION: u16 = 18;
+
+/// First minor version with [`Request::History`] and
+/// [`ReplyMessage::History`].
+pub const HISTORY_VERSION: u16 = 19;
+
+/// First minor version with [`Request::Drain`], [`ReplyMessage::Draining`]
+/// and [`ReplyMessage::Drained`].
+pub const DRAIN_VERSION: u16 = 20;
+
+/// First minor version with [`ReplyMessage::Reshown`].
+pub const RESHOWN_VERSION: u16 = 21;
+
+/// Names of the protocol features, and the first minor version with each.
+pub(crate) const FEATURES: &[(&str, u16)] = &[
+    ("open-url", 1),
+    ("provisional-ids", PROVISIONAL_IDS_VERSION),
+    ("expire-timeout", EXPIRE_TIMEOUT_VERSION),
+    ("position", POSITION_VERSION),
+    ("progress", PROGRESS_VERSION),
+    ("sanitized", SANITIZED_VERSION),
+    ("close", CLOSE_VERSION),
+    ("suppressed", SUPPRESSED_VERSION),
+    ("tag", TAG_VERSION),
+    ("probe", PROBE_VERSION),
+    ("inline-reply", REPLY_VERSION),
+    ("daemon-capabilities", DAEMON_CAPABILITIES_VERSION),
+    ("feature-mask", FEATURE_MASK_VERSION),
+    ("cbor", CBOR_VERSION),
+    ("locale", LOCALE_VERSION),
+    ("checksum", CHECKSUM_VERSION),
+    ("ping", PING_VERSION),
+    ("batch", BATCH_VERSION),
+    ("history", HISTORY_VERSION),
+    ("drain", DRAIN_VERSION),
+    ("reshown", RESHOWN_VERSION),
+];
+
+/// Names of the protocol features available with minor version `minor`,
+/// for diagnostics.
+pub fn features(minor: u16) -> Vec<&'static str> {
+    FEATURES
+        .iter()
+        .filter(|&&(_, version)| version <= minor)
+        .map(|&(name, _)| name)
+        .collect()
+}
+
+bitflags! {
+    /// Optional features.  With [`FEATURE_MASK_VERSION`] or later, each
+    /// side sends the features it is willing to use as a [`WireU32`] right
+    /// after the version, dom0 first, and only those both sent are used.
+    /// This lets either side turn off a feature its version implies, instead
+    /// of claiming an older version.  Unknown bits are ignored, so that bits
+    /// can be added without a new version.
+    #[derive(Default)]
+    pub struct Features: u32 {
+        /// Notifications may carry images.
+        const IMAGES       = 1 << 0;
+        /// The guest may close notifications with [`Request::Close`].
+        const CLOSE        = 1 << 1;
+        /// dom0 forwards inline replies with [`ReplyMessage::Replied`].
+        const INLINE_REPLY = 1 << 2;
+        /// Messages are encoded with [`Cbor`] instead of [`Bincode`].
+        const CBOR         = 1 << 3;
+        /// Each frame ends with a [`crc32`] of the frame.
+        const CHECKSUM     = 1 << 4;
+    }
+}
+
+/// Names of the features in [`FEATURES`] that can be turned off with a
+/// [`Features`] mask.
+const OPTIONAL: &[(&str, Features)] = &[
+    ("images", Features::IMAGES),
+    ("close", Features::CLOSE),
+    ("inline-reply", Features::INLINE_REPLY),
+    ("cbor", Features::CBOR),
+    ("checksum", Features::CHECKSUM),
+];
+
+impl Features {
+    /// The features minor version `minor` implies, which are all there is
+    /// without a mask.
+    pub fn implied(minor: u16) -> Self {
+        let mut features = Features::IMAGES;
+        features.set(Features::CLOSE, minor >= CLOSE_VERSION);
+        features.set(Features::INLINE_REPLY, minor >= REPLY_VERSION);
+        features.set(Features::CBOR, minor >= CBOR_VERSION);
+        features.set(Features::CHECKSUM, minor >= CHECKSUM_VERSION);
+        features
+    }
+}
+
+/// What the two sides of a connection agreed to use: a minor version, and
+/// the optional [`Features`] within it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NegotiatedFeatures {
+    minor: u16,
+    features: Features,
+}
+
+impl NegotiatedFeatures {
+    /// Everything minor version `minor` implies, as when no masks are
+    /// exchanged.
+    pub fn from_version(minor: u16) -> Self {
+        Self {
+            minor,
+            features: Features::implied(minor),
+        }
+    }
+    /// The features of minor version `minor` that this side offered as
+    /// `ours` and the other side as `untrusted_theirs`.
+    pub fn negotiate(minor: u16, ours: Features, untrusted_theirs: u32) -> Self {
+        Self {
+            minor,
+            features: Features::implied(minor)
+                & ours
+                & Features::from_bits_truncate(untrusted_theirs),
+        }
+    }
+    /// Whether masks are exchanged with minor version `minor`.
+    pub fn exchanges_masks(minor: u16) -> bool {
+        minor >= FEATURE_MASK_VERSION
+    }
+    pub fn minor(self) -> u16 {
+        self.minor
+    }
+    pub fn features(self) -> Features {
+        self.features
+    }
+    /// How messages are encoded.
+    pub fn encoding(self) -> Encoding {
+        match self.has(Features::CBOR) {
+            true => Encoding::Cbor,
+            false => Encoding::Bincode,
+        }
+    }
+    /// What follows the body of each frame.
+    pub fn framing(self) -> Framing {
+        match self.has(Features::CHECKSUM) {
+            true => Framing::Crc32,
+            false => Framing::Plain,
+        }
+    }
+    /// Whether all of `features` were agreed on.
+    pub fn has(self, features: Features) -> bool {
+        self.features.contains(features)
+    }
+    /// Names of the protocol features in use, for diagnostics: images,
+    /// which predate versioning, and those of the minor version, without
+    /// the optional ones that were turned off.
+    pub fn names(self) -> Vec<&'static str> {
+        let mut names = features(self.minor);
+        names.insert(0, "images");
+        names.retain(|name| {
+            OPTIONAL
+                .iter()
+                .all(|&(optional, feature)| optional != *name || self.has(feature))
+        });
+        names
+    }
+}
+
+pub const fn merge_versions(major: u16, minor: u16) -> u32 {
+    (major as u32) << 16 | (minor as u32)
+}
+
+pub const fn split_version(combined: u32) -> (u16, u16) {
+    ((combined >> 16) as _, combined as _)
+}
+
+/// First minor version with each enum variant, as `Enum::Variant`.  Every
+/// variant of every enum in the protocol must be listed, so that a new one
+/// cannot be added without saying which version it needs.
+pub const SINCE: &[(&str, u16)] = &[
+    ("Extension::Position", POSITION_VERSION),
+    ("Extension::Progress", PROGRESS_VERSION),
+    ("Extension::Tag", TAG_VERSION),
+    ("Extension::Locale", LOCALE_VERSION),
+    ("Notification::V1", 0),
+    ("Notification::V2", POSITION_VERSION),
+    ("ReplyMessage::Id", 0),
+    ("ReplyMessage::DBusError", 0),
+    ("ReplyMessage::UnknownError", 0),
+    ("ReplyMessage::Dismissed", 0),
+    ("ReplyMessage::ActionInvoked", 0),
+    ("ReplyMessage::ServerRestart", 0),
+    ("ReplyMessage::OpenUrl", 1),
+    ("ReplyMessage::ExpireTimeout", EXPIRE_TIMEOUT_VERSION),
+    ("ReplyMessage::Sanitized", SANITIZED_VERSION),
+    ("ReplyMessage::Suppressed", SUPPRESSED_VERSION),
+    ("ReplyMessage::Capabilities", PROBE_VERSION),
+    ("ReplyMessage::Replied", REPLY_VERSION),
+    (
+        "ReplyMessage::DaemonCapabilities",
+        DAEMON_CAPABILITIES_VERSION,
+    ),
+    ("ReplyMessage::Pong", PING_VERSION),
+    ("ReplyMessage::Batch", BATCH_VERSION),
+    ("ReplyMessage::History", HISTORY_VERSION),
+    ("ReplyMessage::Draining", DRAIN_VERSION),
+    ("ReplyMessage::Drained", DRAIN_VERSION),
+    ("ReplyMessage::Reshown", RESHOWN_VERSION),
+    ("Request::Notify", CLOSE_VERSION),
+    ("Request::Close", CLOSE_VERSION),
+    ("Request::Probe", PROBE_VERSION),
+    ("Request::Ping", PING_VERSION),
+    ("Request::Batch", BATCH_VERSION),
+    ("Request::History", HISTORY_VERSION),
+    ("Request::Drain", DRAIN_VERSION),
+    ("Outcome::Dismissed", HISTORY_VERSION),
+    ("Outcome::ActionInvoked", HISTORY_VERSION),
+    ("Outcome::Replied", HISTORY_VERSION),
+    ("Suppression::Muted", SUPPRESSED_VERSION),
+    ("Suppression::QuietHours", SUPPRESSED_VERSION),
+    ("Suppression::LogOnly", SUPPRESSED_VERSION),
+    ("Urgency::Low", 0),
+    ("Urgency::Normal", 0),
+    ("Urgency::Critical", 0),
+];
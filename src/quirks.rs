@@ -0,0 +1,104 @@
+//! Workarounds for notification daemons that do not behave as their
+//! capabilities suggest.
+//!
+//! Daemons are recognized by the name and specification version they
+//! return from `GetServerInformation`.  The configuration can turn the
+//! workarounds off, or apply those of a named daemon, for daemons that
+//! identify themselves wrongly.
+
+use crate::Capabilities;
+
+/// How to adjust notifications for a daemon.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quirks {
+    /// At most this many lines of body are sent.
+    pub max_lines: Option<usize>,
+    /// At most this many characters of body are sent.
+    pub max_body_chars: Option<usize>,
+    /// Whether only the first line of the summary is shown, so that the
+    /// lines must be joined.
+    pub single_line_summary: bool,
+    /// Capabilities the daemon advertises but does not handle properly.
+    pub disable_capabilities: Capabilities,
+    /// Hints the daemon ignores, which are not sent.
+    pub ignored_hints: &'static [&'static str],
+    /// Name of the hint carrying image data, which changed between versions
+    /// of the specification.
+    pub image_hint: &'static str,
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        NONE
+    }
+}
+
+/// Quirks of a daemon that is not in [`DAEMONS`].
+const NONE: Quirks = Quirks {
+    max_lines: None,
+    max_body_chars: None,
+    single_line_summary: false,
+    disable_capabilities: Capabilities::empty(),
+    ignored_hints: &[],
+    image_hint: "image-data",
+};
+
+/// Known daemons, by the name they return from `GetServerInformation`.
+const DAEMONS: &[(&str, Quirks)] = &[
+    (
+        // Spins at 100% CPU on long bodies, well before the limit of
+        // `sanitize_str`.
+        "xfce4-notifyd",
+        Quirks {
+            max_lines: Some(20),
+            ..NONE
+        },
+    ),
+    (
+        // Cuts the summary at the first line break, and does not keep
+        // notifications around once they are gone from the screen.
+        // Actions are only reachable through `dunstctl context`, which
+        // shows their sanitized labels, so they need no workaround.
+        "dunst",
+        Quirks {
+            single_line_summary: true,
+            disable_capabilities: Capabilities::PERSISTENCE,
+            ..NONE
+        },
+    ),
+    (
+        // Banners show only the start of the body, and the rest is easy to
+        // miss, so the body is cut where the truncation marker is still
+        // visible.  Notifications are placed by the shell, and progress is
+        // not shown.  Actions are not available on the lock screen, but
+        // the links are in the body too.
+        "gnome-shell",
+        Quirks {
+            max_lines: Some(6),
+            max_body_chars: Some(300),
+            ignored_hints: &["x", "y", "value"],
+            ..NONE
+        },
+    ),
+];
+
+/// Whether `name` is a daemon with known quirks.
+pub fn known(name: &str) -> bool {
+    DAEMONS.iter().any(|&(known, _)| known == name)
+}
+
+/// The quirks of the daemon called `name`, which implements version
+/// `spec_version` of the specification.
+pub fn lookup(name: &str, spec_version: &str) -> Quirks {
+    let mut quirks = DAEMONS
+        .iter()
+        .find(|&&(known, _)| known == name)
+        .map_or(NONE, |&(_, quirks)| quirks);
+    let mut version = spec_version.split('.').map(str::parse::<u32>);
+    quirks.image_hint = match (version.next(), version.next()) {
+        (Some(Ok(1)), Some(Ok(0))) => "icon_data",
+        (Some(Ok(1)), Some(Ok(1))) => "image_data",
+        _ => quirks.image_hint,
+    };
+    quirks
+}
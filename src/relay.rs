@@ -0,0 +1,68 @@
+//! Forwarding of critical notifications to an external relay, for setups
+//! where nobody watches the screen of dom0.
+//!
+//! The relay listens on a Unix stream socket or reads a FIFO.  Each
+//! notification is written as one line of tab-separated fields: the
+//! application name, the summary, and the body.  The fields are sanitized
+//! text with tabs and line breaks replaced by spaces, so a qube cannot add
+//! fields or records.  The relay must be running: if nothing is reading,
+//! the notification is not forwarded, so that the proxy never blocks on it.
+//!
+//! A record is at most [`MAX_RECORD`] bytes, so that it is written to a FIFO
+//! in one piece or not at all, and records from several connections never
+//! interleave.  A relay listening on a socket gets one record per
+//! connection, and drops it if the connection ends before the line does.
+
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+
+/// How long to wait for the relay to accept a record.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Largest record, line break included.  Writes to a pipe of up to this
+/// many bytes are atomic.
+pub(crate) const MAX_RECORD: usize = nix::libc::PIPE_BUF;
+
+/// The line written to the relay for a notification with the given fields,
+/// cut short to [`MAX_RECORD`] bytes.
+pub(crate) fn record(fields: &[&str]) -> String {
+    let mut res = String::new();
+    for (i, field) in fields.iter().enumerate() {
+        if i > 0 {
+            res.push('\t')
+        }
+        res.extend(field.chars().map(|c| match c {
+            '\t' | '\n' | '\r' => ' ',
+            c => c,
+        }))
+    }
+    let mut end = res.len().min(MAX_RECORD - 1);
+    while !res.is_char_boundary(end) {
+        end -= 1
+    }
+    res.truncate(end);
+    res.push('\n');
+    res
+}
+
+/// Write `record` to the relay at `path`.
+pub(crate) async fn send(path: &Path, record: &str) -> std::io::Result<()> {
+    let write = async {
+        if std::fs::metadata(path)?.file_type().is_fifo() {
+            // Fails instead of waiting if nothing is reading.
+            tokio::net::unix::pipe::OpenOptions::new()
+                .open_sender(path)?
+                .write_all(record.as_bytes())
+                .await
+        } else {
+            let mut stream = tokio::net::UnixStream::connect(path).await?;
+            stream.write_all(record.as_bytes()).await
+        }
+    };
+    match tokio::time::timeout(WRITE_TIMEOUT, write).await {
+        Ok(out) => out,
+        Err(_) => Err(std::io::ErrorKind::TimedOut.into()),
+    }
+}
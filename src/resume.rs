@@ -0,0 +1,68 @@
+//! Noticing that the system was suspended.
+//!
+//! The monotonic clock stops while the system is suspended, and the wall
+//! clock does not, so a wall clock that got ahead of the monotonic one means
+//! that the system was suspended in between.  Unlike logind's
+//! `PrepareForSleep`, this works the same in dom0 and in qubes without
+//! logind.  A wall clock set forward looks the same, which only costs a
+//! needless resync.
+
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often the clocks are compared.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The shortest gap between the clocks taken as a suspend, so that small
+/// adjustments of the wall clock are ignored.
+pub const MIN_SUSPEND: Duration = Duration::from_secs(5);
+
+/// Both clocks at the last check.
+#[derive(Debug, Clone, Copy)]
+pub struct ClockWatch {
+    wall: SystemTime,
+    monotonic: Instant,
+}
+
+impl Default for ClockWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ClockWatch {
+    pub fn new() -> Self {
+        Self::at(SystemTime::now(), Instant::now())
+    }
+    pub(crate) fn at(wall: SystemTime, monotonic: Instant) -> Self {
+        Self { wall, monotonic }
+    }
+    /// How long the system was suspended since the last check, if at least
+    /// [`MIN_SUSPEND`].
+    pub fn suspended(&mut self) -> Option<Duration> {
+        self.suspended_at(SystemTime::now(), Instant::now())
+    }
+    pub(crate) fn suspended_at(
+        &mut self,
+        wall: SystemTime,
+        monotonic: Instant,
+    ) -> Option<Duration> {
+        // A wall clock set backwards is not a suspend.
+        let wall_elapsed = wall.duration_since(self.wall).unwrap_or_default();
+        let monotonic_elapsed = monotonic.duration_since(self.monotonic);
+        *self = Self::at(wall, monotonic);
+        wall_elapsed
+            .checked_sub(monotonic_elapsed)
+            .filter(|&gap| gap >= MIN_SUSPEND)
+    }
+}
+
+/// Wait until the system resumes from a suspend, checking every
+/// [`CHECK_INTERVAL`], and return how long it was suspended.
+pub async fn next_resume(watch: &mut ClockWatch) -> Duration {
+    loop {
+        tokio::time::sleep(CHECK_INTERVAL).await;
+        if let Some(suspended) = watch.suspended() {
+            return suspended;
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Machine-readable description of the protocol, for implementations in
+//! other languages.
+//!
+//! The description is derived from the `Deserialize` implementations of the
+//! message types: a tracing deserializer records which fields and variants
+//! each type asks for, so it cannot drift from the types.  The one thing
+//! serde does not know, the version in which each variant appeared, is kept
+//! in [`SINCE`].
+
+use crate::{
+    Message, ReplyMessage, Request, CLOSE_VERSION, MAJOR_VERSION, MAX_MESSAGE_SIZE, MINOR_VERSION,
+};
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer as _, Visitor};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+type Error = de::value::Error;
+
+pub use crate::protocol::SINCE;
+
+/// How a value is encoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Format {
+    Bool,
+    U8,
+    U32,
+    U64,
+    I32,
+    Str,
+    Option(Box<Format>),
+    Seq(Box<Format>),
+    Tuple(Vec<Format>),
+    /// A struct or enum, described in [`Schema::containers`].
+    Named(&'static str),
+    /// Not traced yet.
+    Unknown,
+}
+
+/// The contents of an enum variant.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Variant {
+    Unit,
+    Newtype(Format),
+    Tuple(Vec<Format>),
+    Struct(Vec<(&'static str, Format)>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Container {
+    /// The fields, in the order they are encoded.
+    Struct(Vec<(&'static str, Format)>),
+    /// The variants, in the order of their index on the wire.  `None` for
+    /// variants that have not been traced yet.
+    Enum(Vec<(&'static str, Option<Variant>)>),
+}
+
+/// The structs and enums reachable from the traced types.
+#[derive(Debug, Default)]
+pub struct Schema {
+    pub containers: BTreeMap<&'static str, Container>,
+    /// How many times each enum was traced.  Each time, the next variant
+    /// is chosen, so that tracing often enough reaches all of them.
+    visits: BTreeMap<&'static str, usize>,
+}
+
+impl Schema {
+    /// Trace `T` and everything reachable from it.
+    pub fn trace<T: DeserializeOwned>(&mut self) -> Format {
+        // Each pass only reaches one variant of each enum.  Nothing in the
+        // protocol nests deeply enough to need more passes than this.
+        for _ in 0..1000 {
+            let mut format = Format::Unknown;
+            T::deserialize(Tracer {
+                schema: self,
+                format: &mut format,
+            })
+            .expect("protocol types can be traced");
+            if self.is_complete() {
+                return format;
+            }
+        }
+        panic!("tracing did not reach every variant")
+    }
+    fn is_complete(&self) -> bool {
+        self.containers.values().all(|container| match container {
+            Container::Struct(_) => true,
+            Container::Enum(variants) => variants.iter().all(|(_, variant)| variant.is_some()),
+        })
+    }
+}
+
+/// The version in which `variant` of `name` appeared.
+pub fn since(name: &str, variant: &str) -> Option<u16> {
+    SINCE
+        .iter()
+        .find(|&&(known, _)| known.split_once("::") == Some((name, variant)))
+        .map(|&(_, version)| version)
+}
+
+/// A deserializer that produces a placeholder value and records its format.
+struct Tracer<'a> {
+    schema: &'a mut Schema,
+    format: &'a mut Format,
+}
+
+/// Trace `len` values handed to `visitor` as a sequence.
+fn elements<'de, V: Visitor<'de>>(
+    schema: &mut Schema,
+    len: usize,
+    visitor: V,
+) -> Result<(V::Value, Vec<Format>), Error> {
+    let mut elements = Elements {
+        schema,
+        formats: vec![],
+        len,
+    };
+    let value = visitor.visit_seq(&mut elements)?;
+    Ok((value, elements.formats))
+}
+
+impl<'de, 'a> de::Deserializer<'de> for Tracer<'a> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Error> {
+        Err(de::Error::custom(
+            "only the types used by the protocol can be traced",
+        ))
+    }
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.format = Format::Bool;
+        visitor.visit_bool(false)
+    }
+    fn deserialize_u8<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.format = Format::U8;
+        visitor.visit_u8(0)
+    }
+    fn deserialize_u32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.format = Format::U32;
+        visitor.visit_u32(0)
+    }
+    fn deserialize_u64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.format = Format::U64;
+        visitor.visit_u64(0)
+    }
+    fn deserialize_i32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.format = Format::I32;
+        visitor.visit_i32(0)
+    }
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        *self.format = Format::Str;
+        visitor.visit_str("")
+    }
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.deserialize_str(visitor)
+    }
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let mut inner = Format::Unknown;
+        let value = visitor.visit_some(Tracer {
+            schema: self.schema,
+            format: &mut inner,
+        })?;
+        *self.format = Format::Option(Box::new(inner));
+        Ok(value)
+    }
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        let (value, mut formats) = elements(self.schema, 1, visitor)?;
+        *self.format = Format::Seq(Box::new(formats.pop().unwrap_or(Format::Unknown)));
+        Ok(value)
+    }
+    fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let (value, formats) = elements(self.schema, len, visitor)?;
+        *self.format = Format::Tuple(formats);
+        Ok(value)
+    }
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.deserialize_tuple(len, visitor)
+    }
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let (value, formats) = elements(&mut *self.schema, fields.len(), visitor)?;
+        let fields = fields.iter().copied().zip(formats).collect();
+        self.schema
+            .containers
+            .insert(name, Container::Struct(fields));
+        *self.format = Format::Named(name);
+        Ok(value)
+    }
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let visits = self.schema.visits.entry(name).or_default();
+        let index = *visits % variants.len();
+        *visits += 1;
+        self.schema.containers.entry(name).or_insert_with(|| {
+            Container::Enum(variants.iter().map(|&variant| (variant, None)).collect())
+        });
+        let mut shape = None;
+        let value = visitor.visit_enum(VariantTracer {
+            schema: &mut *self.schema,
+            index: index as u32,
+            shape: &mut shape,
+        })?;
+        if let Some(Container::Enum(variants)) = self.schema.containers.get_mut(name) {
+            variants[index].1 = shape;
+        }
+        *self.format = Format::Named(name);
+        Ok(value)
+    }
+
+    serde::forward_to_deserialize_any! {
+        i8 i16 i64 i128 u16 u128 f32 f64 char bytes byte_buf unit unit_struct
+        map identifier ignored_any
+    }
+}
+
+/// The elements of a sequence, tuple or struct being traced.
+struct Elements<'a> {
+    schema: &'a mut Schema,
+    formats: Vec<Format>,
+    len: usize,
+}
+
+impl<'de, 'a> de::SeqAccess<'de> for Elements<'a> {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, Error> {
+        if self.formats.len() == self.len {
+            return Ok(None);
+        }
+        let mut format = Format::Unknown;
+        let value = seed.deserialize(Tracer {
+            schema: self.schema,
+            format: &mut format,
+        })?;
+        self.formats.push(format);
+        Ok(Some(value))
+    }
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.len - self.formats.len())
+    }
+}
+
+/// The chosen variant of an enum being traced.
+struct VariantTracer<'a> {
+    schema: &'a mut Schema,
+    index: u32,
+    shape: &'a mut Option<Variant>,
+}
+
+impl<'de, 'a> de::EnumAccess<'de> for VariantTracer<'a> {
+    type Error = Error;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(self, seed: V) -> Result<(V::Value, Self), Error> {
+        let value = seed.deserialize(self.index.into_deserializer())?;
+        Ok((value, self))
+    }
+}
+
+impl<'de, 'a> de::VariantAccess<'de> for VariantTracer<'a> {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<(), Error> {
+        *self.shape = Some(Variant::Unit);
+        Ok(())
+    }
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, Error> {
+        let mut format = Format::Unknown;
+        let value = seed.deserialize(Tracer {
+            schema: self.schema,
+            format: &mut format,
+        })?;
+        *self.shape = Some(Variant::Newtype(format));
+        Ok(value)
+    }
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+        let (value, formats) = elements(self.schema, len, visitor)?;
+        *self.shape = Some(Variant::Tuple(formats));
+        Ok(value)
+    }
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        let (value, formats) = elements(self.schema, fields.len(), visitor)?;
+        *self.shape = Some(Variant::Struct(
+            fields.iter().copied().zip(formats).collect(),
+        ));
+        Ok(value)
+    }
+}
+
+fn format_json(format: &Format) -> String {
+    match format {
+        Format::Bool => "\"bool\"".to_owned(),
+        Format::U8 => "\"u8\"".to_owned(),
+        Format::U32 => "\"u32\"".to_owned(),
+        Format::U64 => "\"u64\"".to_owned(),
+        Format::I32 => "\"i32\"".to_owned(),
+        Format::Str => "\"string\"".to_owned(),
+        Format::Option(inner) => format!("{{\"option\": {}}}", format_json(inner)),
+        Format::Seq(inner) => format!("{{\"seq\": {}}}", format_json(inner)),
+        Format::Tuple(formats) => format!(
+            "{{\"tuple\": [{}]}}",
+            formats
+                .iter()
+                .map(format_json)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        Format::Named(name) => format!("{{\"type\": \"{}\"}}", name),
+        Format::Unknown => "null".to_owned(),
+    }
+}
+
+fn fields_json(fields: &[(&str, Format)]) -> String {
+    let fields: Vec<_> = fields
+        .iter()
+        .map(|(name, format)| {
+            format!(
+                "{{\"name\": \"{}\", \"format\": {}}}",
+                name,
+                format_json(format)
+            )
+        })
+        .collect();
+    format!("[{}]", fields.join(", "))
+}
+
+/// The description of the protocol, as JSON.
+///
+/// Messages are bincode with fixed-width integers in the byte order of the
+/// host, which is little-endian on every host Qubes OS runs on.  Enums are
+/// encoded as a `u32` variant index followed by the fields of the variant,
+/// options as a `u8` tag, and strings and sequences as a `u64` length.
+pub fn json() -> String {
+    let mut schema = Schema::default();
+    let request = schema.trace::<Request>();
+    let message = schema.trace::<Message>();
+    let reply = schema.trace::<ReplyMessage>();
+    let mut out = String::new();
+    out.push_str("{\n");
+    writeln!(
+        out,
+        "  \"version\": {{\"major\": {}, \"minor\": {}}},",
+        MAJOR_VERSION, MINOR_VERSION
+    )
+    .unwrap();
+    out.push_str(concat!(
+        "  \"framing\": \"Each side first sends ((major << 16) | minor) as a little-endian u32. ",
+        "From minor version 13, each side then sends a little-endian u32 mask of optional ",
+        "features. Each message is then a little-endian u32 length followed by that many bytes ",
+        "in the encoding. If both sides set the checksum feature, the bytes are followed by the ",
+        "CRC-32 (as in zlib) of the length and the bytes, as a little-endian u32.\",\n",
+    ));
+    out.push_str(concat!(
+        "  \"encoding\": \"bincode with fixed-width little-endian integers. Enums are a u32 ",
+        "variant index followed by the fields of the variant, options a u8 tag, and strings and ",
+        "sequences a u64 length. If both sides set the cbor feature, CBOR instead, with structs ",
+        "as maps keyed by field name and enums as maps from variant name to contents.\",\n",
+    ));
+    writeln!(out, "  \"max_message_size\": {},", MAX_MESSAGE_SIZE).unwrap();
+    writeln!(out, "  \"guest_to_dom0\": [").unwrap();
+    writeln!(
+        out,
+        "    {{\"since\": {}, \"format\": {}}},",
+        CLOSE_VERSION,
+        format_json(&request)
+    )
+    .unwrap();
+    writeln!(
+        out,
+        "    {{\"since\": 0, \"until\": {}, \"format\": {}}}",
+        CLOSE_VERSION - 1,
+        format_json(&message)
+    )
+    .unwrap();
+    out.push_str("  ],\n");
+    writeln!(out, "  \"dom0_to_guest\": {},", format_json(&reply)).unwrap();
+    out.push_str("  \"types\": {");
+    let mut first = true;
+    for (name, container) in &schema.containers {
+        out.push_str(if first { "\n" } else { ",\n" });
+        first = false;
+        match container {
+            Container::Struct(fields) => write!(
+                out,
+                "    \"{}\": {{\"struct\": {}}}",
+                name,
+                fields_json(fields)
+            )
+            .unwrap(),
+            Container::Enum(variants) => {
+                let variants: Vec<_> = variants
+                    .iter()
+                    .enumerate()
+                    .map(|(index, (variant, shape))| {
+                        let mut json = format!("{{\"name\": \"{}\", \"index\": {}", variant, index);
+                        if let Some(since) = since(name, variant) {
+                            write!(json, ", \"since\": {}", since).unwrap();
+                        }
+                        match shape {
+                            Some(Variant::Unit) | None => {}
+                            Some(Variant::Newtype(format)) => {
+                                write!(json, ", \"value\": {}", format_json(format)).unwrap()
+                            }
+                            Some(Variant::Tuple(formats)) => write!(
+                                json,
+                                ", \"value\": {}",
+                                format_json(&Format::Tuple(formats.clone()))
+                            )
+                            .unwrap(),
+                            Some(Variant::Struct(fields)) => {
+                                write!(json, ", \"fields\": {}", fields_json(fields)).unwrap()
+                            }
+                        }
+                        json.push('}');
+                        json
+                    })
+                    .collect();
+                write!(
+                    out,
+                    "    \"{}\": {{\"enum\": [\n      {}\n    ]}}",
+                    name,
+                    variants.join(",\n      ")
+                )
+                .unwrap()
+            }
+        }
+    }
+    out.push_str("\n  }\n}\n");
+    out
+}
@@ -0,0 +1,95 @@
+//! Limits on the messages sent to a qube because of signals from the
+//! notification daemon.  A misbehaving daemon can emit `ActionInvoked` and
+//! `NotificationReplied` as often as it likes, and each signal would
+//! otherwise become a frame to the qube, and possibly a link opened there.
+//! Replies count as actions, with their text as the action.
+//!
+//! `NotificationClosed` needs no limit: a notification is forgotten once it
+//! is dismissed, so each one is reported at most once, and dropping a
+//! dismissal would leave the qube thinking that it is still shown.
+
+use crate::config::Config;
+use std::collections::{HashMap, VecDeque};
+use std::num::NonZeroU32;
+use std::time::{Duration, Instant};
+
+/// Window over which messages are counted.
+const SIGNAL_WINDOW: Duration = Duration::from_secs(1);
+
+/// Number of remembered actions above which those too old to be coalesced
+/// are forgotten.
+const MAX_REMEMBERED_ACTIONS: usize = 256;
+
+/// Decides which actions are reported to one qube.
+#[derive(Debug)]
+pub struct SignalLimiter {
+    per_second: Option<NonZeroU32>,
+    coalesce: Duration,
+    /// When recent actions were reported, oldest first.
+    sent: VecDeque<Instant>,
+    /// The last action reported for each notification, and when.
+    last_action: HashMap<u32, (String, Instant)>,
+    /// Whether actions are being dropped, so that a storm is logged once.
+    dropping: bool,
+    /// Actions dropped so far.
+    dropped: u64,
+}
+
+impl SignalLimiter {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            per_second: config.max_signals_per_second,
+            coalesce: config.signal_coalesce,
+            sent: VecDeque::new(),
+            last_action: HashMap::new(),
+            dropping: false,
+            dropped: 0,
+        }
+    }
+    /// Whether `action`, invoked on notification `id` at `now`, is reported
+    /// to the qube.  The same action on the same notification is reported
+    /// once per [`Config::signal_coalesce`], and no more than
+    /// [`Config::max_signals_per_second`] actions are reported in total.
+    pub fn action(&mut self, id: u32, action: &str, now: Instant) -> bool {
+        let repeated = matches!(
+            self.last_action.get(&id),
+            Some((last, at)) if last == action && now.duration_since(*at) < self.coalesce
+        );
+        while let Some(&oldest) = self.sent.front() {
+            if now.duration_since(oldest) < SIGNAL_WINDOW {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        let limited = self
+            .per_second
+            .is_some_and(|limit| self.sent.len() >= limit.get() as usize);
+        if repeated || limited {
+            self.dropped += 1;
+            if !std::mem::replace(&mut self.dropping, true) {
+                eprintln!("Notification daemon sends actions too fast, dropping some");
+            }
+            return false;
+        }
+        if std::mem::replace(&mut self.dropping, false) {
+            eprintln!("{} actions dropped so far", self.dropped);
+        }
+        if self.last_action.len() >= MAX_REMEMBERED_ACTIONS {
+            let coalesce = self.coalesce;
+            self.last_action
+                .retain(|_, (_, at)| now.duration_since(*at) < coalesce);
+        }
+        self.last_action.insert(id, (action.to_owned(), now));
+        self.sent.push_back(now);
+        true
+    }
+    /// Forget notification `id`, which was dismissed.
+    pub fn dismissed(&mut self, id: u32) {
+        self.last_action.remove(&id);
+    }
+    /// Number of actions dropped so far.
+    #[cfg(test)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped
+    }
+}
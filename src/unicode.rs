@@ -0,0 +1,25 @@
+//! Pure-Rust replacement for `qubes_pure_code_point_safe_for_display`, used
+//! when the crate is built without the `qubes-pure` feature.
+//!
+//! This must never accept a code point the C implementation rejects.  It is
+//! deliberately stricter: it rejects combining marks, which can be stacked
+//! without limit, and code points assigned after the Unicode version the
+//! table was generated from.  `test_sanitizer_differential` checks the
+//! whole code space against the C implementation.
+
+mod table;
+
+/// Whether `code_point` is safe to display.
+pub(crate) fn code_point_safe_for_display(code_point: u32) -> bool {
+    table::RANGES
+        .binary_search_by(|&(start, end)| {
+            if end < code_point {
+                std::cmp::Ordering::Less
+            } else if start > code_point {
+                std::cmp::Ordering::Greater
+            } else {
+                std::cmp::Ordering::Equal
+            }
+        })
+        .is_ok()
+}
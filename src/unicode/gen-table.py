@@ -0,0 +1,36 @@
+#!/usr/bin/python3
+# Generate table.rs: the code points the pure-Rust sanitizer accepts.
+#
+# Usage: python3 src/unicode/gen-table.py > src/unicode/table.rs
+#
+# Only letters, numbers, punctuation, symbols, and spaces are accepted.
+# Controls, format characters, separators other than spaces, marks,
+# surrogates, private use, and unassigned code points are rejected, as is
+# everything from plane 3 up.  Whatever Unicode version Python ships is
+# used; code points assigned later are rejected, which is safe.
+import unicodedata
+
+ALLOWED = set("Lu Ll Lt Lm Lo Nd Nl No Pc Pd Ps Pe Pi Pf Po Sm Sc Sk So Zs".split())
+LIMIT = 0x30000
+
+ranges = []
+start = None
+for c in range(LIMIT):
+    allowed = unicodedata.category(chr(c)) in ALLOWED
+    if allowed and start is None:
+        start = c
+    elif not allowed and start is not None:
+        ranges.append((start, c - 1))
+        start = None
+if start is not None:
+    ranges.append((start, LIMIT - 1))
+
+print("// Generated by gen-table.py from Unicode %s.  Do not edit."
+      % unicodedata.unidata_version)
+print()
+print("/// Inclusive ranges of accepted code points, sorted and disjoint.")
+print("#[rustfmt::skip]")
+print("pub(super) const RANGES: &[(u32, u32)] = &[")
+for i in range(0, len(ranges), 4):
+    print("    " + " ".join("(0x%X, 0x%X)," % r for r in ranges[i:i + 4]))
+print("];")